@@ -0,0 +1,11 @@
+fn main() {
+    // the privval gRPC service (its messages are the ones of `tendermint-proto`)
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/privval_service.proto");
+        tonic_build::configure()
+            .extern_path(".tendermint.privval", "::tendermint_proto::privval")
+            .compile(&["proto/privval_service.proto"], &["proto"])
+            .expect("privval gRPC service compiled");
+    }
+}
@@ -1,15 +1,27 @@
-use tracing::Level;
+use std::thread;
 use tracing::{error, info, warn};
+use tracing::{Level, Metadata, Subscriber};
 use tracing_subscriber::fmt;
-use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::layer::{Context, SubscriberExt};
 use vsock::{SockAddr, VsockListener};
 
+use tmkms_light::metrics::METRICS_LOG_TARGET;
 use tmkms_nitro_helper::tracing_layer::Layer;
 use tmkms_nitro_helper::VSOCK_HOST_CID;
 use tracing_subscriber::filter::LevelFilter;
 
 mod nitro;
 
+/// Filters the log records by the log level, except for the metric events
+/// (they are forwarded to the helper over the log channel whatever the log level)
+struct LogFilter(LevelFilter);
+
+impl<S: Subscriber> tracing_subscriber::Layer<S> for LogFilter {
+    fn enabled(&self, metadata: &Metadata<'_>, _ctx: Context<'_, S>) -> bool {
+        metadata.target() == METRICS_LOG_TARGET || *metadata.level() <= self.0
+    }
+}
+
 fn main() {
     let mut env_args = std::env::args();
     let port = env_args
@@ -34,7 +46,7 @@ fn main() {
             }
         })
         .unwrap_or_else(|| Level::INFO);
-    let log_layer = LevelFilter::from(log_level);
+    let log_layer = LogFilter(LevelFilter::from(log_level));
     let layer = Layer::new(VSOCK_HOST_CID, log_server_port);
     let fmt_layer = fmt::layer().with_target(false);
     let layered = tracing_subscriber::registry()
@@ -56,9 +68,13 @@ fn main() {
         match conn {
             Ok(stream) => {
                 info!("got connection on {:?}", addr);
-                if let Err(e) = nitro::entry(stream) {
-                    error!("io error {}", e);
-                }
+                // each chain pushes its own config,
+                // so every connection gets separate signing sessions (one per node)
+                thread::spawn(move || {
+                    if let Err(e) = nitro::entry(stream) {
+                        error!("io error {}", e);
+                    }
+                });
             }
             Err(e) => {
                 warn!("connection error {}", e);
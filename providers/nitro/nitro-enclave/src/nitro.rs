@@ -3,104 +3,137 @@ mod state;
 
 use anomaly::format_err;
 use ed25519_dalek as ed25519;
-use ed25519_dalek::Keypair;
 use nsm_driver::{nsm_exit, nsm_init, nsm_process_request};
 use nsm_io::{Request, Response};
-use rand_core::OsRng;
 use serde_bytes::ByteBuf;
 use std::io;
+use std::net::Shutdown;
 use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
-use subtle::ConstantTimeEq;
-use tendermint::node::Id;
-use tendermint_p2p::secret_connection::{self, PublicKey, SecretConnection};
-use tmkms_light::chain::state::PersistStateSync;
+use tendermint::{chain, net};
+use tendermint_p2p::secret_connection::PublicKey;
+use tmkms_light::admin::{self, SessionControl};
+use tmkms_light::chain::state::StateAuthenticator;
 use tmkms_light::config::validator::ValidatorConfig;
-use tmkms_light::connection::{Connection, PlainConnection};
+use tmkms_light::connection::{manager::Stream, ConnectionManager, ShutdownHandle};
 use tmkms_light::error::{
     Error,
     ErrorKind::{AccessError, InvalidKey, IoError, ParseError},
 };
+use tmkms_light::metrics::TracingSink;
+use tmkms_light::session::{Session, SharedState};
+use tmkms_light::signer::{generate_secret_key, signer_from_secret_key, ConsensusSigner};
 use tmkms_light::utils::{read_u16_payload, write_u16_payload};
 use tmkms_nitro_helper::{
-    NitroConfig, NitroKeygenResponse, NitroRequest, NitroResponse, VSOCK_HOST_CID,
+    NitroKeygenResponse, NitroNodeConfig, NitroRequest, NitroResponse, VSOCK_HOST_CID,
 };
 use tracing::{error, info, trace, warn};
 use vsock::{SockAddr, VsockStream};
-use zeroize::{Zeroize, Zeroizing};
+use zeroize::Zeroizing;
 
-fn get_secret_connection(
-    vsock_port: u32,
-    identity_key: &ed25519::Keypair,
-    peer_id: Option<Id>,
-) -> io::Result<Box<dyn Connection>> {
-    let addr = SockAddr::new_vsock(VSOCK_HOST_CID, vsock_port);
-    let socket = vsock::VsockStream::connect(&addr)?;
-    info!("KMS node ID: {}", PublicKey::from(identity_key));
-    // the `Clone` is not derived for Keypair
-    // TODO: https://github.com/dalek-cryptography/ed25519-dalek/issues/76
-    let identity_key = ed25519::Keypair::from_bytes(&identity_key.to_bytes()).unwrap();
-    let connection = SecretConnection::new(socket, identity_key, secret_connection::Version::V0_34)
-        .map_err(|e| {
-            error!("secret connection failed: {}", e);
-            io::Error::from(io::ErrorKind::Other)
-        })?;
-    let actual_peer_id = connection.remote_pubkey().peer_id();
+/// whether a plain state file (with signed messages) is accepted when no marker exists:
+/// only in a build with the `migrate-plain-state` feature (which has its own PCR0),
+/// so the helper can't decide it
+const ALLOW_PLAIN_STATE: bool = cfg!(feature = "migrate-plain-state");
 
-    // TODO: https://github.com/informalsystems/tendermint-rs/issues/786
-    if let Some(expected_peer_id) = peer_id {
-        if expected_peer_id.ct_eq(&actual_peer_id).unwrap_u8() == 0 {
-            error!(
-                "(vsock port {}): validator peer ID mismatch! (expected {}, got {})",
-                vsock_port, expected_peer_id, actual_peer_id
-            );
-            return Err(io::Error::from(io::ErrorKind::Other));
-        }
+/// validator connection over vsock (which can be shut down from another thread)
+struct ValidatorStream(VsockStream);
+
+impl io::Read for ValidatorStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        io::Read::read(&mut self.0, buf)
     }
-    info!("connected to validator successfully");
+}
 
-    if peer_id.is_none() {
-        // TODO: https://github.com/informalsystems/tendermint-rs/issues/786
-        warn!(
-            "unverified validator peer ID! ({})",
-            connection.remote_pubkey().peer_id()
-        );
+impl io::Write for ValidatorStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::Write::write(&mut self.0, buf)
     }
 
-    Ok(Box::new(connection))
+    fn flush(&mut self) -> io::Result<()> {
+        io::Write::flush(&mut self.0)
+    }
 }
 
-/// keeps retrying with approx. 1 sec sleep until it manages to connect to tendermint privval endpoint
-pub fn get_connection(
-    config: &NitroConfig,
-    id_keypair: Option<&ed25519::Keypair>,
-) -> Box<dyn Connection> {
-    loop {
-        let conn: io::Result<Box<dyn Connection>> = if let Some(ikp) = id_keypair {
-            get_secret_connection(config.enclave_tendermint_conn, ikp, config.peer_id)
-        } else {
-            let addr = SockAddr::new_vsock(VSOCK_HOST_CID, config.enclave_tendermint_conn);
-            if let Ok(socket) = vsock::VsockStream::connect(&addr) {
-                trace!("tendermint vsock port: {}", config.enclave_tendermint_conn);
-                trace!("tendermint peer addr: {:?}", socket.peer_addr());
-                trace!("tendermint local addr: {:?}", socket.local_addr());
-                trace!("tendermint fd: {}", socket.as_raw_fd());
-                info!("connected to validator successfully");
-                let plain_conn = PlainConnection::new(socket);
-                Ok(Box::new(plain_conn))
-            } else {
-                warn!("vsock failed to connect to validator");
-                Err(io::ErrorKind::Other.into())
-            }
-        };
-        if let Err(e) = conn {
-            error!("tendermint connection error {:?}", e);
-            thread::sleep(Duration::new(1, 0));
-        } else {
-            return conn.unwrap();
-        }
+impl Stream for ValidatorStream {
+    fn shutdown_handle(&self) -> Option<ShutdownHandle> {
+        let socket = self.0.try_clone().ok()?;
+        Some(Arc::new(move || socket.shutdown(Shutdown::Both)))
+    }
+}
+
+/// connections to tendermint privval endpoint over the vsock port
+/// forwarded by the helper (with the secret connection if the identity key is set),
+/// retried with backoff
+fn connection_manager(
+    config: &NitroNodeConfig,
+    id_keypair: Option<ed25519::Keypair>,
+) -> Result<ConnectionManager, Error> {
+    let vsock_port = config.enclave_tendermint_conn;
+    if let Some(id_keypair) = &id_keypair {
+        info!("KMS node ID: {}", PublicKey::from(id_keypair));
     }
+    // the address is only shown in logs: the streams are always opened to the vsock port
+    let address = net::Address::Unix {
+        path: format!("vsock:{}", vsock_port).into(),
+    };
+    let mut manager = ConnectionManager::new(address, id_keypair)?;
+    manager.set_allowed_peer_ids(config.peer_id.into_iter().collect());
+    manager.set_protocol_version(config.protocol_version);
+    manager.set_transport(Box::new(
+        move |_: &net::Address, _: Option<Duration>| -> io::Result<Box<dyn Stream>> {
+            let addr = SockAddr::new_vsock(VSOCK_HOST_CID, vsock_port);
+            let socket = vsock::VsockStream::connect(&addr)?;
+            trace!("tendermint vsock port: {}", vsock_port);
+            trace!("tendermint peer addr: {:?}", socket.peer_addr());
+            trace!("tendermint local addr: {:?}", socket.local_addr());
+            trace!("tendermint fd: {}", socket.as_raw_fd());
+            Ok(Box::new(ValidatorStream(socket)))
+        },
+    ));
+    Ok(manager)
+}
+
+/// the Ed25519 identity keypair from its secret key bytes
+fn identity_keypair(id_key_bytes: &[u8]) -> Result<ed25519::Keypair, Error> {
+    let id_secret = ed25519::SecretKey::from_bytes(id_key_bytes)
+        .map_err(|e| format_err!(InvalidKey, "invalid Ed25519 key: {}", e))?;
+    let id_public = ed25519::PublicKey::from(&id_secret);
+    Ok(ed25519::Keypair {
+        secret: id_secret,
+        public: id_public,
+    })
+}
+
+/// connects to one of the validator's nodes and handles its requests
+/// (with the signer and the double-sign state shared by all nodes)
+fn run_session(
+    chain_id: chain::Id,
+    node: NitroNodeConfig,
+    id_keypair: Option<ed25519::Keypair>,
+    signer: Arc<Box<dyn ConsensusSigner>>,
+    shared_state: Arc<Mutex<SharedState<state::StateHolder>>>,
+    control: Arc<SessionControl>,
+) -> Result<(), Error> {
+    let mut manager = connection_manager(&node, id_keypair)?;
+    let conn = manager.connect()?;
+    let mut session = Session::new_shared(
+        ValidatorConfig {
+            chain_id,
+            max_height: node.max_height,
+            idle_timeout: node.idle_timeout,
+            protocol_version: node.protocol_version,
+        },
+        conn,
+        signer,
+        shared_state,
+    );
+    // metric events reach the host over the log channel
+    session.set_metrics(Arc::new(TracingSink));
+    session.set_control(control);
+    session.run(&mut manager)
 }
 
 /// a simple req-rep handling loop
@@ -121,11 +154,8 @@ pub fn entry(mut stream: VsockStream) -> Result<(), Error> {
                 )
                 .map_err(|_e| format_err!(AccessError, "failed to decrypt key"))?,
             );
-            let secret = ed25519::SecretKey::from_bytes(&*key_bytes)
-                .map_err(|e| format_err!(InvalidKey, "invalid Ed25519 key: {}", e))?;
-            let public = ed25519::PublicKey::from(&secret);
-            let keypair = ed25519::Keypair { secret, public };
-            let id_keypair = if let Some(ref ciphertext) = config.sealed_id_key {
+            let signer = signer_from_secret_key(config.key_type, &*key_bytes)?;
+            let id_key_bytes = if let Some(ref ciphertext) = config.sealed_id_key {
                 let id_key_bytes = Zeroizing::new(
                     aws_ne_sys::kms_decrypt(
                         config.aws_region.as_bytes(),
@@ -136,45 +166,64 @@ pub fn entry(mut stream: VsockStream) -> Result<(), Error> {
                     )
                     .map_err(|_e| format_err!(AccessError, "failed to decrypt key"))?,
                 );
-                let id_secret = ed25519::SecretKey::from_bytes(&*id_key_bytes)
-                    .map_err(|e| format_err!(InvalidKey, "invalid Ed25519 key: {}", e))?;
-                let id_public = ed25519::PublicKey::from(&id_secret);
-                let id_keypair = ed25519::Keypair {
-                    secret: id_secret,
-                    public: id_public,
-                };
-                Some(id_keypair)
+                Some(id_key_bytes)
             } else {
                 None
             };
-            let mut state_holder = state::StateHolder::new(config.enclave_state_port)
-                .map_err(|_e| format_err!(IoError, "failed get state connection"))?;
+            let authenticator = StateAuthenticator::new(&*key_bytes, config.chain_id.clone());
+            let mut state_holder =
+                state::StateHolder::new(config.enclave_state_port, authenticator)
+                    .map_err(|_e| format_err!(IoError, "failed get state connection"))?;
             let state = state_holder
-                .load_state()
-                .map_err(|_e| format_err!(IoError, "failed to load initial state"))?;
-            let conn: Box<dyn Connection> = get_connection(&config, id_keypair.as_ref());
-            let mut session = tmkms_light::session::Session::new(
-                ValidatorConfig {
-                    chain_id: config.chain_id.clone(),
-                    max_height: config.max_height,
-                },
-                conn,
-                keypair,
-                state,
-                state_holder,
-            );
-            loop {
-                if let Err(e) = session.request_loop() {
-                    error!("request error: {}", e);
+                .load_initial_state(config.raised_state.clone(), ALLOW_PLAIN_STATE)
+                .map_err(|e| format_err!(IoError, "failed to load initial state: {}", e))?;
+            // redundant nodes of the validator share the key and the double-sign state
+            let signer = Arc::new(signer);
+            let shared_state = Arc::new(Mutex::new(SharedState::new(state, state_holder)));
+            let mut controls = Vec::with_capacity(config.nodes.len());
+            let mut sessions = Vec::with_capacity(config.nodes.len());
+            for node in config.nodes {
+                let id_keypair = match &id_key_bytes {
+                    Some(id_key_bytes) if node.secret_connection => {
+                        Some(identity_keypair(id_key_bytes)?)
+                    }
+                    _ => None,
+                };
+                let control = Arc::new(SessionControl::new(
+                    config.chain_id.clone(),
+                    Some(node.peer.clone()),
+                ));
+                controls.push(control.clone());
+                let chain_id = config.chain_id.clone();
+                let signer = signer.clone();
+                let shared_state = shared_state.clone();
+                sessions.push(thread::spawn(move || {
+                    run_session(chain_id, node, id_keypair, signer, shared_state, control)
+                }));
+            }
+            // the config stream stays open for the admin requests forwarded by the helper
+            thread::spawn(move || {
+                if let Err(e) = admin::serve_forwarded(stream, controls) {
+                    warn!("admin stream closed: {}", e);
+                }
+            });
+            let mut result = Ok(());
+            for session in sessions {
+                if let Err(e) = session
+                    .join()
+                    .unwrap_or_else(|_| Err(format_err!(IoError, "session thread panicked").into()))
+                {
+                    error!("[{}] session stopped: {}", config.chain_id, e);
+                    result = Err(e);
                 }
-                let conn: Box<dyn Connection> = get_connection(&config, id_keypair.as_ref());
-                session.reset_connection(conn);
             }
+            result?;
         }
         Ok(NitroRequest::Keygen(keygen_config)) => {
-            let mut csprng = OsRng {};
-            let mut keypair = Keypair::generate(&mut csprng);
-            let public = keypair.public;
+            let secret_key = generate_secret_key(keygen_config.key_type);
+            let public = signer_from_secret_key(keygen_config.key_type, &secret_key)?
+                .public_key()
+                .to_bytes();
             let pubkeyb64 = String::from_utf8(subtle_encoding::base64::encode(&public))
                 .map_err(|e| format_err!(IoError, "base64 encoding error: {:?}", e))?;
             let keyidb64 =
@@ -192,7 +241,7 @@ pub fn entry(mut stream: VsockStream) -> Result<(), Error> {
                 keygen_config.credentials.aws_secret_key.as_bytes(),
                 keygen_config.credentials.aws_session_token.as_bytes(),
                 keygen_config.kms_key_id.as_bytes(),
-                keypair.secret.as_bytes(),
+                secret_key.as_slice(),
             ) {
                 Ok(encrypted_secret) => {
                     let req = Request::Attestation {
@@ -209,7 +258,7 @@ pub fn entry(mut stream: VsockStream) -> Result<(), Error> {
                     match att {
                         Response::Attestation { document } => Ok(NitroKeygenResponse {
                             encrypted_secret,
-                            public_key: public.as_bytes().to_vec(),
+                            public_key: public,
                             attestation_doc: document,
                         }),
                         _ => Err("failed to obtain an attestation document".to_owned()),
@@ -217,7 +266,6 @@ pub fn entry(mut stream: VsockStream) -> Result<(), Error> {
                 }
                 Err(e) => Err(format!("{:?}", e)),
             };
-            keypair.secret.zeroize();
             let json = serde_json::to_string(&response)
                 .map_err(|e| format_err!(ParseError, "serde keygen response error: {:?}", e))?;
             write_u16_payload(&mut stream, json.as_bytes())
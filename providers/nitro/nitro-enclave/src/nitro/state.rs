@@ -1,50 +1,116 @@
-use anomaly::format_err;
+use anomaly::{fail, format_err};
 use std::io;
 use std::os::unix::io::AsRawFd;
-use tmkms_light::chain::state::{consensus, PersistStateSync, State, StateError, StateErrorKind};
+use std::time::Instant;
+use tmkms_light::chain::state::{
+    consensus, AuthenticatedState, PersistStateSync, State, StateAck, StateAuthenticator,
+    StateError, StateErrorKind, STATE_ACK_TIMEOUT,
+};
 use tmkms_light::utils::{read_u16_payload, write_u16_payload};
 use tmkms_nitro_helper::VSOCK_HOST_CID;
-use tracing::{debug, trace};
+use tracing::{debug, trace, warn};
 use vsock::{SockAddr, VsockStream};
 
 /// as the state needs to be persisted outside of NE,
 /// this is a helper that communicates with the host to load the latest state
 /// on the start up + to update it after each signing
-#[derive(Debug, Clone)]
 pub struct StateHolder {
     state_conn: VsockStream,
+    /// authenticates the state records persisted on the host
+    authenticator: StateAuthenticator,
 }
 
 impl StateHolder {
     /// connects to the host via the vsock port specified in the configuration
-    pub fn new(vsock_port: u32) -> io::Result<Self> {
+    pub fn new(vsock_port: u32, authenticator: StateAuthenticator) -> io::Result<Self> {
         let addr = SockAddr::new_vsock(VSOCK_HOST_CID, vsock_port);
         let state_conn = vsock::VsockStream::connect(&addr)?;
         trace!("state vsock port: {}", vsock_port);
         trace!("state peer addr: {:?}", state_conn.peer_addr());
         trace!("state local addr: {:?}", state_conn.local_addr());
         trace!("state fd: {}", state_conn.as_raw_fd());
-        Ok(Self { state_conn })
+        Ok(Self {
+            state_conn,
+            authenticator,
+        })
+    }
+
+    /// loads and verifies the last state record from the host
+    /// (and applies the raised state from the startup hook if it's higher)
+    pub fn load_initial_state(
+        &mut self,
+        raised_state: Option<consensus::State>,
+        allow_plain_state: bool,
+    ) -> Result<State, StateError> {
+        let json_raw = read_u16_payload(&mut self.state_conn)
+            .map_err(|e| format_err!(StateErrorKind::SyncError, "error reading state: {}", e))?;
+        let record: AuthenticatedState = serde_json::from_slice(&json_raw)
+            .map_err(|e| format_err!(StateErrorKind::SyncError, "error parsing state: {}", e))?;
+        self.authenticator
+            .verify_initial(&record, raised_state, allow_plain_state)
+    }
+
+    /// waits for the host to acknowledge the given state
+    fn wait_ack(&mut self, new_state: &consensus::State) -> Result<(), StateError> {
+        let deadline = Instant::now() + STATE_ACK_TIMEOUT;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.as_nanos() == 0 {
+                fail!(
+                    StateErrorKind::SyncError,
+                    "no acknowledgement of the persisted state within {:?}",
+                    STATE_ACK_TIMEOUT
+                );
+            }
+            self.state_conn
+                .set_read_timeout(Some(timeout))
+                .map_err(|e| {
+                    format_err!(StateErrorKind::SyncError, "error setting timeout: {}", e)
+                })?;
+            let json_raw = read_u16_payload(&mut self.state_conn).map_err(|e| {
+                format_err!(
+                    StateErrorKind::SyncError,
+                    "no acknowledgement of the persisted state: {}",
+                    e
+                )
+            })?;
+            let ack: StateAck = serde_json::from_slice(&json_raw).map_err(|e| {
+                format_err!(
+                    StateErrorKind::SyncError,
+                    "error parsing acknowledgement: {}",
+                    e
+                )
+            })?;
+            if ack.state != *new_state {
+                warn!("ignoring a late acknowledgement of state {}", ack.state);
+                continue;
+            }
+            if let Some(e) = ack.error {
+                fail!(
+                    StateErrorKind::SyncError,
+                    "the host failed to persist the state: {}",
+                    e
+                );
+            }
+            return Ok(());
+        }
     }
 }
 
 impl PersistStateSync for StateHolder {
     /// loads the initial state
     fn load_state(&mut self) -> Result<State, StateError> {
-        let json_raw = read_u16_payload(&mut self.state_conn)
-            .map_err(|e| format_err!(StateErrorKind::SyncError, "error reading state: {}", e))?;
-        let consensus_state: consensus::State = serde_json::from_slice(&json_raw)
-            .map_err(|e| format_err!(StateErrorKind::SyncError, "error parsing state: {}", e))?;
-        Ok(State::from(consensus_state))
+        self.load_initial_state(None, false)
     }
 
     /// sends the update state to be persisted on the host
-    fn persist_state(&mut self, new_state: &consensus::State) -> Result<(), StateError> {
+    fn persist_state(&mut self, new_state: &State) -> Result<(), StateError> {
         trace!("writing new consensus state to state conn");
         trace!("state peer addr: {:?}", self.state_conn.peer_addr());
         trace!("state local addr: {:?}", self.state_conn.local_addr());
         trace!("state fd: {}", self.state_conn.as_raw_fd());
-        let json_raw = serde_json::to_vec(&new_state).map_err(|e| {
+        let record = self.authenticator.authenticate(new_state);
+        let json_raw = serde_json::to_vec(&record).map_err(|e| {
             format_err!(StateErrorKind::SyncError, "error serializing state: {}", e)
         })?;
 
@@ -56,7 +122,10 @@ impl PersistStateSync for StateHolder {
             )
         })?;
 
-        debug!("successfully wrote new consensus state to state connection");
+        self.wait_ack(new_state.consensus_state())?;
+        self.authenticator.acknowledged(&record);
+
+        debug!("the host acknowledged the new consensus state");
 
         Ok(())
     }
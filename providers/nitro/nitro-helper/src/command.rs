@@ -1,18 +1,28 @@
 pub mod launch_all;
 pub mod nitro_enclave;
 
-use std::sync::mpsc::Receiver;
-use std::{fs, path::PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Mutex;
+use std::thread;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 use sysinfo::{ProcessExt, SystemExt};
 use tendermint::net;
+use tmkms_light::admin::{self, AdminRequest, AdminResponse};
+use tmkms_light::chain::state::hook;
+use tmkms_light::grpc;
+use tmkms_light::secure_file;
+use tmkms_light::signer::KeyType;
 use tmkms_light::utils::write_u16_payload;
 use tmkms_light::utils::{print_pubkey, PubkeyDisplay};
-use vsock::SockAddr;
+use vsock::{SockAddr, VsockStream};
 
-use crate::config::{EnclaveConfig, EnclaveOpt, NitroSignOpt, VSockProxyOpt};
+use crate::config::{EnclaveConfig, EnclaveOpt, NitroSignOpt, NitroValidatorOpt, VSockProxyOpt};
 use crate::key_utils::{credential, generate_key};
 use crate::proxy::Proxy;
-use crate::shared::{NitroConfig, NitroRequest};
+use crate::shared::{AwsCredentials, NitroConfig, NitroNodeConfig, NitroRequest};
 use crate::state::StateSyncer;
 
 /// write tmkms.toml + enclave.toml + generate keys
@@ -24,6 +34,7 @@ pub fn init(
     aws_region: String,
     kms_key_id: String,
     cid: Option<u32>,
+    key_type: Option<KeyType>,
 ) -> Result<(), String> {
     if !config_dir.is_dir() || !config_dir.exists() {
         return Err("config path is not a directory or not exists".to_string());
@@ -33,6 +44,7 @@ pub fn init(
 
     let nitro_sign_opt = NitroSignOpt {
         aws_region: aws_region.clone(),
+        key_type: key_type.unwrap_or_default(),
         ..Default::default()
     };
     let enclave_opt = EnclaveOpt::default();
@@ -69,13 +81,15 @@ pub fn init(
             .ok_or_else(|| "cannot create a dir in a root directory".to_owned())?,
     )
     .map_err(|e| format!("failed to create dirs for key storage: {:?}", e))?;
-    fs::create_dir_all(
-        config
-            .state_file_path
-            .parent()
-            .ok_or_else(|| "cannot create a dir in a root directory".to_owned())?,
-    )
-    .map_err(|e| format!("failed to create dirs for state storage: {:?}", e))?;
+    for validator in config.validators.iter() {
+        fs::create_dir_all(
+            validator
+                .state_file_path
+                .parent()
+                .ok_or_else(|| "cannot create a dir in a root directory".to_owned())?,
+        )
+        .map_err(|e| format!("failed to create dirs for state storage: {:?}", e))?;
+    }
     let (pubkey, attestation_doc) = generate_key(
         cid,
         port,
@@ -83,6 +97,7 @@ pub fn init(
         &config.aws_region,
         credentials.clone(),
         kms_key_id.clone(),
+        config.key_type,
     )
     .map_err(|e| format!("failed to generate a key: {:?}", e))?;
     print_pubkey(bech32_prefix, pubkey_display, pubkey);
@@ -98,6 +113,7 @@ pub fn init(
             &config.aws_region,
             credentials,
             kms_key_id,
+            KeyType::Ed25519,
         )
         .map_err(|e| format!("failed to generate a sealed id key: {:?}", e))?;
     }
@@ -114,48 +130,140 @@ pub fn check_vsock_proxy() -> bool {
 }
 
 /// push config to enclave, start up a proxy (if needed) + state syncer
-/// stop_sync_rx: when get data from it, the sync thread will be finished
+/// for each validator entry
+/// stop_sync_rx: when get data from it, the sync threads will be finished
 pub fn start(
     config: &NitroSignOpt,
     cid: Option<u32>,
     stop_sync_rx: Receiver<()>,
 ) -> Result<(), String> {
     tracing::debug!("start helper with config: {:?}, cid: {:?}", config, cid);
+    config.validate()?;
     let credentials = if let Some(credentials) = &config.credentials {
         credentials.clone()
     } else {
         credential::get_credentials()?
     };
-    let peer_id = match config.address {
-        net::Address::Tcp { peer_id, .. } => peer_id,
+    let groups = config.validator_groups();
+    let mut stop_senders = Vec::with_capacity(groups.len());
+    let mut syncers = Vec::with_capacity(groups.len());
+    // config streams to the enclave sessions for forwarding admin requests
+    let mut admin_streams = Vec::with_capacity(groups.len());
+    for group in groups {
+        let (stop_tx, stop_rx) = channel();
+        let (state_syncer, config_stream) =
+            start_validator(config, &group, cid, credentials.clone())?;
+        stop_senders.push(stop_tx);
+        syncers.push(state_syncer.launch_syncer(stop_rx));
+        admin_streams.push((group[0].address.to_string(), config_stream));
+    }
+    if let Some(path) = &config.admin_socket_path {
+        let admin_streams = Mutex::new(admin_streams);
+        admin::serve(path, move |request| {
+            let mut admin_streams = admin_streams.lock().unwrap_or_else(|e| e.into_inner());
+            admin::forward_to_all(&mut admin_streams, request)
+        })
+        .map_err(|e| format!("failed to serve the admin socket: {}", e))?;
+    }
+    thread::spawn(move || {
+        if stop_sync_rx.recv().is_ok() {
+            for tx in stop_senders.iter() {
+                let _ = tx.send(());
+            }
+        }
+    });
+
+    // state syncing runs in an infinite loop (so does the proxy)
+    for syncer in syncers {
+        syncer.join().map_err(|_| "join thread error".to_string())?;
+    }
+    Ok(())
+}
+
+/// push the config of the validator entries of one chain to the enclave,
+/// start up their proxies (if needed) and return the state syncer of the chain
+/// and the config stream (which stays open for admin requests)
+fn start_validator(
+    config: &NitroSignOpt,
+    validators: &[&NitroValidatorOpt],
+    cid: Option<u32>,
+    credentials: AwsCredentials,
+) -> Result<(StateSyncer, VsockStream), String> {
+    // the entries of the chain share the state file and the key (checked in `validate`)
+    let validator = validators[0];
+    let state_syncer = StateSyncer::new(
+        validator.state_file_path.clone(),
+        config.state_durability,
+        validator.enclave_state_port,
+    )
+    .map_err(|e| {
+        format!(
+            "[{}] failed to get a state syncing helper: {:?}",
+            validator.chain_id, e
+        )
+    })?;
+    // the raised state is passed to the enclave separately,
+    // as only the enclave can authenticate the state records
+    let raised_state = match validators.iter().find_map(|v| v.state_hook.as_ref()) {
+        Some(state_hook) => hook::run(state_hook, state_syncer.state())
+            .map_err(|e| format!("[{}] startup state hook: {}", validator.chain_id, e))?,
+        None => None,
+    };
+    let sealed_consensus_key = secure_file::read(
+        &config.sealed_consensus_key(validator),
+        config.allow_insecure_key_files,
+    )
+    .map_err(|e| {
+        format!(
+            "[{}] failed to read a sealed consensus key: {}",
+            validator.chain_id, e
+        )
+    })?;
+    let uses_tcp = validators
+        .iter()
+        .any(|v| matches!(v.address, net::Address::Tcp { .. }));
+    let sealed_id_key = match &config.sealed_id_key_path {
+        Some(p) if uses_tcp => Some(
+            secure_file::read(p, config.allow_insecure_key_files)
+                .map_err(|e| format!("failed to read a sealed identity key: {}", e))?,
+        ),
         _ => None,
     };
-    let state_syncer = StateSyncer::new(config.state_file_path.clone(), config.enclave_state_port)
-        .map_err(|e| format!("failed to get a state syncing helper: {:?}", e))?;
-    let sealed_consensus_key = fs::read(config.sealed_consensus_key_path.clone())
-        .map_err(|e| format!("failed to read a sealed consensus key: {:?}", e))?;
-    let sealed_id_key = if let Some(p) = &config.sealed_id_key_path {
-        if let net::Address::Tcp { .. } = config.address {
-            Some(
-                fs::read(p)
-                    .map_err(|e| format!("failed to read a sealed identity key: {:?}", e))?,
-            )
-        } else {
-            None
+    // the proxy of the enclave session connects to the relay of the gRPC server
+    for node in validators.iter() {
+        if let (Some(grpc), net::Address::Unix { path }) = (&node.grpc, &node.address) {
+            grpc::spawn(grpc, Path::new(path), config.allow_insecure_key_files)
+                .map_err(|e| format!("[{}] gRPC server: {}", node.chain_id, e))?;
         }
-    } else {
-        None
-    };
+    }
+    let nodes = validators
+        .iter()
+        .map(|node| {
+            let (secret_connection, peer_id) = match node.address {
+                net::Address::Tcp { peer_id, .. } => (sealed_id_key.is_some(), peer_id),
+                _ => (false, None),
+            };
+            NitroNodeConfig {
+                max_height: node.max_height,
+                secret_connection,
+                peer_id,
+                enclave_tendermint_conn: node.enclave_tendermint_conn,
+                idle_timeout: node.idle_timeout,
+                protocol_version: node.protocol_version,
+                peer: node.address.to_string(),
+            }
+        })
+        .collect();
     let enclave_config = NitroConfig {
-        chain_id: config.chain_id.clone(),
-        max_height: config.max_height,
+        chain_id: validator.chain_id.clone(),
         sealed_consensus_key,
+        key_type: config.consensus_key_type(validator),
         sealed_id_key,
-        peer_id,
-        enclave_state_port: config.enclave_state_port,
-        enclave_tendermint_conn: config.enclave_tendermint_conn,
+        enclave_state_port: validator.enclave_state_port,
         credentials,
         aws_region: config.aws_region.clone(),
+        raised_state,
+        nodes,
     };
     let addr = if let Some(cid) = cid {
         SockAddr::new_vsock(cid, config.enclave_config_port)
@@ -173,26 +281,29 @@ pub fn start(
         .map_err(|e| format!("failed to serialize the config: {:?}", e))?;
     write_u16_payload(&mut socket, &config_raw)
         .map_err(|e| format!("failed to write the config: {:?}", e))?;
-    let proxy = match &config.address {
-        net::Address::Unix { path } => {
-            tracing::debug!(
-                "{}: Creating a proxy {}...",
-                &config.chain_id,
-                &config.address
-            );
+    for node in validators.iter() {
+        if let net::Address::Unix { path } = &node.address {
+            tracing::debug!("{}: Creating a proxy {}...", &node.chain_id, &node.address);
 
-            Some(Proxy::new(config.enclave_tendermint_conn, path.clone()))
+            Proxy::new(node.enclave_tendermint_conn, path.clone()).launch_proxy();
         }
-        _ => None,
-    };
-    if let Some(p) = proxy {
-        p.launch_proxy();
     }
+    Ok((state_syncer, socket))
+}
 
-    // state syncing runs in an infinite loop (so does the proxy)
-    state_syncer
-        .launch_syncer(stop_sync_rx)
-        .join()
-        .map_err(|_| "join thread error".to_string())?;
-    Ok(())
+/// send a command to the admin socket of the running helper and print its response
+pub fn admin(config: &NitroSignOpt, request: AdminRequest) -> Result<(), String> {
+    let path = config
+        .admin_socket_path
+        .as_ref()
+        .ok_or_else(|| "no `admin_socket_path` in config".to_owned())?;
+    match admin::send_request(path, request).map_err(|e| format!("admin request failed: {}", e))? {
+        AdminResponse::Error(e) => Err(format!("admin command failed: {}", e)),
+        response => {
+            let json = serde_json::to_string_pretty(&response)
+                .map_err(|e| format!("failed to serialize the response: {:?}", e))?;
+            println!("{}", json);
+            Ok(())
+        }
+    }
 }
@@ -8,6 +8,8 @@ use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::process::{Command, Output};
 use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use tmkms_light::metrics::Metrics;
 
 /// The information provided by a `describe-enclaves` request.
 #[derive(Clone, Serialize, Deserialize)]
@@ -119,12 +121,20 @@ pub fn run_enclave(opt: &EnclaveOpt, stop_receiver: Receiver<()>) -> Result<(),
     }
     // lauch enclave server
     tracing::info!("start enclave log server at port {}", opt.log_server_port);
-    let enclave_log_server = LogServer::new(
+    let mut enclave_log_server = LogServer::new(
         opt.log_server_port,
         opt.log_to_console,
         opt.log_file.clone(),
     )
     .map_err(|e| format!("{:?}", e))?;
+    if let Some(addr) = opt.metrics_listen_addr {
+        let metrics = Arc::new(Metrics::default());
+        metrics
+            .clone()
+            .serve(addr)
+            .map_err(|e| format!("failed to serve metrics on {}: {:?}", addr, e))?;
+        enclave_log_server = enclave_log_server.with_metrics(metrics);
+    }
 
     enclave_log_server.launch();
     // run enclave
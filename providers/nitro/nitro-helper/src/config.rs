@@ -1,47 +1,217 @@
 use crate::shared::AwsCredentials;
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::{convert::TryFrom, path::PathBuf};
+use std::{convert::TryFrom, net::SocketAddr, path::PathBuf};
 use structopt::StructOpt;
 use tendermint::{chain, net};
+use tmkms_light::chain::state::{hook::HookConfig, Durability};
+use tmkms_light::config::{grpc::GrpcConfig, validator::ProtocolVersion};
+use tmkms_light::signer::KeyType;
+use tmkms_light::utils::canonical_path;
+use tracing::warn;
+
+/// Keys of the earlier single-validator config format that are now set in `[[validator]]` entries
+const LEGACY_VALIDATOR_KEYS: [&str; 6] = [
+    "address",
+    "chain_id",
+    "max_height",
+    "state_file_path",
+    "enclave_state_port",
+    "enclave_tendermint_conn",
+];
 
 /// nitro options for toml configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct NitroSignOpt {
-    /// Address of the validator (`tcp://` or `unix://`)
-    pub address: net::Address,
-    /// Chain ID of the Tendermint network this validator is part of
-    pub chain_id: chain::Id,
-    /// Height at which to stop signing
-    pub max_height: Option<tendermint::block::Height>,
     /// Path to a file containing a cryptographic key
     pub sealed_consensus_key_path: PathBuf,
+    /// Type of the consensus key (`ed25519`, `secp256k1` or `bls12_381`)
+    #[serde(default)]
+    pub key_type: KeyType,
     /// Path to our Ed25519 identity key (if applicable)
     pub sealed_id_key_path: Option<PathBuf>,
-    /// Path to chain-specific `priv_validator_state.json` file
-    pub state_file_path: PathBuf,
     /// Vsock cid to push config to
     pub enclave_config_cid: u32,
     /// Vsock port to push config to
     pub enclave_config_port: u32,
+    /// AWS region
+    pub aws_region: String,
+    /// AWS credentials -- if not set, they'll be obtained from IAM
+    pub credentials: Option<AwsCredentials>,
+    /// Path to the Unix-domain admin socket (if enabled)
+    pub admin_socket_path: Option<PathBuf>,
+    /// How the state files are written (`fsync` by default, `dsync` or `none`)
+    #[serde(default)]
+    pub state_durability: Durability,
+    /// Load sealed key files even if others could access them (not recommended)
+    #[serde(default)]
+    pub allow_insecure_key_files: bool,
+    /// Validator connections (one signing session in the enclave per entry,
+    /// the entries of one chain share its double-sign state)
+    #[serde(rename = "validator")]
+    pub validators: Vec<NitroValidatorOpt>,
+}
+
+/// A single `[[validator]]` entry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NitroValidatorOpt {
+    /// Address of the validator (`tcp://` or `unix://`)
+    pub address: net::Address,
+    /// Chain ID of the Tendermint network this validator is part of
+    pub chain_id: chain::Id,
+    /// Height at which to stop signing
+    pub max_height: Option<tendermint::block::Height>,
+    /// Path to a sealed consensus key for this chain (if different from the shared one)
+    pub sealed_consensus_key_path: Option<PathBuf>,
+    /// Type of the consensus key for this chain (if different from the shared one)
+    pub key_type: Option<KeyType>,
+    /// Path to chain-specific `priv_validator_state.json` file
+    pub state_file_path: PathBuf,
+    /// Seconds without any request (including pings) after which the connection is rebuilt (if set)
+    pub idle_timeout: Option<u16>,
+    /// Protocol version of the node (`v0.33`, `v0.34` (default), `v0.37` or `v0.38`)
+    #[serde(default)]
+    pub protocol_version: ProtocolVersion,
     /// Vsock port to listen on for state synchronization
     pub enclave_state_port: u32,
     /// Vsock port to forward privval plain traffic to TM over UDS (or just pass to enclave if TCP/secret connection)
     pub enclave_tendermint_conn: u32,
-    /// AWS credentials -- if not set, they'll be obtained from IAM
-    pub credentials: Option<AwsCredentials>,
-    /// AWS region
-    pub aws_region: String,
+    /// Startup hook checking the latest block height of the chain (if enabled)
+    pub state_hook: Option<HookConfig>,
+    /// Privval gRPC server for the node (relayed to the session on the `unix://` address)
+    pub grpc: Option<GrpcConfig>,
 }
 
 impl NitroSignOpt {
+    /// Reads the toml config (a config in the earlier single-validator format,
+    /// without `[[validator]]` entries, is read as a config with one entry)
     pub fn from_file(config_path: PathBuf) -> Result<Self, String> {
         let toml_string = std::fs::read_to_string(config_path)
             .map_err(|e| format!("toml config file failed to read: {:?}", e))?;
-        toml::from_str(&toml_string)
+        let mut table: toml::value::Table = toml::from_str(&toml_string)
+            .map_err(|e| format!("toml config file failed to parse: {:?}", e))?;
+        if !table.contains_key("validator") && table.contains_key("chain_id") {
+            warn!("single-validator config: the validator settings should be moved to a `[[validator]]` entry");
+            let validator: toml::value::Table = LEGACY_VALIDATOR_KEYS
+                .iter()
+                .filter_map(|key| table.remove(*key).map(|value| ((*key).to_owned(), value)))
+                .collect();
+            table.insert(
+                "validator".to_owned(),
+                toml::Value::Array(vec![toml::Value::Table(validator)]),
+            );
+        }
+        toml::Value::Table(table)
+            .try_into()
             .map_err(|e| format!("toml config file failed to parse: {:?}", e))
     }
+
+    /// Checks that entries of the same chain (i.e. redundant nodes of one validator)
+    /// share the state file, the key and the state vsock port, that different chains
+    /// use separate state files and that every entry has its own tendermint vsock port
+    pub fn validate(&self) -> Result<(), String> {
+        if self.validators.is_empty() {
+            return Err("no `[[validator]]` entries in config".to_owned());
+        }
+        for (i, validator) in self.validators.iter().enumerate() {
+            if validator.grpc.is_some() && !matches!(validator.address, net::Address::Unix { .. }) {
+                return Err(format!(
+                    "[{}] `grpc` needs a `unix://` address for the session to connect to",
+                    validator.chain_id
+                ));
+            }
+            self.consensus_key_type(validator)
+                .check_protocol_version(validator.protocol_version)
+                .map_err(|e| format!("[{}] {}", validator.chain_id, e))?;
+            if validator.enclave_state_port == validator.enclave_tendermint_conn
+                || validator.enclave_state_port == self.enclave_config_port
+                || validator.enclave_tendermint_conn == self.enclave_config_port
+            {
+                return Err(format!(
+                    "[{}] vsock ports need to be distinct",
+                    validator.chain_id
+                ));
+            }
+            let state_file_path = canonical_path(&validator.state_file_path);
+            for other in self.validators[..i].iter() {
+                let same_chain = other.chain_id == validator.chain_id;
+                let same_state = canonical_path(&other.state_file_path) == state_file_path;
+                if same_chain && !same_state {
+                    return Err(format!(
+                        "[{}] validator entries of the same chain need to share the state file",
+                        validator.chain_id
+                    ));
+                }
+                if !same_chain && same_state {
+                    return Err(format!(
+                        "[{}] state file {} is used by another chain",
+                        validator.chain_id,
+                        validator.state_file_path.display()
+                    ));
+                }
+                if same_chain
+                    && (canonical_path(&self.sealed_consensus_key(other))
+                        != canonical_path(&self.sealed_consensus_key(validator))
+                        || self.consensus_key_type(other) != self.consensus_key_type(validator))
+                {
+                    return Err(format!(
+                        "[{}] validator entries of the same chain need to share the consensus key",
+                        validator.chain_id
+                    ));
+                }
+                if same_chain && other.enclave_state_port != validator.enclave_state_port {
+                    return Err(format!(
+                        "[{}] validator entries of the same chain need to share the `enclave_state_port`",
+                        validator.chain_id
+                    ));
+                }
+                let ports = [other.enclave_state_port, other.enclave_tendermint_conn];
+                if (!same_chain && ports.contains(&validator.enclave_state_port))
+                    || ports.contains(&validator.enclave_tendermint_conn)
+                {
+                    return Err(format!(
+                        "[{}] vsock ports are used by another validator entry",
+                        validator.chain_id
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Validator entries grouped by chain (in the order of their first appearance)
+    pub fn validator_groups(&self) -> Vec<Vec<&NitroValidatorOpt>> {
+        let mut groups: Vec<Vec<&NitroValidatorOpt>> = Vec::new();
+        for validator in self.validators.iter() {
+            match groups
+                .iter_mut()
+                .find(|group| group[0].chain_id == validator.chain_id)
+            {
+                Some(group) => group.push(validator),
+                None => groups.push(vec![validator]),
+            }
+        }
+        groups
+    }
+
+    /// The sealed consensus key path to use for the given validator entry
+    pub fn sealed_consensus_key(&self, validator: &NitroValidatorOpt) -> PathBuf {
+        validator
+            .sealed_consensus_key_path
+            .clone()
+            .unwrap_or_else(|| self.sealed_consensus_key_path.clone())
+    }
+
+    /// The consensus key type of the given validator entry
+    /// (the shared one's, unless the entry has its own key)
+    pub fn consensus_key_type(&self, validator: &NitroValidatorOpt) -> KeyType {
+        match validator.sealed_consensus_key_path {
+            Some(_) => validator.key_type.unwrap_or(self.key_type),
+            None => self.key_type,
+        }
+    }
 }
 
 #[derive(StructOpt, Clone, Serialize, Deserialize, Debug)]
@@ -98,6 +268,9 @@ pub struct EnclaveOpt {
     /// output the enclave to console
     #[structopt(long, short = "l")]
     pub log_to_console: bool,
+    /// Serve Prometheus metrics of the enclave on this address
+    #[structopt(long)]
+    pub metrics_listen_addr: Option<SocketAddr>,
 }
 
 impl Default for EnclaveOpt {
@@ -110,11 +283,30 @@ impl Default for EnclaveOpt {
             log_server_port: 6050,
             log_file: None,
             log_to_console: true,
+            metrics_listen_addr: None,
         }
     }
 }
 
 impl Default for NitroSignOpt {
+    fn default() -> Self {
+        Self {
+            sealed_consensus_key_path: "secrets/secret.key".into(),
+            key_type: KeyType::Ed25519,
+            sealed_id_key_path: Some("secrets/id.key".into()),
+            enclave_config_cid: 15,
+            enclave_config_port: 5050,
+            aws_region: "ap-southeast-1".to_owned(),
+            credentials: None,
+            admin_socket_path: None,
+            state_durability: Durability::default(),
+            allow_insecure_key_files: false,
+            validators: vec![NitroValidatorOpt::default()],
+        }
+    }
+}
+
+impl Default for NitroValidatorOpt {
     fn default() -> Self {
         Self {
             address: net::Address::Unix {
@@ -122,15 +314,15 @@ impl Default for NitroSignOpt {
             },
             chain_id: chain::Id::try_from("testchain-1".to_owned()).expect("valid chain-id"),
             max_height: None,
-            sealed_consensus_key_path: "secrets/secret.key".into(),
-            sealed_id_key_path: Some("secrets/id.key".into()),
+            sealed_consensus_key_path: None,
+            key_type: None,
             state_file_path: "state/priv_validator_state.json".into(),
-            enclave_config_cid: 15,
-            enclave_config_port: 5050,
+            idle_timeout: None,
+            protocol_version: ProtocolVersion::default(),
             enclave_state_port: 5555,
             enclave_tendermint_conn: 5000,
-            credentials: None,
-            aws_region: "ap-southeast-1".to_owned(),
+            state_hook: None,
+            grpc: None,
         }
     }
 }
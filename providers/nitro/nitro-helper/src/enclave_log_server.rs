@@ -3,8 +3,10 @@ use chrono::offset::Local;
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Write};
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
+use tmkms_light::metrics::{Metrics, METRICS_LOG_TARGET};
 use tmkms_nitro_helper::tracing_layer::Log;
 use tracing::Level;
 use tracing::{debug, error, info, trace, warn};
@@ -18,6 +20,8 @@ pub struct LogServer {
     to_console: bool,
     // put log to file
     log_file: Option<File>,
+    // records the metric events from the enclave
+    metrics: Option<Arc<Metrics>>,
 }
 
 impl LogServer {
@@ -40,9 +44,16 @@ impl LogServer {
             local_port,
             to_console,
             log_file: file,
+            metrics: None,
         })
     }
 
+    /// Records the metric events (instead of logging them)
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
     /// Creates a listening socket
     /// Returns the file descriptor for it or the appropriate error
     fn sock_listen(&self) -> Result<VsockListener, String> {
@@ -95,6 +106,13 @@ impl LogServer {
 
     fn process_log(&mut self, raw_log: &[u8]) -> Result<(), String> {
         let log = Log::from_raw(raw_log).map_err(|e| format!("{:?}", e))?;
+        if log.target == METRICS_LOG_TARGET {
+            if let Some(metrics) = &self.metrics {
+                return metrics
+                    .record_log_message(&log.message)
+                    .map_err(|e| format!("invalid metric event: {:?}", e));
+            }
+        }
         let mut s = log.format();
         if self.to_console {
             match log.level {
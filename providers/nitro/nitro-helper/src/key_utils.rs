@@ -1,8 +1,9 @@
 use crate::shared::AwsCredentials;
 use crate::shared::{NitroKeygenConfig, NitroKeygenResponse, NitroRequest, NitroResponse};
 
-use ed25519_dalek::PublicKey;
-use std::{fs::OpenOptions, io::Write, os::unix::fs::OpenOptionsExt, path::Path};
+use std::path::Path;
+use tmkms_light::secure_file;
+use tmkms_light::signer::{ConsensusPublicKey, KeyType};
 use tmkms_light::utils::{read_u16_payload, write_u16_payload};
 use vsock::SockAddr;
 
@@ -108,11 +109,13 @@ pub fn generate_key(
     region: &str,
     credentials: AwsCredentials,
     kms_key_id: String,
-) -> Result<(PublicKey, Vec<u8>), String> {
+    key_type: KeyType,
+) -> Result<(ConsensusPublicKey, Vec<u8>), String> {
     let keygen_request = NitroKeygenConfig {
         credentials,
         kms_key_id,
         aws_region: region.into(),
+        key_type,
     };
 
     let request = NitroRequest::Keygen(keygen_request);
@@ -134,16 +137,10 @@ pub fn generate_key(
         .map_err(|e| format!("failed to get keygen response from enclave: {:?}", e))?;
 
     let resp: NitroKeygenResponse = response?;
-    OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .mode(0o600)
-        .open(path.as_ref())
-        .and_then(|mut file| file.write_all(&resp.encrypted_secret))
+    secure_file::write(path.as_ref(), &resp.encrypted_secret)
         .map_err(|e| format!("couldn't write `{}`: {}", path.as_ref().display(), e))?;
     Ok((
-        PublicKey::from_bytes(&resp.public_key)
+        ConsensusPublicKey::from_raw(key_type, &resp.public_key)
             .map_err(|e| format!("Invalid pubkey key: {:?}", e))?,
         resp.attestation_doc,
     ))
@@ -8,7 +8,7 @@ mod state;
 
 use command::launch_all::launch_all;
 use command::nitro_enclave::{describe_enclave, run_enclave, stop_enclave};
-use command::{check_vsock_proxy, init, start};
+use command::{admin, check_vsock_proxy, init, start};
 use config::{EnclaveOpt, VSockProxyOpt};
 
 use crate::command::nitro_enclave::run_vsock_proxy;
@@ -16,6 +16,8 @@ use crate::config::{EnclaveConfig, NitroSignOpt};
 use std::path::PathBuf;
 use std::sync::mpsc::channel;
 use structopt::StructOpt;
+use tmkms_light::admin::AdminRequest;
+use tmkms_light::signer::KeyType;
 use tmkms_light::utils::PubkeyDisplay;
 use tracing::Level;
 use tracing_subscriber::FmtSubscriber;
@@ -80,6 +82,9 @@ enum CommandHelper {
         kms_key_id: String,
         #[structopt(long)]
         cid: Option<u32>,
+        /// consensus key type: ed25519 (default), secp256k1 or bls12_381 (with the `bls` feature)
+        #[structopt(short = "t")]
+        key_type: Option<KeyType>,
     },
     #[structopt(name = "start", about = "start tmkms process")]
     /// start tmkms process (push config + start up proxy and state persistence)
@@ -93,6 +98,14 @@ enum CommandHelper {
         #[structopt(short, parse(from_occurrences))]
         v: u32,
     },
+    #[structopt(name = "admin", about = "send a command to the admin socket")]
+    /// send a command to the admin socket of the running helper
+    Admin {
+        #[structopt(short, default_value = "tmkms.toml")]
+        config_path: PathBuf,
+        /// status, pause, resume or shutdown
+        command: AdminRequest,
+    },
     #[structopt(name = "launch-all", about = "launch all")]
     LaunchAll {
         /// tmkms config path
@@ -130,6 +143,7 @@ fn run() -> Result<(), String> {
             aws_region,
             kms_key_id,
             cid,
+            key_type,
         }) => {
             init(
                 config_dir,
@@ -138,6 +152,7 @@ fn run() -> Result<(), String> {
                 aws_region,
                 kms_key_id,
                 cid,
+                key_type,
             )?;
         }
         TmkmsLight::Helper(CommandHelper::Start {
@@ -157,6 +172,13 @@ fn run() -> Result<(), String> {
             .map_err(|_| "Error to set Ctrl-C channel".to_string())?;
             start(&config, cid, receiver)?;
         }
+        TmkmsLight::Helper(CommandHelper::Admin {
+            config_path,
+            command,
+        }) => {
+            let config = NitroSignOpt::from_file(config_path)?;
+            admin(&config, command)?;
+        }
         TmkmsLight::Enclave(CommandEnclave::Info) => {
             let info = describe_enclave()?;
             let s = serde_json::to_string_pretty(&info)
@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
-use tendermint::{chain, node};
+use tendermint::{chain, consensus, node};
+use tmkms_light::config::validator::ProtocolVersion;
+use tmkms_light::signer::KeyType;
 
 /// CID for listening on the host
 pub const VSOCK_HOST_CID: u32 = 3;
@@ -10,22 +12,42 @@ pub const VSOCK_HOST_CID: u32 = 3;
 pub struct NitroConfig {
     /// Chain ID of the Tendermint network this validator is part of
     pub chain_id: chain::Id,
-    /// Height at which to stop signing
-    pub max_height: Option<tendermint::block::Height>,
     /// AWS KMS-encrypted key
     pub sealed_consensus_key: Vec<u8>,
+    /// type of the consensus key
+    pub key_type: KeyType,
     /// AWS KMS-encrypted Ed25519 identity key (if secret connection)
     pub sealed_id_key: Option<Vec<u8>>,
-    /// peer id to check with secret connections
-    pub peer_id: Option<node::Id>,
     /// Vsock port to listen on for state synchronization
     pub enclave_state_port: u32,
-    /// Vsock port to forward privval plain traffic to TM over UDS or TCP
-    pub enclave_tendermint_conn: u32,
     /// AWS credentials -- if not set, they'll be obtained from IAM
     pub credentials: AwsCredentials,
     /// AWS region
     pub aws_region: String,
+    /// higher state from the startup state hook (if any)
+    pub raised_state: Option<consensus::State>,
+    /// the nodes of the validator (they share the double-sign state)
+    pub nodes: Vec<NitroNodeConfig>,
+}
+
+/// one of the (possibly redundant) nodes of the validator
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NitroNodeConfig {
+    /// Height at which to stop signing
+    pub max_height: Option<tendermint::block::Height>,
+    /// whether the node is connected over TCP with the secret connection
+    pub secret_connection: bool,
+    /// peer id to check with secret connections
+    pub peer_id: Option<node::Id>,
+    /// Vsock port to forward privval plain traffic to TM over UDS or TCP
+    pub enclave_tendermint_conn: u32,
+    /// seconds without any request after which the connection is rebuilt (if set)
+    pub idle_timeout: Option<u16>,
+    /// Tendermint / CometBFT protocol version of the node
+    pub protocol_version: ProtocolVersion,
+    /// address of the node in the helper config (shown in the admin statuses)
+    pub peer: String,
 }
 
 /// configuration sent during key generation
@@ -37,6 +59,8 @@ pub struct NitroKeygenConfig {
     pub kms_key_id: String,
     /// AWS region
     pub aws_region: String,
+    /// type of the generated key
+    pub key_type: KeyType,
 }
 
 /// types of initial requests sent to NE
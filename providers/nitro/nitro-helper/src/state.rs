@@ -1,15 +1,12 @@
 use crate::shared::VSOCK_HOST_CID;
-use anomaly::{fail, format_err};
+use anomaly::format_err;
 use std::os::unix::io::AsRawFd;
+use std::path::Path;
 use std::sync::mpsc::{Receiver, TryRecvError};
 use std::thread;
-use std::{
-    fs,
-    io::{self, prelude::*},
-    path::{Path, PathBuf},
+use tmkms_light::chain::state::{
+    consensus, AuthenticatedState, Durability, StateAck, StateError, StateErrorKind, StateFile,
 };
-use tempfile::NamedTempFile;
-use tmkms_light::chain::state::{consensus, StateError, StateErrorKind};
 use tmkms_light::utils::{read_u16_payload, write_u16_payload};
 use tracing::{debug, info, warn};
 use vsock::{SockAddr, VsockListener, VsockStream};
@@ -17,41 +14,23 @@ use vsock::{SockAddr, VsockListener, VsockStream};
 /// helps the enclave to load the state previously persisted on the host
 /// + to persist new states
 pub struct StateSyncer {
-    state_file_path: PathBuf,
+    state_file: StateFile<AuthenticatedState>,
     vsock_listener: VsockListener,
-    state: consensus::State,
+    /// the last state record written by the enclave
+    state: AuthenticatedState,
 }
 
 impl StateSyncer {
     /// creates a new state file or loads the previous one
     /// and binds a listener for incoming vsock connections from the enclave
     /// on the proxy CID on the provided port
-    pub fn new<P: AsRef<Path>>(path: P, vsock_port: u32) -> Result<Self, StateError> {
-        let state_file_path = path.as_ref().to_owned();
-        let state = match fs::read_to_string(&path) {
-            Ok(state_json) => {
-                let consensus_state: consensus::State =
-                    serde_json::from_str(&state_json).map_err(|e| {
-                        format_err!(
-                            StateErrorKind::SyncError,
-                            "error parsing {}: {}",
-                            path.as_ref().display(),
-                            e
-                        )
-                    })?;
-
-                Ok(consensus_state)
-            }
-            Err(e) if e.kind() == io::ErrorKind::NotFound => {
-                Self::write_initial_state(&state_file_path)
-            }
-            Err(e) => fail!(
-                StateErrorKind::SyncError,
-                "error reading {}: {}",
-                path.as_ref().display(),
-                e
-            ),
-        }?;
+    pub fn new<P: AsRef<Path>>(
+        path: P,
+        durability: Durability,
+        vsock_port: u32,
+    ) -> Result<Self, StateError> {
+        let mut state_file = StateFile::new(path, durability);
+        let state = state_file.load()?;
 
         let sockaddr = SockAddr::new_vsock(VSOCK_HOST_CID, vsock_port);
         let vsock_listener = VsockListener::bind(&sockaddr).map_err(|e| {
@@ -63,27 +42,25 @@ impl StateSyncer {
         })?;
 
         Ok(Self {
-            state_file_path,
+            state_file,
             vsock_listener,
             state,
         })
     }
 
-    /// Write the initial state to the given path on disk
-    fn write_initial_state(path: &Path) -> Result<consensus::State, StateError> {
-        let consensus_state = consensus::State {
-            height: 0u32.into(),
-            ..Default::default()
-        };
-
-        Self::persist_state(path, &consensus_state)?;
-
-        Ok(consensus_state)
+    /// the state loaded from the host (to be pushed to the enclave)
+    pub fn state(&self) -> &consensus::State {
+        &self.state.state
     }
 
-    /// dump the current state to the provided vsock stream
+    /// dump the current state (with the marker sealed by the enclave)
+    /// to the provided vsock stream
     fn sync_to_stream(&self, stream: &mut VsockStream) -> Result<(), StateError> {
-        let json_raw = serde_json::to_vec(&self.state).map_err(|e| {
+        let record = AuthenticatedState {
+            marker: self.state_file.load_marker()?,
+            ..self.state.clone()
+        };
+        let json_raw = serde_json::to_vec(&record).map_err(|e| {
             format_err!(
                 StateErrorKind::SyncError,
                 "failed to serialize state: {}",
@@ -95,8 +72,27 @@ impl StateSyncer {
         })
     }
 
+    /// replies to the enclave after the state was written (or failed to be)
+    fn send_ack(stream: &mut VsockStream, ack: &StateAck) -> Result<(), StateError> {
+        let json_raw = serde_json::to_vec(ack).map_err(|e| {
+            format_err!(
+                StateErrorKind::SyncError,
+                "failed to serialize acknowledgement: {}",
+                e
+            )
+        })?;
+        write_u16_payload(stream, &json_raw).map_err(|e| {
+            format_err!(
+                StateErrorKind::SyncError,
+                "failed to write acknowledgement: {}",
+                e
+            )
+            .into()
+        })
+    }
+
     /// load state from the provided vsock stream
-    fn sync_from_stream(mut stream: &mut VsockStream) -> Result<consensus::State, StateError> {
+    fn sync_from_stream(mut stream: &mut VsockStream) -> Result<AuthenticatedState, StateError> {
         let json_raw = read_u16_payload(&mut stream)
             .map_err(|e| format_err!(StateErrorKind::SyncError, "failed to read state: {}", e))?;
         serde_json::from_slice(&json_raw).map_err(|e| {
@@ -125,20 +121,45 @@ impl StateSyncer {
                             warn!("error serializing to json {}", e);
                         } else {
                             loop {
-                                if let Ok(consensus_state) = Self::sync_from_stream(&mut stream) {
-                                    self.state = consensus_state;
-                                    if let Err(e) =
-                                        Self::persist_state(&self.state_file_path, &self.state)
-                                    {
+                                let mut record = match Self::sync_from_stream(&mut stream) {
+                                    Ok(record) => record,
+                                    Err(e) => {
+                                        // the enclave closed the stream (or sent an invalid record):
+                                        // wait for its next connection
+                                        warn!("state connection closed: {}", e);
+                                        break;
+                                    }
+                                };
+                                let marker = record.marker.take();
+                                let persisted = marker
+                                    .map_or(Ok(()), |marker| {
+                                        self.state_file.persist_marker(&marker)
+                                    })
+                                    .and_then(|_| self.state_file.persist(&record));
+                                let error = match persisted {
+                                    // only a written state is sent to a reconnecting enclave
+                                    Ok(()) => {
+                                        self.state = record.clone();
+                                        None
+                                    }
+                                    Err(e) => {
                                         warn!("state persistence failed: {}", e);
+                                        Some(e.to_string())
                                     }
-                                    match stop_recv.try_recv() {
-                                        Ok(()) | Err(TryRecvError::Disconnected) => {
-                                            warn!("stop state persistence");
-                                            return;
-                                        }
-                                        Err(TryRecvError::Empty) => continue,
+                                };
+                                let ack = StateAck {
+                                    state: record.state,
+                                    error,
+                                };
+                                if let Err(e) = Self::send_ack(&mut stream, &ack) {
+                                    warn!("{}", e);
+                                }
+                                match stop_recv.try_recv() {
+                                    Ok(()) | Err(TryRecvError::Disconnected) => {
+                                        warn!("stop state persistence");
+                                        return;
                                     }
+                                    Err(TryRecvError::Empty) => continue,
                                 }
                             }
                         }
@@ -150,58 +171,4 @@ impl StateSyncer {
             }
         })
     }
-
-    /// write the new state into a file on the host
-    fn persist_state(path: &Path, new_state: &consensus::State) -> Result<(), StateError> {
-        debug!(
-            "writing new consensus state to {}: {:?}",
-            path.display(),
-            &new_state
-        );
-
-        let json = serde_json::to_string(&new_state).map_err(|e| {
-            format_err!(
-                StateErrorKind::SyncError,
-                "error serializing to json {}: {}",
-                path.display(),
-                e
-            )
-        })?;
-
-        let state_file_dir = path.parent().unwrap_or_else(|| {
-            panic!("state file cannot be root directory");
-        });
-
-        let mut state_file = NamedTempFile::new_in(state_file_dir).map_err(|e| {
-            format_err!(
-                StateErrorKind::SyncError,
-                "error creating a named temp file {}: {}",
-                path.display(),
-                e
-            )
-        })?;
-        state_file.write_all(json.as_bytes()).map_err(|e| {
-            format_err!(
-                StateErrorKind::SyncError,
-                "error writing {}: {}",
-                path.display(),
-                e
-            )
-        })?;
-        state_file.persist(&path).map_err(|e| {
-            format_err!(
-                StateErrorKind::SyncError,
-                "error persisting {}: {}",
-                path.display(),
-                e
-            )
-        })?;
-
-        debug!(
-            "successfully wrote new consensus state to {}",
-            path.display(),
-        );
-
-        Ok(())
-    }
 }
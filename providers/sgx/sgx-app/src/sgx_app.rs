@@ -8,86 +8,148 @@ mod cloud;
 use ed25519_dalek::Keypair;
 use rand::rngs::OsRng;
 use sgx_isa::{Report, Targetinfo};
-use std::{io, net::TcpStream, thread, time::Duration};
-use subtle::ConstantTimeEq;
-use tendermint_p2p::secret_connection::{self, PublicKey, SecretConnection};
+use std::{
+    io,
+    net::TcpStream,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use tendermint::net;
+use tendermint_p2p::secret_connection::PublicKey;
 use tmkms_light::{
-    connection::{Connection, PlainConnection},
+    admin::{self, SessionControl},
+    chain::state::StateAuthenticator,
+    config::validator::{ProtocolVersion, ValidatorConfig},
+    connection::{manager::Stream, ConnectionManager, ShutdownHandle},
+    metrics::MetricsSink,
+    session::{Session, SharedState},
+    signer::{ConsensusSigner, KeyType},
     utils::write_u16_payload,
 };
 use tmkms_light_sgx_runner::{
-    RemoteConnectionConfig, {SgxInitRequest, SgxInitResponse},
+    validator_shutdown_addr, validator_socket_addr, RemoteConnectionConfig, ValidatorNode,
+    {SgxInitRequest, SgxInitResponse},
 };
 use tracing::{debug, error, info, warn};
 
-fn get_secret_connection(config: &RemoteConnectionConfig) -> io::Result<Box<dyn Connection>> {
-    let RemoteConnectionConfig {
-        peer_id,
-        host,
-        port,
-        sealed_key,
-    } = config;
-    let socket = TcpStream::connect(format!("{}:{}", host, port))?;
-    // TODO: just unseal once in the caller
-    if let Ok(identity_key) = keypair_seal::unseal(&sealed_key) {
-        info!("KMS node ID: {}", PublicKey::from(&identity_key));
+/// whether a plain state file (with signed messages) is accepted when no marker exists:
+/// only in a build with the `migrate-plain-state` feature (which has its own MRENCLAVE),
+/// so the runner can't decide it
+const ALLOW_PLAIN_STATE: bool = cfg!(feature = "migrate-plain-state");
 
-        let connection =
-            SecretConnection::new(socket, identity_key, secret_connection::Version::V0_34)
-                .map_err(|e| {
-                    error!("secret connection failed: {}", e);
-                    io::Error::from(io::ErrorKind::Other)
-                })?;
-        let actual_peer_id = connection.remote_pubkey().peer_id();
+/// validator connection provided by the runner
+/// (the enclave can't shut its usercall streams down, so the runner does it)
+struct RunnerStream {
+    stream: TcpStream,
+    /// usercall address the stream was opened on
+    addr: String,
+}
 
-        // TODO: https://github.com/informalsystems/tendermint-rs/issues/786
-        if let Some(expected_peer_id) = peer_id {
-            if expected_peer_id.ct_eq(&actual_peer_id).unwrap_u8() == 0 {
-                error!(
-                    "{}:{}: validator peer ID mismatch! (expected {}, got {})",
-                    host, port, expected_peer_id, actual_peer_id
-                );
-                return Err(io::Error::from(io::ErrorKind::Other));
-            }
-        }
-        info!("connected to validator successfully");
+impl io::Read for RunnerStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        io::Read::read(&mut self.stream, buf)
+    }
+}
 
-        if peer_id.is_none() {
-            // TODO: https://github.com/informalsystems/tendermint-rs/issues/786
-            warn!(
-                "unverified validator peer ID! ({})",
-                connection.remote_pubkey().peer_id()
-            );
-        }
+impl io::Write for RunnerStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::Write::write(&mut self.stream, buf)
+    }
 
-        Ok(Box::new(connection))
-    } else {
-        error!("unsealing failed");
-        Err(io::ErrorKind::Other.into())
+    fn flush(&mut self) -> io::Result<()> {
+        io::Write::flush(&mut self.stream)
     }
 }
 
-/// keeps retrying with approx. 1 sec sleep until it manages to connect to tendermint privval endpoint
-/// (either TCP or Unix socket exposed via "tendermint" usercall extension)
-pub fn get_connection(secret_connection: Option<&RemoteConnectionConfig>) -> Box<dyn Connection> {
-    loop {
-        let conn: io::Result<Box<dyn Connection>> = if let Some(config) = secret_connection {
-            get_secret_connection(config)
-        } else {
-            TcpStream::connect("tendermint").map(|socket| {
-                let plain_conn = PlainConnection::new(socket);
-                Box::new(plain_conn) as Box<dyn Connection>
-            })
-        };
-        if let Err(e) = conn {
-            error!("tendermint connection error {:?}", e);
-            thread::sleep(Duration::new(1, 0));
-        } else {
-            return conn.unwrap();
-        }
+impl Stream for RunnerStream {
+    fn shutdown_handle(&self) -> Option<ShutdownHandle> {
+        let shutdown_addr = validator_shutdown_addr(&self.addr);
+        Some(Arc::new(move || {
+            TcpStream::connect(&shutdown_addr).map(drop)
+        }))
     }
 }
 
+/// TCP connections go through the enclave runner's usercalls
+/// and the Unix sockets are exposed via "tendermint/<node index>" usercall extension
+fn open_stream(address: &net::Address, _timeout: Option<Duration>) -> io::Result<Box<dyn Stream>> {
+    let addr = match address {
+        net::Address::Tcp { host, port, .. } => format!("{}:{}", host, port),
+        net::Address::Unix { path } => path.to_string_lossy().into_owned(),
+    };
+    let stream = TcpStream::connect(&addr)?;
+    Ok(Box::new(RunnerStream { stream, addr }))
+}
+
+/// connections to tendermint privval endpoint of the `index`-th node
+/// (either TCP with the secret connection or the Unix socket), retried with backoff
+fn connection_manager(
+    index: usize,
+    secret_connection: Option<&RemoteConnectionConfig>,
+    protocol_version: ProtocolVersion,
+) -> io::Result<ConnectionManager> {
+    let (address, identity_key) = match secret_connection {
+        Some(RemoteConnectionConfig {
+            peer_id,
+            host,
+            port,
+            sealed_key,
+        }) => {
+            let identity_key = keypair_seal::unseal(sealed_key).map_err(|_| {
+                error!("unsealing failed");
+                io::Error::from(io::ErrorKind::Other)
+            })?;
+            info!("KMS node ID: {}", PublicKey::from(&identity_key));
+            let address = net::Address::Tcp {
+                peer_id: *peer_id,
+                host: host.clone(),
+                port: *port,
+            };
+            (address, Some(identity_key))
+        }
+        None => {
+            let address = net::Address::Unix {
+                path: validator_socket_addr(index).into(),
+            };
+            (address, None)
+        }
+    };
+    let mut manager = ConnectionManager::new(address, identity_key).map_err(|e| {
+        error!("connection config error: {}", e);
+        io::Error::from(io::ErrorKind::Other)
+    })?;
+    manager.set_transport(Box::new(open_stream));
+    manager.set_protocol_version(protocol_version);
+    Ok(manager)
+}
+
+/// connects to one of the validator's nodes and handles its requests
+/// (with the signer and the double-sign state shared by all nodes)
+fn run_session(
+    index: usize,
+    config: ValidatorConfig,
+    secret_connection: Option<RemoteConnectionConfig>,
+    signer: Arc<Box<dyn ConsensusSigner>>,
+    shared_state: Arc<Mutex<SharedState<state::StateHolder>>>,
+    metrics: Arc<dyn MetricsSink>,
+    control: Arc<SessionControl>,
+) -> io::Result<()> {
+    let mut manager =
+        connection_manager(index, secret_connection.as_ref(), config.protocol_version)?;
+    let conn = manager.connect().map_err(|e| {
+        error!("tendermint connection error: {}", e);
+        io::Error::from(io::ErrorKind::Other)
+    })?;
+    let mut session = Session::new_shared(config, conn, signer, shared_state);
+    session.set_metrics(metrics);
+    session.set_control(control);
+    session.run(&mut manager).map_err(|e| {
+        error!("request error: {}", e);
+        io::Error::from(io::ErrorKind::Other)
+    })
+}
+
 /// a simple req-rep handling loop
 /// `TcpStream` is either provided in tests or from the "init"
 /// enclave runner's user call extension.
@@ -117,11 +179,24 @@ pub fn entry(mut host_response: TcpStream, request: SgxInitRequest) -> io::Resul
                 error!("sealing failed");
             }
         }
-        SgxInitRequest::KeyGen { cloud_backup } => {
-            let kp = Keypair::generate(&mut csprng);
-            let cloud_backup_key_data =
-                cloud_backup.and_then(|key| cloud::cloud_backup(&mut csprng, key, &kp).ok());
-            if let Ok(sealed_key_data) = keypair_seal::seal(&mut csprng, &kp) {
+        SgxInitRequest::KeyGen {
+            cloud_backup,
+            key_type,
+        } => {
+            let sealed = if key_type == KeyType::Ed25519 {
+                let kp = Keypair::generate(&mut csprng);
+                let cloud_backup_key_data =
+                    cloud_backup.and_then(|key| cloud::cloud_backup(&mut csprng, key, &kp).ok());
+                keypair_seal::seal(&mut csprng, &kp)
+                    .map(|sealed_key_data| (sealed_key_data, cloud_backup_key_data))
+            } else {
+                if cloud_backup.is_some() {
+                    warn!("cloud backups are only supported for Ed25519 keys");
+                }
+                keypair_seal::seal_new_key(&mut csprng, key_type)
+                    .map(|sealed_key_data| (sealed_key_data, None))
+            };
+            if let Ok((sealed_key_data, cloud_backup_key_data)) = sealed {
                 let response = SgxInitResponse::GenOrRecover {
                     sealed_key_data,
                     cloud_backup_key_data,
@@ -165,27 +240,76 @@ pub fn entry(mut host_response: TcpStream, request: SgxInitRequest) -> io::Resul
         }
         SgxInitRequest::Start {
             sealed_key,
-            config,
-            secret_connection,
+            nodes,
             initial_state,
+            raised_state,
         } => {
-            let state_holder = state::StateHolder::new()?;
-            if let Ok(keypair) = keypair_seal::unseal(&sealed_key) {
-                let conn: Box<dyn Connection> = get_connection(secret_connection.as_ref());
-                let mut session = tmkms_light::session::Session::new(
-                    config,
-                    conn,
-                    keypair,
-                    initial_state.into(),
-                    state_holder,
-                );
-                loop {
-                    if let Err(e) = session.request_loop() {
-                        error!("request error: {}", e);
+            let chain_id = match nodes.first() {
+                Some(ValidatorNode { config, .. })
+                    if nodes
+                        .iter()
+                        .all(|node| node.config.chain_id == config.chain_id) =>
+                {
+                    config.chain_id.clone()
+                }
+                _ => {
+                    error!("the validator nodes need to be of one chain");
+                    return Err(io::ErrorKind::InvalidInput.into());
+                }
+            };
+            if let Ok((signer, secret_key)) = keypair_seal::unseal_signer(&sealed_key) {
+                let mut authenticator = StateAuthenticator::new(&secret_key, chain_id);
+                let initial_state = authenticator
+                    .verify_initial(&initial_state, raised_state, ALLOW_PLAIN_STATE)
+                    .map_err(|e| {
+                        error!("invalid initial state: {}", e);
+                        io::Error::from(io::ErrorKind::InvalidData)
+                    })?;
+                let state_holder = state::StateHolder::new(authenticator)?;
+                let metrics: Arc<dyn MetricsSink> = Arc::new(state::StreamMetrics::new()?);
+                // redundant nodes of the validator share the key and the double-sign state
+                let signer = Arc::new(signer);
+                let shared_state =
+                    Arc::new(Mutex::new(SharedState::new(initial_state, state_holder)));
+                let mut controls = Vec::with_capacity(nodes.len());
+                let mut sessions = Vec::with_capacity(nodes.len());
+                for (index, node) in nodes.into_iter().enumerate() {
+                    let control = Arc::new(SessionControl::new(
+                        node.config.chain_id.clone(),
+                        Some(node.peer),
+                    ));
+                    controls.push(control.clone());
+                    let signer = signer.clone();
+                    let shared_state = shared_state.clone();
+                    let metrics = metrics.clone();
+                    sessions.push(thread::spawn(move || {
+                        run_session(
+                            index,
+                            node.config,
+                            node.secret_connection,
+                            signer,
+                            shared_state,
+                            metrics,
+                            control,
+                        )
+                    }));
+                }
+                // the init stream stays open for the admin requests forwarded by the runner
+                thread::spawn(move || {
+                    if let Err(e) = admin::serve_forwarded(host_response, controls) {
+                        warn!("admin stream closed: {}", e);
+                    }
+                });
+                let mut result = Ok(());
+                for session in sessions {
+                    if let Err(e) = session
+                        .join()
+                        .unwrap_or_else(|_| Err(io::ErrorKind::Other.into()))
+                    {
+                        result = Err(e);
                     }
-                    let conn: Box<dyn Connection> = get_connection(secret_connection.as_ref());
-                    session.reset_connection(conn);
                 }
+                result?;
             } else {
                 error!("unsealing failed");
                 return Err(io::ErrorKind::Other.into());
@@ -238,6 +362,7 @@ mod tests {
         sender
             .send(Some(SgxInitRequest::KeyGen {
                 cloud_backup: Some(cloud_backup),
+                key_type: KeyType::Ed25519,
             }))
             .expect("send request1");
         let (mut stream_signer, _) = listener.accept().unwrap();
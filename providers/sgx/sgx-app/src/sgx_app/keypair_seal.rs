@@ -5,9 +5,11 @@ use aes_gcm_siv::{
 use ed25519_dalek::{Keypair, PublicKey, SecretKey};
 use rand::{rngs::OsRng, RngCore};
 use sgx_isa::{ErrorCode, Keyname, Keypolicy, Keyrequest, Report};
+use sha2::{Digest, Sha256};
 use std::convert::TryInto;
+use tmkms_light::signer::{generate_secret_key, signer_from_secret_key, ConsensusSigner, KeyType};
 use tmkms_light_sgx_runner::SealedKeyData;
-use zeroize::Zeroize;
+use zeroize::{Zeroize, Zeroizing};
 
 fn seal_payload(
     csprng: &mut OsRng,
@@ -35,6 +37,8 @@ fn seal_payload(
             seal_key_request: key_request.into(),
             nonce,
             sealed_secret,
+            key_type: KeyType::Ed25519,
+            public_key: None,
         })
     } else {
         key.zeroize();
@@ -53,6 +57,22 @@ pub fn seal(csprng: &mut OsRng, keypair: &Keypair) -> Result<SealedKeyData, Erro
     seal_payload(csprng, payload, keypair.public.to_bytes())
 }
 
+/// Generates a consensus key of the provided type (other than Ed25519) and seals it
+/// (the key request's ID is the SHA-256 digest of its public key, as the key may not fit)
+pub fn seal_new_key(csprng: &mut OsRng, key_type: KeyType) -> Result<SealedKeyData, ErrorCode> {
+    let secret_key = generate_secret_key(key_type);
+    let public_key = signer_from_secret_key(key_type, &secret_key)
+        .map_err(|_| ErrorCode::InvalidSignature)?
+        .public_key()
+        .to_bytes();
+    let mut keyid = [0u8; 32];
+    keyid.copy_from_slice(&Sha256::digest(&public_key));
+    let mut sealed_data = seal_secret(csprng, &secret_key, keyid)?;
+    sealed_data.key_type = key_type;
+    sealed_data.public_key = Some(public_key);
+    Ok(sealed_data)
+}
+
 pub fn seal_secret(
     csprng: &mut OsRng,
     secret: &[u8],
@@ -102,3 +122,20 @@ pub fn unseal(sealed_data: &SealedKeyData) -> Result<Keypair, ErrorCode> {
         Err(ErrorCode::InvalidSignature)
     }
 }
+
+/// Checks the provided keyrequests and attempts to unseal the consensus key of any type
+/// (the secret key bytes are returned as well, as they key the state records' MAC)
+pub fn unseal_signer(
+    sealed_data: &SealedKeyData,
+) -> Result<(Box<dyn ConsensusSigner>, Zeroizing<Vec<u8>>), ErrorCode> {
+    let public_key = sealed_data
+        .consensus_public_key()
+        .map_err(|_| ErrorCode::InvalidSignature)?;
+    let secret_key = Zeroizing::new(unseal_secret(sealed_data)?);
+    let signer = signer_from_secret_key(sealed_data.key_type, &secret_key)
+        .map_err(|_| ErrorCode::InvalidSignature)?;
+    if signer.public_key() != public_key {
+        return Err(ErrorCode::InvalidSignature);
+    }
+    Ok((signer, secret_key))
+}
@@ -1,54 +1,161 @@
-use anomaly::format_err;
-use std::{io, net::TcpStream};
+use anomaly::{fail, format_err};
+use std::{
+    io,
+    net::TcpStream,
+    sync::{
+        mpsc::{channel, Receiver, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Instant,
+};
 use tmkms_light::{
-    chain::state::{consensus, PersistStateSync, State, StateError, StateErrorKind},
+    chain::state::{
+        consensus, PersistStateSync, State, StateAck, StateAuthenticator, StateError,
+        StateErrorKind, STATE_ACK_TIMEOUT,
+    },
+    metrics::{MetricEvent, MetricsSink},
     utils::{read_u16_payload, write_u16_payload},
 };
-use tracing::debug;
+use tracing::{debug, warn};
 
 /// holds the connection for persiting the state outside of the enclave
 pub struct StateHolder {
-    state_conn: TcpStream,
+    // `TcpStream::try_clone` isn't supported in SGX,
+    // so the connection is shared with the acknowledgement reader
+    state_conn: Arc<TcpStream>,
+    /// acknowledgements of the persisted states from the runner
+    acks: Receiver<StateAck>,
+    /// authenticates the state records persisted by the runner
+    authenticator: StateAuthenticator,
 }
 
 impl StateHolder {
     /// tries to connect to "state" address which is provided
     /// as "usercall extension" in the runner
-    pub fn new() -> io::Result<Self> {
+    /// (and starts a thread reading the acknowledgements, as socket timeouts
+    /// have no effect in SGX)
+    pub fn new(authenticator: StateAuthenticator) -> io::Result<Self> {
+        let state_conn = Arc::new(TcpStream::connect("state")?);
+        let (ack_sender, acks) = channel();
+        let reader = state_conn.clone();
+        thread::spawn(move || loop {
+            let ack = read_u16_payload(&mut &*reader)
+                .map_err(|e| format!("error reading from state socket: {}", e))
+                .and_then(|json_raw| {
+                    serde_json::from_slice::<StateAck>(&json_raw)
+                        .map_err(|e| format!("error parsing acknowledgement: {}", e))
+                });
+            match ack {
+                Ok(ack) => {
+                    if ack_sender.send(ack).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    warn!("{}", e);
+                    return;
+                }
+            }
+        });
         Ok(Self {
-            state_conn: TcpStream::connect("state")?,
+            state_conn,
+            acks,
+            authenticator,
         })
     }
+
+    /// waits for the runner to acknowledge the given state
+    fn wait_ack(&self, new_state: &consensus::State) -> Result<(), StateError> {
+        let deadline = Instant::now() + STATE_ACK_TIMEOUT;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let ack = self.acks.recv_timeout(timeout).map_err(|e| match e {
+                RecvTimeoutError::Timeout => format_err!(
+                    StateErrorKind::SyncError,
+                    "no acknowledgement of the persisted state within {:?}",
+                    STATE_ACK_TIMEOUT
+                ),
+                RecvTimeoutError::Disconnected => format_err!(
+                    StateErrorKind::SyncError,
+                    "state connection closed before the acknowledgement"
+                ),
+            })?;
+            if ack.state != *new_state {
+                warn!("ignoring a late acknowledgement of state {}", ack.state);
+                continue;
+            }
+            if let Some(e) = ack.error {
+                fail!(
+                    StateErrorKind::SyncError,
+                    "the runner failed to persist the state: {}",
+                    e
+                );
+            }
+            return Ok(());
+        }
+    }
 }
 
 impl PersistStateSync for StateHolder {
     fn load_state(&mut self) -> Result<State, StateError> {
-        // TODO: currently unused as the initial state is now provided/loaded via "args"
+        // TODO: the initial state is provided/loaded via "args"
+        // (and the state connection is only read by the acknowledgement reader),
         // so `PersistStateSync` is to be revisited
-        let json_raw = read_u16_payload(&mut self.state_conn)
-            .map_err(|e| format_err!(StateErrorKind::SyncError, "error reading state: {}", e))?;
-        let consensus_state: consensus::State = serde_json::from_slice(&json_raw)
-            .map_err(|e| format_err!(StateErrorKind::SyncError, "error parsing state: {}", e))?;
-        Ok(State::from(consensus_state))
+        fail!(
+            StateErrorKind::SyncError,
+            "the initial state is provided in the start request"
+        )
     }
 
-    fn persist_state(&mut self, new_state: &consensus::State) -> Result<(), StateError> {
+    fn persist_state(&mut self, new_state: &State) -> Result<(), StateError> {
         debug!("writing new consensus state to state conn");
 
-        let json_raw = serde_json::to_vec(&new_state).map_err(|e| {
+        let record = self.authenticator.authenticate(new_state);
+        let json_raw = serde_json::to_vec(&record).map_err(|e| {
             format_err!(StateErrorKind::SyncError, "error serializing state: {}", e)
         })?;
-
-        write_u16_payload(&mut self.state_conn, &json_raw).map_err(|e| {
+        write_u16_payload(&mut &*self.state_conn, &json_raw).map_err(|e| {
             format_err!(
                 StateErrorKind::SyncError,
-                "error state writting to socket {}",
+                "error writting to state socket {}",
                 e
             )
         })?;
+        self.wait_ack(new_state.consensus_state())?;
+        self.authenticator.acknowledged(&record);
 
-        debug!("successfully wrote new consensus state to state connection");
+        debug!("the runner acknowledged the new consensus state");
 
         Ok(())
     }
 }
+
+/// forwards the metric events to the runner over their own connection
+/// (provided as "usercall extension" in the runner, like the state connection)
+pub struct StreamMetrics {
+    metrics_conn: Mutex<TcpStream>,
+}
+
+impl StreamMetrics {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            metrics_conn: Mutex::new(TcpStream::connect("metrics")?),
+        })
+    }
+}
+
+impl MetricsSink for StreamMetrics {
+    fn record(&self, event: MetricEvent) {
+        let sent = serde_json::to_vec(&event)
+            .map_err(|e| format!("error serializing metric event: {}", e))
+            .and_then(|json_raw| {
+                let mut metrics_conn = self.metrics_conn.lock().unwrap_or_else(|e| e.into_inner());
+                write_u16_payload(&mut *metrics_conn, &json_raw)
+                    .map_err(|e| format!("error writting to metrics socket {}", e))
+            });
+        if let Err(e) = sent {
+            warn!("failed to send metric event: {}", e);
+        }
+    }
+}
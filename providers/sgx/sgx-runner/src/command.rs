@@ -1,17 +1,25 @@
 use crate::config::RecoverConfig;
 use crate::shared::{CloudBackupKey, CloudBackupSeal, SealedKeyData};
-use crate::{config, runner::TmkmsSgxSigner};
+use crate::{config, runner::TmkmsSgxSigner, state::StateSyncer};
 use crate::{shared::get_claim, shared::SgxInitResponse, SgxInitRequest};
 
 use rsa::PublicKeyPemEncoding;
 use std::fs;
-use std::path::PathBuf;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
 use tendermint::net;
 use tmkms_light::{
+    admin::{self, AdminRequest, AdminResponse},
+    chain::state::{hook, Durability},
     config::validator::ValidatorConfig,
+    grpc,
+    metrics::Metrics,
+    signer::KeyType,
     utils::{print_pubkey, PubkeyDisplay},
 };
-use tracing::debug;
+use tracing::{debug, error};
 
 /// generate a key wrap for cloud backups
 pub fn keywrap(
@@ -33,12 +41,13 @@ pub fn keywrap(
     let request = SgxInitRequest::GenWrapKey { targetinfo };
     let request_bytes = serde_json::to_vec(&request)
         .map_err(|e| format!("failed to convert request to json: {:?}", e))?;
-    let (state_syncer, _, state_stream) = TmkmsSgxSigner::get_state_syncer("/tmp/state.json")
-        .map_err(|e| format!("state persistence error: {:?}", e))?;
+    let (state_syncer, _, state_stream) =
+        TmkmsSgxSigner::get_state_syncer("/tmp/state.json", Durability::None)
+            .map_err(|e| format!("state persistence error: {:?}", e))?;
     let enclave_args: Vec<&[u8]> = vec![request_bytes.as_ref(), log_level.as_bytes()];
     let runner = TmkmsSgxSigner::launch_enclave_app(
         &enclave_path,
-        None,
+        Vec::new(),
         state_syncer,
         state_stream,
         &enclave_args,
@@ -86,6 +95,19 @@ pub fn keywrap(
     }
 }
 
+/// the state persistence helper for the enclave app runs that generate or recover keys
+/// (they don't sign, so the state file of the first validator entry is only loaded)
+fn keygen_state_syncer(config: &config::SgxSignOpt) -> Result<(StateSyncer, UnixStream), String> {
+    let validator = config
+        .validators
+        .first()
+        .ok_or_else(|| "no `[[validator]]` entries in config".to_owned())?;
+    let (state_syncer, _, state_stream) =
+        TmkmsSgxSigner::get_state_syncer(&validator.state_file_path, config.state_durability)
+            .map_err(|e| format!("state persistence error: {:?}", e))?;
+    Ok((state_syncer, state_stream))
+}
+
 /// write tmkms.toml + generate keys (sealed for machine CPU
 /// + backup if an external key is provided)
 pub fn init(
@@ -95,20 +117,22 @@ pub fn init(
     wrap_backup_key_path: Option<PathBuf>,
     external_cloud_key_path: Option<PathBuf>,
     key_backup_data_path: Option<PathBuf>,
+    key_type: KeyType,
+    allow_insecure_key_files: bool,
     log_level: String,
 ) -> Result<(), String> {
+    let config = config::SgxSignOpt {
+        allow_insecure_key_files,
+        ..Default::default()
+    };
     let cloud_backup = match (wrap_backup_key_path, external_cloud_key_path) {
         (Some(p1), Some(p2)) => {
-            let sealed_rsa_key: SealedKeyData = serde_json::from_slice(
-                &fs::read(p1)
-                    .map_err(|e| format!("failed to read sealed wrap key for external backup key: {:?}", e))?,
-            )
-            .map_err(|e| format!("failed to parse sealed wrap key for external backup key: {:?}", e))?;
-            let backup_key: CloudBackupSeal = serde_json::from_slice(
-                &fs::read(p2)
-                    .map_err(|e| format!("failed to read external backup key: {:?}", e))?,
-            )
-            .map_err(|e| format!("failed to parse external backup key: {:?}", e))?;
+            let sealed_rsa_key: SealedKeyData =
+                config::read_json_file(p1, config.allow_insecure_key_files)
+                    .map_err(|e| format!("sealed wrap key for external backup key: {}", e))?;
+            let backup_key: CloudBackupSeal =
+                config::read_json_file(p2, config.allow_insecure_key_files)
+                    .map_err(|e| format!("external backup key: {}", e))?;
             let cloud_backup = CloudBackupKey {
                 sealed_rsa_key,
                 backup_key,
@@ -120,8 +144,10 @@ pub fn init(
         }
         _ => None,
     };
+    if cloud_backup.is_some() && key_type != KeyType::Ed25519 {
+        return Err("cloud backups are only supported for Ed25519 consensus keys".to_owned());
+    }
     let cp = config_path.unwrap_or_else(|| "tmkms.toml".into());
-    let config = config::SgxSignOpt::default();
     let t =
         toml::to_string_pretty(&config).map_err(|e| format!("config to toml failed: {:?}", e))?;
     fs::write(cp, t).map_err(|e| format!("failed to write a config: {:?}", e))?;
@@ -132,25 +158,29 @@ pub fn init(
             .ok_or_else(|| "cannot create a dir in a root directory".to_owned())?,
     )
     .map_err(|e| format!("failed to create dirs for key storage: {:?}", e))?;
-    fs::create_dir_all(
-        config
-            .state_file_path
-            .parent()
-            .ok_or_else(|| "cannot create a dir in a root directory".to_owned())?,
-    )
-    .map_err(|e| format!("failed to create dirs for state storage: {:?}", e))?;
-    let request = SgxInitRequest::KeyGen { cloud_backup };
+    for validator in config.validators.iter() {
+        fs::create_dir_all(
+            validator
+                .state_file_path
+                .parent()
+                .ok_or_else(|| "cannot create a dir in a root directory".to_owned())?,
+        )
+        .map_err(|e| format!("failed to create dirs for state storage: {:?}", e))?;
+    }
+    let request = SgxInitRequest::KeyGen {
+        cloud_backup: cloud_backup.clone(),
+        key_type,
+    };
     let request_bytes = serde_json::to_vec(&request)
         .map_err(|e| format!("failed to convert request to json: {:?}", e))?;
 
     debug!("launching enclave");
-    let (state_syncer, _, state_stream) = TmkmsSgxSigner::get_state_syncer(&config.state_file_path)
-        .map_err(|e| format!("state persistence error: {:?}", e))?;
+    let (state_syncer, state_stream) = keygen_state_syncer(&config)?;
     let enclave_args: Vec<&[u8]> = vec![request_bytes.as_ref(), log_level.as_bytes()];
 
     let runner = TmkmsSgxSigner::launch_enclave_app(
         &config.enclave_path,
-        None,
+        Vec::new(),
         state_syncer,
         state_stream,
         &enclave_args,
@@ -163,24 +193,32 @@ pub fn init(
     let (sealed_key_data, cloud_backup_key_data) = sealed_key
         .get_gen_response()
         .ok_or_else(|| "failed to generate consensus key".to_owned())?;
-    config::write_sealed_file(config.sealed_consensus_key_path, &sealed_key_data)
+    config::write_sealed_file(&config.sealed_consensus_key_path, &sealed_key_data)
         .map_err(|e| format!("failed to write consensus key: {:?}", e))?;
-    let public_key = ed25519_dalek::PublicKey::from_bytes(&sealed_key_data.seal_key_request.keyid)
-        .map_err(|e| format!("invalid keyid: {:?}", e))?;
-    print_pubkey(bech32_prefix, pubkey_display, public_key);
+    print_pubkey(
+        bech32_prefix,
+        pubkey_display,
+        sealed_key_data.consensus_public_key()?,
+    );
     let base_backup_path = key_backup_data_path.unwrap_or_else(|| "".into());
     if let Some(bkp) = cloud_backup_key_data {
         config::write_backup_file(base_backup_path.join("consensus-key.backup"), &bkp)
             .map_err(|e| format!("failed to write consensus key backup: {:?}", e))?;
     }
-    if let Some(id_path) = config.sealed_id_key_path {
-        let (state_syncer, _, state_stream) =
-            TmkmsSgxSigner::get_state_syncer(&config.state_file_path)
-                .map_err(|e| format!("state persistence error: {:?}", e))?;
+    if let Some(id_path) = &config.sealed_id_key_path {
+        // the identity key is always an Ed25519 key
+        let request = SgxInitRequest::KeyGen {
+            cloud_backup,
+            key_type: KeyType::Ed25519,
+        };
+        let request_bytes = serde_json::to_vec(&request)
+            .map_err(|e| format!("failed to convert request to json: {:?}", e))?;
+        let enclave_args: Vec<&[u8]> = vec![request_bytes.as_ref(), log_level.as_bytes()];
+        let (state_syncer, state_stream) = keygen_state_syncer(&config)?;
 
         let runner = TmkmsSgxSigner::launch_enclave_app(
             &config.enclave_path,
-            None,
+            Vec::new(),
             state_syncer,
             state_stream,
             &enclave_args,
@@ -211,51 +249,155 @@ pub fn start(config_path: Option<PathBuf>, log_level: String) -> Result<(), Stri
     } else {
         let toml_string = fs::read_to_string(cp)
             .map_err(|e| format!("toml config file failed to read: {:?}", e))?;
-        let config: config::SgxSignOpt = toml::from_str(&toml_string)
-            .map_err(|e| format!("toml config file failed to parse: {:?}", e))?;
-        let tm_conn = match &config.address {
+        let config = config::SgxSignOpt::from_toml(&toml_string)?;
+        config.validate()?;
+        let metrics = match config.metrics_listen_addr {
+            Some(addr) => {
+                let metrics = Arc::new(Metrics::default());
+                metrics
+                    .clone()
+                    .serve(addr)
+                    .map_err(|e| format!("failed to serve metrics on {}: {:?}", addr, e))?;
+                Some(metrics)
+            }
+            None => None,
+        };
+        // streams to the enclave instances for forwarding admin requests
+        let admin_streams: Arc<Mutex<Vec<(String, UnixStream)>>> = Arc::new(Mutex::new(Vec::new()));
+        if let Some(path) = &config.admin_socket_path {
+            let admin_streams = admin_streams.clone();
+            admin::serve(path, move |request| {
+                let mut admin_streams = admin_streams.lock().unwrap_or_else(|e| e.into_inner());
+                admin::forward_to_all(&mut admin_streams, request)
+            })
+            .map_err(|e| format!("failed to serve the admin socket: {}", e))?;
+        }
+        let config = Arc::new(config);
+        let sessions: Vec<_> = config
+            .validator_groups()
+            .into_iter()
+            .map(|group| {
+                let config = config.clone();
+                let validators: Vec<_> = group.into_iter().cloned().collect();
+                let log_level = log_level.clone();
+                let metrics = metrics.clone();
+                let admin_streams = admin_streams.clone();
+                thread::spawn(move || {
+                    start_validator(&config, validators, log_level, metrics, admin_streams)
+                })
+            })
+            .collect();
+        let mut result = Ok(());
+        for session in sessions {
+            if let Err(e) = session
+                .join()
+                .unwrap_or_else(|e| Err(format!("session thread panicked: {:?}", e)))
+            {
+                error!("{}", e);
+                result = Err("enclave running failed".to_owned());
+            }
+        }
+        result
+    }
+}
+
+/// launches an enclave instance for the `[[validator]]` entries of one chain
+/// (with its own state file and state persistence helper shared by the nodes of the validator)
+fn start_validator(
+    config: &config::SgxSignOpt,
+    validators: Vec<config::SgxValidatorOpt>,
+    log_level: String,
+    metrics: Option<Arc<Metrics>>,
+    admin_streams: Arc<Mutex<Vec<(String, UnixStream)>>>,
+) -> Result<(), String> {
+    // the entries of the chain share the state file and the key (checked in `validate`)
+    let validator = &validators[0];
+    let tm_conns = validators
+        .iter()
+        .map(|node| match &node.address {
             net::Address::Unix { path } => {
                 debug!(
                     "{}: Connecting to socket at {}...",
-                    &config.chain_id, &config.address
+                    &node.chain_id, &node.address
                 );
 
                 Some(path.clone())
             }
             _ => None,
-        };
-        let remote = if let (None, Some(path)) = (&tm_conn, config.sealed_id_key_path) {
-            Some((config.address, path))
-        } else {
-            None
-        };
-        let (state_syncer, state, state_stream) =
-            TmkmsSgxSigner::get_state_syncer(&config.state_file_path)
-                .map_err(|e| format!("state persistence error: {:?}", e))?;
-        let start_request_bytes = TmkmsSgxSigner::get_start_request_bytes(
-            config.sealed_consensus_key_path,
-            ValidatorConfig {
-                chain_id: config.chain_id,
-                max_height: config.max_height,
-            },
-            state,
-            remote,
-        )
-        .map_err(|e| format!("failed to get enclave request: {:?}", e))?;
-        let enclave_args: Vec<&[u8]> = vec![start_request_bytes.as_ref(), log_level.as_bytes()];
-        let runner = TmkmsSgxSigner::launch_enclave_app(
-            &config.enclave_path,
-            tm_conn,
-            state_syncer,
-            state_stream,
-            &enclave_args,
+        })
+        .collect();
+    let (mut state_syncer, state, state_stream) =
+        TmkmsSgxSigner::get_state_syncer(&validator.state_file_path, config.state_durability)
+            .map_err(|e| format!("[{}] state persistence error: {:?}", validator.chain_id, e))?;
+    // the raised state is passed to the enclave app separately,
+    // as only the enclave app can authenticate the state records
+    let raised_state = match validators.iter().find_map(|v| v.state_hook.as_ref()) {
+        Some(state_hook) => hook::run(state_hook, &state.state)
+            .map_err(|e| format!("[{}] startup state hook: {}", validator.chain_id, e))?,
+        None => None,
+    };
+    if let Some(metrics) = metrics {
+        state_syncer.set_metrics(metrics);
+    }
+    let nodes = validators
+        .iter()
+        .map(|node| {
+            let config = ValidatorConfig {
+                chain_id: node.chain_id.clone(),
+                max_height: node.max_height,
+                idle_timeout: node.idle_timeout,
+                protocol_version: node.protocol_version,
+            };
+            (config, node.address.clone())
+        })
+        .collect();
+    let start_request_bytes = TmkmsSgxSigner::get_start_request_bytes(
+        config.sealed_consensus_key(validator),
+        nodes,
+        state,
+        raised_state,
+        config.sealed_id_key_path.clone(),
+        config.allow_insecure_key_files,
+    )
+    .map_err(|e| {
+        format!(
+            "[{}] failed to get enclave request: {:?}",
+            validator.chain_id, e
         )
-        .map_err(|e| format!("failed to launch the enclave app: {:?}", e))?;
-        runner
-            .start()
-            .map_err(|e| format!("enclave running failed: {:?}", e))?;
-        Ok(())
+    })?;
+    for node in validators.iter() {
+        if let (Some(grpc), net::Address::Unix { path }) = (&node.grpc, &node.address) {
+            grpc::spawn(grpc, Path::new(path), config.allow_insecure_key_files)
+                .map_err(|e| format!("[{}] gRPC server: {}", node.chain_id, e))?;
+        }
     }
+    let enclave_args: Vec<&[u8]> = vec![start_request_bytes.as_ref(), log_level.as_bytes()];
+    let runner = TmkmsSgxSigner::launch_enclave_app(
+        &config.enclave_path,
+        tm_conns,
+        state_syncer,
+        state_stream,
+        &enclave_args,
+    )
+    .map_err(|e| {
+        format!(
+            "[{}] failed to launch the enclave app: {:?}",
+            validator.chain_id, e
+        )
+    })?;
+    let admin_stream = runner.admin_stream().map_err(|e| {
+        format!(
+            "[{}] failed to get the admin stream: {:?}",
+            validator.chain_id, e
+        )
+    })?;
+    admin_streams
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push((validator.address.to_string(), admin_stream));
+    runner
+        .start()
+        .map_err(|e| format!("[{}] enclave running failed: {:?}", validator.chain_id, e))
 }
 
 /// recover the previously backed up id/consensus key (e.g. in cloud settings where
@@ -269,46 +411,35 @@ pub fn recover(recover_config: RecoverConfig, log_level: String) -> Result<(), S
     } else {
         let toml_string = fs::read_to_string(cp)
             .map_err(|e| format!("toml config file failed to read: {:?}", e))?;
-        let config: config::SgxSignOpt = toml::from_str(&toml_string)
-            .map_err(|e| format!("toml config file failed to parse: {:?}", e))?;
+        let config = config::SgxSignOpt::from_toml(&toml_string)?;
+        config.validate()?;
         if !recover_config.recover_consensus_key && config.sealed_id_key_path.is_none() {
             return Err("empty id key path in config".to_owned());
         }
         let cloud_backup = {
-            let sealed_rsa_key: SealedKeyData = serde_json::from_slice(
-                &fs::read(recover_config.wrap_backup_key_path).map_err(|e| {
-                    format!(
-                        "failed to read sealed wrap key for external backup key: {:?}",
-                        e
-                    )
-                })?,
+            let sealed_rsa_key: SealedKeyData = config::read_json_file(
+                recover_config.wrap_backup_key_path,
+                config.allow_insecure_key_files,
             )
-            .map_err(|e| {
-                format!(
-                    "failed to parse sealed wrap key for external backup key: {:?}",
-                    e
-                )
-            })?;
-            let backup_key: CloudBackupSeal = serde_json::from_slice(
-                &fs::read(recover_config.external_cloud_key_path)
-                    .map_err(|e| format!("failed to read external backup key: {:?}", e))?,
+            .map_err(|e| format!("sealed wrap key for external backup key: {}", e))?;
+            let backup_key: CloudBackupSeal = config::read_json_file(
+                recover_config.external_cloud_key_path,
+                config.allow_insecure_key_files,
             )
-            .map_err(|e| format!("failed to parse external backup key: {:?}", e))?;
+            .map_err(|e| format!("external backup key: {}", e))?;
             CloudBackupKey {
                 sealed_rsa_key,
                 backup_key,
             }
         };
 
-        let key_data = serde_json::from_str(
-            &fs::read_to_string(
-                recover_config
-                    .key_backup_data_path
-                    .join("consensus-key.backup"),
-            )
-            .map_err(|e| format!("failed to read backup data: {:?}", e))?,
+        let key_data = config::read_json_file(
+            recover_config
+                .key_backup_data_path
+                .join("consensus-key.backup"),
+            config.allow_insecure_key_files,
         )
-        .map_err(|e| format!("failed to parse backup data: {:?}", e))?;
+        .map_err(|e| format!("backup data: {}", e))?;
         let request = SgxInitRequest::CloudRecover {
             cloud_backup,
             key_data,
@@ -316,13 +447,11 @@ pub fn recover(recover_config: RecoverConfig, log_level: String) -> Result<(), S
         let request_bytes = serde_json::to_vec(&request)
             .map_err(|e| format!("failed to convert request to json: {:?}", e))?;
         debug!("launching enclave");
-        let (state_syncer, _, state_stream) =
-            TmkmsSgxSigner::get_state_syncer(&config.state_file_path)
-                .map_err(|e| format!("state persistence error: {:?}", e))?;
+        let (state_syncer, state_stream) = keygen_state_syncer(&config)?;
         let enclave_args: Vec<&[u8]> = vec![request_bytes.as_ref(), log_level.as_bytes()];
         let runner = TmkmsSgxSigner::launch_enclave_app(
             &config.enclave_path,
-            None,
+            Vec::new(),
             state_syncer,
             state_stream,
             &enclave_args,
@@ -339,14 +468,11 @@ pub fn recover(recover_config: RecoverConfig, log_level: String) -> Result<(), S
         if recover_config.recover_consensus_key {
             config::write_sealed_file(config.sealed_consensus_key_path, &sealed_key_data)
                 .map_err(|e| format!("failed to write consensus key: {:?}", e))?;
-            let public_key =
-                ed25519_dalek::PublicKey::from_bytes(&sealed_key_data.seal_key_request.keyid)
-                    .map_err(|e| format!("ivalid keyid: {:?}", e))?;
             println!("recovered key");
             print_pubkey(
                 recover_config.bech32_prefix,
                 recover_config.pubkey_display,
-                public_key,
+                sealed_key_data.consensus_public_key()?,
             );
         } else {
             // checked above after config parsing
@@ -357,3 +483,23 @@ pub fn recover(recover_config: RecoverConfig, log_level: String) -> Result<(), S
         Ok(())
     }
 }
+
+/// send a command to the admin socket of the running runner and print its response
+pub fn admin(config_path: Option<PathBuf>, request: AdminRequest) -> Result<(), String> {
+    let cp = config_path.unwrap_or_else(|| "tmkms.toml".into());
+    let toml_string =
+        fs::read_to_string(cp).map_err(|e| format!("toml config file failed to read: {:?}", e))?;
+    let config = config::SgxSignOpt::from_toml(&toml_string)?;
+    let path = config
+        .admin_socket_path
+        .ok_or_else(|| "no `admin_socket_path` in config".to_owned())?;
+    match admin::send_request(&path, request).map_err(|e| format!("admin request failed: {}", e))? {
+        AdminResponse::Error(e) => Err(format!("admin command failed: {}", e)),
+        response => {
+            let json = serde_json::to_string_pretty(&response)
+                .map_err(|e| format!("failed to serialize the response: {:?}", e))?;
+            println!("{}", json);
+            Ok(())
+        }
+    }
+}
@@ -1,34 +1,177 @@
 use crate::shared::CloudBackupKeyData;
 use crate::shared::SealedKeyData;
-use serde::{Deserialize, Serialize};
-use std::{convert::TryFrom, path::PathBuf};
-use std::{fs::OpenOptions, io, os::unix::fs::OpenOptionsExt, path::Path};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{convert::TryFrom, net::SocketAddr, path::PathBuf};
+use std::{io, path::Path};
 use structopt::StructOpt;
 use tendermint::{chain, net};
-use tmkms_light::utils::PubkeyDisplay;
-use tracing::error;
+use tmkms_light::{
+    chain::state::{hook::HookConfig, Durability},
+    config::{grpc::GrpcConfig, validator::ProtocolVersion},
+    secure_file,
+    utils::{canonical_path, PubkeyDisplay},
+};
+use tracing::{error, warn};
+
+/// Keys of the earlier single-validator config format that are now set in `[[validator]]` entries
+const LEGACY_VALIDATOR_KEYS: [&str; 4] = ["address", "chain_id", "max_height", "state_file_path"];
 
 /// runner configuration in toml
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct SgxSignOpt {
+    /// Path to a file containing a cryptographic key
+    pub sealed_consensus_key_path: PathBuf,
+    /// Path to our Ed25519 identity key (if applicable)
+    pub sealed_id_key_path: Option<PathBuf>,
+    /// Path to sgxs + signature files
+    pub enclave_path: PathBuf,
+    /// Address to serve Prometheus metrics on (if enabled)
+    pub metrics_listen_addr: Option<SocketAddr>,
+    /// Path to the Unix-domain admin socket (if enabled)
+    pub admin_socket_path: Option<PathBuf>,
+    /// How the state files are written (`fsync` by default, `dsync` or `none`)
+    #[serde(default)]
+    pub state_durability: Durability,
+    /// Load sealed key and backup files even if others could access them (not recommended)
+    #[serde(default)]
+    pub allow_insecure_key_files: bool,
+    /// Validator connections (one enclave instance per chain)
+    #[serde(rename = "validator")]
+    pub validators: Vec<SgxValidatorOpt>,
+}
+
+/// A single `[[validator]]` entry
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SgxValidatorOpt {
     /// Address of the validator (`tcp://` or `unix://`)
     pub address: net::Address,
     /// Chain ID of the Tendermint network this validator is part of
     pub chain_id: chain::Id,
     /// Height at which to stop signing
     pub max_height: Option<tendermint::block::Height>,
-    /// Path to a file containing a cryptographic key
-    pub sealed_consensus_key_path: PathBuf,
-    /// Path to our Ed25519 identity key (if applicable)
-    pub sealed_id_key_path: Option<PathBuf>,
+    /// Path to a sealed consensus key for this chain (if different from the shared one)
+    pub sealed_consensus_key_path: Option<PathBuf>,
     /// Path to chain-specific `priv_validator_state.json` file
     pub state_file_path: PathBuf,
-    /// Path to sgxs + signature files
-    pub enclave_path: PathBuf,
+    /// Seconds without any request (including pings) after which the connection is rebuilt (if set)
+    pub idle_timeout: Option<u16>,
+    /// Protocol version of the node (`v0.33`, `v0.34` (default), `v0.37` or `v0.38`)
+    #[serde(default)]
+    pub protocol_version: ProtocolVersion,
+    /// Startup hook checking the latest block height of the chain (if enabled)
+    pub state_hook: Option<HookConfig>,
+    /// Privval gRPC server for the node (relayed to the session on the `unix://` address)
+    pub grpc: Option<GrpcConfig>,
+}
+
+impl SgxSignOpt {
+    /// Parses the toml config (a config in the earlier single-validator format,
+    /// without `[[validator]]` entries, is read as a config with one entry)
+    pub fn from_toml(toml_string: &str) -> Result<Self, String> {
+        let mut table: toml::value::Table = toml::from_str(toml_string)
+            .map_err(|e| format!("toml config file failed to parse: {:?}", e))?;
+        if !table.contains_key("validator") && table.contains_key("chain_id") {
+            warn!("single-validator config: the validator settings should be moved to a `[[validator]]` entry");
+            let validator: toml::value::Table = LEGACY_VALIDATOR_KEYS
+                .iter()
+                .filter_map(|key| table.remove(*key).map(|value| ((*key).to_owned(), value)))
+                .collect();
+            table.insert(
+                "validator".to_owned(),
+                toml::Value::Array(vec![toml::Value::Table(validator)]),
+            );
+        }
+        toml::Value::Table(table)
+            .try_into()
+            .map_err(|e| format!("toml config file failed to parse: {:?}", e))
+    }
+
+    /// Checks that entries of the same chain (i.e. redundant nodes of one validator)
+    /// share the state file and the key and that different chains use separate state files
+    pub fn validate(&self) -> Result<(), String> {
+        if self.validators.is_empty() {
+            return Err("no `[[validator]]` entries in config".to_owned());
+        }
+        for (i, validator) in self.validators.iter().enumerate() {
+            if validator.grpc.is_some() && !matches!(validator.address, net::Address::Unix { .. }) {
+                return Err(format!(
+                    "[{}] `grpc` needs a `unix://` address for the session to connect to",
+                    validator.chain_id
+                ));
+            }
+            let state_file_path = canonical_path(&validator.state_file_path);
+            for other in self.validators[..i].iter() {
+                let same_chain = other.chain_id == validator.chain_id;
+                let same_state = canonical_path(&other.state_file_path) == state_file_path;
+                if same_chain && !same_state {
+                    return Err(format!(
+                        "[{}] validator entries of the same chain need to share the state file",
+                        validator.chain_id
+                    ));
+                }
+                if !same_chain && same_state {
+                    return Err(format!(
+                        "[{}] state file {} is used by another chain",
+                        validator.chain_id,
+                        validator.state_file_path.display()
+                    ));
+                }
+                if same_chain
+                    && canonical_path(&self.sealed_consensus_key(other))
+                        != canonical_path(&self.sealed_consensus_key(validator))
+                {
+                    return Err(format!(
+                        "[{}] validator entries of the same chain need to share the consensus key",
+                        validator.chain_id
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Validator entries grouped by chain (in the order of their first appearance)
+    pub fn validator_groups(&self) -> Vec<Vec<&SgxValidatorOpt>> {
+        let mut groups: Vec<Vec<&SgxValidatorOpt>> = Vec::new();
+        for validator in self.validators.iter() {
+            match groups
+                .iter_mut()
+                .find(|group| group[0].chain_id == validator.chain_id)
+            {
+                Some(group) => group.push(validator),
+                None => groups.push(vec![validator]),
+            }
+        }
+        groups
+    }
+
+    /// The sealed consensus key path to use for the given validator entry
+    pub fn sealed_consensus_key(&self, validator: &SgxValidatorOpt) -> PathBuf {
+        validator
+            .sealed_consensus_key_path
+            .clone()
+            .unwrap_or_else(|| self.sealed_consensus_key_path.clone())
+    }
 }
 
 impl Default for SgxSignOpt {
+    fn default() -> Self {
+        Self {
+            sealed_consensus_key_path: "secrets/secret.key".into(),
+            sealed_id_key_path: Some("secrets/id.key".into()),
+            enclave_path: "enclave/tmkms-light-sgx-app.sgxs".into(),
+            metrics_listen_addr: None,
+            admin_socket_path: None,
+            state_durability: Durability::default(),
+            allow_insecure_key_files: false,
+            validators: vec![SgxValidatorOpt::default()],
+        }
+    }
+}
+
+impl Default for SgxValidatorOpt {
     fn default() -> Self {
         Self {
             address: net::Address::Unix {
@@ -36,30 +179,37 @@ impl Default for SgxSignOpt {
             },
             chain_id: chain::Id::try_from("testchain-1".to_owned()).expect("valid chain-id"),
             max_height: None,
-            sealed_consensus_key_path: "secrets/secret.key".into(),
-            sealed_id_key_path: Some("secrets/id.key".into()),
+            sealed_consensus_key_path: None,
             state_file_path: "state/priv_validator_state.json".into(),
-            enclave_path: "enclave/tmkms-light-sgx-app.sgxs".into(),
+            idle_timeout: None,
+            protocol_version: ProtocolVersion::default(),
+            state_hook: None,
+            grpc: None,
         }
     }
 }
 
 fn write_json_file<P: AsRef<Path>, T: ?Sized + Serialize>(path: P, data: &T) -> io::Result<()> {
-    OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .mode(0o600)
-        .open(path.as_ref())
-        .and_then(|file| {
-            serde_json::to_writer(file, data).map_err(|e| {
-                error!(
-                    "failed to write key (backup or sealed) data payload: {:?}",
-                    e
-                );
-                io::ErrorKind::Other.into()
-            })
-        })
+    let json = serde_json::to_vec(data).map_err(|e| {
+        error!(
+            "failed to write key (backup or sealed) data payload: {:?}",
+            e
+        );
+        io::Error::from(io::ErrorKind::Other)
+    })?;
+    secure_file::write(path.as_ref(), &json)
+}
+
+/// read sealed key or backup data
+/// (refusing a file that others could access unless `allow_insecure` is set)
+pub fn read_json_file<P: AsRef<Path>, T: DeserializeOwned>(
+    path: P,
+    allow_insecure: bool,
+) -> Result<T, String> {
+    let json = secure_file::read(path.as_ref(), allow_insecure)
+        .map_err(|e| format!("failed to read {}: {}", path.as_ref().display(), e))?;
+    serde_json::from_slice(&json)
+        .map_err(|e| format!("failed to parse {}: {:?}", path.as_ref().display(), e))
 }
 
 /// write sealed key data
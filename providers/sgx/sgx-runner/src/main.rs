@@ -8,6 +8,8 @@ use shared::SgxInitRequest;
 use std::fmt::Debug;
 use std::path::PathBuf;
 use structopt::StructOpt;
+use tmkms_light::admin::AdminRequest;
+use tmkms_light::signer::KeyType;
 use tmkms_light::utils::PubkeyDisplay;
 use tracing::{error, Level};
 use tracing_subscriber::FmtSubscriber;
@@ -46,6 +48,13 @@ enum TmkmsLight {
         external_cloud_key_path: Option<PathBuf>,
         #[structopt(short)]
         key_backup_data_path: Option<PathBuf>,
+        /// consensus key type: ed25519 (default), secp256k1 or bls12_381 (with the `bls` feature)
+        #[structopt(short = "t")]
+        key_type: Option<KeyType>,
+        /// load the wrap and backup key files even if others could access them
+        /// (also set in the generated config; not recommended)
+        #[structopt(long)]
+        allow_insecure_key_files: bool,
         #[structopt(short, parse(from_occurrences))]
         v: u32,
     },
@@ -66,6 +75,14 @@ enum TmkmsLight {
         #[structopt(short, parse(from_occurrences))]
         v: u32,
     },
+    #[structopt(name = "admin", about = "Send a command to the admin socket")]
+    /// send a command to the admin socket of the running tmkms process
+    Admin {
+        #[structopt(short)]
+        config_path: Option<PathBuf>,
+        /// status, pause, resume or shutdown
+        command: AdminRequest,
+    },
 }
 
 fn set_log(v: u32) -> String {
@@ -102,6 +119,8 @@ fn main() {
             wrap_backup_key_path,
             external_cloud_key_path,
             key_backup_data_path,
+            key_type,
+            allow_insecure_key_files,
             v,
         } => {
             let log_level_str = set_log(v);
@@ -112,6 +131,8 @@ fn main() {
                 wrap_backup_key_path,
                 external_cloud_key_path,
                 key_backup_data_path,
+                key_type.unwrap_or_default(),
+                allow_insecure_key_files,
                 log_level_str,
             )
         }
@@ -123,6 +144,10 @@ fn main() {
             let log_level_str = set_log(v);
             command::recover(config, log_level_str)
         }
+        TmkmsLight::Admin {
+            config_path,
+            command,
+        } => command::admin(config_path, command),
     };
     if let Err(e) = result {
         error!("{}", e);
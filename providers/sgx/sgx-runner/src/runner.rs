@@ -1,4 +1,8 @@
-use crate::shared::{RemoteConnectionConfig, SealedKeyData, SgxInitRequest, SgxInitResponse};
+use crate::config;
+use crate::shared::{
+    RemoteConnectionConfig, SealedKeyData, SgxInitRequest, SgxInitResponse, ValidatorNode,
+    VALIDATOR_SHUTDOWN_ADDR, VALIDATOR_SOCKET_ADDR,
+};
 use crate::state::StateSyncer;
 use aesm_client::AesmClient;
 use anomaly::format_err;
@@ -7,13 +11,17 @@ use enclave_runner::{
     EnclaveBuilder,
 };
 use sgxs_loaders::isgx::Device;
+use std::collections::HashMap;
+use std::net::{Shutdown, TcpStream};
 use std::os::unix::net::UnixStream;
 use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
 use std::thread;
-use std::{fs, path::PathBuf};
 use std::{future::Future, io, pin::Pin};
 use tendermint::consensus;
 use tendermint::net;
+use tmkms_light::chain::state::{AuthenticatedState, Durability};
 use tmkms_light::config::validator::ValidatorConfig;
 use tmkms_light::error::{Error, ErrorKind};
 use tmkms_light::utils::read_u16_payload;
@@ -22,13 +30,50 @@ use tracing::{debug, error};
 /// type alias for outputs in UsercallExtension async return type
 type UserCallStream = io::Result<Option<Box<dyn AsyncStream>>>;
 
+/// the host side of the enclave's validator connection
+/// (kept for shutting it down on the enclave's request)
+#[derive(Debug)]
+enum ValidatorStream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl ValidatorStream {
+    fn shutdown(&self) -> io::Result<()> {
+        match self {
+            ValidatorStream::Unix(stream) => stream.shutdown(Shutdown::Both),
+            ValidatorStream::Tcp(stream) => stream.shutdown(Shutdown::Both),
+        }
+    }
+}
+
 /// custom runner for tmkms <-> enclave app communication
 /// TODO: Windows support (via random TCP or custom in-memory stream)?
 #[derive(Debug)]
 struct TmkmsSgxRunner {
     init_stream: UnixStream,
     state_stream: UnixStream,
-    tm_conn: Option<PathBuf>,
+    metrics_stream: UnixStream,
+    /// Unix socket paths of the validator nodes (`None` for TCP nodes)
+    tm_conns: Vec<Option<PathBuf>>,
+    /// the last validator connections of the enclave app (by their usercall addresses)
+    validator_streams: Mutex<HashMap<String, ValidatorStream>>,
+}
+
+impl TmkmsSgxRunner {
+    fn set_validator_stream(&self, addr: &str, stream: ValidatorStream) {
+        self.validator_streams
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(addr.to_owned(), stream);
+    }
+
+    fn take_validator_stream(&self, addr: &str) -> Option<ValidatorStream> {
+        self.validator_streams
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(addr)
+    }
 }
 
 impl UsercallExtension for TmkmsSgxRunner {
@@ -48,21 +93,59 @@ impl UsercallExtension for TmkmsSgxRunner {
                     let stream = tokio::net::UnixStream::from_std(this.state_stream.try_clone()?)?;
                     Ok(Some(Box::new(stream)))
                 }
-                "tendermint" => {
-                    if let Some(ref path) = this.tm_conn {
-                        let stream = tokio::net::UnixStream::connect(path).await?;
+                "metrics" => {
+                    let stream =
+                        tokio::net::UnixStream::from_std(this.metrics_stream.try_clone()?)?;
+                    Ok(Some(Box::new(stream)))
+                }
+                _ => {
+                    if let Some(validator_addr) = strip_addr_prefix(addr, VALIDATOR_SHUTDOWN_ADDR) {
+                        if let Some(stream) = this.take_validator_stream(validator_addr) {
+                            debug!("shutting down the validator connection {}", validator_addr);
+                            stream.shutdown()?;
+                        }
+                        // the enclave app only waits for the reply
+                        let (closed, _) = UnixStream::pair()?;
+                        let stream = tokio::net::UnixStream::from_std(closed)?;
                         Ok(Some(Box::new(stream)))
+                    } else if let Some(index) = strip_addr_prefix(addr, VALIDATOR_SOCKET_ADDR) {
+                        let path = index
+                            .parse::<usize>()
+                            .ok()
+                            .and_then(|index| this.tm_conns.get(index))
+                            .and_then(Option::as_ref);
+                        if let Some(path) = path {
+                            let stream = UnixStream::connect(path)?;
+                            stream.set_nonblocking(true)?;
+                            this.set_validator_stream(
+                                addr,
+                                ValidatorStream::Unix(stream.try_clone()?),
+                            );
+                            let stream = tokio::net::UnixStream::from_std(stream)?;
+                            Ok(Some(Box::new(stream)))
+                        } else {
+                            Ok(None)
+                        }
                     } else {
-                        Ok(None)
+                        // the validator connection over TCP ("host:port")
+                        let stream = TcpStream::connect(addr)?;
+                        stream.set_nonblocking(true)?;
+                        this.set_validator_stream(addr, ValidatorStream::Tcp(stream.try_clone()?));
+                        let stream = tokio::net::TcpStream::from_std(stream)?;
+                        Ok(Some(Box::new(stream)))
                     }
                 }
-                _ => Ok(None),
             }
         }
         Box::pin(connect_stream_inner(self, addr))
     }
 }
 
+/// the rest of the usercall address after the given prefix and "/"
+fn strip_addr_prefix<'a>(addr: &'a str, prefix: &str) -> Option<&'a str> {
+    addr.strip_prefix(prefix)?.strip_prefix('/')
+}
+
 /// controller for launching the enclave app and providing the communication with it
 pub struct TmkmsSgxSigner {
     stream_to_enclave: UnixStream,
@@ -74,7 +157,8 @@ impl TmkmsSgxSigner {
     /// and the unix socket to pass to the enclave runner
     pub fn get_state_syncer<P: AsRef<Path>>(
         state_path: P,
-    ) -> Result<(StateSyncer, consensus::State, UnixStream), Error> {
+        durability: Durability,
+    ) -> Result<(StateSyncer, AuthenticatedState, UnixStream), Error> {
         let (state_from_enclave, state_stream) = UnixStream::pair().map_err(|e| {
             format_err!(
                 ErrorKind::IoError,
@@ -83,8 +167,8 @@ impl TmkmsSgxSigner {
             )
         })?;
 
-        let (state_syncer, state) =
-            StateSyncer::new(state_path, state_from_enclave).map_err(|e| {
+        let (state_syncer, state) = StateSyncer::new(state_path, durability, state_from_enclave)
+            .map_err(|e| {
                 format_err!(
                     ErrorKind::IoError,
                     "failed to get state persistence helper {}",
@@ -95,19 +179,25 @@ impl TmkmsSgxSigner {
     }
 
     /// launches the `tmkms-light-sgx-app` from the provided path
+    /// (`tm_conns` are the Unix socket paths of the validator nodes in the start request)
     pub fn launch_enclave_app<P: AsRef<Path>>(
         sgxs_path: P,
-        tm_conn: Option<PathBuf>,
+        tm_conns: Vec<Option<PathBuf>>,
         state_syncer: StateSyncer,
         state_stream: UnixStream,
         args: &[&[u8]],
     ) -> io::Result<Self> {
         let (stream_to_enclave, init_stream) = UnixStream::pair()?;
-        state_syncer.launch_syncer();
+        // metric events (e.g. of every ping) have their own stream,
+        // so they don't hold up the states and their acknowledgements
+        let (metrics_from_enclave, metrics_stream) = UnixStream::pair()?;
+        state_syncer.launch_syncer(metrics_from_enclave);
         let runner = TmkmsSgxRunner {
             init_stream,
             state_stream,
-            tm_conn,
+            metrics_stream,
+            tm_conns,
+            validator_streams: Mutex::new(HashMap::new()),
         };
         let mut device = Device::new()?
             .einittoken_provider(AesmClient::new())
@@ -151,57 +241,57 @@ impl TmkmsSgxSigner {
 
     /// get the request payload that's passed as an argument to the enclave
     /// to start up the tmkms handling
+    /// (`nodes` are the addresses of the validator's nodes with their configs;
+    /// the identity key is needed for `tcp://` addresses)
     pub fn get_start_request_bytes<P: AsRef<Path>>(
         sealed_key_path: P,
-        config: ValidatorConfig,
-        initial_state: consensus::State,
-        remote_conn: Option<(net::Address, P)>,
+        nodes: Vec<(ValidatorConfig, net::Address)>,
+        initial_state: AuthenticatedState,
+        raised_state: Option<consensus::State>,
+        sealed_id_key_path: Option<P>,
+        allow_insecure_key_files: bool,
     ) -> Result<Vec<u8>, Error> {
         let sealed_key: SealedKeyData =
-            serde_json::from_slice(&fs::read(sealed_key_path).map_err(|e| {
-                format_err!(
-                    ErrorKind::IoError,
-                    "error reading sealed consensus key: {:?}",
-                    e
-                )
-            })?)
-            .map_err(|e| {
-                format_err!(
-                    ErrorKind::IoError,
-                    "invalid sealed consensus key format: {:?}",
-                    e
-                )
-            })?;
-        let secret_connection = match remote_conn {
-            Some((
-                net::Address::Tcp {
-                    peer_id,
-                    host,
-                    port,
-                },
-                id_path,
-            )) => {
-                let sealed_id_key: SealedKeyData =
-                    serde_json::from_slice(&fs::read(id_path).map_err(|e| {
-                        format_err!(ErrorKind::IoError, "error reading id sealed key: {:?}", e)
-                    })?)
-                    .map_err(|e| {
-                        format_err!(ErrorKind::IoError, "invalid sealed id key format: {:?}", e)
-                    })?;
-                Some(RemoteConnectionConfig {
-                    peer_id,
-                    host,
-                    port,
-                    sealed_key: sealed_id_key,
-                })
-            }
-            _ => None,
-        };
+            config::read_json_file(sealed_key_path, allow_insecure_key_files)
+                .map_err(|e| format_err!(ErrorKind::IoError, "sealed consensus key: {}", e))?;
+        let mut validator_nodes = Vec::with_capacity(nodes.len());
+        for (config, address) in nodes {
+            sealed_key
+                .key_type
+                .check_protocol_version(config.protocol_version)?;
+            let peer = address.to_string();
+            let secret_connection = match (address, &sealed_id_key_path) {
+                (
+                    net::Address::Tcp {
+                        peer_id,
+                        host,
+                        port,
+                    },
+                    Some(id_path),
+                ) => {
+                    let sealed_id_key: SealedKeyData =
+                        config::read_json_file(id_path, allow_insecure_key_files)
+                            .map_err(|e| format_err!(ErrorKind::IoError, "sealed id key: {}", e))?;
+                    Some(RemoteConnectionConfig {
+                        peer_id,
+                        host,
+                        port,
+                        sealed_key: sealed_id_key,
+                    })
+                }
+                _ => None,
+            };
+            validator_nodes.push(ValidatorNode {
+                config,
+                secret_connection,
+                peer,
+            });
+        }
         let req_bytes = serde_json::to_vec(&SgxInitRequest::Start {
             sealed_key,
-            config,
-            secret_connection,
+            nodes: validator_nodes,
             initial_state,
+            raised_state,
         })
         .map_err(|e| {
             format_err!(
@@ -221,6 +311,12 @@ impl TmkmsSgxSigner {
         }
     }
 
+    /// a handle to the init stream for forwarding admin requests
+    /// to the started enclave app
+    pub fn admin_stream(&self) -> io::Result<UnixStream> {
+        self.stream_to_enclave.try_clone()
+    }
+
     /// run the main privval handling
     pub fn start(self) -> Result<(), Error> {
         self.join_enclave_thread()
@@ -6,7 +6,29 @@ use std::fmt;
 use std::str::FromStr;
 use tendermint::consensus;
 use tendermint::node;
+use tmkms_light::chain::state::AuthenticatedState;
 use tmkms_light::config::validator::ValidatorConfig;
+use tmkms_light::signer::{ConsensusPublicKey, KeyType};
+
+/// prefix of the usercall address the enclave app connects to for shutting down
+/// one of its validator connections (which it can't do itself),
+/// so that its reader thread gets unblocked
+pub const VALIDATOR_SHUTDOWN_ADDR: &str = "tendermint-shutdown";
+
+/// prefix of the usercall address of a validator node's Unix socket
+/// (followed by the index of the node in the start request)
+pub const VALIDATOR_SOCKET_ADDR: &str = "tendermint";
+
+/// usercall address of the Unix socket of the `index`-th validator node
+pub fn validator_socket_addr(index: usize) -> String {
+    format!("{}/{}", VALIDATOR_SOCKET_ADDR, index)
+}
+
+/// usercall address for shutting down the validator connection
+/// that was opened on the given usercall address
+pub fn validator_shutdown_addr(addr: &str) -> String {
+    format!("{}/{}", VALIDATOR_SHUTDOWN_ADDR, addr)
+}
 
 /// keyseal is fixed in the enclave app
 pub type AesGcmSivNonce = [u8; 12];
@@ -66,6 +88,25 @@ pub struct SealedKeyData {
     pub seal_key_request: KeyRequestWrap,
     pub nonce: AesGcmSivNonce,
     pub sealed_secret: Ciphertext,
+    /// type of the sealed consensus key
+    #[serde(default)]
+    pub key_type: KeyType,
+    /// public key of a key of another type than Ed25519
+    /// (the key request's ID is then the SHA-256 digest of the public key)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<Vec<u8>>,
+}
+
+impl SealedKeyData {
+    /// The public key of the sealed consensus key
+    pub fn consensus_public_key(&self) -> Result<ConsensusPublicKey, String> {
+        let raw = match &self.public_key {
+            Some(public_key) => public_key.as_slice(),
+            None => &self.seal_key_request.keyid,
+        };
+        ConsensusPublicKey::from_raw(self.key_type, raw)
+            .map_err(|e| format!("invalid public key: {}", e))
+    }
 }
 
 /// ed25519 pubkey alias
@@ -83,6 +124,16 @@ pub struct CloudBackupKeyData {
     pub public_key: ed25519_dalek::PublicKey,
 }
 
+/// one of the (possibly redundant) nodes of the validator
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ValidatorNode {
+    pub config: ValidatorConfig,
+    /// `None` for the node's Unix socket (exposed by the runner)
+    pub secret_connection: Option<RemoteConnectionConfig>,
+    /// address of the node in the runner config (shown in the admin statuses)
+    pub peer: String,
+}
+
 /// configuration for direct remote communication with TM
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RemoteConnectionConfig {
@@ -201,7 +252,10 @@ pub enum SgxInitRequest {
     },
     /// generate a new keypair
     KeyGen {
+        /// (only for Ed25519 keys)
         cloud_backup: Option<CloudBackupKey>,
+        #[serde(default)]
+        key_type: KeyType,
     },
     /// reseal the keypair from a backup
     CloudRecover {
@@ -211,9 +265,12 @@ pub enum SgxInitRequest {
     /// start the main loop for processing Tendermint privval requests
     Start {
         sealed_key: SealedKeyData,
-        config: ValidatorConfig,
-        secret_connection: Option<RemoteConnectionConfig>,
-        initial_state: consensus::State,
+        /// the nodes of the validator (they share the double-sign state)
+        nodes: Vec<ValidatorNode>,
+        /// the last state record written by the enclave app
+        initial_state: AuthenticatedState,
+        /// higher state from the startup state hook (if any)
+        raised_state: Option<consensus::State>,
     },
 }
 
@@ -1,62 +1,48 @@
-use anomaly::{fail, format_err};
+use anomaly::format_err;
 use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::Arc;
 use std::thread;
-use std::{
-    fs,
-    io::{self, prelude::*},
-    path::{Path, PathBuf},
+use tmkms_light::chain::state::{
+    AuthenticatedState, Durability, StateAck, StateError, StateErrorKind, StateFile,
 };
-use tempfile::NamedTempFile;
-use tmkms_light::chain::state::{consensus, StateError, StateErrorKind};
-use tmkms_light::utils::read_u16_payload;
+use tmkms_light::metrics::{MetricEvent, Metrics, MetricsSink};
+use tmkms_light::utils::{read_u16_payload, write_u16_payload};
 use tracing::{debug, warn};
 
 pub struct StateSyncer {
-    state_file_path: PathBuf,
+    state_file: StateFile<AuthenticatedState>,
     stream_to_enclave: UnixStream,
+    metrics: Option<Arc<Metrics>>,
 }
 
 impl StateSyncer {
     pub fn new<P: AsRef<Path>>(
         path: P,
+        durability: Durability,
         stream_to_enclave: UnixStream,
-    ) -> Result<(Self, consensus::State), StateError> {
-        let state_file_path = path.as_ref().to_owned();
-        let state = match fs::read_to_string(&path) {
-            Ok(state_json) => {
-                let consensus_state: consensus::State =
-                    serde_json::from_str(&state_json).map_err(|e| {
-                        format_err!(
-                            StateErrorKind::SyncError,
-                            "error parsing {}: {}",
-                            path.as_ref().display(),
-                            e
-                        )
-                    })?;
-
-                Ok(consensus_state)
-            }
-            Err(e) if e.kind() == io::ErrorKind::NotFound => {
-                Self::write_initial_state(&state_file_path)
-            }
-            Err(e) => fail!(
-                StateErrorKind::SyncError,
-                "error reading {}: {}",
-                path.as_ref().display(),
-                e
-            ),
-        }?;
+    ) -> Result<(Self, AuthenticatedState), StateError> {
+        let mut state_file = StateFile::new(path, durability);
+        let mut state = state_file.load()?;
+        // the enclave app checks the records against the marker it sealed
+        state.marker = state_file.load_marker()?;
         Ok((
             Self {
-                state_file_path,
+                state_file,
                 stream_to_enclave,
+                metrics: None,
             },
             state,
         ))
     }
 
-    /// load state from the provided vsock stream
-    fn sync_from_stream(&mut self) -> Result<consensus::State, StateError> {
+    /// records the metric events sent by the enclave app
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    /// load state from the provided stream
+    fn sync_from_stream(&mut self) -> Result<AuthenticatedState, StateError> {
         let json_raw = read_u16_payload(&mut self.stream_to_enclave)
             .map_err(|e| format_err!(StateErrorKind::SyncError, "failed to read state: {}", e))?;
         serde_json::from_slice(&json_raw).map_err(|e| {
@@ -69,79 +55,72 @@ impl StateSyncer {
         })
     }
 
-    /// Write the initial state to the given path on disk
-    fn write_initial_state(path: &Path) -> Result<consensus::State, StateError> {
-        let consensus_state = consensus::State {
-            height: 0u32.into(),
-            ..Default::default()
-        };
-
-        Self::persist_state(path, &consensus_state)?;
-
-        Ok(consensus_state)
-    }
-
-    /// Launches the state syncer
-    pub fn launch_syncer(mut self) {
-        thread::spawn(move || loop {
-            if let Ok(ref consensus_state) = self.sync_from_stream() {
-                if let Err(e) = Self::persist_state(&self.state_file_path, consensus_state) {
-                    warn!("state persistence failed: {}", e);
-                }
-            }
-        });
-    }
-
-    fn persist_state(path: &Path, new_state: &consensus::State) -> Result<(), StateError> {
-        debug!(
-            "writing new consensus state to {}: {:?}",
-            path.display(),
-            &new_state
-        );
-
-        let json = serde_json::to_string(&new_state).map_err(|e| {
+    /// replies to the enclave app after the state was written (or failed to be)
+    fn send_ack(&mut self, ack: &StateAck) -> Result<(), StateError> {
+        let json_raw = serde_json::to_vec(ack).map_err(|e| {
             format_err!(
                 StateErrorKind::SyncError,
-                "error serializing to json {}: {}",
-                path.display(),
+                "failed to serialize acknowledgement: {}",
                 e
             )
         })?;
-
-        let state_file_dir = path.parent().unwrap_or_else(|| {
-            panic!("state file cannot be root directory");
-        });
-
-        let mut state_file = NamedTempFile::new_in(state_file_dir).map_err(|e| {
+        write_u16_payload(&mut self.stream_to_enclave, &json_raw).map_err(|e| {
             format_err!(
                 StateErrorKind::SyncError,
-                "error creating a named temp file {}: {}",
-                path.display(),
+                "failed to write acknowledgement: {}",
                 e
             )
-        })?;
-        state_file.write_all(json.as_bytes()).map_err(|e| {
-            format_err!(
-                StateErrorKind::SyncError,
-                "error writing {}: {}",
-                path.display(),
-                e
-            )
-        })?;
-        state_file.persist(&path).map_err(|e| {
-            format_err!(
-                StateErrorKind::SyncError,
-                "error persisting {}: {}",
-                path.display(),
-                e
-            )
-        })?;
-
-        debug!(
-            "successfully wrote new consensus state to {}",
-            path.display(),
-        );
+            .into()
+        })
+    }
 
-        Ok(())
+    /// Launches the state syncer and the reader of the metric events
+    /// sent over `metrics_from_enclave` (each stops when its stream is closed)
+    pub fn launch_syncer(mut self, mut metrics_from_enclave: UnixStream) {
+        let metrics = self.metrics.take();
+        thread::spawn(move || loop {
+            let event = read_u16_payload(&mut metrics_from_enclave)
+                .map_err(|e| format!("failed to read metric event: {}", e))
+                .and_then(|json_raw| {
+                    serde_json::from_slice::<MetricEvent>(&json_raw)
+                        .map_err(|e| format!("failed to deserialize metric event: {}", e))
+                });
+            match (event, &metrics) {
+                (Ok(event), Some(metrics)) => metrics.record(event),
+                (Ok(_), None) => {}
+                (Err(e), _) => {
+                    debug!("metrics stream closed: {}", e);
+                    break;
+                }
+            }
+        });
+        thread::spawn(move || loop {
+            match self.sync_from_stream() {
+                Ok(mut record) => {
+                    let marker = record.marker.take();
+                    let error = marker
+                        .map_or(Ok(()), |marker| self.state_file.persist_marker(&marker))
+                        .and_then(|_| self.state_file.persist(&record))
+                        .err()
+                        .map(|e| {
+                            warn!("state persistence failed: {}", e);
+                            e.to_string()
+                        });
+                    let ack = StateAck {
+                        state: record.state,
+                        error,
+                    };
+                    if let Err(e) = self.send_ack(&ack) {
+                        warn!("{}", e);
+                    }
+                }
+                Err(e) => {
+                    // the enclave app closed the stream (or sent an invalid record):
+                    // its states are no longer acknowledged, so it stops signing
+                    warn!("state syncer stopped: {}", e);
+                    break;
+                }
+            }
+        });
     }
 }
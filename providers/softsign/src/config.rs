@@ -1,29 +1,283 @@
 use serde::{Deserialize, Serialize};
-use std::{convert::TryFrom, path::PathBuf};
-use tendermint::{chain, net};
+use std::{convert::TryFrom, net::SocketAddr, path::PathBuf};
+use tendermint::{chain, net, node};
+
+use crate::encrypted_key::PassphraseSource;
+use tmkms_light::{
+    chain::state::{hook::HookConfig, Durability},
+    config::{grpc::GrpcConfig, threshold::ThresholdConfig, validator::ProtocolVersion},
+    signer::KeyType,
+    utils::canonical_path,
+};
+use tracing::warn;
+
+/// Keys of the earlier single-validator config format that are now set in `[[validator]]` entries
+const LEGACY_VALIDATOR_KEYS: [&str; 6] = [
+    "address",
+    "chain_id",
+    "max_height",
+    "state_file_path",
+    "timeout",
+    "retry",
+];
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct SoftSignOpt {
+    /// Path to a file containing a cryptographic key
+    /// (the key share of this node in threshold signing)
+    pub consensus_key_path: PathBuf,
+    /// Type of the consensus key (`ed25519`, `secp256k1` or `bls12_381`)
+    #[serde(default)]
+    pub key_type: KeyType,
+    /// Path to our Ed25519 identity key (if applicable)
+    pub id_key_path: Option<PathBuf>,
+    /// Path to the audit log of sign requests and decisions (if enabled)
+    pub audit_log_path: Option<PathBuf>,
+    /// Address to serve Prometheus metrics on (if enabled)
+    pub metrics_listen_addr: Option<SocketAddr>,
+    /// Path to the Unix-domain admin socket (if enabled)
+    pub admin_socket_path: Option<PathBuf>,
+    /// How the state files are written (`fsync` by default, `dsync` or `none`)
+    #[serde(default)]
+    pub state_durability: Durability,
+    /// Load key files even if others could access them (not recommended)
+    #[serde(default)]
+    pub allow_insecure_key_files: bool,
+    /// Where the passphrase of encrypted keys comes from (asked on the terminal if not set)
+    pub key_passphrase: Option<PassphraseSource>,
+    /// Threshold (FROST) Ed25519 signing with other KMS nodes (if enabled)
+    pub threshold: Option<ThresholdConfig>,
+    /// Validator connections (one per chain)
+    #[serde(rename = "validator")]
+    pub validators: Vec<ValidatorOpt>,
+}
+
+/// A single `[[validator]]` entry
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ValidatorOpt {
     /// Address of the validator (`tcp://` or `unix://`)
     pub address: net::Address,
     /// Chain ID of the Tendermint network this validator is part of
     pub chain_id: chain::Id,
     /// Height at which to stop signing
     pub max_height: Option<tendermint::block::Height>,
-    /// Path to a file containing a cryptographic key
-    pub consensus_key_path: PathBuf,
-    /// Path to our Ed25519 identity key (if applicable)
-    pub id_key_path: Option<PathBuf>,
+    /// Path to a consensus key for this chain (if different from the shared one)
+    pub consensus_key_path: Option<PathBuf>,
+    /// Type of the consensus key for this chain (if different from the shared one)
+    pub key_type: Option<KeyType>,
     /// Path to chain-specific `priv_validator_state.json` file
     pub state_file_path: PathBuf,
+    /// Path to the state of what the key share of this node signed for the chain
+    /// (in threshold signing, also for the other nodes)
+    pub cosigner_state_file_path: Option<PathBuf>,
     /// Optional timeout value in seconds
     pub timeout: Option<u16>,
+    /// Seconds without any request (including pings) after which the connection is rebuilt (if set)
+    pub idle_timeout: Option<u16>,
+    /// Protocol version of the node (`v0.33`, `v0.34` (default), `v0.37` or `v0.38`)
+    #[serde(default)]
+    pub protocol_version: ProtocolVersion,
     /// Retry connection
     pub retry: bool,
+    /// Maximum delay between reconnect attempts in seconds (30 if not set; at least 1)
+    pub max_backoff: Option<u16>,
+    /// Only connect to validators with one of these peer IDs (if not empty)
+    #[serde(default)]
+    pub allowed_peer_ids: Vec<node::Id>,
+    /// Startup hook checking the latest block height of the chain (if enabled)
+    pub state_hook: Option<HookConfig>,
+    /// Privval gRPC server for the node (relayed to the session on the `unix://` address)
+    pub grpc: Option<GrpcConfig>,
+}
+
+impl SoftSignOpt {
+    /// Default config for keys of the provided type
+    /// (the validator is raised to the first protocol version accepting them)
+    pub fn with_key_type(key_type: KeyType) -> Self {
+        let mut config = Self {
+            key_type,
+            ..Default::default()
+        };
+        for validator in config.validators.iter_mut() {
+            validator.protocol_version = validator
+                .protocol_version
+                .max(key_type.min_protocol_version());
+        }
+        config
+    }
+
+    /// Parses the toml config (a config in the earlier single-validator format,
+    /// without `[[validator]]` entries, is read as a config with one entry)
+    pub fn from_toml(toml_string: &str) -> Result<Self, String> {
+        let mut table: toml::value::Table = toml::from_str(toml_string)
+            .map_err(|e| format!("toml config file failed to parse: {}", e))?;
+        if !table.contains_key("validator") && table.contains_key("chain_id") {
+            warn!("single-validator config: the validator settings should be moved to a `[[validator]]` entry");
+            let validator: toml::value::Table = LEGACY_VALIDATOR_KEYS
+                .iter()
+                .filter_map(|key| table.remove(*key).map(|value| ((*key).to_owned(), value)))
+                .collect();
+            table.insert(
+                "validator".to_owned(),
+                toml::Value::Array(vec![toml::Value::Table(validator)]),
+            );
+        }
+        toml::Value::Table(table)
+            .try_into()
+            .map_err(|e| format!("toml config file failed to parse: {}", e))
+    }
+
+    /// Checks that entries of the same chain (i.e. redundant nodes of one validator)
+    /// share the state file and the key and that different chains use separate state files
+    /// (the paths are compared once resolved)
+    pub fn validate(&self) -> Result<(), String> {
+        if self.validators.is_empty() {
+            return Err("no `[[validator]]` entries in config".to_owned());
+        }
+        for (i, validator) in self.validators.iter().enumerate() {
+            if validator.grpc.is_some() && !matches!(validator.address, net::Address::Unix { .. }) {
+                return Err(format!(
+                    "[{}] `grpc` needs a `unix://` address for the session to connect to",
+                    validator.chain_id
+                ));
+            }
+            if validator.max_backoff == Some(0) {
+                return Err(format!(
+                    "[{}] `max_backoff` needs to be at least 1 second",
+                    validator.chain_id
+                ));
+            }
+            self.consensus_key(validator)
+                .1
+                .check_protocol_version(validator.protocol_version)
+                .map_err(|e| format!("[{}] {}", validator.chain_id, e))?;
+            let state_file_path = canonical_path(&validator.state_file_path);
+            for other in self.validators[..i].iter() {
+                let same_chain = other.chain_id == validator.chain_id;
+                let same_state = canonical_path(&other.state_file_path) == state_file_path;
+                if same_chain && !same_state {
+                    return Err(format!(
+                        "[{}] validator entries of the same chain need to share the state file",
+                        validator.chain_id
+                    ));
+                }
+                if !same_chain && same_state {
+                    return Err(format!(
+                        "[{}] state file {} is used by another chain",
+                        validator.chain_id,
+                        validator.state_file_path.display()
+                    ));
+                }
+                if same_chain && self.consensus_key(other) != self.consensus_key(validator) {
+                    return Err(format!(
+                        "[{}] validator entries of the same chain need to share the consensus key",
+                        validator.chain_id
+                    ));
+                }
+            }
+        }
+        if let Some(threshold) = &self.threshold {
+            self.validate_threshold(threshold)?;
+        }
+        Ok(())
+    }
+
+    /// Checks that the key share is used for all chains and that
+    /// each chain has its own cosigner state file
+    fn validate_threshold(&self, threshold: &ThresholdConfig) -> Result<(), String> {
+        threshold.validate().map_err(|e| e.to_string())?;
+        if self.key_type != KeyType::Ed25519 {
+            return Err("threshold signing needs an `ed25519` key type".to_owned());
+        }
+        if self.id_key_path.is_none() {
+            return Err("threshold signing needs an `id_key_path`".to_owned());
+        }
+        for (i, validator) in self.validators.iter().enumerate() {
+            if validator.consensus_key_path.is_some() || validator.key_type.is_some() {
+                return Err(format!(
+                    "[{}] the key share is used for all chains in threshold signing",
+                    validator.chain_id
+                ));
+            }
+            let cosigner_state = validator.cosigner_state_file_path.as_ref().ok_or_else(|| {
+                format!(
+                    "[{}] threshold signing needs a `cosigner_state_file_path`",
+                    validator.chain_id
+                )
+            })?;
+            let cosigner_state_path = canonical_path(cosigner_state);
+            for other in self.validators.iter() {
+                if canonical_path(&other.state_file_path) == cosigner_state_path {
+                    return Err(format!(
+                        "[{}] cosigner state file {} is used as a state file",
+                        validator.chain_id,
+                        cosigner_state.display()
+                    ));
+                }
+            }
+            for other in self.validators[..i].iter() {
+                let same_chain = other.chain_id == validator.chain_id;
+                let same_state = other
+                    .cosigner_state_file_path
+                    .as_deref()
+                    .map(canonical_path)
+                    == Some(cosigner_state_path.clone());
+                if same_chain != same_state {
+                    return Err(format!(
+                        "[{}] each chain needs its own cosigner state file",
+                        validator.chain_id
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Validator entries grouped by chain (in the order of their first appearance)
+    pub fn validator_groups(&self) -> Vec<Vec<&ValidatorOpt>> {
+        let mut groups: Vec<Vec<&ValidatorOpt>> = Vec::new();
+        for validator in self.validators.iter() {
+            match groups
+                .iter_mut()
+                .find(|group| group[0].chain_id == validator.chain_id)
+            {
+                Some(group) => group.push(validator),
+                None => groups.push(vec![validator]),
+            }
+        }
+        groups
+    }
+
+    /// The consensus key path and type to use for the given validator entry
+    pub fn consensus_key(&self, validator: &ValidatorOpt) -> (PathBuf, KeyType) {
+        match &validator.consensus_key_path {
+            Some(path) => (path.clone(), validator.key_type.unwrap_or(self.key_type)),
+            None => (self.consensus_key_path.clone(), self.key_type),
+        }
+    }
 }
 
 impl Default for SoftSignOpt {
+    fn default() -> Self {
+        Self {
+            consensus_key_path: "secrets/secret.key".into(),
+            key_type: KeyType::Ed25519,
+            id_key_path: Some("secrets/id.key".into()),
+            audit_log_path: None,
+            metrics_listen_addr: None,
+            admin_socket_path: None,
+            state_durability: Durability::default(),
+            allow_insecure_key_files: false,
+            key_passphrase: None,
+            threshold: None,
+            validators: vec![ValidatorOpt::default()],
+        }
+    }
+}
+
+impl Default for ValidatorOpt {
     fn default() -> Self {
         Self {
             address: net::Address::Unix {
@@ -31,11 +285,230 @@ impl Default for SoftSignOpt {
             },
             chain_id: chain::Id::try_from("testchain-1".to_owned()).expect("valid chain-id"),
             max_height: None,
-            consensus_key_path: "secrets/secret.key".into(),
-            id_key_path: Some("secrets/id.key".into()),
+            consensus_key_path: None,
+            key_type: None,
             state_file_path: "state/priv_validator_state.json".into(),
+            cosigner_state_file_path: None,
             timeout: None,
+            idle_timeout: None,
+            protocol_version: ProtocolVersion::default(),
             retry: true,
+            max_backoff: None,
+            allowed_peer_ids: Vec::new(),
+            state_hook: None,
+            grpc: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_multiple_validators() {
+        let config: SoftSignOpt = toml::from_str(
+            r#"
+            consensus_key_path = "secrets/secret.key"
+            id_key_path = "secrets/id.key"
+
+            [[validator]]
+            address = "unix:///tmp/a.socket"
+            chain_id = "chain-a"
+            state_file_path = "state/a.json"
+            retry = true
+
+            [validator.state_hook]
+            rpc_url = "http://127.0.0.1:26657"
+            on_behind = "raise"
+
+            [[validator]]
+            address = "unix:///tmp/b.socket"
+            chain_id = "chain-b"
+            consensus_key_path = "secrets/b.key"
+            key_type = "secp256k1"
+            state_file_path = "state/b.json"
+            retry = false
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.validators.len(), 2);
+        assert!(config.validators[0].state_hook.is_some());
+        assert!(config.validators[1].state_hook.is_none());
+        assert_eq!(
+            config.consensus_key(&config.validators[0]),
+            ("secrets/secret.key".into(), KeyType::Ed25519)
+        );
+        assert_eq!(
+            config.consensus_key(&config.validators[1]),
+            ("secrets/b.key".into(), KeyType::Secp256k1)
+        );
+    }
+
+    #[test]
+    fn reads_single_validator_config() {
+        let config = SoftSignOpt::from_toml(
+            r#"
+            address = "unix:///tmp/a.socket"
+            chain_id = "chain-a"
+            consensus_key_path = "secrets/secret.key"
+            id_key_path = "secrets/id.key"
+            state_file_path = "state/a.json"
+            retry = true
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.validators.len(), 1);
+        assert_eq!(config.validators[0].chain_id.as_str(), "chain-a");
+        assert!(SoftSignOpt::from_toml("consensus_key_path = \"secrets/secret.key\"").is_err());
+    }
+
+    #[test]
+    fn compares_resolved_state_paths() {
+        let other_chain = ValidatorOpt {
+            chain_id: chain::Id::try_from("testchain-2".to_owned()).unwrap(),
+            state_file_path: "state/../state/./priv_validator_state.json".into(),
+            ..Default::default()
+        };
+        let config = SoftSignOpt {
+            validators: vec![ValidatorOpt::default(), other_chain],
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_zero_max_backoff() {
+        let config = SoftSignOpt {
+            validators: vec![ValidatorOpt {
+                max_backoff: Some(0),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[cfg(feature = "bls")]
+    #[test]
+    fn rejects_bls_keys_before_cometbft_v1() {
+        let mut config = SoftSignOpt {
+            key_type: KeyType::Bls12_381,
+            validators: vec![ValidatorOpt {
+                protocol_version: ProtocolVersion::V0_38,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        config.validators[0].protocol_version = ProtocolVersion::V1;
+        assert!(config.validate().is_ok());
+    }
+
+    #[cfg(feature = "bls")]
+    #[test]
+    fn init_config_of_bls_keys_is_valid() {
+        let config = SoftSignOpt::with_key_type(KeyType::Bls12_381);
+        assert_eq!(config.validators[0].protocol_version, ProtocolVersion::V1);
+        assert!(config.validate().is_ok());
+        let config = SoftSignOpt::with_key_type(KeyType::Ed25519);
+        assert_eq!(
+            config.validators[0].protocol_version,
+            ProtocolVersion::default()
+        );
+    }
+
+    #[test]
+    fn rejects_shared_state_file() {
+        let other_chain = ValidatorOpt {
+            chain_id: chain::Id::try_from("testchain-2".to_owned()).unwrap(),
+            ..Default::default()
+        };
+        let config = SoftSignOpt {
+            validators: vec![ValidatorOpt::default(), other_chain],
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn groups_redundant_nodes() {
+        let second_node = ValidatorOpt {
+            address: net::Address::Unix {
+                path: "/tmp/validator2.socket".into(),
+            },
+            ..Default::default()
+        };
+        let split_state = ValidatorOpt {
+            state_file_path: "state/other.json".into(),
+            ..Default::default()
+        };
+        let config = SoftSignOpt {
+            validators: vec![ValidatorOpt::default(), second_node],
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+        assert_eq!(config.validator_groups().len(), 1);
+        assert_eq!(config.validator_groups()[0].len(), 2);
+        let config = SoftSignOpt {
+            validators: vec![ValidatorOpt::default(), split_state],
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn validates_threshold_signing() {
+        let config = |cosigner_state: &str| -> SoftSignOpt {
+            toml::from_str(&format!(
+                r#"
+                consensus_key_path = "secrets/share.key"
+                id_key_path = "secrets/id.key"
+
+                [threshold]
+                id = 1
+                threshold = 2
+                listen_addr = "127.0.0.1:26670"
+
+                [[threshold.peer]]
+                id = 2
+                address = "tcp://f88883b673fc69d7869cab098de3bafc2ff76eb8@10.0.0.2:26670"
+
+                [[threshold.peer]]
+                id = 3
+                address = "tcp://f88883b673fc69d7869cab098de3bafc2ff76eb8@10.0.0.3:26670"
+
+                [[validator]]
+                address = "unix:///tmp/a.socket"
+                chain_id = "chain-a"
+                state_file_path = "state/a.json"
+                cosigner_state_file_path = "state/cosigner-a.json"
+                retry = true
+
+                [[validator]]
+                address = "unix:///tmp/b.socket"
+                chain_id = "chain-b"
+                state_file_path = "state/b.json"
+                cosigner_state_file_path = "{}"
+                retry = true
+                "#,
+                cosigner_state
+            ))
+            .unwrap()
+        };
+        let valid = config("state/cosigner-b.json");
+        assert!(valid.validate().is_ok());
+        assert_eq!(valid.threshold.as_ref().unwrap().peers.len(), 2);
+        // the chains need separate cosigner states (not shared with the session states)
+        assert!(config("state/cosigner-a.json").validate().is_err());
+        assert!(config("state/b.json").validate().is_err());
+        let mut own_key = config("state/cosigner-b.json");
+        own_key.validators[1].consensus_key_path = Some("secrets/b.key".into());
+        assert!(own_key.validate().is_err());
+        let mut no_state = config("state/cosigner-b.json");
+        no_state.validators[0].cosigner_state_file_path = None;
+        assert!(no_state.validate().is_err());
+    }
+}
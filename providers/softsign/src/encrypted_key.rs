@@ -0,0 +1,289 @@
+//! Password-encrypted key files
+//!
+//! The (base64-encoded) file content is:
+//! `"TMKMSENC" | version (1) | m_cost (u32 LE) | t_cost (u32 LE) | p_cost (u32 LE) |
+//! salt (16) | nonce (24) | ciphertext + tag`, where the key is derived with Argon2id
+//! and the secret is encrypted with XChaCha20Poly1305 (with the header as associated data).
+
+use std::path::{Path, PathBuf};
+
+use anomaly::{fail, format_err};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    aead::{Aead, NewAead, Payload},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use tmkms_light::error::{Error, ErrorKind};
+use tmkms_light::secure_file;
+use zeroize::Zeroizing;
+
+/// Prefix of encrypted key files
+const MAGIC: &[u8] = b"TMKMSENC";
+/// Current version of the format
+const VERSION: u8 = 1;
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 24;
+const HEADER_LENGTH: usize = MAGIC.len() + 1 + 3 * 4 + SALT_LENGTH + NONCE_LENGTH;
+/// Upper bound of the memory cost accepted from a key file (4 GiB)
+const MAX_M_COST: u32 = 4 * 1024 * 1024;
+
+/// Argon2id cost parameters
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct KdfParams {
+    /// memory in KiB
+    pub m_cost: u32,
+    /// iterations
+    pub t_cost: u32,
+    /// parallelism
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            m_cost: 64 * 1024,
+            t_cost: 3,
+            p_cost: 1,
+        }
+    }
+}
+
+/// Where the passphrase of encrypted keys comes from
+/// (it's asked on the terminal if not set)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum PassphraseSource {
+    /// environment variable with the passphrase
+    Env(String),
+    /// file with the passphrase (a trailing newline is ignored)
+    File(PathBuf),
+}
+
+/// Checks if the (base64-decoded) key file content is encrypted
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+fn derive_key(
+    passphrase: &str,
+    params: KdfParams,
+    salt: &[u8],
+) -> Result<Zeroizing<[u8; 32]>, Error> {
+    let argon2_params = Params::new(params.m_cost, params.t_cost, params.p_cost, Some(32))
+        .map_err(|e| format_err!(ErrorKind::CryptoError, "invalid Argon2 parameters: {}", e))?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, argon2_params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut *key)
+        .map_err(|e| format_err!(ErrorKind::CryptoError, "key derivation failed: {}", e))?;
+    Ok(key)
+}
+
+/// Encrypts the secret with a key derived from the passphrase
+pub fn encrypt(secret: &[u8], passphrase: &str, params: KdfParams) -> Result<Vec<u8>, Error> {
+    let mut salt = [0u8; SALT_LENGTH];
+    OsRng.fill_bytes(&mut salt);
+    let mut nonce = [0u8; NONCE_LENGTH];
+    OsRng.fill_bytes(&mut nonce);
+    let mut data = Vec::with_capacity(HEADER_LENGTH + secret.len() + 16);
+    data.extend_from_slice(MAGIC);
+    data.push(VERSION);
+    data.extend_from_slice(&params.m_cost.to_le_bytes());
+    data.extend_from_slice(&params.t_cost.to_le_bytes());
+    data.extend_from_slice(&params.p_cost.to_le_bytes());
+    data.extend_from_slice(&salt);
+    data.extend_from_slice(&nonce);
+    let key = derive_key(passphrase, params, &salt)?;
+    let ciphertext = XChaCha20Poly1305::new(Key::from_slice(&*key))
+        .encrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: secret,
+                aad: &data,
+            },
+        )
+        .map_err(|_| format_err!(ErrorKind::CryptoError, "encryption failed"))?;
+    data.extend_from_slice(&ciphertext);
+    Ok(data)
+}
+
+/// Decrypts the secret (fails with a wrong passphrase or a modified file)
+pub fn decrypt(data: &[u8], passphrase: &str) -> Result<Zeroizing<Vec<u8>>, Error> {
+    if !is_encrypted(data) || data.len() < HEADER_LENGTH {
+        fail!(ErrorKind::InvalidKey, "not an encrypted key");
+    }
+    let version = data[MAGIC.len()];
+    if version != VERSION {
+        fail!(
+            ErrorKind::InvalidKey,
+            "unsupported encrypted key version: {}",
+            version
+        );
+    }
+    let (header, ciphertext) = data.split_at(HEADER_LENGTH);
+    let u32_at = |i: usize| {
+        let start = MAGIC.len() + 1 + 4 * i;
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&header[start..start + 4]);
+        u32::from_le_bytes(bytes)
+    };
+    let params = KdfParams {
+        m_cost: u32_at(0),
+        t_cost: u32_at(1),
+        p_cost: u32_at(2),
+    };
+    if params.m_cost > MAX_M_COST {
+        fail!(
+            ErrorKind::InvalidKey,
+            "memory cost {} KiB is too high",
+            params.m_cost
+        );
+    }
+    let salt_start = MAGIC.len() + 1 + 3 * 4;
+    let salt = &header[salt_start..salt_start + SALT_LENGTH];
+    let nonce = &header[salt_start + SALT_LENGTH..];
+    let key = derive_key(passphrase, params, salt)?;
+    let secret = XChaCha20Poly1305::new(Key::from_slice(&*key))
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .map_err(|_| {
+            format_err!(
+                ErrorKind::CryptoError,
+                "decryption failed (wrong passphrase?)"
+            )
+        })?;
+    Ok(Zeroizing::new(secret))
+}
+
+/// Gets the passphrase (once) for decrypting or encrypting keys
+pub struct KeyUnlocker {
+    source: Option<PassphraseSource>,
+    passphrase: Option<Zeroizing<String>>,
+    /// whether key and passphrase files accessible by others are loaded
+    allow_insecure: bool,
+}
+
+impl KeyUnlocker {
+    pub fn new(source: Option<PassphraseSource>, allow_insecure: bool) -> Self {
+        Self {
+            source,
+            passphrase: None,
+            allow_insecure,
+        }
+    }
+
+    /// whether key files accessible by others are loaded
+    pub fn allow_insecure(&self) -> bool {
+        self.allow_insecure
+    }
+
+    /// reads the passphrase from the configured source or asks for it on the terminal
+    /// (twice if `confirm` is set, i.e. for encrypting new keys)
+    pub fn passphrase(&mut self, confirm: bool) -> Result<&str, Error> {
+        if self.passphrase.is_none() {
+            let passphrase = match &self.source {
+                Some(PassphraseSource::Env(var)) => Zeroizing::new(
+                    std::env::var(var)
+                        .map_err(|e| format_err!(ErrorKind::ConfigError, "{}: {}", var, e))?,
+                ),
+                Some(PassphraseSource::File(path)) => {
+                    let content = Zeroizing::new(
+                        secure_file::read_to_string(path, self.allow_insecure).map_err(|e| {
+                            format_err!(
+                                ErrorKind::IoError,
+                                "couldn't read passphrase from {}: {}",
+                                path.display(),
+                                e
+                            )
+                        })?,
+                    );
+                    Zeroizing::new(content.trim_end_matches(&['\r', '\n'][..]).to_owned())
+                }
+                None => {
+                    let passphrase = read_tty("key passphrase: ")?;
+                    if confirm && *passphrase != *read_tty("repeat the key passphrase: ")? {
+                        fail!(ErrorKind::ConfigError, "the passphrases don't match");
+                    }
+                    passphrase
+                }
+            };
+            if passphrase.is_empty() {
+                fail!(ErrorKind::ConfigError, "empty key passphrase");
+            }
+            self.passphrase = Some(passphrase);
+        }
+        Ok(self.passphrase.as_ref().expect("passphrase set").as_str())
+    }
+
+    /// decrypts the (base64-decoded) key file content if it's encrypted
+    pub fn unlock(
+        &mut self,
+        path: &Path,
+        data: Zeroizing<Vec<u8>>,
+    ) -> Result<Zeroizing<Vec<u8>>, Error> {
+        if !is_encrypted(&data) {
+            return Ok(data);
+        }
+        decrypt(&data, self.passphrase(false)?)
+            .map_err(|e| format_err!(ErrorKind::InvalidKey, "{}: {}", path.display(), e).into())
+    }
+}
+
+fn read_tty(prompt: &str) -> Result<Zeroizing<String>, Error> {
+    rpassword::read_password_from_tty(Some(prompt))
+        .map(Zeroizing::new)
+        .map_err(|e| format_err!(ErrorKind::IoError, "couldn't read passphrase: {}", e).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const TEST_PARAMS: KdfParams = KdfParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    #[test]
+    fn encryption_roundtrip() {
+        let secret = [7u8; 32];
+        let data = encrypt(&secret, "correct horse", TEST_PARAMS).unwrap();
+        assert!(is_encrypted(&data));
+        assert_eq!(
+            decrypt(&data, "correct horse").unwrap().as_slice(),
+            &secret[..]
+        );
+        assert!(decrypt(&data, "wrong horse").is_err());
+        // the header is authenticated
+        let mut weaker = data.clone();
+        weaker[MAGIC.len() + 5] ^= 1;
+        assert!(decrypt(&weaker, "correct horse").is_err());
+        let mut other_version = data;
+        other_version[MAGIC.len()] = 2;
+        assert!(decrypt(&other_version, "correct horse").is_err());
+    }
+
+    #[test]
+    fn unlocks_with_passphrase_file() {
+        let path = std::env::temp_dir().join(format!("softsign-pass-{}", std::process::id()));
+        secure_file::write(&path, b"correct horse\n").unwrap();
+        let mut unlocker = KeyUnlocker::new(Some(PassphraseSource::File(path.clone())), false);
+        let data = encrypt(&[7u8; 32], "correct horse", TEST_PARAMS).unwrap();
+        let secret = unlocker.unlock(&path, Zeroizing::new(data)).unwrap();
+        assert_eq!(secret.as_slice(), &[7u8; 32][..]);
+        // plain keys are passed through
+        let plain = unlocker
+            .unlock(&path, Zeroizing::new(vec![1u8; 32]))
+            .unwrap();
+        assert_eq!(plain.as_slice(), &[1u8; 32][..]);
+        fs::remove_file(&path).unwrap();
+    }
+}
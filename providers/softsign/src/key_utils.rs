@@ -1,11 +1,6 @@
 //! Utilities
 
-use std::{
-    fs::{self, OpenOptions},
-    io::Write,
-    os::unix::fs::OpenOptionsExt,
-    path::Path,
-};
+use std::path::Path;
 
 use anomaly::format_err;
 use ed25519_dalek as ed25519;
@@ -13,22 +8,29 @@ use ed25519_dalek::SECRET_KEY_LENGTH;
 use rand_core::{OsRng, RngCore};
 use subtle_encoding::base64;
 use tmkms_light::error::{Error, ErrorKind};
+use tmkms_light::secure_file;
+use tmkms_light::signer::{generate_secret_key, signer_from_secret_key, ConsensusSigner, KeyType};
+use tmkms_light::threshold::KeyShare;
 use zeroize::Zeroizing;
 
-/// File permissions for secret data
-pub const SECRET_FILE_PERMS: u32 = 0o600;
+use crate::encrypted_key::{self, KdfParams, KeyUnlocker};
 
 /// Load Base64-encoded secret data (i.e. key) from the given path
-pub fn load_base64_secret(path: impl AsRef<Path>) -> Result<Zeroizing<Vec<u8>>, Error> {
-    // TODO(tarcieri): check file permissions are correct
-    let base64_data = Zeroizing::new(fs::read_to_string(path.as_ref()).map_err(|e| {
-        format_err!(
-            ErrorKind::IoError,
-            "couldn't read key from {}: {}",
-            path.as_ref().display(),
-            e
-        )
-    })?);
+/// (refusing a file that others could access unless `allow_insecure` is set)
+pub fn load_base64_secret(
+    path: impl AsRef<Path>,
+    allow_insecure: bool,
+) -> Result<Zeroizing<Vec<u8>>, Error> {
+    let base64_data = Zeroizing::new(
+        secure_file::read_to_string(path.as_ref(), allow_insecure).map_err(|e| {
+            format_err!(
+                ErrorKind::IoError,
+                "couldn't read key from {}: {}",
+                path.as_ref().display(),
+                e
+            )
+        })?,
+    );
 
     // TODO(tarcieri): constant-time string trimming
     let data = Zeroizing::new(base64::decode(base64_data.trim_end()).map_err(|e| {
@@ -43,42 +45,96 @@ pub fn load_base64_secret(path: impl AsRef<Path>) -> Result<Zeroizing<Vec<u8>>,
     Ok(data)
 }
 
+/// Load a Base64-encoded secret key (and decrypt it if it's encrypted)
+pub fn load_secret_key(
+    path: impl AsRef<Path>,
+    unlocker: &mut KeyUnlocker,
+) -> Result<Zeroizing<Vec<u8>>, Error> {
+    let data = load_base64_secret(path.as_ref(), unlocker.allow_insecure())?;
+    unlocker.unlock(path.as_ref(), data)
+}
+
 /// Load a Base64-encoded Ed25519 secret key
-pub fn load_base64_ed25519_key(path: impl AsRef<Path>) -> Result<ed25519::Keypair, Error> {
-    let key_bytes = load_base64_secret(path)?;
+pub fn load_base64_ed25519_key(
+    path: impl AsRef<Path>,
+    unlocker: &mut KeyUnlocker,
+) -> Result<ed25519::Keypair, Error> {
+    let key_bytes = load_secret_key(path, unlocker)?;
 
-    let secret = ed25519::SecretKey::from_bytes(&*key_bytes)
+    let secret = ed25519::SecretKey::from_bytes(&key_bytes)
         .map_err(|e| format_err!(ErrorKind::InvalidKey, "invalid Ed25519 key: {}", e))?;
 
     let public = ed25519::PublicKey::from(&secret);
     Ok(ed25519::Keypair { secret, public })
 }
 
+/// Load a Base64-encoded consensus key of the given type
+pub fn load_base64_consensus_key(
+    path: impl AsRef<Path>,
+    key_type: KeyType,
+    unlocker: &mut KeyUnlocker,
+) -> Result<Box<dyn ConsensusSigner>, Error> {
+    let key_bytes = load_secret_key(path, unlocker)?;
+    signer_from_secret_key(key_type, &key_bytes)
+}
+
+/// Load the key share of threshold signing
+pub fn load_key_share(
+    path: impl AsRef<Path>,
+    unlocker: &mut KeyUnlocker,
+) -> Result<KeyShare, Error> {
+    let key_bytes = load_secret_key(path, unlocker)?;
+    KeyShare::from_bytes(&key_bytes)
+}
+
 /// Store Base64-encoded secret data at the given path
 pub fn write_base64_secret(path: impl AsRef<Path>, data: &[u8]) -> Result<(), Error> {
     let base64_data = Zeroizing::new(base64::encode(data));
 
-    OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .mode(SECRET_FILE_PERMS)
-        .open(path.as_ref())
-        .and_then(|mut file| file.write_all(&*base64_data))
-        .map_err(|e| {
-            format_err!(
-                ErrorKind::IoError,
-                "couldn't write `{}`: {}",
-                path.as_ref().display(),
-                e
-            )
-            .into()
-        })
+    secure_file::write(path.as_ref(), &base64_data).map_err(|e| {
+        format_err!(
+            ErrorKind::IoError,
+            "couldn't write `{}`: {}",
+            path.as_ref().display(),
+            e
+        )
+        .into()
+    })
+}
+
+/// Store the secret key at the given path
+/// (encrypted with the passphrase if `unlocker` is provided)
+pub fn write_secret_key(
+    path: impl AsRef<Path>,
+    data: &[u8],
+    unlocker: Option<&mut KeyUnlocker>,
+) -> Result<(), Error> {
+    match unlocker {
+        Some(unlocker) => {
+            let encrypted =
+                encrypted_key::encrypt(data, unlocker.passphrase(true)?, KdfParams::default())?;
+            write_base64_secret(path, &encrypted)
+        }
+        None => write_base64_secret(path, data),
+    }
 }
 
 /// Generate a Secret Connection key at the given path
-pub fn generate_key(path: impl AsRef<Path>) -> Result<(), Error> {
+pub fn generate_key(
+    path: impl AsRef<Path>,
+    unlocker: Option<&mut KeyUnlocker>,
+) -> Result<(), Error> {
     let mut secret_key = Zeroizing::new([0u8; SECRET_KEY_LENGTH]);
     OsRng.fill_bytes(&mut *secret_key);
-    write_base64_secret(path, &*secret_key)
+    write_secret_key(path, &secret_key[..], unlocker)
+}
+
+/// Generate a consensus key of the given type at the given path
+pub fn generate_consensus_key(
+    path: impl AsRef<Path>,
+    key_type: KeyType,
+    unlocker: Option<&mut KeyUnlocker>,
+) -> Result<(), Error> {
+    let secret_key = generate_secret_key(key_type);
+    write_secret_key(path, &secret_key, unlocker)
 }
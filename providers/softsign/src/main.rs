@@ -1,22 +1,35 @@
 mod config;
+mod encrypted_key;
 mod key_utils;
-mod state;
-use state::StateHolder;
-use std::{fmt::Debug, os::unix::net::UnixStream};
-use std::{fs, path::PathBuf};
-use std::{net::TcpStream, time::Duration};
+mod priv_validator;
+use ed25519_dalek as ed25519;
+use encrypted_key::KeyUnlocker;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{fmt::Debug, thread};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 use structopt::StructOpt;
-use subtle::ConstantTimeEq;
-use tendermint::net;
-use tendermint_p2p::secret_connection::{self, PublicKey, SecretConnection};
-use tmkms_light::connection::{Connection, PlainConnection};
+use tendermint::{chain, net};
+use tendermint_p2p::secret_connection::PublicKey;
+use tmkms_light::connection::ConnectionManager;
 use tmkms_light::{
-    chain::state::PersistStateSync,
+    admin::{self, AdminRequest, AdminResponse, SessionControl},
+    audit::{self, AuditLog},
+    chain::state::{hook, PersistStateSync, State, StateFile},
     config::validator::ValidatorConfig,
+    grpc,
+    metrics::Metrics,
+    session::{Session, SharedState},
+    signer::{ConsensusSigner, KeyType},
+    threshold::{self, Cosigner, Peers, ThresholdSigner},
     utils::{print_pubkey, PubkeyDisplay},
 };
-use tracing::{debug, info, warn, Level};
+use tracing::{error, info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
+use zeroize::Zeroizing;
 
 #[derive(Debug, StructOpt)]
 #[structopt(
@@ -29,6 +42,30 @@ enum TmkmsLight {
     Init {
         #[structopt(short)]
         config_path: Option<PathBuf>,
+        /// consensus key type: ed25519 (default), secp256k1 or bls12_381 (with the `bls` feature)
+        #[structopt(short)]
+        key_type: Option<KeyType>,
+        /// encrypt the generated keys with a passphrase
+        #[structopt(short)]
+        encrypt: bool,
+    },
+    #[structopt(
+        name = "threshold-keygen",
+        about = "generate the key share with the other threshold signing nodes"
+    )]
+    /// runs the distributed key generation with the other nodes in the `[threshold]` config
+    ThresholdKeygen {
+        #[structopt(short)]
+        config_path: Option<PathBuf>,
+        /// seconds to wait for the other nodes (300 by default)
+        #[structopt(short)]
+        wait: Option<u64>,
+        /// encrypt the key share with a passphrase
+        #[structopt(short)]
+        encrypt: bool,
+        /// replace an existing key share
+        #[structopt(short)]
+        force: bool,
     },
     #[structopt(name = "start", about = "start tmkms process")]
     /// start tmkms process
@@ -36,6 +73,77 @@ enum TmkmsLight {
         #[structopt(short)]
         config_path: Option<PathBuf>,
     },
+    #[structopt(name = "verify-audit-log", about = "verify the audit log")]
+    /// verify the hash chain of the audit log
+    VerifyAuditLog {
+        #[structopt(short)]
+        config_path: Option<PathBuf>,
+        /// audit log path (if different from the one in config)
+        #[structopt(short)]
+        audit_log_path: Option<PathBuf>,
+        /// expected hash of the last record (e.g. from a previous verification)
+        #[structopt(short)]
+        expected_head_hash: Option<String>,
+    },
+    #[structopt(name = "admin", about = "send a command to the admin socket")]
+    /// sends a command to the admin socket of the running tmkms process
+    Admin {
+        #[structopt(short)]
+        config_path: Option<PathBuf>,
+        /// status, pause, resume or shutdown
+        command: AdminRequest,
+    },
+    #[structopt(name = "encrypt-keys", about = "encrypt plain key files")]
+    /// encrypts the plain consensus and identity key files with a passphrase
+    EncryptKeys {
+        #[structopt(short)]
+        config_path: Option<PathBuf>,
+    },
+    #[structopt(
+        name = "import",
+        about = "import Tendermint priv_validator_key.json / priv_validator_state.json"
+    )]
+    /// imports the consensus key and/or the state of a chain from Tendermint's files
+    Import {
+        #[structopt(short)]
+        config_path: Option<PathBuf>,
+        /// chain id of the `[[validator]]` entry (if there are more chains in config)
+        #[structopt(long)]
+        chain_id: Option<chain::Id>,
+        /// Tendermint `priv_validator_key.json` to import as the consensus key
+        #[structopt(short)]
+        key_path: Option<PathBuf>,
+        /// Tendermint `priv_validator_state.json` to import as the state
+        #[structopt(short)]
+        state_path: Option<PathBuf>,
+        /// replace an existing consensus key
+        #[structopt(short)]
+        force: bool,
+        /// encrypt the imported key with a passphrase
+        #[structopt(short)]
+        encrypt: bool,
+    },
+    #[structopt(
+        name = "export",
+        about = "export Tendermint priv_validator_key.json / priv_validator_state.json"
+    )]
+    /// exports the consensus key and/or the state of a chain to Tendermint's files
+    Export {
+        #[structopt(short)]
+        config_path: Option<PathBuf>,
+        /// chain id of the `[[validator]]` entry (if there are more chains in config)
+        #[structopt(long)]
+        chain_id: Option<chain::Id>,
+        /// where to write `priv_validator_key.json`
+        #[structopt(short)]
+        key_path: Option<PathBuf>,
+        /// where to write `priv_validator_state.json`
+        #[structopt(short)]
+        state_path: Option<PathBuf>,
+        /// replace existing files
+        #[structopt(short)]
+        force: bool,
+    },
     #[structopt(name = "pubkey", about = "display consensus public key")]
     /// displays consensus public key
     Pubkey {
@@ -51,21 +159,89 @@ enum TmkmsLight {
 fn main() {
     let opt = TmkmsLight::from_args();
     match opt {
-        TmkmsLight::Init { config_path } => {
+        TmkmsLight::Init {
+            config_path,
+            key_type,
+            encrypt,
+        } => {
             let cp = config_path.unwrap_or_else(|| "tmkms.toml".into());
-            let config = config::SoftSignOpt::default();
+            let mut unlocker = if encrypt {
+                Some(KeyUnlocker::new(None, false))
+            } else {
+                None
+            };
+            let config = config::SoftSignOpt::with_key_type(key_type.unwrap_or_default());
             let t = toml::to_string_pretty(&config).expect("config in toml");
             fs::write(cp, t).expect("written config");
             fs::create_dir_all(config.consensus_key_path.parent().expect("not root dir"))
                 .expect("create dirs for key storage");
-            key_utils::generate_key(config.consensus_key_path).expect("keygen failed");
-            if let Some(id_path) = config.id_key_path {
+            key_utils::generate_consensus_key(
+                &config.consensus_key_path,
+                config.key_type,
+                unlocker.as_mut(),
+            )
+            .expect("keygen failed");
+            if let Some(id_path) = &config.id_key_path {
                 fs::create_dir_all(id_path.parent().expect("not root dir"))
                     .expect("create dirs for key storage");
-                key_utils::generate_key(id_path).expect("keygen failed");
+                key_utils::generate_key(id_path, unlocker.as_mut()).expect("keygen failed");
             }
-            fs::create_dir_all(config.state_file_path.parent().expect("not root dir"))
+            for validator in config.validators.iter() {
+                fs::create_dir_all(validator.state_file_path.parent().expect("not root dir"))
+                    .expect("create dirs for state storage");
+            }
+        }
+        TmkmsLight::ThresholdKeygen {
+            config_path,
+            wait,
+            encrypt,
+            force,
+        } => {
+            let config = load_config(config_path);
+            let threshold = config
+                .threshold
+                .as_ref()
+                .unwrap_or_else(|| exit_with("no `[threshold]` in config"));
+            if config.consensus_key_path.exists() && !force {
+                exit_with(format!(
+                    "{} exists (use -f to replace it)",
+                    config.consensus_key_path.display()
+                ));
+            }
+            let subscriber = FmtSubscriber::builder()
+                .with_max_level(Level::INFO)
+                .finish();
+            tracing::subscriber::set_global_default(subscriber)
+                .expect("setting default subscriber failed");
+            let mut unlocker = KeyUnlocker::new(
+                config.key_passphrase.clone(),
+                config.allow_insecure_key_files,
+            );
+            let id_key_path = config.id_key_path.as_ref().expect("validated config");
+            let identity_key = key_utils::load_base64_ed25519_key(id_key_path, &mut unlocker)
+                .unwrap_or_else(|e| exit_with(e));
+            info!("KMS node ID: {}", PublicKey::from(&identity_key));
+            let wait = Duration::from_secs(wait.unwrap_or(DEFAULT_KEYGEN_WAIT));
+            let key_share = threshold::dkg::run(threshold, &identity_key, wait)
+                .unwrap_or_else(|e| exit_with(e));
+            fs::create_dir_all(config.consensus_key_path.parent().expect("not root dir"))
                 .expect("create dirs for key storage");
+            let key_bytes = key_share.to_bytes().unwrap_or_else(|e| exit_with(e));
+            key_utils::write_secret_key(
+                &config.consensus_key_path,
+                &key_bytes,
+                if encrypt { Some(&mut unlocker) } else { None },
+            )
+            .unwrap_or_else(|e| exit_with(e));
+            println!(
+                "wrote the key share to {}",
+                config.consensus_key_path.display()
+            );
+            print_pubkey(
+                None,
+                None,
+                key_share.public_key().unwrap_or_else(|e| exit_with(e)),
+            );
         }
         TmkmsLight::Start { config_path } => {
             let cp = config_path.unwrap_or_else(|| "tmkms.toml".into());
@@ -80,124 +256,333 @@ fn main() {
                 tracing::subscriber::set_global_default(subscriber)
                     .expect("setting default subscriber failed");
                 let toml_string = fs::read_to_string(cp).expect("toml config file read");
-                let config: config::SoftSignOpt =
-                    toml::from_str(&toml_string).expect("configuration");
-                let mut state_holder = StateHolder::new(config.state_file_path);
-                let state = state_holder.load_state().expect("state loaded");
-                let keypair = key_utils::load_base64_ed25519_key(config.consensus_key_path)
-                    .expect("secret keypair");
-                let connection: Box<dyn Connection> = match &config.address {
-                    net::Address::Tcp {
-                        peer_id,
-                        host,
-                        port,
-                    } => {
-                        debug!(
-                            "[{}@{}] connecting to validator...",
-                            &config.chain_id, &config.address
-                        );
-                        /// Default timeout in seconds
-                        const DEFAULT_TIMEOUT: u16 = 10;
-
-                        let identity_key_path = config.id_key_path.as_ref().unwrap_or_else(|| {
-                            panic!(
-                                "config error: no `secret_key` for validator: {}:{}",
-                                host, port
+                let config = config::SoftSignOpt::from_toml(&toml_string).expect("configuration");
+                if let Err(e) = config.validate() {
+                    eprintln!("invalid config: {}", e);
+                    std::process::exit(1);
+                }
+                let audit_log = config.audit_log_path.as_ref().map(|path| {
+                    Arc::new(Mutex::new(AuditLog::open(path).expect("audit log opened")))
+                });
+                let metrics = config.metrics_listen_addr.map(|addr| {
+                    let metrics = Arc::new(Metrics::default());
+                    metrics.clone().serve(addr).expect("metrics listener");
+                    metrics
+                });
+                // the keys are unlocked once (before the sessions start)
+                let mut unlocker = KeyUnlocker::new(
+                    config.key_passphrase.clone(),
+                    config.allow_insecure_key_files,
+                );
+                let uses_tcp = config
+                    .validators
+                    .iter()
+                    .any(|v| matches!(v.address, net::Address::Tcp { .. }));
+                let identity_key = match &config.id_key_path {
+                    Some(path) if uses_tcp || config.threshold.is_some() => Some(Arc::new(
+                        key_utils::load_base64_ed25519_key(path, &mut unlocker)
+                            .expect("id keypair"),
+                    )),
+                    _ => None,
+                };
+                // the key share of threshold signing (with the other nodes to sign with)
+                let threshold = config.threshold.as_ref().map(|threshold| {
+                    let identity_key = identity_key.as_ref().expect("validated config");
+                    let key_share =
+                        key_utils::load_key_share(&config.consensus_key_path, &mut unlocker)
+                            .expect("key share");
+                    let mut cosigner = Cosigner::new(key_share).expect("key share");
+                    for group in config.validator_groups() {
+                        let cosigner_state = group[0]
+                            .cosigner_state_file_path
+                            .as_ref()
+                            .expect("validated config");
+                        cosigner
+                            .add_chain(
+                                group[0].chain_id.clone(),
+                                group[0].protocol_version,
+                                StateFile::new(cosigner_state, config.state_durability),
+                            )
+                            .expect("cosigner state loaded");
+                    }
+                    let cosigner = Arc::new(cosigner);
+                    threshold::serve(threshold, identity_key, cosigner.clone())
+                        .expect("threshold signing listener");
+                    let peers = Peers::new(threshold, identity_key).expect("threshold peers");
+                    (cosigner, Arc::new(peers))
+                });
+                let mut sessions = Vec::new();
+                let mut controls = Vec::new();
+                for group in config.validator_groups() {
+                    // redundant nodes of the same validator share the key and the double-sign state
+                    let signer: Arc<Box<dyn ConsensusSigner>> = match &threshold {
+                        Some((cosigner, peers)) => Arc::new(Box::new(ThresholdSigner::new(
+                            group[0].chain_id.clone(),
+                            cosigner.clone(),
+                            peers.clone(),
+                        ))),
+                        None => {
+                            let (key_path, key_type) = config.consensus_key(group[0]);
+                            Arc::new(
+                                key_utils::load_base64_consensus_key(
+                                    key_path,
+                                    key_type,
+                                    &mut unlocker,
+                                )
+                                .expect("secret keypair"),
                             )
-                        });
-
-                        let identity_key = key_utils::load_base64_ed25519_key(identity_key_path)
-                            .expect("id keypair");
-                        info!("KMS node ID: {}", PublicKey::from(&identity_key));
-                        let mut msocket;
-                        loop {
-                            msocket = TcpStream::connect(format!("{}:{}", host, port)).ok();
-                            if msocket.is_some() || !config.retry {
-                                break;
-                            }
                         }
-                        let socket = msocket.expect("tcp connection");
-                        let timeout =
-                            Duration::from_secs(config.timeout.unwrap_or(DEFAULT_TIMEOUT).into());
-                        socket
-                            .set_read_timeout(Some(timeout))
-                            .expect("read timeout set");
-                        socket
-                            .set_write_timeout(Some(timeout))
-                            .expect("write timeout set");
-
-                        let connection = SecretConnection::new(
-                            socket,
-                            identity_key,
-                            secret_connection::Version::V0_34,
-                        )
-                        .expect("secret connection");
-                        let actual_peer_id = connection.remote_pubkey().peer_id();
-
-                        // TODO: https://github.com/informalsystems/tendermint-rs/issues/786
-                        if let Some(expected_peer_id) = peer_id {
-                            if expected_peer_id.ct_eq(&actual_peer_id).unwrap_u8() == 0 {
-                                panic!(
-                                    "{}:{}: validator peer ID mismatch! (expected {}, got {})",
-                                    host, port, expected_peer_id, actual_peer_id
-                                );
+                    };
+                    let mut state_file: StateFile =
+                        StateFile::new(&group[0].state_file_path, config.state_durability);
+                    let mut state = state_file.load_state().expect("state loaded");
+                    if let Some(state_hook) = group.iter().find_map(|v| v.state_hook.as_ref()) {
+                        match hook::run(state_hook, state.consensus_state()) {
+                            Ok(None) => {}
+                            Ok(Some(raised)) => {
+                                state = State::from(raised);
+                                state_file
+                                    .persist_state(&state)
+                                    .expect("raised state persisted");
+                            }
+                            Err(e) => {
+                                error!("[{}] startup state hook: {}", group[0].chain_id, e);
+                                std::process::exit(1);
                             }
                         }
-                        info!(
-                            "[{}@{}] connected to validator successfully",
-                            &config.chain_id, &config.address
-                        );
-
-                        if peer_id.is_none() {
-                            // TODO: https://github.com/informalsystems/tendermint-rs/issues/786
-                            warn!(
-                                "[{}@{}]: unverified validator peer ID! ({})",
-                                &config.chain_id,
-                                &config.address,
-                                connection.remote_pubkey().peer_id()
-                            );
-                        }
-
-                        Box::new(connection)
                     }
-                    net::Address::Unix { path } => {
-                        if let Some(timeout) = config.timeout {
-                            warn!("timeouts not supported with Unix sockets: {}", timeout);
+                    let shared_state = Arc::new(Mutex::new(SharedState::new(state, state_file)));
+                    for validator in group {
+                        if let (Some(grpc), net::Address::Unix { path }) =
+                            (&validator.grpc, &validator.address)
+                        {
+                            grpc::spawn(grpc, Path::new(path), config.allow_insecure_key_files)
+                                .expect("gRPC server");
                         }
-
-                        debug!(
-                            "{}: Connecting to socket at {}...",
-                            &config.chain_id, &config.address
-                        );
-                        let mut msocket;
-                        loop {
-                            msocket = UnixStream::connect(path).ok();
-                            if msocket.is_some() || !config.retry {
-                                break;
-                            }
+                        let identity_key = identity_key.clone();
+                        let validator = validator.clone();
+                        let signer = signer.clone();
+                        let shared_state = shared_state.clone();
+                        let audit_log = audit_log.clone();
+                        let metrics = metrics.clone();
+                        let control = Arc::new(SessionControl::new(
+                            validator.chain_id.clone(),
+                            Some(validator.address.to_string()),
+                        ));
+                        controls.push(control.clone());
+                        sessions.push(thread::spawn(move || {
+                            run_session(
+                                validator,
+                                signer,
+                                shared_state,
+                                audit_log,
+                                metrics,
+                                control,
+                                identity_key,
+                            )
+                        }));
+                    }
+                }
+                if let Some(path) = &config.admin_socket_path {
+                    admin::serve(path, move |request| {
+                        admin::handle_request(&controls, request)
+                    })
+                    .expect("admin socket");
+                }
+                for session in sessions {
+                    session.join().expect("session thread");
+                }
+            }
+        }
+        TmkmsLight::VerifyAuditLog {
+            config_path,
+            audit_log_path,
+            expected_head_hash,
+        } => {
+            let path = audit_log_path.unwrap_or_else(|| {
+                let cp = config_path.unwrap_or_else(|| "tmkms.toml".into());
+                let toml_string = fs::read_to_string(cp).expect("toml config file read");
+                let config = config::SoftSignOpt::from_toml(&toml_string).expect("configuration");
+                config.audit_log_path.unwrap_or_else(|| {
+                    eprintln!("no `audit_log_path` in config");
+                    std::process::exit(1);
+                })
+            });
+            match audit::verify(&path) {
+                Ok(verified) => {
+                    if let Some(expected) = expected_head_hash {
+                        if expected != verified.head_hash {
+                            eprintln!(
+                                "{}: last record hash {} does not match the expected {} (truncated?)",
+                                path.display(),
+                                verified.head_hash,
+                                expected
+                            );
+                            std::process::exit(1);
                         }
-                        let socket = msocket.expect("unix socket open");
-                        let conn = PlainConnection::new(socket);
-
-                        info!(
-                            "[{}@{}] connected to validator successfully",
-                            &config.chain_id, &config.address
-                        );
-
-                        Box::new(conn)
                     }
+                    println!("records: {}", verified.records);
+                    println!("last record hash: {}", verified.head_hash);
+                }
+                Err(e) => {
+                    eprintln!("audit log verification failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        TmkmsLight::Admin {
+            config_path,
+            command,
+        } => {
+            let cp = config_path.unwrap_or_else(|| "tmkms.toml".into());
+            let toml_string = fs::read_to_string(cp).expect("toml config file read");
+            let config = config::SoftSignOpt::from_toml(&toml_string).expect("configuration");
+            let path = config.admin_socket_path.unwrap_or_else(|| {
+                eprintln!("no `admin_socket_path` in config");
+                std::process::exit(1);
+            });
+            match admin::send_request(&path, command) {
+                Ok(AdminResponse::Error(e)) => {
+                    eprintln!("admin command failed: {}", e);
+                    std::process::exit(1);
+                }
+                Ok(response) => println!(
+                    "{}",
+                    serde_json::to_string_pretty(&response).expect("admin response in json")
+                ),
+                Err(e) => {
+                    eprintln!("admin request failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        TmkmsLight::EncryptKeys { config_path } => {
+            let config = load_config(config_path);
+            let mut unlocker = KeyUnlocker::new(
+                config.key_passphrase.clone(),
+                config.allow_insecure_key_files,
+            );
+            let mut key_paths: Vec<PathBuf> = config
+                .validators
+                .iter()
+                .map(|validator| config.consensus_key(validator).0)
+                .chain(config.id_key_path.clone())
+                .collect();
+            key_paths.sort();
+            key_paths.dedup();
+            for path in key_paths {
+                let data = key_utils::load_base64_secret(&path, config.allow_insecure_key_files)
+                    .unwrap_or_else(|e| exit_with(e));
+                if encrypted_key::is_encrypted(&data) {
+                    println!("{} is already encrypted", path.display());
+                    continue;
+                }
+                key_utils::write_secret_key(&path, &data, Some(&mut unlocker))
+                    .unwrap_or_else(|e| exit_with(e));
+                println!("encrypted {}", path.display());
+            }
+        }
+        TmkmsLight::Import {
+            config_path,
+            chain_id,
+            key_path,
+            state_path,
+            force,
+            encrypt,
+        } => {
+            let config = load_config(config_path);
+            let validator = find_validator(&config, chain_id.as_ref());
+            if key_path.is_some() && config.threshold.is_some() {
+                exit_with("a key can't be imported in threshold signing (see `threshold-keygen`)");
+            }
+            if let Some(key_path) = key_path {
+                let mut unlocker = if encrypt {
+                    Some(KeyUnlocker::new(
+                        config.key_passphrase.clone(),
+                        config.allow_insecure_key_files,
+                    ))
+                } else {
+                    None
                 };
-                let mut session = tmkms_light::session::Session::new(
-                    ValidatorConfig {
-                        chain_id: config.chain_id,
-                        max_height: config.max_height,
-                    },
-                    connection,
-                    keypair,
-                    state,
-                    state_holder,
+                let (key_type, secret_key) =
+                    priv_validator::read_key(&key_path).unwrap_or_else(|e| exit_with(e));
+                let (consensus_key_path, config_key_type) = config.consensus_key(validator);
+                if key_type != config_key_type {
+                    exit_with(format!(
+                        "{} is a {:?} key, but {:?} is configured",
+                        key_path.display(),
+                        key_type,
+                        config_key_type
+                    ));
+                }
+                if consensus_key_path.exists() && !force {
+                    exit_with(format!(
+                        "{} exists (use -f to replace it)",
+                        consensus_key_path.display()
+                    ));
+                }
+                key_utils::write_secret_key(&consensus_key_path, &secret_key, unlocker.as_mut())
+                    .unwrap_or_else(|e| exit_with(e));
+                println!(
+                    "imported the consensus key to {}",
+                    consensus_key_path.display()
+                );
+            }
+            if let Some(state_path) = state_path {
+                let imported =
+                    priv_validator::read_state(&state_path).unwrap_or_else(|e| exit_with(e));
+                let mut state_file: StateFile =
+                    StateFile::new(&validator.state_file_path, config.state_durability);
+                let current = state_file.load_state().unwrap_or_else(|e| exit_with(e));
+                priv_validator::check_import(current.consensus_state(), imported.consensus_state())
+                    .unwrap_or_else(|e| exit_with(format!("[{}] {}", validator.chain_id, e)));
+                state_file
+                    .persist_state(&imported)
+                    .unwrap_or_else(|e| exit_with(e));
+                println!(
+                    "[{}] imported the state {} to {}",
+                    validator.chain_id,
+                    imported.consensus_state(),
+                    validator.state_file_path.display()
+                );
+            }
+        }
+        TmkmsLight::Export {
+            config_path,
+            chain_id,
+            key_path,
+            state_path,
+            force,
+        } => {
+            let config = load_config(config_path);
+            let validator = find_validator(&config, chain_id.as_ref());
+            if key_path.is_some() && config.threshold.is_some() {
+                exit_with("the key share of threshold signing can't be exported");
+            }
+            if let Some(key_path) = key_path {
+                let (consensus_key_path, key_type) = config.consensus_key(validator);
+                let mut unlocker = KeyUnlocker::new(
+                    config.key_passphrase.clone(),
+                    config.allow_insecure_key_files,
+                );
+                let secret_key = key_utils::load_secret_key(&consensus_key_path, &mut unlocker)
+                    .unwrap_or_else(|e| exit_with(e));
+                priv_validator::write_key(&key_path, key_type, &secret_key, force)
+                    .unwrap_or_else(|e| exit_with(e));
+                println!("exported the consensus key to {}", key_path.display());
+            }
+            if let Some(state_path) = state_path {
+                let mut state_file: StateFile =
+                    StateFile::new(&validator.state_file_path, config.state_durability);
+                let state = state_file.load_state().unwrap_or_else(|e| exit_with(e));
+                priv_validator::write_state(&state_path, &state, force)
+                    .unwrap_or_else(|e| exit_with(e));
+                println!(
+                    "[{}] exported the state {} to {}",
+                    validator.chain_id,
+                    state.consensus_state(),
+                    state_path.display()
                 );
-                session.request_loop().expect("request loop");
             }
         }
         TmkmsLight::Pubkey {
@@ -211,12 +596,156 @@ fn main() {
                 std::process::exit(1);
             } else {
                 let toml_string = fs::read_to_string(cp).expect("toml config file read");
-                let config: config::SoftSignOpt =
-                    toml::from_str(&toml_string).expect("configuration");
-                let keypair = key_utils::load_base64_ed25519_key(config.consensus_key_path)
-                    .expect("secret keypair");
-                print_pubkey(bech32_prefix, ptype, keypair.public);
+                let config = config::SoftSignOpt::from_toml(&toml_string).expect("configuration");
+                let mut unlocker = KeyUnlocker::new(
+                    config.key_passphrase.clone(),
+                    config.allow_insecure_key_files,
+                );
+                if config.threshold.is_some() {
+                    // the group key of the shares
+                    let key_share =
+                        key_utils::load_key_share(&config.consensus_key_path, &mut unlocker)
+                            .expect("key share");
+                    print_pubkey(
+                        bech32_prefix,
+                        ptype,
+                        key_share.public_key().expect("group key"),
+                    );
+                    return;
+                }
+                let signer = key_utils::load_base64_consensus_key(
+                    &config.consensus_key_path,
+                    config.key_type,
+                    &mut unlocker,
+                )
+                .expect("secret keypair");
+                print_pubkey(bech32_prefix.clone(), ptype, signer.public_key());
+                for validator in config.validators.iter() {
+                    if validator.consensus_key_path.is_some() {
+                        let (key_path, key_type) = config.consensus_key(validator);
+                        let signer =
+                            key_utils::load_base64_consensus_key(key_path, key_type, &mut unlocker)
+                                .expect("secret keypair");
+                        println!("[{}]", validator.chain_id);
+                        print_pubkey(bech32_prefix.clone(), ptype, signer.public_key());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Loads and validates the config (exiting if it's invalid)
+fn load_config(config_path: Option<PathBuf>) -> config::SoftSignOpt {
+    let cp = config_path.unwrap_or_else(|| "tmkms.toml".into());
+    let toml_string = fs::read_to_string(cp).expect("toml config file read");
+    let config = config::SoftSignOpt::from_toml(&toml_string).expect("configuration");
+    if let Err(e) = config.validate() {
+        exit_with(format!("invalid config: {}", e));
+    }
+    config
+}
+
+/// The `[[validator]]` entry of the given chain
+/// (or the only chain if none is given)
+fn find_validator<'a>(
+    config: &'a config::SoftSignOpt,
+    chain_id: Option<&chain::Id>,
+) -> &'a config::ValidatorOpt {
+    let groups = config.validator_groups();
+    match chain_id {
+        Some(chain_id) => groups
+            .iter()
+            .find(|group| group[0].chain_id == *chain_id)
+            .map(|group| group[0])
+            .unwrap_or_else(|| exit_with(format!("no `[[validator]]` entry for {}", chain_id))),
+        None if groups.len() == 1 => groups[0][0],
+        None => exit_with("more chains in config: specify --chain-id"),
+    }
+}
+
+/// Prints the error and exits with status 1
+fn exit_with(e: impl std::fmt::Display) -> ! {
+    eprintln!("{}", e);
+    std::process::exit(1);
+}
+
+/// Default timeout in seconds
+const DEFAULT_TIMEOUT: u16 = 10;
+
+/// Default seconds to wait for the other nodes in the threshold key generation
+const DEFAULT_KEYGEN_WAIT: u64 = 300;
+
+/// Connects to the validator node of the given `[[validator]]` entry
+/// and runs its signing session (reconnecting if enabled)
+fn run_session(
+    validator: config::ValidatorOpt,
+    signer: Arc<Box<dyn ConsensusSigner>>,
+    shared_state: Arc<Mutex<SharedState<StateFile>>>,
+    audit_log: Option<Arc<Mutex<AuditLog>>>,
+    metrics: Option<Arc<Metrics>>,
+    control: Arc<SessionControl>,
+    identity_key: Option<Arc<ed25519::Keypair>>,
+) {
+    let identity_key = match &validator.address {
+        // the `Clone` is not derived for Keypair
+        net::Address::Tcp { .. } => identity_key.map(|key| {
+            let key_bytes = Zeroizing::new(key.to_bytes());
+            ed25519::Keypair::from_bytes(&*key_bytes).expect("id keypair")
+        }),
+        net::Address::Unix { .. } => {
+            if let Some(timeout) = validator.timeout {
+                warn!(
+                    "timeouts not supported with Unix sockets (see `idle_timeout`): {}",
+                    timeout
+                );
             }
+            None
+        }
+    };
+    if let Some(identity_key) = &identity_key {
+        info!("KMS node ID: {}", PublicKey::from(identity_key));
+    }
+    let mut manager = match ConnectionManager::new(validator.address.clone(), identity_key) {
+        Ok(manager) => manager,
+        Err(e) => {
+            error!("[{}] {}", &validator.chain_id, e);
+            return;
         }
+    };
+    manager.set_allowed_peer_ids(validator.allowed_peer_ids.clone());
+    manager.set_timeout(Some(Duration::from_secs(
+        validator.timeout.unwrap_or(DEFAULT_TIMEOUT).into(),
+    )));
+    manager.set_retry(validator.retry);
+    manager.set_protocol_version(validator.protocol_version);
+    if let Some(max_backoff) = validator.max_backoff {
+        manager.set_max_backoff(Duration::from_secs(max_backoff.into()));
+    }
+    // the connection errors are logged by the manager
+    let connection = match manager.connect() {
+        Ok(connection) => connection,
+        Err(_) => return,
+    };
+    let mut session = Session::new_shared(
+        ValidatorConfig {
+            chain_id: validator.chain_id.clone(),
+            max_height: validator.max_height,
+            idle_timeout: validator.idle_timeout,
+            protocol_version: validator.protocol_version,
+        },
+        connection,
+        signer,
+        shared_state,
+    );
+    if let Some(audit_log) = audit_log {
+        session.set_audit_log(audit_log);
+    }
+    if let Some(metrics) = metrics {
+        session.set_metrics(metrics);
+    }
+    session.set_control(control);
+    if let Err(e) = session.run(&mut manager) {
+        error!("[{}] session stopped: {}", &validator.chain_id, e);
     }
 }
@@ -0,0 +1,567 @@
+//! Conversion from/to Tendermint's `priv_validator_key.json` and `priv_validator_state.json`
+
+use std::{convert::TryFrom, fs, io::Write, path::Path};
+
+use anomaly::{fail, format_err};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use subtle_encoding::{base64, hex};
+use tendermint::{block, consensus, PublicKey};
+use tendermint_proto::types::{CanonicalBlockId, CanonicalProposal, CanonicalVote};
+use tmkms_light::chain::state::{LastSigned, State};
+use tmkms_light::error::{Error, ErrorKind};
+use tmkms_light::secure_file;
+use tmkms_light::signer::{signer_from_secret_key, ConsensusPublicKey, KeyType};
+use zeroize::{Zeroize, Zeroizing};
+
+const ED25519_PUB_KEY_TYPE: &str = "tendermint/PubKeyEd25519";
+const ED25519_PRIV_KEY_TYPE: &str = "tendermint/PrivKeyEd25519";
+const SECP256K1_PUB_KEY_TYPE: &str = "tendermint/PubKeySecp256k1";
+const SECP256K1_PRIV_KEY_TYPE: &str = "tendermint/PrivKeySecp256k1";
+#[cfg(feature = "bls")]
+const BLS12_381_PUB_KEY_TYPE: &str = "cometbft/PubKeyBls12_381";
+#[cfg(feature = "bls")]
+const BLS12_381_PRIV_KEY_TYPE: &str = "cometbft/PrivKeyBls12_381";
+
+/// Amino JSON encoding of a key (`{"type": "...", "value": "<base64>"}`)
+#[derive(Serialize, Deserialize)]
+struct AminoKey {
+    #[serde(rename = "type")]
+    key_type: String,
+    value: String,
+}
+
+impl Drop for AminoKey {
+    fn drop(&mut self) {
+        self.value.zeroize();
+    }
+}
+
+/// Tendermint's `priv_validator_key.json`
+#[derive(Serialize, Deserialize)]
+struct PrivValidatorKey {
+    address: String,
+    pub_key: AminoKey,
+    priv_key: AminoKey,
+}
+
+/// Tendermint's `priv_validator_state.json`
+/// (its steps are 1 for proposals, 2 for prevotes and 3 for precommits,
+/// while `consensus::State` uses 0, 1 and 2; its step 0, nothing signed
+/// at the height and round yet, is -1 in `consensus::State`)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PrivValidatorState {
+    pub height: String,
+    pub round: i32,
+    pub step: i8,
+    /// base64-encoded signature of `signbytes`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    /// hex-encoded sign bytes of the last signed proposal or vote
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signbytes: Option<String>,
+}
+
+impl TryFrom<PrivValidatorState> for consensus::State {
+    type Error = Error;
+
+    fn try_from(tm_state: PrivValidatorState) -> Result<Self, Error> {
+        let height = tm_state
+            .height
+            .parse::<u64>()
+            .ok()
+            .and_then(|h| block::Height::try_from(h).ok())
+            .ok_or_else(|| format_err!(ErrorKind::ParseError, "invalid height"))?;
+        let round = block::Round::try_from(tm_state.round)
+            .map_err(|e| format_err!(ErrorKind::ParseError, "invalid round: {}", e))?;
+        let step = match tm_state.step {
+            // nothing was signed at this height and round yet
+            // (so even its proposal can be signed)
+            0..=3 => tm_state.step - 1,
+            step => fail!(ErrorKind::ParseError, "invalid step: {}", step),
+        };
+        // the block id is only in the sign bytes
+        let block_id = match tm_state.signbytes.as_deref() {
+            Some(signbytes) if !signbytes.is_empty() && tm_state.step > 0 => {
+                let signbytes = decode_signbytes(signbytes)?;
+                signed_block_id(&signbytes, tm_state.step, height, round)?
+            }
+            _ => None,
+        };
+        Ok(consensus::State {
+            height,
+            round,
+            step,
+            block_id,
+        })
+    }
+}
+
+impl TryFrom<PrivValidatorState> for State {
+    type Error = Error;
+
+    /// the state with the last signed message (if the file has both its sign bytes
+    /// and signature, so that it can be signed again at the same height/round/step)
+    fn try_from(tm_state: PrivValidatorState) -> Result<Self, Error> {
+        let last_signed = match (tm_state.signbytes.as_deref(), tm_state.signature.as_deref()) {
+            (Some(signbytes), Some(signature))
+                if !signbytes.is_empty() && !signature.is_empty() && tm_state.step > 0 =>
+            {
+                Some(LastSigned {
+                    sign_bytes: decode_signbytes(signbytes)?,
+                    signature: base64::decode(signature).map_err(|e| {
+                        format_err!(ErrorKind::ParseError, "invalid signature: {}", e)
+                    })?,
+                })
+            }
+            _ => None,
+        };
+        Ok(State::new(
+            consensus::State::try_from(tm_state)?,
+            last_signed,
+        ))
+    }
+}
+
+impl From<&consensus::State> for PrivValidatorState {
+    fn from(state: &consensus::State) -> Self {
+        // the initial state has nothing signed (nor has step -1)
+        let step = if *state == consensus::State::default() {
+            0
+        } else {
+            state.step + 1
+        };
+        Self {
+            height: state.height.value().to_string(),
+            round: state.round.value() as i32,
+            step,
+            signature: None,
+            signbytes: None,
+        }
+    }
+}
+
+impl From<&State> for PrivValidatorState {
+    fn from(state: &State) -> Self {
+        let mut tm_state = PrivValidatorState::from(state.consensus_state());
+        // Tendermint refuses to sign at the same height/round/step without them
+        if let Some(last_signed) = state.last_signed() {
+            tm_state.signbytes = Some(
+                String::from_utf8(hex::encode_upper(&last_signed.sign_bytes))
+                    .expect("hex is ASCII"),
+            );
+            tm_state.signature = Some(
+                String::from_utf8(base64::encode(&last_signed.signature)).expect("base64 is ASCII"),
+            );
+        }
+        tm_state
+    }
+}
+
+/// decodes the hex-encoded sign bytes (upper case in Tendermint's files)
+fn decode_signbytes(signbytes: &str) -> Result<Vec<u8>, Error> {
+    hex::decode_upper(signbytes)
+        .or_else(|_| hex::decode(signbytes))
+        .map_err(|e| format_err!(ErrorKind::ParseError, "invalid signbytes: {}", e).into())
+}
+
+/// decodes the block id from the sign bytes of the last proposal or vote
+/// (and checks they are at the same height and round as the state)
+fn signed_block_id(
+    signbytes: &[u8],
+    tm_step: i8,
+    height: block::Height,
+    round: block::Round,
+) -> Result<Option<block::Id>, Error> {
+    let (signed_height, signed_round, block_id) = if tm_step == 1 {
+        let proposal = CanonicalProposal::decode_length_delimited(signbytes)
+            .map_err(|e| format_err!(ErrorKind::ParseError, "invalid signed proposal: {}", e))?;
+        (proposal.height, proposal.round, proposal.block_id)
+    } else {
+        let vote = CanonicalVote::decode_length_delimited(signbytes)
+            .map_err(|e| format_err!(ErrorKind::ParseError, "invalid signed vote: {}", e))?;
+        (vote.height, vote.round, vote.block_id)
+    };
+    if signed_height != height.value() as i64 || signed_round != round.value() as i64 {
+        fail!(
+            ErrorKind::ParseError,
+            "signbytes at height {} round {} don't match the state",
+            signed_height,
+            signed_round
+        );
+    }
+    match block_id {
+        // `<nil>`
+        None => Ok(None),
+        Some(CanonicalBlockId { ref hash, .. }) if hash.is_empty() => Ok(None),
+        Some(raw) => block::Id::try_from(raw)
+            .map(Some)
+            .map_err(|e| format_err!(ErrorKind::ParseError, "invalid block id: {}", e).into()),
+    }
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T, Error> {
+    // these files come from Tendermint's directory, so they aren't checked with `secure_file`
+    let json = Zeroizing::new(fs::read_to_string(path).map_err(|e| {
+        format_err!(
+            ErrorKind::IoError,
+            "couldn't read {}: {}",
+            path.display(),
+            e
+        )
+    })?);
+    serde_json::from_str(&json).map_err(|e| {
+        format_err!(
+            ErrorKind::ParseError,
+            "couldn't parse {}: {}",
+            path.display(),
+            e
+        )
+        .into()
+    })
+}
+
+/// writes the JSON file (with permissions for secret data),
+/// refusing to replace an existing file unless `force` is set
+fn write_json<T: Serialize>(path: &Path, value: &T, force: bool) -> Result<(), Error> {
+    let json = Zeroizing::new(
+        serde_json::to_string_pretty(value)
+            .map_err(|e| format_err!(ErrorKind::SerializationError, "JSON encoding: {}", e))?,
+    );
+    let mut options = secure_file::write_options();
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    options
+        .open(path)
+        .and_then(|mut file| {
+            secure_file::restrict_permissions(&file)?;
+            file.write_all(json.as_bytes())
+        })
+        .map_err(|e| {
+            format_err!(
+                ErrorKind::IoError,
+                "couldn't write `{}`: {}",
+                path.display(),
+                e
+            )
+            .into()
+        })
+}
+
+/// Reads the consensus key (its type and raw secret key bytes) from `priv_validator_key.json`
+/// (and checks it matches the public key in the file)
+pub fn read_key(path: &Path) -> Result<(KeyType, Zeroizing<Vec<u8>>), Error> {
+    let tm_key: PrivValidatorKey = read_json(path)?;
+    let priv_bytes = Zeroizing::new(
+        base64::decode(&tm_key.priv_key.value)
+            .map_err(|e| format_err!(ErrorKind::InvalidKey, "invalid private key: {}", e))?,
+    );
+    let (key_type, secret_key) = match tm_key.priv_key.key_type.as_str() {
+        // secret key followed by the public key
+        ED25519_PRIV_KEY_TYPE if priv_bytes.len() == 64 => {
+            (KeyType::Ed25519, Zeroizing::new(priv_bytes[..32].to_vec()))
+        }
+        SECP256K1_PRIV_KEY_TYPE => (KeyType::Secp256k1, priv_bytes),
+        #[cfg(feature = "bls")]
+        BLS12_381_PRIV_KEY_TYPE => (KeyType::Bls12_381, priv_bytes),
+        key_type => fail!(
+            ErrorKind::InvalidKey,
+            "unsupported private key: {} ({} bytes)",
+            key_type,
+            priv_bytes.len()
+        ),
+    };
+    let public_key = signer_from_secret_key(key_type, &secret_key)?.public_key();
+    let expected_pub_bytes = base64::decode(&tm_key.pub_key.value)
+        .map_err(|e| format_err!(ErrorKind::InvalidKey, "invalid public key: {}", e))?;
+    if public_key.to_bytes() != expected_pub_bytes {
+        fail!(
+            ErrorKind::InvalidKey,
+            "the public key in {} doesn't match the private key",
+            path.display()
+        );
+    }
+    Ok((key_type, secret_key))
+}
+
+/// Writes the consensus key as `priv_validator_key.json`
+pub fn write_key(
+    path: &Path,
+    key_type: KeyType,
+    secret_key: &[u8],
+    force: bool,
+) -> Result<(), Error> {
+    let public_key = signer_from_secret_key(key_type, secret_key)?.public_key();
+    let pub_bytes = public_key.to_bytes();
+    let address = public_key
+        .address()
+        .ok_or_else(|| format_err!(ErrorKind::InvalidKey, "unsupported public key"))?;
+    let (pub_key_type, priv_key_type, priv_bytes) = match public_key {
+        ConsensusPublicKey::Tendermint(PublicKey::Ed25519(_)) => {
+            let mut priv_bytes = Zeroizing::new(secret_key.to_vec());
+            priv_bytes.extend_from_slice(&pub_bytes);
+            (ED25519_PUB_KEY_TYPE, ED25519_PRIV_KEY_TYPE, priv_bytes)
+        }
+        ConsensusPublicKey::Tendermint(PublicKey::Secp256k1(_)) => (
+            SECP256K1_PUB_KEY_TYPE,
+            SECP256K1_PRIV_KEY_TYPE,
+            Zeroizing::new(secret_key.to_vec()),
+        ),
+        #[cfg(feature = "bls")]
+        ConsensusPublicKey::Bls12_381(_) => (
+            BLS12_381_PUB_KEY_TYPE,
+            BLS12_381_PRIV_KEY_TYPE,
+            Zeroizing::new(secret_key.to_vec()),
+        ),
+        pk => fail!(ErrorKind::InvalidKey, "unsupported public key: {:?}", pk),
+    };
+    let encode = |bytes: &[u8]| String::from_utf8(base64::encode(bytes)).expect("base64 is ASCII");
+    let tm_key = PrivValidatorKey {
+        address: address.to_string(),
+        pub_key: AminoKey {
+            key_type: pub_key_type.to_owned(),
+            value: encode(&pub_bytes),
+        },
+        priv_key: AminoKey {
+            key_type: priv_key_type.to_owned(),
+            value: encode(&priv_bytes),
+        },
+    };
+    write_json(path, &tm_key, force)
+}
+
+/// Reads the state (with the last signed message) from `priv_validator_state.json`
+pub fn read_state(path: &Path) -> Result<State, Error> {
+    State::try_from(read_json::<PrivValidatorState>(path)?)
+}
+
+/// Writes the state (with the last signed message) as `priv_validator_state.json`
+pub fn write_state(path: &Path, state: &State, force: bool) -> Result<(), Error> {
+    write_json(path, &PrivValidatorState::from(state), force)
+}
+
+/// Checks that the imported state doesn't lower the current one
+/// or conflict with it (a different block id at the same height, round and step)
+pub fn check_import(current: &consensus::State, imported: &consensus::State) -> Result<(), String> {
+    let hrs = |state: &consensus::State| (state.height, state.round, state.step);
+    if hrs(imported) < hrs(current) {
+        return Err(format!(
+            "the imported state {} is lower than the current state {}",
+            imported, current
+        ));
+    }
+    if hrs(imported) == hrs(current) && imported.block_id != current.block_id {
+        return Err(format!(
+            "the imported state {} has another block id than the current state {}",
+            imported, current
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tmkms_light::signer::generate_secret_key;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("softsign-{}-{}.json", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn key_roundtrip() {
+        let key_types = [
+            KeyType::Ed25519,
+            KeyType::Secp256k1,
+            #[cfg(feature = "bls")]
+            KeyType::Bls12_381,
+        ];
+        for &key_type in key_types.iter() {
+            let path = temp_path("priv_validator_key");
+            let secret_key = generate_secret_key(key_type);
+            write_key(&path, key_type, &secret_key, false).unwrap();
+            // not replaced without `force`
+            assert!(write_key(&path, key_type, &secret_key, false).is_err());
+            let (read_type, read_secret) = read_key(&path).unwrap();
+            assert_eq!(read_type, key_type);
+            assert_eq!(*read_secret, *secret_key);
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn translates_steps() {
+        let tm_state: PrivValidatorState =
+            serde_json::from_str(r#"{"height": "42", "round": 1, "step": 3}"#).unwrap();
+        let state = consensus::State::try_from(tm_state).unwrap();
+        assert_eq!(state.height, 42u32.into());
+        assert_eq!(state.round, 1u16.into());
+        assert_eq!(state.step, 2);
+        let exported = PrivValidatorState::from(&state);
+        assert_eq!((exported.height.as_str(), exported.step), ("42", 3));
+        assert_eq!(
+            PrivValidatorState::from(&consensus::State::default()).step,
+            0
+        );
+    }
+
+    #[test]
+    fn translates_nothing_signed() {
+        // e.g. the state of an upgrade height
+        let tm_state: PrivValidatorState =
+            serde_json::from_str(r#"{"height": "42", "round": 0, "step": 0}"#).unwrap();
+        let state = consensus::State::try_from(tm_state).unwrap();
+        assert_eq!(state.height, 42u32.into());
+        assert_eq!(state.step, -1);
+        // the first proposal of the height is still signed
+        let proposal = consensus::State {
+            step: 0,
+            block_id: Some(block::Id {
+                hash: tendermint::Hash::Sha256([1; 32]),
+                part_set_header: Default::default(),
+            }),
+            ..state.clone()
+        };
+        assert!(State::from(state.clone())
+            .check_consensus_state(&proposal)
+            .is_ok());
+        // but nothing at a lower height
+        let lower = consensus::State {
+            height: 41u32.into(),
+            ..proposal
+        };
+        assert!(State::from(state.clone())
+            .check_consensus_state(&lower)
+            .is_err());
+        let exported = PrivValidatorState::from(&state);
+        assert_eq!((exported.height.as_str(), exported.step), ("42", 0));
+        assert_eq!(consensus::State::try_from(exported).unwrap(), state);
+    }
+
+    #[test]
+    fn refuses_conflicting_imports() {
+        let current = consensus::State {
+            height: 42u32.into(),
+            round: 0u16.into(),
+            step: 1,
+            block_id: Some(block::Id {
+                hash: tendermint::Hash::Sha256([1; 32]),
+                part_set_header: Default::default(),
+            }),
+        };
+        assert!(check_import(&current, &current).is_ok());
+        let later = consensus::State {
+            step: 2,
+            block_id: None,
+            ..current.clone()
+        };
+        assert!(check_import(&current, &later).is_ok());
+        assert!(check_import(&later, &current).is_err());
+        let other_block = consensus::State {
+            block_id: Some(block::Id {
+                hash: tendermint::Hash::Sha256([2; 32]),
+                part_set_header: Default::default(),
+            }),
+            ..current.clone()
+        };
+        assert!(check_import(&current, &other_block).is_err());
+        let nil = consensus::State {
+            block_id: None,
+            ..current.clone()
+        };
+        assert!(check_import(&current, &nil).is_err());
+    }
+
+    #[test]
+    fn keeps_the_last_signed_message() {
+        let tm_state = PrivValidatorState {
+            height: "42".to_owned(),
+            round: 0,
+            step: 3,
+            signature: Some(String::from_utf8(base64::encode([2u8; 64])).unwrap()),
+            signbytes: Some("0102".to_owned()),
+        };
+        // the sign bytes aren't a vote
+        assert!(State::try_from(tm_state).is_err());
+
+        let vote = CanonicalVote {
+            r#type: 2,
+            height: 42,
+            round: 0,
+            chain_id: "testchain-1".to_owned(),
+            ..Default::default()
+        };
+        let mut signbytes = Vec::new();
+        vote.encode_length_delimited(&mut signbytes).unwrap();
+        let tm_state = PrivValidatorState {
+            height: "42".to_owned(),
+            round: 0,
+            step: 3,
+            signature: Some(String::from_utf8(base64::encode([2u8; 64])).unwrap()),
+            signbytes: Some(String::from_utf8(hex::encode_upper(&signbytes)).unwrap()),
+        };
+        let state = State::try_from(tm_state).unwrap();
+        let last_signed = state.last_signed().unwrap();
+        assert_eq!(last_signed.sign_bytes, signbytes);
+        assert_eq!(last_signed.signature, vec![2u8; 64]);
+
+        let exported = PrivValidatorState::from(&state);
+        assert_eq!(
+            exported.signbytes.as_deref(),
+            Some(
+                String::from_utf8(hex::encode_upper(&signbytes))
+                    .unwrap()
+                    .as_str()
+            )
+        );
+        assert_eq!(
+            State::try_from(exported).unwrap().last_signed(),
+            Some(last_signed)
+        );
+    }
+
+    #[test]
+    fn reads_block_id_from_signbytes() {
+        let vote = CanonicalVote {
+            r#type: 1,
+            height: 42,
+            round: 0,
+            // the part set header is always encoded (it isn't nullable)
+            block_id: Some(CanonicalBlockId {
+                hash: vec![1; 32],
+                part_set_header: Some(Default::default()),
+            }),
+            chain_id: "testchain-1".to_owned(),
+            ..Default::default()
+        };
+        let mut signbytes = Vec::new();
+        vote.encode_length_delimited(&mut signbytes).unwrap();
+        let tm_state = PrivValidatorState {
+            height: "42".to_owned(),
+            round: 0,
+            step: 2,
+            signature: None,
+            signbytes: Some(String::from_utf8(hex::encode_upper(&signbytes)).unwrap()),
+        };
+        let state = consensus::State::try_from(tm_state).unwrap();
+        assert_eq!(state.step, 1);
+        assert_eq!(
+            state.block_id.unwrap().hash.as_bytes(),
+            vec![1u8; 32].as_slice()
+        );
+        let other_height = PrivValidatorState {
+            height: "43".to_owned(),
+            round: 0,
+            step: 2,
+            signature: None,
+            signbytes: Some(String::from_utf8(hex::encode_upper(&signbytes)).unwrap()),
+        };
+        assert!(consensus::State::try_from(other_height).is_err());
+    }
+}
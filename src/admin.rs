@@ -0,0 +1,496 @@
+//! Local admin control of signing sessions (status, pause, resume and shutdown)
+//! Copyright (c) 2021, Foris Limited (licensed under the Apache License, Version 2.0)
+//!
+//! The host binaries serve `AdminRequest`s on a Unix-domain socket
+//! (one JSON line per request and response). Sessions running in enclaves
+//! get the requests forwarded by the host as length-prefixed JSON payloads.
+
+use crate::chain::state::consensus;
+use crate::error::{Error, ErrorKind};
+use crate::utils::{read_u16_payload, write_u16_payload};
+use anomaly::format_err;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{Read, Write},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Instant,
+};
+use tendermint::chain;
+use tracing::info;
+
+/// Commands accepted on the admin socket
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminRequest {
+    /// report the status of all sessions
+    Status,
+    /// answer sign requests with an error (pings are still answered)
+    Pause,
+    /// sign again after `Pause`
+    Resume,
+    /// pause, wait for the requests being handled and exit
+    Shutdown,
+}
+
+impl FromStr for AdminRequest {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "status" => Ok(AdminRequest::Status),
+            "pause" => Ok(AdminRequest::Pause),
+            "resume" => Ok(AdminRequest::Resume),
+            "shutdown" => Ok(AdminRequest::Shutdown),
+            _ => Err("unknown admin command".to_owned()),
+        }
+    }
+}
+
+/// Replies on the admin socket
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminResponse {
+    /// status of all sessions
+    Status(Vec<SessionStatus>),
+    /// the command was carried out
+    Done,
+    /// the command failed
+    Error(String),
+}
+
+/// Height/round/step of a signed message
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct SignedHrs {
+    pub height: u64,
+    pub round: u32,
+    pub step: i8,
+}
+
+/// Status of a single session
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SessionStatus {
+    /// chain id of the session
+    pub chain_id: chain::Id,
+    /// address of the validator node (if known)
+    pub peer: Option<String>,
+    /// whether the connection to the validator node is up
+    pub connected: bool,
+    /// whether signing is paused
+    pub paused: bool,
+    /// the last newly signed height/round/step
+    pub last_signed: Option<SignedHrs>,
+    /// seconds since the session was created
+    pub uptime_secs: u64,
+}
+
+/// Shared handle for controlling a session (and observing its status)
+pub struct SessionControl {
+    chain_id: chain::Id,
+    peer: Option<String>,
+    started_at: Instant,
+    paused: AtomicBool,
+    connected: AtomicBool,
+    last_signed: Mutex<Option<SignedHrs>>,
+    /// held while a request is being handled
+    busy: Mutex<()>,
+}
+
+impl SessionControl {
+    pub fn new(chain_id: chain::Id, peer: Option<String>) -> Self {
+        Self {
+            chain_id,
+            peer,
+            started_at: Instant::now(),
+            paused: AtomicBool::new(false),
+            connected: AtomicBool::new(true),
+            last_signed: Mutex::new(None),
+            busy: Mutex::new(()),
+        }
+    }
+
+    /// Current status of the session
+    pub fn status(&self) -> SessionStatus {
+        SessionStatus {
+            chain_id: self.chain_id.clone(),
+            peer: self.peer.clone(),
+            connected: self.connected.load(Ordering::SeqCst),
+            paused: self.is_paused(),
+            last_signed: *lock(&self.last_signed),
+            uptime_secs: self.started_at.elapsed().as_secs(),
+        }
+    }
+
+    /// Answer sign requests with an error from now on
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Sign requests again
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Pauses signing and waits until the request being handled (if any) is answered
+    /// (its consensus state is persisted before it is signed)
+    pub fn drain(&self) {
+        self.pause();
+        drop(lock(&self.busy));
+    }
+
+    pub(crate) fn set_connected(&self, connected: bool) {
+        self.connected.store(connected, Ordering::SeqCst);
+    }
+
+    pub(crate) fn record_signed(&self, consensus_state: &consensus::State) {
+        *lock(&self.last_signed) = Some(SignedHrs {
+            height: consensus_state.height.value(),
+            round: consensus_state.round.value(),
+            step: consensus_state.step,
+        });
+    }
+
+    /// Marks the session as busy until the returned guard is dropped
+    pub(crate) fn begin_request(&self) -> MutexGuard<'_, ()> {
+        lock(&self.busy)
+    }
+}
+
+/// the guarded values stay consistent even if a holder panicked
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(e) => e.into_inner(),
+    }
+}
+
+/// Applies the request to the sessions running in this process
+/// (after `Shutdown`, the caller is expected to exit)
+pub fn handle_request(controls: &[Arc<SessionControl>], request: AdminRequest) -> AdminResponse {
+    match request {
+        AdminRequest::Status => {
+            AdminResponse::Status(controls.iter().map(|control| control.status()).collect())
+        }
+        AdminRequest::Pause => {
+            controls.iter().for_each(|control| control.pause());
+            AdminResponse::Done
+        }
+        AdminRequest::Resume => {
+            controls.iter().for_each(|control| control.resume());
+            AdminResponse::Done
+        }
+        AdminRequest::Shutdown => {
+            controls.iter().for_each(|control| control.drain());
+            AdminResponse::Done
+        }
+    }
+}
+
+/// Combines the responses from several sessions (e.g. enclave instances)
+pub fn merge_responses(responses: Vec<AdminResponse>) -> AdminResponse {
+    let mut statuses = Vec::new();
+    let mut done = false;
+    for response in responses {
+        match response {
+            AdminResponse::Status(status) => statuses.extend(status),
+            AdminResponse::Done => done = true,
+            AdminResponse::Error(e) => return AdminResponse::Error(e),
+        }
+    }
+    if done {
+        AdminResponse::Done
+    } else {
+        AdminResponse::Status(statuses)
+    }
+}
+
+/// Forwards the request to a session in an enclave and returns its response
+pub fn forward_request<S: Read + Write>(
+    stream: &mut S,
+    request: AdminRequest,
+) -> Result<AdminResponse, Error> {
+    let json = serde_json::to_vec(&request).map_err(|e| {
+        format_err!(
+            ErrorKind::SerializationError,
+            "error serializing admin request: {}",
+            e
+        )
+    })?;
+    write_u16_payload(stream, &json)
+        .map_err(|e| format_err!(ErrorKind::IoError, "error sending admin request: {}", e))?;
+    let json_raw = read_u16_payload(stream)?;
+    serde_json::from_slice(&json_raw).map_err(|e| {
+        format_err!(
+            ErrorKind::SerializationError,
+            "error parsing admin response: {}",
+            e
+        )
+        .into()
+    })
+}
+
+/// Forwards the request to the sessions in enclaves and combines their responses
+/// (`sessions` pairs the validator node address known to the host with the stream)
+pub fn forward_to_all<S: Read + Write>(
+    sessions: &mut [(String, S)],
+    request: AdminRequest,
+) -> AdminResponse {
+    let responses = sessions
+        .iter_mut()
+        .map(|(peer, stream)| match forward_request(stream, request) {
+            Ok(AdminResponse::Status(mut statuses)) => {
+                for status in statuses.iter_mut() {
+                    status.peer.get_or_insert_with(|| peer.clone());
+                }
+                AdminResponse::Status(statuses)
+            }
+            Ok(response) => response,
+            Err(e) => AdminResponse::Error(e.to_string()),
+        })
+        .collect();
+    merge_responses(responses)
+}
+
+/// Answers the requests forwarded by the host (until the stream is closed)
+/// for the sessions of the enclave (one per validator node)
+pub fn serve_forwarded<S: Read + Write>(
+    mut stream: S,
+    controls: Vec<Arc<SessionControl>>,
+) -> Result<(), Error> {
+    loop {
+        let json_raw = read_u16_payload(&mut stream)?;
+        let response = match serde_json::from_slice(&json_raw) {
+            Ok(request) => {
+                info!("admin request: {:?}", request);
+                handle_request(&controls, request)
+            }
+            Err(e) => AdminResponse::Error(format!("invalid admin request: {}", e)),
+        };
+        let json = serde_json::to_vec(&response).map_err(|e| {
+            format_err!(
+                ErrorKind::SerializationError,
+                "error serializing admin response: {}",
+                e
+            )
+        })?;
+        write_u16_payload(&mut stream, &json)
+            .map_err(|e| format_err!(ErrorKind::IoError, "error sending admin response: {}", e))?;
+    }
+}
+
+/// Unix-domain admin socket server and client
+#[cfg(unix)]
+mod socket {
+    use super::{AdminRequest, AdminResponse};
+    use crate::error::{Error, ErrorKind};
+    use anomaly::format_err;
+    use std::{
+        fs,
+        io::{BufRead, BufReader, Write},
+        os::unix::{
+            fs::{FileTypeExt, PermissionsExt},
+            net::{UnixListener, UnixStream},
+        },
+        path::Path,
+        thread,
+        time::Duration,
+    };
+    use tracing::{info, warn};
+
+    /// how long a client may take to send its request (or to read the response);
+    /// the requests are answered one at a time, so an idle client would block the others
+    const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Serves the admin socket (in a background thread); the process exits
+    /// after a successful `Shutdown` is answered
+    pub fn serve<F>(path: impl AsRef<Path>, handler: F) -> Result<thread::JoinHandle<()>, Error>
+    where
+        F: Fn(AdminRequest) -> AdminResponse + Send + 'static,
+    {
+        let path = path.as_ref();
+        // a socket left behind by a previous run
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if metadata.file_type().is_socket() {
+                let _ = fs::remove_file(path);
+            }
+        }
+        let listener = UnixListener::bind(path).map_err(|e| {
+            format_err!(
+                ErrorKind::IoError,
+                "error binding admin socket {}: {}",
+                path.display(),
+                e
+            )
+        })?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600)).map_err(|e| {
+            format_err!(
+                ErrorKind::IoError,
+                "error restricting admin socket {}: {}",
+                path.display(),
+                e
+            )
+        })?;
+        info!("serving admin socket on {}", path.display());
+        Ok(thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = respond(stream, &handler) {
+                            warn!("admin request failed: {}", e);
+                        }
+                    }
+                    Err(e) => warn!("admin connection failed: {}", e),
+                }
+            }
+        }))
+    }
+
+    /// Answers a single request
+    fn respond<F>(stream: UnixStream, handler: &F) -> Result<(), Error>
+    where
+        F: Fn(AdminRequest) -> AdminResponse,
+    {
+        stream
+            .set_read_timeout(Some(CLIENT_TIMEOUT))
+            .and_then(|_| stream.set_write_timeout(Some(CLIENT_TIMEOUT)))
+            .map_err(|e| format_err!(ErrorKind::IoError, "error setting admin timeout: {}", e))?;
+        let mut line = String::new();
+        let mut reader = BufReader::new(&stream);
+        reader
+            .read_line(&mut line)
+            .map_err(|e| format_err!(ErrorKind::IoError, "error reading admin request: {}", e))?;
+        let (request, response) = match serde_json::from_str::<AdminRequest>(&line) {
+            Ok(request) => {
+                info!("admin request: {:?}", request);
+                (Some(request), handler(request))
+            }
+            Err(e) => (
+                None,
+                AdminResponse::Error(format!("invalid request: {}", e)),
+            ),
+        };
+        let mut json = serde_json::to_vec(&response).map_err(|e| {
+            format_err!(
+                ErrorKind::SerializationError,
+                "error serializing admin response: {}",
+                e
+            )
+        })?;
+        json.push(b'\n');
+        (&stream)
+            .write_all(&json)
+            .map_err(|e| format_err!(ErrorKind::IoError, "error writing admin response: {}", e))?;
+        if let (Some(AdminRequest::Shutdown), AdminResponse::Done) = (request, &response) {
+            info!("shutting down (admin request)");
+            std::process::exit(0);
+        }
+        Ok(())
+    }
+
+    /// Sends the request to the admin socket and returns the response
+    pub fn send_request(
+        path: impl AsRef<Path>,
+        request: AdminRequest,
+    ) -> Result<AdminResponse, Error> {
+        let path = path.as_ref();
+        let mut stream = UnixStream::connect(path).map_err(|e| {
+            format_err!(
+                ErrorKind::IoError,
+                "error connecting to admin socket {}: {}",
+                path.display(),
+                e
+            )
+        })?;
+        let mut json = serde_json::to_vec(&request).map_err(|e| {
+            format_err!(
+                ErrorKind::SerializationError,
+                "error serializing admin request: {}",
+                e
+            )
+        })?;
+        json.push(b'\n');
+        stream
+            .write_all(&json)
+            .map_err(|e| format_err!(ErrorKind::IoError, "error sending admin request: {}", e))?;
+        let mut line = String::new();
+        BufReader::new(&stream)
+            .read_line(&mut line)
+            .map_err(|e| format_err!(ErrorKind::IoError, "error reading admin response: {}", e))?;
+        serde_json::from_str(&line).map_err(|e| {
+            format_err!(
+                ErrorKind::SerializationError,
+                "error parsing admin response: {}",
+                e
+            )
+            .into()
+        })
+    }
+}
+
+#[cfg(unix)]
+pub use socket::{send_request, serve};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn pauses_and_reports_status() {
+        let chain_id = chain::Id::try_from("testchain-1".to_owned()).unwrap();
+        let control = Arc::new(SessionControl::new(
+            chain_id,
+            Some("unix:///tmp/validator.socket".to_owned()),
+        ));
+        let cs = consensus::State {
+            height: 5u32.into(),
+            step: 2,
+            ..Default::default()
+        };
+        control.record_signed(&cs);
+        let controls = [control.clone()];
+        assert!(matches!(
+            handle_request(&controls, AdminRequest::Pause),
+            AdminResponse::Done
+        ));
+        assert!(control.is_paused());
+        match merge_responses(vec![handle_request(&controls, AdminRequest::Status)]) {
+            AdminResponse::Status(statuses) => {
+                assert_eq!(statuses.len(), 1);
+                assert!(statuses[0].paused);
+                assert_eq!(
+                    statuses[0].last_signed,
+                    Some(SignedHrs {
+                        height: 5,
+                        round: 0,
+                        step: 2
+                    })
+                );
+            }
+            response => panic!("unexpected response: {:?}", response),
+        }
+        handle_request(&controls, AdminRequest::Resume);
+        assert!(!control.is_paused());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn idle_clients_dont_block_the_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("admin.socket");
+        serve(&path, |_| AdminResponse::Done).unwrap();
+        // connects, but never sends its request
+        let _idle = std::os::unix::net::UnixStream::connect(&path).unwrap();
+        assert!(matches!(
+            send_request(&path, AdminRequest::Status),
+            Ok(AdminResponse::Done)
+        ));
+    }
+}
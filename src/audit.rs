@@ -0,0 +1,287 @@
+//! Tamper-evident audit log of sign requests and decisions
+//! Copyright (c) 2021, Foris Limited (licensed under the Apache License, Version 2.0)
+//!
+//! Every record is a JSON line that includes the SHA-256 hash
+//! of the previous line, so that edits, removed records or a partially
+//! written record can be detected by `verify`.
+
+use crate::chain::state::consensus;
+use crate::error::{Error, ErrorKind};
+use anomaly::{fail, format_err};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+use subtle_encoding::{base64, hex};
+use tendermint::{chain, Time};
+
+/// `prev_hash` of the first record
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Decision taken on a sign request
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// newly signed
+    Signed,
+    /// the previous signature was sent again
+    Resent,
+    /// refused as a double sign
+    DoubleSignRefused,
+    /// refused because of a different chain id
+    ChainIdMismatch,
+    /// refused because the height is above the configured maximum
+    MaxHeightExceeded,
+    /// refused because of other errors (e.g. height/round/step regression)
+    Failed,
+}
+
+/// Request details to be recorded
+#[derive(Clone, Debug)]
+pub struct AuditEntry<'a> {
+    /// chain id of the request
+    pub chain_id: &'a chain::Id,
+    /// height/round/step and block id of the request
+    pub consensus_state: &'a consensus::State,
+    /// timestamp of the vote or proposal
+    pub timestamp: Option<Time>,
+    /// the decision
+    pub outcome: Outcome,
+    /// the signature (if any)
+    pub signature: Option<Vec<u8>>,
+}
+
+/// A single line in the audit log
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuditRecord {
+    /// sequence number of the record
+    pub seq: u64,
+    /// when the record was written
+    pub logged_at: Time,
+    /// chain id of the request
+    pub chain_id: chain::Id,
+    /// height of the request
+    pub height: u64,
+    /// round of the request
+    pub round: u32,
+    /// step of the request (0: proposal, 1: prevote, 2: precommit)
+    pub step: i8,
+    /// hex-encoded block id hash (`None` for nil votes)
+    pub block_id: Option<String>,
+    /// timestamp of the vote or proposal
+    pub timestamp: Option<Time>,
+    /// the decision
+    pub outcome: Outcome,
+    /// base64-encoded signature
+    pub signature: Option<String>,
+    /// hex-encoded SHA-256 of the previous line
+    pub prev_hash: String,
+}
+
+/// Append-only audit log file
+pub struct AuditLog {
+    path: PathBuf,
+    file: File,
+    next_seq: u64,
+    prev_hash: String,
+}
+
+fn line_hash(line: &[u8]) -> String {
+    String::from_utf8(hex::encode(Sha256::digest(line))).expect("hex is valid UTF-8")
+}
+
+/// Result of a successful verification
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VerifiedLog {
+    /// number of records
+    pub records: u64,
+    /// hash of the last line (to be compared with an externally kept copy
+    /// in order to detect removed records at the end of the log)
+    pub head_hash: String,
+}
+
+/// Checks the hash chain and the sequence numbers of the audit log
+pub fn verify(path: impl AsRef<Path>) -> Result<VerifiedLog, Error> {
+    let path = path.as_ref();
+    let contents = fs::read(path).map_err(|e| {
+        format_err!(
+            ErrorKind::IoError,
+            "error reading {}: {}",
+            path.display(),
+            e
+        )
+    })?;
+    if !contents.is_empty() && !contents.ends_with(b"\n") {
+        fail!(
+            ErrorKind::VerificationError,
+            "{}: the last record is incomplete",
+            path.display()
+        );
+    }
+    let mut prev_hash = GENESIS_HASH.to_owned();
+    let mut records = 0u64;
+    for line in contents.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+        let record: AuditRecord = serde_json::from_slice(line).map_err(|e| {
+            format_err!(
+                ErrorKind::VerificationError,
+                "{}: malformed record {}: {}",
+                path.display(),
+                records,
+                e
+            )
+        })?;
+        if record.seq != records {
+            fail!(
+                ErrorKind::VerificationError,
+                "{}: expected record {}, found {}",
+                path.display(),
+                records,
+                record.seq
+            );
+        }
+        if record.prev_hash != prev_hash {
+            fail!(
+                ErrorKind::VerificationError,
+                "{}: hash chain broken at record {}",
+                path.display(),
+                record.seq
+            );
+        }
+        prev_hash = line_hash(line);
+        records += 1;
+    }
+    Ok(VerifiedLog {
+        records,
+        head_hash: prev_hash,
+    })
+}
+
+impl AuditLog {
+    /// Opens (and verifies) the existing log or creates a new one
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref().to_owned();
+        let (next_seq, prev_hash) = if path.exists() {
+            let verified = verify(&path)?;
+            (verified.records, verified.head_hash)
+        } else {
+            (0, GENESIS_HASH.to_owned())
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| {
+                format_err!(
+                    ErrorKind::IoError,
+                    "error opening {}: {}",
+                    path.display(),
+                    e
+                )
+            })?;
+        Ok(Self {
+            path,
+            file,
+            next_seq,
+            prev_hash,
+        })
+    }
+
+    /// Appends a record and syncs it to disk
+    pub fn append(&mut self, entry: AuditEntry<'_>) -> Result<(), Error> {
+        let cs = entry.consensus_state;
+        let record = AuditRecord {
+            seq: self.next_seq,
+            logged_at: Time::now(),
+            chain_id: entry.chain_id.clone(),
+            height: cs.height.value(),
+            round: cs.round.value(),
+            step: cs.step,
+            block_id: cs.block_id.as_ref().map(|id| id.hash.to_string()),
+            timestamp: entry.timestamp,
+            outcome: entry.outcome,
+            signature: entry
+                .signature
+                .as_ref()
+                .map(|sig| String::from_utf8(base64::encode(sig)).expect("base64 is valid UTF-8")),
+            prev_hash: self.prev_hash.clone(),
+        };
+        let mut line = serde_json::to_vec(&record).map_err(|e| {
+            format_err!(
+                ErrorKind::SerializationError,
+                "error serializing audit record: {}",
+                e
+            )
+        })?;
+        let hash = line_hash(&line);
+        line.push(b'\n');
+        self.file
+            .write_all(&line)
+            .and_then(|_| self.file.sync_data())
+            .map_err(|e| {
+                format_err!(
+                    ErrorKind::IoError,
+                    "error writing {}: {}",
+                    self.path.display(),
+                    e
+                )
+            })?;
+        self.next_seq += 1;
+        self.prev_hash = hash;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    fn write_log(path: &Path, n: u32) {
+        let chain_id = chain::Id::try_from("testchain-1".to_owned()).unwrap();
+        let mut log = AuditLog::open(path).unwrap();
+        for height in 1..=n {
+            let cs = consensus::State {
+                height: height.into(),
+                step: 1,
+                ..Default::default()
+            };
+            log.append(AuditEntry {
+                chain_id: &chain_id,
+                consensus_state: &cs,
+                timestamp: None,
+                outcome: Outcome::Signed,
+                signature: Some(vec![1u8; 64]),
+            })
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn detects_edits_and_truncation() {
+        let path = std::env::temp_dir().join(format!("tmkms-audit-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        write_log(&path, 2);
+        // appending after reopening continues the hash chain
+        write_log(&path, 1);
+        let verified = verify(&path).unwrap();
+        assert_eq!(verified.records, 3);
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let edited = contents.replacen("\"signed\"", "\"double_sign_refused\"", 1);
+        fs::write(&path, &edited).unwrap();
+        assert!(verify(&path).is_err());
+
+        let mut lines: Vec<&str> = contents.lines().collect();
+        lines.remove(1);
+        fs::write(&path, lines.join("\n") + "\n").unwrap();
+        assert!(verify(&path).is_err());
+
+        fs::write(&path, &contents[..contents.len() - 10]).unwrap();
+        assert!(verify(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,29 +1,93 @@
 //! Copyright (c) 2018-2021 Iqlusion Inc. (licensed under the Apache License, Version 2.0)
 //! Modifications Copyright (c) 2021, Foris Limited (licensed under the Apache License, Version 2.0)
 
+mod authenticated;
 mod error;
+#[cfg(unix)]
+mod file;
+pub mod hook;
+pub use self::authenticated::{AuthenticatedState, StateAuthenticator, StateMarker};
 pub use self::error::{StateError, StateErrorKind};
+#[cfg(unix)]
+pub use self::file::{Durability, StateFile};
 use anomaly::fail;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
 pub use tendermint::consensus;
 use tendermint::{proposal::SignProposalRequest, vote::SignVoteRequest};
+
+/// How long the enclave waits for the host to acknowledge a persisted state
+/// before refusing to sign
+pub const STATE_ACK_TIMEOUT: Duration = Duration::from_secs(3);
+
 /// State tracking for double signing prevention
 #[derive(Debug, Clone)]
 pub struct State {
     consensus_state: consensus::State,
+    /// the message signed at this state (if known)
+    last_signed: Option<LastSigned>,
+}
+
+/// The sign bytes and the signature of the last signed vote or proposal
+/// (like `SignBytes` and `Signature` in Go's `FilePV` last sign state)
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LastSigned {
+    /// canonical (length-delimited) sign bytes
+    #[serde(
+        rename = "signbytes",
+        with = "tendermint_proto::serializers::bytes::hexstring"
+    )]
+    pub sign_bytes: Vec<u8>,
+    /// the produced signature
+    #[serde(with = "tendermint_proto::serializers::bytes::base64string")]
+    pub signature: Vec<u8>,
+}
+
+/// The consensus state as persisted in the state file
+/// (the JSON is the plain `consensus::State`, with the `signbytes` and `signature`
+/// of the last signed message if they are known)
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SignState {
+    #[serde(flatten)]
+    pub consensus_state: consensus::State,
+    #[serde(flatten)]
+    pub last_signed: Option<LastSigned>,
 }
 
 /// State persistence over sockets or files
 pub trait PersistStateSync {
     fn load_state(&mut self) -> Result<State, StateError>;
-    fn persist_state(&mut self, new_state: &consensus::State) -> Result<(), StateError>;
+    fn persist_state(&mut self, new_state: &State) -> Result<(), StateError>;
+}
+
+/// Reply of the host after it (durably) wrote a state sent by the enclave
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateAck {
+    /// the state that was written (so that a late reply isn't taken for a newer state)
+    pub state: consensus::State,
+    /// the error if the state couldn't be written
+    pub error: Option<String>,
 }
 
 impl State {
+    /// the consensus state with the message signed at it
+    pub fn new(consensus_state: consensus::State, last_signed: Option<LastSigned>) -> Self {
+        Self {
+            consensus_state,
+            last_signed,
+        }
+    }
+
     /// the underlying consensus state
     pub fn consensus_state(&self) -> &consensus::State {
         &self.consensus_state
     }
 
+    /// the message signed at this state (if known)
+    pub fn last_signed(&self) -> Option<&LastSigned> {
+        self.last_signed.as_ref()
+    }
+
     fn check_height(&self, new_state: &consensus::State) -> Result<(), StateError> {
         if new_state.height < self.consensus_state.height {
             fail!(
@@ -99,50 +163,73 @@ impl State {
     }
 
     /// Update the state + check
-    pub fn check_update_consensus_state<S: PersistStateSync>(
+    pub fn check_update<S: PersistStateSync>(
         &mut self,
-        new_state: consensus::State,
+        new_state: State,
         syncer: &mut S,
     ) -> Result<(), StateError> {
-        self.check_consensus_state(&new_state)?;
+        self.check_consensus_state(&new_state.consensus_state)?;
         syncer.persist_state(&new_state)?;
-        self.consensus_state = new_state;
+        *self = new_state;
         Ok(())
     }
 }
 
 impl From<consensus::State> for State {
     fn from(consensus_state: consensus::State) -> Self {
-        Self { consensus_state }
+        Self {
+            consensus_state,
+            last_signed: None,
+        }
     }
 }
 
-impl From<SignProposalRequest> for State {
-    fn from(req: SignProposalRequest) -> Self {
+impl From<SignState> for State {
+    fn from(state: SignState) -> Self {
+        Self::new(state.consensus_state, state.last_signed)
+    }
+}
+
+impl From<&State> for SignState {
+    fn from(state: &State) -> Self {
         Self {
-            consensus_state: consensus::State {
-                height: req.proposal.height,
-                round: req.proposal.round,
-                step: 0,
-                block_id: req.proposal.block_id,
-            },
+            consensus_state: state.consensus_state.clone(),
+            last_signed: state.last_signed.clone(),
         }
     }
 }
 
-impl From<SignVoteRequest> for State {
-    fn from(req: SignVoteRequest) -> Self {
+impl From<consensus::State> for SignState {
+    fn from(consensus_state: consensus::State) -> Self {
         Self {
-            consensus_state: consensus::State {
-                height: req.vote.height,
-                round: req.vote.round,
-                step: if req.vote.is_precommit() { 2 } else { 1 },
-                block_id: req.vote.block_id,
-            },
+            consensus_state,
+            last_signed: None,
         }
     }
 }
 
+impl From<SignProposalRequest> for State {
+    fn from(req: SignProposalRequest) -> Self {
+        Self::from(consensus::State {
+            height: req.proposal.height,
+            round: req.proposal.round,
+            step: 0,
+            block_id: req.proposal.block_id,
+        })
+    }
+}
+
+impl From<SignVoteRequest> for State {
+    fn from(req: SignVoteRequest) -> Self {
+        Self::from(consensus::State {
+            height: req.vote.height,
+            round: req.vote.round,
+            step: if req.vote.is_precommit() { 2 } else { 1 },
+            block_id: req.vote.block_id,
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -178,11 +265,9 @@ mod tests {
         ($name:ident, $old_state:expr, $new_state:expr) => {
             #[test]
             fn $name() {
-                State {
-                    consensus_state: $old_state,
-                }
-                .check_consensus_state(&$new_state)
-                .unwrap();
+                State::from($old_state)
+                    .check_consensus_state(&$new_state)
+                    .unwrap();
             }
         };
     }
@@ -192,11 +277,9 @@ mod tests {
         ($name:ident, $old_state:expr, $new_state:expr) => {
             #[test]
             fn $name() {
-                let err = State {
-                    consensus_state: $old_state,
-                }
-                .check_consensus_state(&$new_state)
-                .expect_err("expected StateErrorKind::DoubleSign but succeeded");
+                let err = State::from($old_state)
+                    .check_consensus_state(&$new_state)
+                    .expect_err("expected StateErrorKind::DoubleSign but succeeded");
 
                 assert_eq!(err.kind(), &StateErrorKind::DoubleSign)
             }
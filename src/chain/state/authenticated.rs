@@ -0,0 +1,425 @@
+//! State records authenticated by the enclave
+//! (so that the untrusted host can't feed it an edited or older state)
+
+use super::{consensus, LastSigned, State, StateError, StateErrorKind};
+use anomaly::fail;
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use subtle_encoding::hex;
+use tendermint::chain;
+use tracing::{debug, warn};
+use zeroize::Zeroizing;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// domain separation of the record key derived from the enclave's secret
+const RECORD_KEY_LABEL: &[u8] = b"tmkms-light state record key v1";
+/// domain separation of the state markers (from the state records)
+const MARKER_LABEL: &[u8] = b"tmkms-light state marker v1";
+
+/// A state record as persisted on the host
+/// (the JSON is the plain `consensus::State` with a few extra fields,
+/// so a plain state file is loaded as an unauthenticated record)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuthenticatedState {
+    #[serde(flatten)]
+    pub state: consensus::State,
+    /// the message signed at the state (if known)
+    #[serde(flatten)]
+    pub last_signed: Option<LastSigned>,
+    /// sequence number (increasing with every record written by the enclave)
+    #[serde(default)]
+    pub seq: u64,
+    /// hex-encoded HMAC-SHA256 of the chain id, the sequence number, the state
+    /// and the last signed message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+    /// the marker sent along with the record (kept in its own file by the host)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub marker: Option<StateMarker>,
+}
+
+/// Sealed by the enclave along with its first state record of a run:
+/// once a marker exists, the enclave no longer accepts a plain state record
+/// (so the host can't switch back to unauthenticated records)
+/// or a record older than the marker
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StateMarker {
+    /// sequence number of the record the marker was sealed with
+    pub seq: u64,
+    /// hex-encoded HMAC-SHA256 of the marker label, the chain id and the sequence number
+    pub mac: String,
+}
+
+impl From<consensus::State> for AuthenticatedState {
+    fn from(state: consensus::State) -> Self {
+        Self {
+            state,
+            last_signed: None,
+            seq: 0,
+            mac: None,
+            marker: None,
+        }
+    }
+}
+
+/// Authenticates the state records written by the enclave
+/// and verifies the ones provided by the host
+pub struct StateAuthenticator {
+    key: Zeroizing<Vec<u8>>,
+    chain_id: chain::Id,
+    /// the highest sequence number issued or accepted in this run
+    last_seq: u64,
+    /// whether a marker exists (so plain state records are refused)
+    marked: bool,
+    /// whether the host acknowledged a marker sealed in this run
+    marker_persisted: bool,
+}
+
+impl StateAuthenticator {
+    /// derives the record key from the enclave's secret (e.g. the unsealed consensus key)
+    pub fn new(secret: &[u8], chain_id: chain::Id) -> Self {
+        let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any size");
+        mac.update(RECORD_KEY_LABEL);
+        Self {
+            key: Zeroizing::new(mac.finalize().into_bytes().to_vec()),
+            chain_id,
+            last_seq: 0,
+            marked: false,
+            marker_persisted: false,
+        }
+    }
+
+    fn mac(
+        &self,
+        seq: u64,
+        state: &consensus::State,
+        last_signed: Option<&LastSigned>,
+    ) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC takes keys of any size");
+        let chain_id = self.chain_id.as_str().as_bytes();
+        mac.update(&(chain_id.len() as u64).to_le_bytes());
+        mac.update(chain_id);
+        mac.update(&seq.to_le_bytes());
+        mac.update(&serde_json::to_vec(state).expect("state serialization"));
+        // (the records without a last signed message have the same MAC as before)
+        if let Some(last_signed) = last_signed {
+            mac.update(&(last_signed.sign_bytes.len() as u64).to_le_bytes());
+            mac.update(&last_signed.sign_bytes);
+            mac.update(&last_signed.signature);
+        }
+        mac
+    }
+
+    fn marker_mac(&self, seq: u64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC takes keys of any size");
+        mac.update(MARKER_LABEL);
+        let chain_id = self.chain_id.as_str().as_bytes();
+        mac.update(&(chain_id.len() as u64).to_le_bytes());
+        mac.update(chain_id);
+        mac.update(&seq.to_le_bytes());
+        mac
+    }
+
+    /// authenticates the new state with the next sequence number
+    /// (a marker is sent along until the host acknowledged one in this run)
+    pub fn authenticate(&mut self, state: &State) -> AuthenticatedState {
+        self.last_seq += 1;
+        let tag = self
+            .mac(self.last_seq, state.consensus_state(), state.last_signed())
+            .finalize()
+            .into_bytes();
+        let marker = if self.marker_persisted {
+            None
+        } else {
+            let marker_tag = self.marker_mac(self.last_seq).finalize().into_bytes();
+            self.marked = true;
+            Some(StateMarker {
+                seq: self.last_seq,
+                mac: String::from_utf8(hex::encode(marker_tag)).expect("hex is ASCII"),
+            })
+        };
+        AuthenticatedState {
+            state: state.consensus_state().clone(),
+            last_signed: state.last_signed().cloned(),
+            seq: self.last_seq,
+            mac: Some(String::from_utf8(hex::encode(tag)).expect("hex is ASCII")),
+            marker,
+        }
+    }
+
+    /// to be called once the host acknowledged the record
+    /// (its marker, if any, was then persisted)
+    pub fn acknowledged(&mut self, record: &AuthenticatedState) {
+        if record.marker.is_some() {
+            self.marker_persisted = true;
+        }
+    }
+
+    /// checks the marker kept by the host: the records older than it are then refused,
+    /// and so are the plain ones
+    fn open_marker(&mut self, marker: &StateMarker) -> Result<(), StateError> {
+        let tag = hex::decode(&marker.mac).unwrap_or_default();
+        if self.marker_mac(marker.seq).verify(&tag).is_err() {
+            fail!(
+                StateErrorKind::AuthenticationError,
+                "invalid MAC of the state marker {}",
+                marker.seq
+            );
+        }
+        self.marked = true;
+        self.last_seq = self.last_seq.max(marker.seq);
+        Ok(())
+    }
+
+    /// checks the MAC of the record and that its sequence number doesn't go backwards;
+    /// a record without a MAC is refused once a marker exists; otherwise, it's accepted
+    /// if nothing was signed at its state yet (height 0, as if the host sent the enclave's
+    /// first record again) or if `allow_plain_state` is set (e.g. to migrate a state file
+    /// written before the records were authenticated). `allow_plain_state` is decided
+    /// by the enclave itself (e.g. from its measured build), never by the host
+    pub fn verify(
+        &mut self,
+        record: &AuthenticatedState,
+        allow_plain_state: bool,
+    ) -> Result<consensus::State, StateError> {
+        match &record.mac {
+            Some(tag) => {
+                let tag = hex::decode(tag).unwrap_or_default();
+                if self
+                    .mac(record.seq, &record.state, record.last_signed.as_ref())
+                    .verify(&tag)
+                    .is_err()
+                {
+                    fail!(
+                        StateErrorKind::AuthenticationError,
+                        "invalid MAC of the state record {}",
+                        record.seq
+                    );
+                }
+                if record.seq < self.last_seq {
+                    fail!(
+                        StateErrorKind::AuthenticationError,
+                        "state record {} is older than the last one ({})",
+                        record.seq,
+                        self.last_seq
+                    );
+                }
+                self.last_seq = record.seq;
+            }
+            None if self.marked => fail!(
+                StateErrorKind::AuthenticationError,
+                "the state record is not authenticated, but the enclave already sealed a state marker"
+            ),
+            None if record.state.height.value() == 0 => {
+                debug!("starting from an empty state");
+            }
+            None if allow_plain_state => {
+                warn!("accepting an unauthenticated state: {}", record.state);
+            }
+            None => fail!(
+                StateErrorKind::AuthenticationError,
+                "the state record is not authenticated"
+            ),
+        }
+        Ok(record.state.clone())
+    }
+
+    /// verifies the initial record (and the marker the host sent along with it)
+    /// and applies the (unauthenticated) raised state from the startup hook if it's higher,
+    /// as raising the state can't lead to double signing
+    ///
+    /// The marker is persisted before any authenticated record of a run,
+    /// so an authenticated record without it is refused (the host can't drop the marker
+    /// to get an older record accepted)
+    pub fn verify_initial(
+        &mut self,
+        record: &AuthenticatedState,
+        raised_state: Option<consensus::State>,
+        allow_plain_state: bool,
+    ) -> Result<State, StateError> {
+        match &record.marker {
+            Some(marker) => self.open_marker(marker)?,
+            None if record.mac.is_some() => fail!(
+                StateErrorKind::AuthenticationError,
+                "the state record {} came without the state marker",
+                record.seq
+            ),
+            None => {}
+        }
+        let state = self.verify(record, allow_plain_state)?;
+        match raised_state {
+            Some(raised) if raised.height > state.height => Ok(State::from(raised)),
+            _ => Ok(State::new(state, record.last_signed.clone())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    fn authenticator() -> StateAuthenticator {
+        StateAuthenticator::new(
+            &[7u8; 32],
+            chain::Id::try_from("testchain-1".to_owned()).unwrap(),
+        )
+    }
+
+    fn state_at(height: u32) -> consensus::State {
+        consensus::State {
+            height: height.into(),
+            step: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rejects_edited_and_older_records() {
+        let mut enclave = authenticator();
+        let first = enclave.authenticate(&state_at(1).into());
+        let second = enclave.authenticate(&state_at(2).into());
+        assert_eq!(second.seq, 2);
+        // the records survive the JSON round trip on the host
+        let json = serde_json::to_string(&second).unwrap();
+        let loaded: AuthenticatedState = serde_json::from_str(&json).unwrap();
+        assert_eq!(authenticator().verify(&loaded, false).unwrap(), state_at(2));
+
+        let mut edited = second.clone();
+        edited.state = state_at(100);
+        assert!(authenticator().verify(&edited, false).is_err());
+        let mut other_chain = StateAuthenticator::new(
+            &[7u8; 32],
+            chain::Id::try_from("testchain-2".to_owned()).unwrap(),
+        );
+        assert!(other_chain.verify(&second, false).is_err());
+
+        let mut restarted = authenticator();
+        restarted.verify(&second, false).unwrap();
+        assert!(restarted.verify(&first, false).is_err());
+        // the next record continues after the verified one
+        assert_eq!(restarted.authenticate(&state_at(3).into()).seq, 3);
+    }
+
+    #[test]
+    fn refuses_plain_and_older_records_after_a_marker() {
+        let mut enclave = authenticator();
+        let first = enclave.authenticate(&state_at(1).into());
+        let first_marker = first
+            .marker
+            .clone()
+            .expect("marker sent with the first record");
+        assert_eq!(first_marker.seq, 1);
+        enclave.acknowledged(&first);
+        let mut second = enclave.authenticate(&state_at(2).into());
+        assert!(second.marker.is_none());
+
+        // the host keeps the marker and sends it along with the last record
+        second.marker = Some(first_marker.clone());
+        let mut restarted = authenticator();
+        assert_eq!(
+            restarted
+                .verify_initial(&second, None, false)
+                .unwrap()
+                .consensus_state(),
+            &state_at(2)
+        );
+        let third = restarted.authenticate(&state_at(3).into());
+        let third_marker = third.marker.clone().expect("marker sent in every run");
+        assert_eq!(third_marker.seq, 3);
+
+        // a plain state or a record older than the marker is refused
+        let mut plain = AuthenticatedState::from(state_at(5));
+        plain.marker = Some(first_marker);
+        assert!(authenticator().verify_initial(&plain, None, true).is_err());
+        second.marker = Some(third_marker.clone());
+        assert!(authenticator()
+            .verify_initial(&second, None, false)
+            .is_err());
+
+        let mut forged = third_marker;
+        forged.seq = 1;
+        let mut record = third;
+        record.marker = Some(forged);
+        assert!(authenticator()
+            .verify_initial(&record, None, false)
+            .is_err());
+    }
+
+    #[test]
+    fn refuses_rolled_back_plain_state_without_marker() {
+        let mut enclave = authenticator();
+        let first = enclave.authenticate(&state_at(10).into());
+        enclave.acknowledged(&first);
+        enclave.authenticate(&state_at(20).into());
+
+        // the host leaves out the marker and sends an older state as a plain one
+        let rolled_back = AuthenticatedState::from(state_at(10));
+        assert!(rolled_back.marker.is_none());
+        assert!(authenticator()
+            .verify_initial(&rolled_back, None, false)
+            .is_err());
+        // or the first authenticated record without its marker
+        let mut unmarked = first;
+        unmarked.marker = None;
+        assert!(authenticator()
+            .verify_initial(&unmarked, None, false)
+            .is_err());
+        // only an empty state is accepted (as the enclave's first record could be sent again)
+        let empty = consensus::State {
+            height: 0u32.into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            authenticator()
+                .verify_initial(&AuthenticatedState::from(empty.clone()), None, false)
+                .unwrap()
+                .consensus_state(),
+            &empty
+        );
+    }
+
+    #[test]
+    fn accepts_plain_state_only_if_allowed() {
+        let plain = serde_json::to_string(&state_at(5)).unwrap();
+        let record: AuthenticatedState = serde_json::from_str(&plain).unwrap();
+        assert!(record.mac.is_none());
+        assert!(authenticator().verify(&record, false).is_err());
+        assert_eq!(authenticator().verify(&record, true).unwrap(), state_at(5));
+
+        let record = authenticator().authenticate(&state_at(5).into());
+        let raised = authenticator()
+            .verify_initial(&record, Some(state_at(10)), false)
+            .unwrap();
+        assert_eq!(raised.consensus_state(), &state_at(10));
+        let not_raised = authenticator()
+            .verify_initial(&record, Some(state_at(3)), false)
+            .unwrap();
+        assert_eq!(not_raised.consensus_state(), &state_at(5));
+    }
+
+    #[test]
+    fn authenticates_the_last_signed_message() {
+        let last_signed = LastSigned {
+            sign_bytes: vec![1; 100],
+            signature: vec![2; 64],
+        };
+        let record =
+            authenticator().authenticate(&State::new(state_at(5), Some(last_signed.clone())));
+        let json = serde_json::to_string(&record).unwrap();
+        let loaded: AuthenticatedState = serde_json::from_str(&json).unwrap();
+        let state = authenticator()
+            .verify_initial(&loaded, None, false)
+            .unwrap();
+        assert_eq!(state.last_signed(), Some(&last_signed));
+
+        let mut edited = loaded;
+        edited.last_signed = Some(LastSigned {
+            signature: vec![3; 64],
+            ..last_signed
+        });
+        assert!(authenticator().verify(&edited, false).is_err());
+    }
+}
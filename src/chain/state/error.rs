@@ -29,6 +29,10 @@ pub enum StateErrorKind {
     /// Error syncing state
     #[error("error syncing state")]
     SyncError,
+
+    /// State record failed the authentication
+    #[error("state authentication failed")]
+    AuthenticationError,
 }
 
 impl StateErrorKind {
@@ -0,0 +1,523 @@
+//! Durable file-backed state persistence (on the host)
+
+use super::{
+    consensus, PersistStateSync, SignState, State, StateError, StateErrorKind, StateMarker,
+};
+use crate::secure_file;
+use anomaly::{fail, format_err};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    convert::TryInto,
+    fmt::Debug,
+    fs::{self, File},
+    io::{self, Write},
+    marker::PhantomData,
+    os::unix::fs::{FileExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+};
+use tempfile::NamedTempFile;
+use tracing::debug;
+
+/// marks the state file with fixed-size records (`Durability::Dsync`)
+const RECORD_MAGIC: &[u8; 8] = b"TMKMSST1";
+/// size of one record slot (the record file has two of them)
+const SLOT_SIZE: usize = 1024;
+/// magic + sequence number + payload length
+const RECORD_HEADER_LEN: usize = 8 + 8 + 2;
+/// SHA-256 of the header and the payload
+const RECORD_CHECKSUM_LEN: usize = 32;
+
+/// How the state file is written
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Durability {
+    /// JSON is written to a temporary file that is renamed, without fsync
+    /// (a power loss may roll the state back; only for testing)
+    None,
+    /// JSON is written to a temporary file that is fsynced and renamed,
+    /// and the directory is fsynced afterwards
+    #[default]
+    Fsync,
+    /// checksummed records are written alternately to the two slots of a preallocated file
+    /// opened with `O_DSYNC` (one synchronous write per state, so the lowest latency;
+    /// the state file is then no longer plain JSON)
+    Dsync,
+}
+
+/// The state file of one chain
+/// (holding a `SignState` or a record that wraps the consensus state, e.g. `AuthenticatedState`)
+pub struct StateFile<T = SignState> {
+    path: PathBuf,
+    durability: Durability,
+    /// the opened record file and the sequence number of its last record
+    /// (`Durability::Dsync`)
+    records: Option<(File, u64)>,
+    state_type: PhantomData<T>,
+}
+
+impl<T> StateFile<T>
+where
+    T: Debug + Serialize + DeserializeOwned + From<consensus::State>,
+{
+    pub fn new<P: AsRef<Path>>(path: P, durability: Durability) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+            durability,
+            records: None,
+            state_type: PhantomData,
+        }
+    }
+
+    /// the path of the state file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Loads the state (in either format) or writes the initial one if the file doesn't exist
+    pub fn load(&mut self) -> Result<T, StateError> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let consensus_state = T::from(consensus::State {
+                    height: 0u32.into(),
+                    ..Default::default()
+                });
+                self.persist(&consensus_state)?;
+                return Ok(consensus_state);
+            }
+            Err(e) => fail!(
+                StateErrorKind::SyncError,
+                "error reading {}: {}",
+                self.path.display(),
+                e
+            ),
+        };
+        if is_record_file(&bytes) {
+            let (seq, consensus_state) = decode_records(&bytes).ok_or_else(|| {
+                format_err!(
+                    StateErrorKind::SyncError,
+                    "no valid state record in {}",
+                    self.path.display()
+                )
+            })?;
+            if self.durability == Durability::Dsync {
+                self.records = Some((self.open_records()?, seq));
+            }
+            Ok(consensus_state)
+        } else {
+            let consensus_state: T = serde_json::from_slice(&bytes).map_err(|e| {
+                format_err!(
+                    StateErrorKind::SyncError,
+                    "error parsing {}: {}",
+                    self.path.display(),
+                    e
+                )
+            })?;
+            if self.durability == Durability::Dsync {
+                // switch the plain JSON file to records
+                self.persist(&consensus_state)?;
+            }
+            Ok(consensus_state)
+        }
+    }
+
+    /// Writes the new state according to the durability mode
+    pub fn persist(&mut self, new_state: &T) -> Result<(), StateError> {
+        debug!(
+            "writing new consensus state to {}: {:?}",
+            self.path.display(),
+            &new_state
+        );
+        let path = &self.path;
+        match (self.durability, self.records.as_mut()) {
+            (Durability::Dsync, Some((file, seq))) => {
+                let record = encode_record(*seq + 1, new_state)?;
+                let offset = (((*seq + 1) % 2) as usize * SLOT_SIZE) as u64;
+                file.write_all_at(&record, offset).map_err(|e| {
+                    format_err!(
+                        StateErrorKind::SyncError,
+                        "error writing {}: {}",
+                        path.display(),
+                        e
+                    )
+                })?;
+                *seq += 1;
+            }
+            (Durability::Dsync, None) => {
+                // both slots hold the state, so the file starts with a record
+                let mut contents = encode_record(0, new_state)?;
+                contents.extend_from_slice(&encode_record(1, new_state)?);
+                self.replace(&contents, true)?;
+                self.records = Some((self.open_records()?, 1));
+            }
+            (durability, _) => {
+                let json = serde_json::to_vec(&new_state).map_err(|e| {
+                    format_err!(
+                        StateErrorKind::SyncError,
+                        "error serializing to json {}: {}",
+                        path.display(),
+                        e
+                    )
+                })?;
+                self.replace(&json, durability == Durability::Fsync)?;
+            }
+        }
+        debug!(
+            "successfully wrote new consensus state to {}",
+            path.display(),
+        );
+        Ok(())
+    }
+
+    /// the file of the marker sealed by the enclave (next to the state file)
+    fn marker_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".marker");
+        path.into()
+    }
+
+    /// Loads the marker sealed by the enclave (if it sealed one)
+    pub fn load_marker(&self) -> Result<Option<StateMarker>, StateError> {
+        let path = self.marker_path();
+        match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map(Some).map_err(|e| {
+                format_err!(
+                    StateErrorKind::SyncError,
+                    "error parsing {}: {}",
+                    path.display(),
+                    e
+                )
+                .into()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => fail!(
+                StateErrorKind::SyncError,
+                "error reading {}: {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+
+    /// Writes the marker sealed by the enclave
+    pub fn persist_marker(&self, marker: &StateMarker) -> Result<(), StateError> {
+        let path = self.marker_path();
+        let json = serde_json::to_vec(marker).map_err(|e| {
+            format_err!(
+                StateErrorKind::SyncError,
+                "error serializing to json {}: {}",
+                path.display(),
+                e
+            )
+        })?;
+        replace(&path, &json, self.durability != Durability::None)
+    }
+
+    /// replaces the file contents via a renamed temporary file
+    fn replace(&self, contents: &[u8], sync: bool) -> Result<(), StateError> {
+        replace(&self.path, contents, sync)
+    }
+
+    fn open_records(&self) -> Result<File, StateError> {
+        secure_file::write_options()
+            // replaces the `O_NOFOLLOW` flag of the options
+            .custom_flags(libc::O_NOFOLLOW | libc::O_DSYNC)
+            .open(&self.path)
+            .map_err(|e| {
+                format_err!(
+                    StateErrorKind::SyncError,
+                    "error opening {}: {}",
+                    self.path.display(),
+                    e
+                )
+                .into()
+            })
+    }
+}
+
+/// replaces the file contents via a renamed temporary file
+fn replace(path: &Path, contents: &[u8], sync: bool) -> Result<(), StateError> {
+    let state_file_dir = path.parent().unwrap_or_else(|| {
+        panic!("state file cannot be root directory");
+    });
+    let sync_error = |e: io::Error| {
+        format_err!(
+            StateErrorKind::SyncError,
+            "error syncing {}: {}",
+            path.display(),
+            e
+        )
+    };
+    let mut state_file = NamedTempFile::new_in(state_file_dir).map_err(|e| {
+        format_err!(
+            StateErrorKind::SyncError,
+            "error creating a named temp file {}: {}",
+            path.display(),
+            e
+        )
+    })?;
+    secure_file::restrict_permissions(state_file.as_file()).map_err(|e| {
+        format_err!(
+            StateErrorKind::SyncError,
+            "error setting permissions of {}: {}",
+            path.display(),
+            e
+        )
+    })?;
+    state_file.write_all(contents).map_err(|e| {
+        format_err!(
+            StateErrorKind::SyncError,
+            "error writing {}: {}",
+            path.display(),
+            e
+        )
+    })?;
+    if sync {
+        state_file.as_file().sync_all().map_err(sync_error)?;
+    }
+    state_file.persist(path).map_err(|e| {
+        format_err!(
+            StateErrorKind::SyncError,
+            "error persisting {}: {}",
+            path.display(),
+            e
+        )
+    })?;
+    if sync {
+        // the rename is only durable once the directory entry is synced
+        File::open(state_file_dir)
+            .and_then(|dir| dir.sync_all())
+            .map_err(sync_error)?;
+    }
+    Ok(())
+}
+
+impl PersistStateSync for StateFile {
+    fn load_state(&mut self) -> Result<State, StateError> {
+        self.load().map(State::from)
+    }
+
+    fn persist_state(&mut self, new_state: &State) -> Result<(), StateError> {
+        self.persist(&SignState::from(new_state))
+    }
+}
+
+/// magic | sequence number | payload length | JSON payload | SHA-256 | zero padding
+fn encode_record<T: Serialize>(seq: u64, state: &T) -> Result<Vec<u8>, StateError> {
+    let json = serde_json::to_vec(state).map_err(|e| {
+        format_err!(
+            StateErrorKind::SyncError,
+            "error serializing to json: {}",
+            e
+        )
+    })?;
+    if RECORD_HEADER_LEN + json.len() + RECORD_CHECKSUM_LEN > SLOT_SIZE {
+        fail!(
+            StateErrorKind::SyncError,
+            "state too large for a record: {} bytes",
+            json.len()
+        );
+    }
+    let mut record = Vec::with_capacity(SLOT_SIZE);
+    record.extend_from_slice(RECORD_MAGIC);
+    record.extend_from_slice(&seq.to_le_bytes());
+    record.extend_from_slice(&(json.len() as u16).to_le_bytes());
+    record.extend_from_slice(&json);
+    let checksum = Sha256::digest(&record);
+    record.extend_from_slice(&checksum);
+    record.resize(SLOT_SIZE, 0);
+    Ok(record)
+}
+
+/// a slot that was torn by a crash fails the checksum
+fn decode_record<T: DeserializeOwned>(slot: &[u8]) -> Option<(u64, T)> {
+    if slot.len() < RECORD_HEADER_LEN || !slot.starts_with(RECORD_MAGIC) {
+        return None;
+    }
+    let seq = u64::from_le_bytes(slot[8..16].try_into().ok()?);
+    let len = u16::from_le_bytes(slot[16..18].try_into().ok()?) as usize;
+    let payload_end = RECORD_HEADER_LEN + len;
+    let checksum = slot.get(payload_end..payload_end + RECORD_CHECKSUM_LEN)?;
+    if Sha256::digest(&slot[..payload_end]).as_slice() != checksum {
+        return None;
+    }
+    let state = serde_json::from_slice(&slot[RECORD_HEADER_LEN..payload_end]).ok()?;
+    Some((seq, state))
+}
+
+/// a record file has two slots, one of which (at least) starts with the magic
+/// (slot 0 may have been torn by a crash)
+fn is_record_file(bytes: &[u8]) -> bool {
+    bytes.len() == 2 * SLOT_SIZE
+        || bytes
+            .chunks(SLOT_SIZE)
+            .any(|slot| slot.starts_with(RECORD_MAGIC))
+}
+
+/// the valid record with the highest sequence number
+fn decode_records<T: DeserializeOwned>(bytes: &[u8]) -> Option<(u64, T)> {
+    bytes
+        .chunks(SLOT_SIZE)
+        .filter_map(decode_record)
+        .max_by_key(|(seq, _)| *seq)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::state::LastSigned;
+
+    fn state_at(height: u32, step: i8) -> consensus::State {
+        consensus::State {
+            height: height.into(),
+            step,
+            ..Default::default()
+        }
+    }
+
+    fn open(path: &Path, durability: Durability) -> StateFile<consensus::State> {
+        StateFile::new(path, durability)
+    }
+
+    fn test_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("tmkms-state-{}-{}.json", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn persists_and_loads_in_every_mode() {
+        for (name, durability) in [
+            ("none", Durability::None),
+            ("fsync", Durability::Fsync),
+            ("dsync", Durability::Dsync),
+        ]
+        .iter()
+        {
+            let path = test_path(name);
+            let mut state_file = open(&path, *durability);
+            assert_eq!(state_file.load().unwrap(), state_at(0, 0));
+            for height in 1..=3 {
+                state_file.persist(&state_at(height, 1)).unwrap();
+            }
+            assert_eq!(open(&path, *durability).load().unwrap(), state_at(3, 1));
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn recovers_from_torn_record() {
+        let path = test_path("torn");
+        let mut state_file = open(&path, Durability::Dsync);
+        state_file.load().unwrap();
+        state_file.persist(&state_at(1, 2)).unwrap();
+        state_file.persist(&state_at(2, 0)).unwrap();
+        // the write of the last record (sequence number 3) was interrupted halfway
+        let mut bytes = fs::read(&path).unwrap();
+        let torn = SLOT_SIZE + RECORD_HEADER_LEN + 10;
+        for b in bytes[torn..2 * SLOT_SIZE].iter_mut() {
+            *b = 0;
+        }
+        fs::write(&path, &bytes).unwrap();
+        let mut state_file = open(&path, Durability::Dsync);
+        assert_eq!(state_file.load().unwrap(), state_at(1, 2));
+        // writing continues after the last valid record
+        state_file.persist(&state_at(3, 0)).unwrap();
+        assert_eq!(
+            open(&path, Durability::Dsync).load().unwrap(),
+            state_at(3, 0)
+        );
+        // no valid record at all is an error rather than a reset to the initial state
+        fs::write(&path, &bytes[..RECORD_HEADER_LEN]).unwrap();
+        assert!(open(&path, Durability::Dsync).load().is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn recovers_from_torn_first_slot() {
+        let path = test_path("torn-first");
+        let mut state_file = open(&path, Durability::Dsync);
+        state_file.load().unwrap();
+        state_file.persist(&state_at(1, 2)).unwrap();
+        // the write of the record with sequence number 3 went to slot 1
+        state_file.persist(&state_at(2, 0)).unwrap();
+        // the write of the next one (to slot 0) was interrupted at its start,
+        // so the file doesn't start with the magic anymore
+        state_file.persist(&state_at(2, 1)).unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        for b in bytes[..RECORD_HEADER_LEN].iter_mut() {
+            *b = 0;
+        }
+        fs::write(&path, &bytes).unwrap();
+        let mut state_file = open(&path, Durability::Dsync);
+        assert_eq!(state_file.load().unwrap(), state_at(2, 0));
+        state_file.persist(&state_at(3, 0)).unwrap();
+        assert_eq!(
+            open(&path, Durability::Dsync).load().unwrap(),
+            state_at(3, 0)
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_last_signed_message() {
+        let path = test_path("signed");
+        let signed = State::new(
+            state_at(7, 2),
+            Some(LastSigned {
+                sign_bytes: vec![1; 120],
+                signature: vec![2; 64],
+            }),
+        );
+        for durability in [Durability::Fsync, Durability::Dsync].iter() {
+            let mut state_file: StateFile = StateFile::new(&path, *durability);
+            state_file.load_state().unwrap();
+            state_file.persist_state(&signed).unwrap();
+            let loaded = StateFile::<SignState>::new(&path, *durability)
+                .load_state()
+                .unwrap();
+            assert_eq!(loaded.consensus_state(), signed.consensus_state());
+            assert_eq!(loaded.last_signed(), signed.last_signed());
+        }
+        // the fields of Go's state file
+        let json = serde_json::to_value(SignState::from(&signed)).unwrap();
+        let signature = String::from_utf8(subtle_encoding::base64::encode([2u8; 64])).unwrap();
+        assert_eq!(json["signature"], signature);
+        assert!(json["signbytes"].as_str().unwrap().starts_with("0101"));
+        // a plain consensus state (of earlier versions) has no last signed message
+        fs::remove_file(&path).unwrap();
+        open(&path, Durability::Fsync)
+            .persist(&state_at(8, 0))
+            .unwrap();
+        let loaded = StateFile::<SignState>::new(&path, Durability::Fsync)
+            .load_state()
+            .unwrap();
+        assert_eq!(loaded.consensus_state(), &state_at(8, 0));
+        assert!(loaded.last_signed().is_none());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_truncated_json_and_switches_modes() {
+        let path = test_path("switch");
+        let mut state_file = open(&path, Durability::Fsync);
+        state_file.load().unwrap();
+        state_file.persist(&state_at(5, 1)).unwrap();
+        let json = fs::read(&path).unwrap();
+        // JSON -> records -> JSON
+        let mut state_file = open(&path, Durability::Dsync);
+        assert_eq!(state_file.load().unwrap(), state_at(5, 1));
+        assert!(fs::read(&path).unwrap().starts_with(RECORD_MAGIC));
+        state_file.persist(&state_at(6, 0)).unwrap();
+        assert_eq!(
+            open(&path, Durability::Fsync).load().unwrap(),
+            state_at(6, 0)
+        );
+        // a torn plain JSON write (i.e. without the temporary file) is not silently reset
+        fs::write(&path, &json[..json.len() / 2]).unwrap();
+        assert!(open(&path, Durability::Fsync).load().is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
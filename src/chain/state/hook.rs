@@ -0,0 +1,289 @@
+//! Startup hook that checks the latest block height of the chain
+//! before the first signature (e.g. when the state file was restored from an old backup)
+
+use super::consensus;
+use crate::error::{Error, ErrorKind};
+use anomaly::{fail, format_err};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    convert::TryFrom,
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    process::Command,
+    time::Duration,
+};
+use tendermint::block;
+use tracing::{info, warn};
+
+/// timeout for connecting to and reading from the RPC endpoint
+const RPC_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What to do when the local state is behind the chain
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BehindAction {
+    /// refuse to start
+    #[default]
+    Refuse,
+    /// raise the state height to the latest block height
+    Raise,
+}
+
+/// Startup state hook configuration
+/// (either `cmd` or `rpc_url` needs to be set)
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HookConfig {
+    /// Command (and its arguments) that prints `{"latest_block_height": "<height>"}`
+    pub cmd: Option<Vec<String>>,
+    /// Tendermint RPC address (e.g. `http://127.0.0.1:26657`) whose `/status` is queried
+    pub rpc_url: Option<String>,
+    /// What to do when the local state is behind the chain (`refuse` or `raise`)
+    #[serde(default)]
+    pub on_behind: BehindAction,
+}
+
+/// Gets the latest block height from the configured command or RPC endpoint
+pub fn latest_block_height(config: &HookConfig) -> Result<block::Height, Error> {
+    match (&config.cmd, &config.rpc_url) {
+        (Some(cmd), None) => run_command(cmd),
+        (None, Some(rpc_url)) => query_status(rpc_url),
+        _ => fail!(
+            ErrorKind::ConfigError,
+            "state hook needs exactly one of `cmd` or `rpc_url`"
+        ),
+    }
+}
+
+/// Compares the latest block height with the loaded state:
+/// returns the raised state if the local state was behind and the hook is set to raise it
+/// (it then needs to be persisted before signing), or fails if it is set to refuse
+pub fn run(
+    config: &HookConfig,
+    state: &consensus::State,
+) -> Result<Option<consensus::State>, Error> {
+    let latest_height = latest_block_height(config)?;
+    if state.height >= latest_height {
+        info!(
+            "state height {} is not behind the latest block height {}",
+            state.height, latest_height
+        );
+        return Ok(None);
+    }
+    match config.on_behind {
+        BehindAction::Refuse => fail!(
+            ErrorKind::HookError,
+            "state height {} is behind the latest block height {}",
+            state.height,
+            latest_height
+        ),
+        BehindAction::Raise => {
+            warn!(
+                "raising state height {} to the latest block height {}",
+                state.height, latest_height
+            );
+            Ok(Some(consensus::State {
+                height: latest_height,
+                ..Default::default()
+            }))
+        }
+    }
+}
+
+/// runs the command and parses its output
+fn run_command(cmd: &[String]) -> Result<block::Height, Error> {
+    let (program, args) = cmd
+        .split_first()
+        .ok_or_else(|| format_err!(ErrorKind::ConfigError, "empty state hook command"))?;
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| format_err!(ErrorKind::HookError, "failed to run {}: {}", program, e))?;
+    if !output.status.success() {
+        fail!(
+            ErrorKind::HookError,
+            "{} failed ({}): {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let value: Value = serde_json::from_slice(&output.stdout).map_err(|e| {
+        format_err!(
+            ErrorKind::HookError,
+            "error parsing {} output: {}",
+            program,
+            e
+        )
+    })?;
+    parse_height(&value["latest_block_height"])
+}
+
+/// queries the `/status` endpoint (over plain HTTP)
+fn query_status(rpc_url: &str) -> Result<block::Height, Error> {
+    let rest = rpc_url.strip_prefix("http://").ok_or_else(|| {
+        format_err!(
+            ErrorKind::ConfigError,
+            "unsupported RPC address {} (only http:// is supported)",
+            rpc_url
+        )
+    })?;
+    let (authority, base_path) = match rest.find('/') {
+        Some(i) => (&rest[..i], rest[i..].trim_end_matches('/')),
+        None => (rest, ""),
+    };
+    let addr = authority
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| format_err!(ErrorKind::HookError, "failed to resolve {}", authority))?;
+    let mut stream = TcpStream::connect_timeout(&addr, RPC_TIMEOUT)
+        .map_err(|e| format_err!(ErrorKind::HookError, "failed to connect to {}: {}", addr, e))?;
+    stream
+        .set_read_timeout(Some(RPC_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(RPC_TIMEOUT)))
+        .map_err(|e| format_err!(ErrorKind::HookError, "failed to set timeouts: {}", e))?;
+    let request = format!(
+        "GET {}/status HTTP/1.0\r\nHost: {}\r\nAccept: application/json\r\n\r\n",
+        base_path, authority
+    );
+    let mut response = Vec::new();
+    stream
+        .write_all(request.as_bytes())
+        .and_then(|_| stream.read_to_end(&mut response))
+        .map_err(|e| format_err!(ErrorKind::HookError, "{} request failed: {}", rpc_url, e))?;
+    let response = String::from_utf8_lossy(&response);
+    let body_start = response
+        .find("\r\n\r\n")
+        .ok_or_else(|| format_err!(ErrorKind::HookError, "malformed response from {}", rpc_url))?;
+    let (head, body) = (&response[..body_start], &response[body_start + 4..]);
+    let status_line = head.lines().next().unwrap_or_default();
+    if status_line.split_whitespace().nth(1) != Some("200") {
+        fail!(
+            ErrorKind::HookError,
+            "{} responded with: {}",
+            rpc_url,
+            status_line
+        );
+    }
+    let value: Value = serde_json::from_str(body).map_err(|e| {
+        format_err!(
+            ErrorKind::HookError,
+            "error parsing {} status: {}",
+            rpc_url,
+            e
+        )
+    })?;
+    parse_height(&value["result"]["sync_info"]["latest_block_height"])
+}
+
+/// the height is a string in Tendermint JSON, but a number is accepted as well
+fn parse_height(value: &Value) -> Result<block::Height, Error> {
+    let height = match value {
+        Value::String(s) => s.parse::<u64>().ok(),
+        Value::Number(n) => n.as_u64(),
+        _ => None,
+    }
+    .ok_or_else(|| {
+        format_err!(
+            ErrorKind::HookError,
+            "invalid latest block height: {}",
+            value
+        )
+    })?;
+    block::Height::try_from(height).map_err(|e| {
+        format_err!(
+            ErrorKind::HookError,
+            "invalid latest block height {}: {}",
+            height,
+            e
+        )
+        .into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::BufRead, io::BufReader, net::TcpListener, thread};
+
+    /// serves one `/status` request with the given latest block height
+    fn status_stand_in(latest_height: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            assert!(request_line.starts_with("GET /status "));
+            let mut line = String::new();
+            while line != "\r\n" {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+            }
+            let body = format!(
+                r#"{{"id":-1,"result":{{"sync_info":{{"latest_block_height":"{}"}}}}}}"#,
+                latest_height
+            );
+            let mut stream = reader.into_inner();
+            write!(
+                stream,
+                "HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n{}",
+                body
+            )
+            .unwrap();
+        });
+        format!("http://{}", addr)
+    }
+
+    fn state_at(height: u32) -> consensus::State {
+        consensus::State {
+            height: height.into(),
+            step: 2,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn raises_state_behind_the_chain() {
+        let config = HookConfig {
+            rpc_url: Some(status_stand_in("100")),
+            on_behind: BehindAction::Raise,
+            ..Default::default()
+        };
+        let raised = run(&config, &state_at(42)).unwrap().unwrap();
+        assert_eq!(raised.height, 100u32.into());
+        assert_eq!(raised.step, 0);
+        let config = HookConfig {
+            rpc_url: Some(status_stand_in("100")),
+            on_behind: BehindAction::Raise,
+            ..Default::default()
+        };
+        assert!(run(&config, &state_at(100)).unwrap().is_none());
+    }
+
+    #[test]
+    fn refuses_state_behind_the_chain() {
+        let config = HookConfig {
+            rpc_url: Some(status_stand_in("100")),
+            ..Default::default()
+        };
+        let err = run(&config, &state_at(42)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::HookError);
+    }
+
+    #[test]
+    fn parses_command_output() {
+        let config = HookConfig {
+            cmd: Some(vec![
+                "echo".to_owned(),
+                r#"{"latest_block_height": "7"}"#.to_owned(),
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(latest_block_height(&config).unwrap(), 7u32.into());
+        assert!(latest_block_height(&HookConfig::default()).is_err());
+    }
+}
@@ -1 +1,3 @@
+pub mod grpc;
+pub mod threshold;
 pub mod validator;
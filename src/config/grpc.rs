@@ -0,0 +1,26 @@
+//! gRPC privval server configuration
+
+use serde::{Deserialize, Serialize};
+use std::{net::SocketAddr, path::PathBuf};
+
+/// Privval gRPC server of a validator entry (Tendermint 0.35+ nodes
+/// with a gRPC `priv-validator.laddr`)
+///
+/// The server relays the node's calls to the signing session, which connects
+/// to the entry's `unix://` address as it would to a node.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GrpcConfig {
+    /// Address to serve the privval gRPC API on
+    pub listen_addr: SocketAddr,
+
+    /// Path to the PEM server certificate (TLS if set)
+    pub cert_path: Option<PathBuf>,
+
+    /// Path to the PEM private key of the server certificate
+    pub key_path: Option<PathBuf>,
+
+    /// Path to the PEM CA certificate that client certificates
+    /// need to be signed by (mutual TLS if set)
+    pub client_ca_path: Option<PathBuf>,
+}
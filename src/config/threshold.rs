@@ -0,0 +1,154 @@
+//! Threshold signing configuration
+
+use crate::error::{Error, ErrorKind};
+use anomaly::fail;
+use serde::{Deserialize, Serialize};
+use std::{net::SocketAddr, time::Duration};
+use tendermint::net;
+
+/// Seconds to wait for the other nodes in each signing round (if not configured)
+pub const DEFAULT_ROUND_TIMEOUT: u16 = 2;
+
+/// Threshold (FROST) Ed25519 signing with other KMS nodes
+///
+/// The consensus key is split between this node and its peers in a distributed
+/// key generation, and any `threshold` of the nodes sign together
+/// (more than half of them, so that any two signing quorums overlap).
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ThresholdConfig {
+    /// Participant number of this node (unique among the nodes, starting from 1)
+    pub id: u16,
+
+    /// Number of nodes that take part in each signature
+    /// (more than half of the nodes)
+    pub threshold: u16,
+
+    /// Address to accept the connections of the other nodes on
+    pub listen_addr: SocketAddr,
+
+    /// Seconds to wait for the other nodes in each signing round (2 if not set)
+    pub timeout: Option<u16>,
+
+    /// The other nodes
+    #[serde(rename = "peer")]
+    pub peers: Vec<ThresholdPeer>,
+}
+
+/// Another node of the threshold signing
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ThresholdPeer {
+    /// Participant number of the node
+    pub id: u16,
+
+    /// Address of the node with the peer ID of its identity key
+    /// (`tcp://<peer ID>@<host>:<port>`)
+    pub address: net::Address,
+}
+
+impl ThresholdConfig {
+    /// Number of the nodes (this one included)
+    pub fn max_signers(&self) -> u16 {
+        self.peers.len() as u16 + 1
+    }
+
+    /// How long to wait for the other nodes in each signing round
+    pub fn round_timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(DEFAULT_ROUND_TIMEOUT).into())
+    }
+
+    /// Checks the participant numbers, the threshold and the peer addresses
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.peers.len() >= u16::MAX as usize {
+            fail!(ErrorKind::ConfigError, "too many threshold peers");
+        }
+        if self.threshold < 2 || self.threshold > self.max_signers() {
+            fail!(
+                ErrorKind::ConfigError,
+                "`threshold` needs to be between 2 and the number of nodes ({})",
+                self.max_signers()
+            );
+        }
+        // any two signing quorums need to share a node,
+        // or they could sign conflicting votes without any node double signing
+        if u32::from(self.threshold) * 2 <= u32::from(self.max_signers()) {
+            fail!(
+                ErrorKind::ConfigError,
+                "`threshold` needs to be more than half of the number of nodes ({})",
+                self.max_signers()
+            );
+        }
+        if self.id == 0 {
+            fail!(ErrorKind::ConfigError, "participant numbers start from 1");
+        }
+        for (i, peer) in self.peers.iter().enumerate() {
+            if peer.id == 0 {
+                fail!(ErrorKind::ConfigError, "participant numbers start from 1");
+            }
+            if peer.id == self.id || self.peers[..i].iter().any(|p| p.id == peer.id) {
+                fail!(
+                    ErrorKind::ConfigError,
+                    "participant number {} is used by more nodes",
+                    peer.id
+                );
+            }
+            if !matches!(
+                peer.address,
+                net::Address::Tcp {
+                    peer_id: Some(_),
+                    ..
+                }
+            ) {
+                fail!(
+                    ErrorKind::ConfigError,
+                    "threshold peer {} needs a `tcp://<peer ID>@<host>:<port>` address",
+                    peer.id
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(peers: &[(u16, &str)]) -> ThresholdConfig {
+        let peers: Vec<String> = peers
+            .iter()
+            .map(|(id, address)| format!("{{\"id\": {}, \"address\": \"{}\"}}", id, address))
+            .collect();
+        let json = format!(
+            "{{\"id\": 1, \"threshold\": 2, \"listen_addr\": \"127.0.0.1:26670\", \"peer\": [{}]}}",
+            peers.join(",")
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn validates_participants() {
+        let peer_address = "tcp://f88883b673fc69d7869cab098de3bafc2ff76eb8@127.0.0.1:26671";
+        let valid = config(&[(2, peer_address), (3, peer_address)]);
+        assert!(valid.validate().is_ok());
+        assert_eq!(valid.max_signers(), 3);
+        assert_eq!(valid.round_timeout(), Duration::from_secs(2));
+
+        // too few nodes for the threshold
+        assert!(config(&[]).validate().is_err());
+        // two disjoint pairs of the 4 nodes could sign conflicting votes
+        assert!(
+            config(&[(2, peer_address), (3, peer_address), (4, peer_address)])
+                .validate()
+                .is_err()
+        );
+        // a participant number used twice
+        assert!(config(&[(2, peer_address), (2, peer_address)])
+            .validate()
+            .is_err());
+        assert!(config(&[(1, peer_address)]).validate().is_err());
+        // the peer ID is needed to authenticate the node
+        assert!(config(&[(2, "tcp://127.0.0.1:26671")]).validate().is_err());
+    }
+}
@@ -3,7 +3,9 @@
 //! Modifications Copyright (c) 2021, Foris Limited (licensed under the Apache License, Version 2.0)
 
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use tendermint::chain;
+use tendermint_p2p::secret_connection;
 
 /// Validator configuration
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -14,4 +16,93 @@ pub struct ValidatorConfig {
 
     /// Height at which to stop signing
     pub max_height: Option<tendermint::block::Height>,
+
+    /// Seconds without any request (including pings) after which
+    /// the connection is dropped and rebuilt (if set)
+    pub idle_timeout: Option<u16>,
+
+    /// Tendermint / CometBFT protocol version of the validator node (`v0.34` by default)
+    #[serde(default)]
+    pub protocol_version: ProtocolVersion,
+}
+
+/// Privval protocol versions
+#[derive(Copy, Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq, PartialOrd, Ord)]
+pub enum ProtocolVersion {
+    /// Tendermint v0.33 (Amino-encoded messages)
+    #[serde(rename = "v0.33")]
+    V0_33,
+    /// Tendermint v0.34
+    #[serde(rename = "v0.34")]
+    #[default]
+    V0_34,
+    /// CometBFT v0.37 (the same privval messages as v0.34)
+    #[serde(rename = "v0.37")]
+    V0_37,
+    /// CometBFT v0.38 (precommits may carry vote extensions)
+    #[serde(rename = "v0.38")]
+    V0_38,
+    /// CometBFT v1 (the same privval messages as v0.38, and BLS12-381 keys)
+    #[serde(rename = "v1")]
+    V1,
+}
+
+impl ProtocolVersion {
+    /// Whether the messages and sign bytes are Protobuf-encoded (v0.34 and later)
+    pub fn is_protobuf(self) -> bool {
+        self >= ProtocolVersion::V0_34
+    }
+
+    /// Whether non-nil precommits carry vote extensions to be signed (v0.38)
+    pub fn has_vote_extensions(self) -> bool {
+        self >= ProtocolVersion::V0_38
+    }
+
+    /// Version of the secret connection handshake
+    /// (unchanged since v0.34, CometBFT included)
+    pub fn secret_connection_version(self) -> secret_connection::Version {
+        if self.is_protobuf() {
+            secret_connection::Version::V0_34
+        } else {
+            secret_connection::Version::V0_33
+        }
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let version = match self {
+            ProtocolVersion::V0_33 => "v0.33",
+            ProtocolVersion::V0_34 => "v0.34",
+            ProtocolVersion::V0_37 => "v0.37",
+            ProtocolVersion::V0_38 => "v0.38",
+            ProtocolVersion::V1 => "v1",
+        };
+        f.write_str(version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Versioned {
+        protocol_version: ProtocolVersion,
+    }
+
+    #[test]
+    fn parses_protocol_versions() {
+        for version in ["v0.33", "v0.34", "v0.37", "v0.38", "v1"].iter() {
+            let json = format!("{{\"protocol_version\": \"{}\"}}", version);
+            let parsed: Versioned = serde_json::from_str(&json).unwrap();
+            assert_eq!(&parsed.protocol_version.to_string(), version);
+        }
+        assert!(!ProtocolVersion::V0_33.is_protobuf());
+        assert!(ProtocolVersion::V0_38.is_protobuf());
+        assert!(!ProtocolVersion::V0_37.has_vote_extensions());
+        assert!(ProtocolVersion::V0_38.has_vote_extensions());
+        assert!(ProtocolVersion::V1.has_vote_extensions());
+        assert!(serde_json::from_str::<Versioned>("{\"protocol_version\": \"v0.35\"}").is_err());
+    }
 }
@@ -2,13 +2,27 @@
 //! Copyright (c) 2018-2021 Iqlusion Inc. (licensed under the Apache License, Version 2.0)
 //! Modifications Copyright (c) 2021, Foris Limited (licensed under the Apache License, Version 2.0)
 
+mod backoff;
+pub mod manager;
+
+pub use manager::{ConnectionManager, Transport};
 use std::io;
 use std::marker::{Send, Sync};
+use std::sync::Arc;
 use tendermint_p2p::secret_connection::SecretConnection;
 use tracing::{debug, trace};
 
+/// Shuts a connection down from another thread
+/// (so that a read blocked on it returns)
+pub type ShutdownHandle = Arc<dyn Fn() -> io::Result<()> + Send + Sync>;
+
 /// Connections to a validator
-pub trait Connection: io::Read + io::Write + Sync + Send {}
+pub trait Connection: io::Read + io::Write + Sync + Send {
+    /// The handle for shutting the connection down (if its stream supports it)
+    fn shutdown_handle(&self) -> Option<ShutdownHandle> {
+        None
+    }
+}
 
 /// Protocol implementation of the plain connection (Unix domain socket or local vsock-proxy)
 pub struct PlainConnection<IoHandler> {
@@ -0,0 +1,66 @@
+//! Delays between reconnect attempts to a validator
+
+use rand_core::{OsRng, RngCore};
+use std::time::Duration;
+
+/// the delay before the first reconnect attempt
+const INITIAL_DELAY: Duration = Duration::from_millis(500);
+
+/// Exponential backoff with jitter (each delay is between the half and the whole
+/// of the current one, which doubles up to the maximum)
+pub struct Backoff {
+    current: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    pub fn new(max: Duration) -> Self {
+        Self {
+            current: INITIAL_DELAY.min(max),
+            max,
+        }
+    }
+
+    /// the upper bound of the delays
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// the delay before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let half_millis = (self.current.as_millis() / 2) as u64;
+        let jitter = if half_millis > 0 {
+            OsRng.next_u64() % (half_millis + 1)
+        } else {
+            0
+        };
+        let delay = Duration::from_millis(half_millis + jitter);
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    /// starts from the initial delay again (e.g. after a connection that lasted)
+    pub fn reset(&mut self) {
+        self.current = INITIAL_DELAY.min(self.max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grows_up_to_max_with_jitter() {
+        let max = Duration::from_secs(4);
+        let mut backoff = Backoff::new(max);
+        let mut upper = INITIAL_DELAY;
+        for _ in 0..10 {
+            let delay = backoff.next_delay();
+            assert!(delay >= upper / 2 && delay <= upper);
+            upper = (upper * 2).min(max);
+        }
+        assert!(backoff.next_delay() >= max / 2);
+        backoff.reset();
+        assert!(backoff.next_delay() <= INITIAL_DELAY);
+    }
+}
@@ -0,0 +1,413 @@
+//! Opening (and reopening) connections to a validator
+
+use super::{backoff::Backoff, Connection, PlainConnection, ShutdownHandle};
+use crate::config::validator::ProtocolVersion;
+use crate::error::{Error, ErrorKind};
+use anomaly::{fail, format_err};
+use ed25519_dalek as ed25519;
+use std::{
+    io,
+    net::{Shutdown, TcpStream},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+use subtle::{Choice, ConstantTimeEq};
+use tendermint::{net, node};
+use tendermint_p2p::secret_connection::{PublicKey, SecretConnection};
+use tracing::{debug, error, info, warn};
+
+/// Default maximum delay between reconnect attempts
+pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Byte streams to a validator (a secret connection is established over them
+/// if the manager has an identity key)
+pub trait Stream: io::Read + io::Write + Send + Sync {
+    /// The handle for shutting the stream down from another thread (if supported)
+    fn shutdown_handle(&self) -> Option<ShutdownHandle> {
+        None
+    }
+}
+
+impl Stream for TcpStream {
+    fn shutdown_handle(&self) -> Option<ShutdownHandle> {
+        let socket = self.try_clone().ok()?;
+        Some(Arc::new(move || socket.shutdown(Shutdown::Both)))
+    }
+}
+
+#[cfg(unix)]
+impl Stream for std::os::unix::net::UnixStream {
+    fn shutdown_handle(&self) -> Option<ShutdownHandle> {
+        let socket = self.try_clone().ok()?;
+        Some(Arc::new(move || socket.shutdown(Shutdown::Both)))
+    }
+}
+
+/// A connection with the shutdown handle of its stream
+/// (which is no longer reachable once the secret connection wraps it)
+struct StreamConnection {
+    connection: Box<dyn Connection>,
+    shutdown: Option<ShutdownHandle>,
+}
+
+impl io::Read for StreamConnection {
+    fn read(&mut self, data: &mut [u8]) -> io::Result<usize> {
+        self.connection.read(data)
+    }
+}
+
+impl io::Write for StreamConnection {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.connection.write(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.connection.flush()
+    }
+}
+
+impl Connection for StreamConnection {
+    fn shutdown_handle(&self) -> Option<ShutdownHandle> {
+        self.shutdown.clone()
+    }
+}
+
+/// Opens byte streams to the validator address
+/// (enclaves provide their own, e.g. over vsock or a usercall extension)
+pub trait Transport: Send + Sync {
+    /// Opens a stream to the address (with the read/write timeout if supported)
+    fn open(
+        &self,
+        address: &net::Address,
+        timeout: Option<Duration>,
+    ) -> io::Result<Box<dyn Stream>>;
+}
+
+impl<F> Transport for F
+where
+    F: Fn(&net::Address, Option<Duration>) -> io::Result<Box<dyn Stream>> + Send + Sync,
+{
+    fn open(
+        &self,
+        address: &net::Address,
+        timeout: Option<Duration>,
+    ) -> io::Result<Box<dyn Stream>> {
+        self(address, timeout)
+    }
+}
+
+/// TCP and Unix domain sockets
+pub struct SocketTransport;
+
+impl Transport for SocketTransport {
+    fn open(
+        &self,
+        address: &net::Address,
+        timeout: Option<Duration>,
+    ) -> io::Result<Box<dyn Stream>> {
+        match address {
+            net::Address::Tcp { host, port, .. } => {
+                let socket = TcpStream::connect(format!("{}:{}", host, port))?;
+                socket.set_read_timeout(timeout)?;
+                socket.set_write_timeout(timeout)?;
+                Ok(Box::new(socket))
+            }
+            #[cfg(unix)]
+            net::Address::Unix { path } => {
+                // timeouts aren't applied to the local sockets
+                let socket = std::os::unix::net::UnixStream::connect(path)?;
+                Ok(Box::new(socket))
+            }
+            #[cfg(not(unix))]
+            net::Address::Unix { .. } => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Unix sockets are not supported on this platform",
+            )),
+        }
+    }
+}
+
+/// Opens connections to a validator: a secret connection (with the peer ID verification)
+/// if an identity key is set, otherwise a plain one (e.g. over a Unix socket);
+/// after a lost connection, it's reopened with an exponential backoff (if retrying is enabled)
+pub struct ConnectionManager {
+    address: net::Address,
+    identity_key: Option<ed25519::Keypair>,
+    allowed_peer_ids: Vec<node::Id>,
+    timeout: Option<Duration>,
+    protocol_version: ProtocolVersion,
+    retry: bool,
+    backoff: Backoff,
+    transport: Box<dyn Transport>,
+    /// when the last connection was opened
+    connected_at: Option<Instant>,
+}
+
+impl ConnectionManager {
+    /// Creates a manager for the address (`tcp://` addresses need an identity key)
+    pub fn new(
+        address: net::Address,
+        identity_key: Option<ed25519::Keypair>,
+    ) -> Result<Self, Error> {
+        if let (net::Address::Tcp { .. }, None) = (&address, &identity_key) {
+            fail!(
+                ErrorKind::ConfigError,
+                "no identity key for validator: {}",
+                address
+            );
+        }
+        Ok(Self {
+            address,
+            identity_key,
+            allowed_peer_ids: Vec::new(),
+            timeout: None,
+            protocol_version: ProtocolVersion::default(),
+            retry: true,
+            backoff: Backoff::new(DEFAULT_MAX_BACKOFF),
+            transport: Box::new(SocketTransport),
+            connected_at: None,
+        })
+    }
+
+    /// Only accept validators with one of these peer IDs (if not empty)
+    /// in addition to the peer ID in the `tcp://` address
+    pub fn set_allowed_peer_ids(&mut self, allowed_peer_ids: Vec<node::Id>) {
+        self.allowed_peer_ids = allowed_peer_ids;
+    }
+
+    /// Read/write timeout of the opened streams
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Protocol version of the validator node (for the secret connection handshake)
+    pub fn set_protocol_version(&mut self, protocol_version: ProtocolVersion) {
+        self.protocol_version = protocol_version;
+    }
+
+    /// Whether to keep retrying to connect (enabled by default)
+    pub fn set_retry(&mut self, retry: bool) {
+        self.retry = retry;
+    }
+
+    /// Upper bound of the delays between reconnect attempts
+    pub fn set_max_backoff(&mut self, max_backoff: Duration) {
+        self.backoff = Backoff::new(max_backoff);
+    }
+
+    /// Replaces the default TCP / Unix socket transport
+    pub fn set_transport(&mut self, transport: Box<dyn Transport>) {
+        self.transport = transport;
+    }
+
+    /// Whether to keep retrying to connect
+    pub fn retry(&self) -> bool {
+        self.retry
+    }
+
+    /// The validator address
+    pub fn address(&self) -> &net::Address {
+        &self.address
+    }
+
+    /// Connects to the validator (retrying with backoff if enabled);
+    /// a reconnect waits first, unless the previous connection lasted
+    pub fn connect(&mut self) -> Result<Box<dyn Connection>, Error> {
+        if let Some(connected_at) = self.connected_at.take() {
+            // a connection that lasted isn't part of a series of failures
+            if connected_at.elapsed() > self.backoff.max() {
+                self.backoff.reset();
+            }
+            thread::sleep(self.backoff.next_delay());
+        }
+        loop {
+            match self.try_connect() {
+                Ok(connection) => {
+                    self.connected_at = Some(Instant::now());
+                    return Ok(connection);
+                }
+                Err(e) => {
+                    error!("[{}] connection failed: {}", &self.address, e);
+                    if !self.retry {
+                        return Err(e);
+                    }
+                    let delay = self.backoff.next_delay();
+                    debug!("[{}] reconnecting in {:?}", &self.address, delay);
+                    thread::sleep(delay);
+                }
+            }
+        }
+    }
+
+    /// Makes one attempt to connect to the validator
+    pub fn try_connect(&self) -> Result<Box<dyn Connection>, Error> {
+        debug!("[{}] connecting to validator...", &self.address);
+        let socket = self
+            .transport
+            .open(&self.address, self.timeout)
+            .map_err(|e| format_err!(ErrorKind::IoError, "{}", e))?;
+        let shutdown = socket.shutdown_handle();
+        let connection: Box<dyn Connection> = match &self.identity_key {
+            Some(identity_key) => {
+                // the `Clone` is not derived for Keypair
+                // TODO: https://github.com/dalek-cryptography/ed25519-dalek/issues/76
+                let identity_key = ed25519::Keypair::from_bytes(&identity_key.to_bytes())
+                    .map_err(|e| format_err!(ErrorKind::InvalidKey, "identity key: {}", e))?;
+                let connection = SecretConnection::new(
+                    socket,
+                    identity_key,
+                    self.protocol_version.secret_connection_version(),
+                )
+                .map_err(|e| format_err!(ErrorKind::ProtocolError, "secret connection: {}", e))?;
+                self.verify_peer_id(connection.remote_pubkey().peer_id())?;
+                Box::new(connection)
+            }
+            None => Box::new(PlainConnection::new(socket)),
+        };
+        info!("[{}] connected to validator successfully", &self.address);
+        Ok(Box::new(StreamConnection {
+            connection,
+            shutdown,
+        }))
+    }
+
+    /// The public key of the identity key (if any)
+    pub fn node_id(&self) -> Option<PublicKey> {
+        self.identity_key.as_ref().map(PublicKey::from)
+    }
+
+    /// Checks the peer ID against the `tcp://` address and the allowlist
+    /// (in constant time)
+    ///
+    /// The secret connection of `tendermint-p2p` doesn't take the expected peer ID,
+    /// so it's checked once the handshake is done: a node with another ID completes
+    /// the handshake, but the connection is dropped before any request is read from it.
+    /// Without a peer ID in the address and without `allowed_peer_ids`, any node
+    /// completing the handshake is accepted.
+    fn verify_peer_id(&self, actual_peer_id: node::Id) -> Result<(), Error> {
+        let expected_peer_id = match &self.address {
+            net::Address::Tcp { peer_id, .. } => peer_id.as_ref(),
+            net::Address::Unix { .. } => None,
+        };
+        if let Some(expected_peer_id) = expected_peer_id {
+            if expected_peer_id.ct_eq(&actual_peer_id).unwrap_u8() == 0 {
+                fail!(
+                    ErrorKind::AccessError,
+                    "validator peer ID mismatch! (expected {}, got {})",
+                    expected_peer_id,
+                    actual_peer_id
+                );
+            }
+        }
+        if !self.allowed_peer_ids.is_empty() {
+            // all entries are compared, so the time doesn't depend on the matching one
+            let allowed = self
+                .allowed_peer_ids
+                .iter()
+                .fold(Choice::from(0), |allowed, id| {
+                    allowed | id.ct_eq(&actual_peer_id)
+                });
+            if allowed.unwrap_u8() == 0 {
+                fail!(
+                    ErrorKind::AccessError,
+                    "validator peer ID {} is not allowed",
+                    actual_peer_id
+                );
+            }
+        } else if expected_peer_id.is_none() {
+            warn!(
+                "[{}] unverified validator peer ID! ({})",
+                &self.address, actual_peer_id
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// in-memory stream answering with the written data
+    #[derive(Clone, Default)]
+    struct Echo(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Read for Echo {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut data = self.0.lock().unwrap();
+            let n = buf.len().min(data.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            data.drain(..n);
+            Ok(n)
+        }
+    }
+
+    impl io::Write for Echo {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Stream for Echo {}
+
+    fn peer_id(byte: u8) -> node::Id {
+        node::Id::new([byte; 20])
+    }
+
+    fn tcp_manager(peer_id: Option<node::Id>) -> ConnectionManager {
+        let address = net::Address::Tcp {
+            peer_id,
+            host: "127.0.0.1".to_owned(),
+            port: 26658,
+        };
+        assert!(ConnectionManager::new(address.clone(), None).is_err());
+        let secret = ed25519::SecretKey::from_bytes(&[1u8; 32]).unwrap();
+        let public = ed25519::PublicKey::from(&secret);
+        let identity_key = Some(ed25519::Keypair { secret, public });
+        ConnectionManager::new(address, identity_key).unwrap()
+    }
+
+    #[test]
+    fn verifies_peer_ids() {
+        let manager = tcp_manager(Some(peer_id(1)));
+        assert!(manager.verify_peer_id(peer_id(1)).is_ok());
+        assert!(manager.verify_peer_id(peer_id(2)).is_err());
+
+        let mut manager = tcp_manager(None);
+        assert!(manager.verify_peer_id(peer_id(2)).is_ok());
+        manager.set_allowed_peer_ids(vec![peer_id(1), peer_id(3)]);
+        assert!(manager.verify_peer_id(peer_id(3)).is_ok());
+        assert!(manager.verify_peer_id(peer_id(2)).is_err());
+    }
+
+    #[test]
+    fn opens_plain_connections_with_injected_transport() {
+        let echo = Echo::default();
+        let stream = echo.clone();
+        let mut manager = ConnectionManager::new(
+            net::Address::Unix {
+                path: "tendermint".into(),
+            },
+            None,
+        )
+        .unwrap();
+        manager.set_transport(Box::new(
+            move |_: &net::Address, _: Option<Duration>| -> io::Result<Box<dyn Stream>> {
+                Ok(Box::new(stream.clone()))
+            },
+        ));
+        let mut connection = manager.connect().unwrap();
+        io::Write::write_all(&mut connection, b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        io::Read::read_exact(&mut connection, &mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        assert!(echo.0.lock().unwrap().is_empty());
+    }
+}
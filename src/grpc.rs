@@ -0,0 +1,360 @@
+//! Privval gRPC server (Tendermint 0.35+)
+//! Copyright (c) 2021, Foris Limited (licensed under the Apache License, Version 2.0)
+//!
+//! The node calls the signer over gRPC instead of waiting for it to connect.
+//! The server relays each call as a raw privval message to the signing session,
+//! which connects to a Unix socket of the server as it would to a node,
+//! so the session's chain ID, double-sign and height checks apply unchanged
+//! (and the sessions in enclaves reach it through their usual vsock or usercall streams).
+
+use crate::config::grpc::GrpcConfig;
+use crate::error::{Error, ErrorKind};
+use crate::rpc::MsgReader;
+use anomaly::{fail, format_err};
+use prost::Message as _;
+use std::{
+    fs,
+    io::Write,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::Duration,
+};
+use tendermint_proto::privval::{
+    message::Sum, Message as PrivMessage, PubKeyRequest, PubKeyResponse, RemoteSignerError,
+    SignProposalRequest, SignVoteRequest, SignedProposalResponse, SignedVoteResponse,
+};
+use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
+use tonic::{Request, Response, Status};
+use tracing::{debug, error, info, warn};
+
+/// code generated from `proto/privval_service.proto`
+mod service {
+    tonic::include_proto!("tendermint.privval");
+}
+
+use service::priv_validator_api_server::{PrivValidatorApi, PrivValidatorApiServer};
+
+/// How long a call waits for the signing session to (re)connect
+const SESSION_WAIT: Duration = Duration::from_secs(5);
+
+/// Relays the calls to the signing session connected to its Unix socket
+struct Relay {
+    /// the session's connection (replaced whenever the session reconnects)
+    connection: Mutex<Option<(UnixStream, MsgReader)>>,
+    /// notified when the session connects
+    connected: Condvar,
+}
+
+impl Relay {
+    /// Accepts the connections of the session (on its own thread, so that
+    /// the calls never wait for a connection while holding the lock)
+    fn accept_loop(&self, listener: UnixListener) {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    debug!("signing session connected");
+                    // a previous connection was dropped by the session (e.g. after an error)
+                    *self.connection.lock().unwrap_or_else(|e| e.into_inner()) =
+                        Some((stream, MsgReader::default()));
+                    self.connected.notify_all();
+                }
+                Err(e) => warn!("signing session connection failed: {}", e),
+            }
+        }
+    }
+
+    /// Sends the request to the session and waits for its response
+    /// (the calls are relayed one at a time, as the session answers them in order)
+    fn call(&self, request: Sum) -> Result<Sum, Box<Status>> {
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut connection, _) = self
+            .connected
+            .wait_timeout_while(connection, SESSION_WAIT, |connection| connection.is_none())
+            .unwrap_or_else(|e| e.into_inner());
+        let (stream, msg_reader) = connection
+            .as_mut()
+            .ok_or_else(|| Box::new(Status::unavailable("signing session not connected")))?;
+        match exchange(stream, msg_reader, request) {
+            Ok(response) => Ok(response),
+            Err(e) => {
+                warn!("signing session connection failed: {}", e);
+                *connection = None;
+                Err(Box::new(Status::unavailable(format!(
+                    "signing session connection failed: {}",
+                    e
+                ))))
+            }
+        }
+    }
+}
+
+/// Writes the request and reads the response of the session
+fn exchange(
+    stream: &mut UnixStream,
+    msg_reader: &mut MsgReader,
+    request: Sum,
+) -> Result<Sum, Error> {
+    let mut buf = Vec::new();
+    PrivMessage { sum: Some(request) }
+        .encode_length_delimited(&mut buf)
+        .map_err(|e| format_err!(ErrorKind::ProtocolError, "failed to encode request: {}", e))?;
+    stream
+        .write_all(&buf)
+        .map_err(|e| format_err!(ErrorKind::IoError, "write request failed: {}", e))?;
+    let msg = msg_reader.read_msg(stream)?;
+    PrivMessage::decode_length_delimited(msg.as_ref())
+        .map_err(|e| format_err!(ErrorKind::ProtocolError, "malformed message packet: {}", e))?
+        .sum
+        .ok_or_else(|| format_err!(ErrorKind::ProtocolError, "empty response").into())
+}
+
+/// Errors of the session (e.g. a double sign) are returned as the call's status
+fn check(error: Option<RemoteSignerError>) -> Result<(), Box<Status>> {
+    match error {
+        Some(error) => Err(Box::new(Status::invalid_argument(format!(
+            "{} (code {})",
+            error.description, error.code
+        )))),
+        None => Ok(()),
+    }
+}
+
+fn unexpected(response: Sum) -> Status {
+    Status::internal(format!("unexpected response: {:?}", response))
+}
+
+/// gRPC service of the relay
+struct RelayService(Arc<Relay>);
+
+impl RelayService {
+    async fn relay(&self, request: Sum) -> Result<Sum, Box<Status>> {
+        let relay = self.0.clone();
+        tokio::task::spawn_blocking(move || relay.call(request))
+            .await
+            .map_err(|e| Box::new(Status::internal(format!("relay task failed: {}", e))))?
+    }
+}
+
+#[tonic::async_trait]
+impl PrivValidatorApi for RelayService {
+    async fn get_pub_key(
+        &self,
+        request: Request<PubKeyRequest>,
+    ) -> Result<Response<PubKeyResponse>, Status> {
+        match self
+            .relay(Sum::PubKeyRequest(request.into_inner()))
+            .await
+            .map_err(|e| *e)?
+        {
+            Sum::PubKeyResponse(mut response) => {
+                check(response.error.take()).map_err(|e| *e)?;
+                Ok(Response::new(response))
+            }
+            other => Err(unexpected(other)),
+        }
+    }
+
+    async fn sign_vote(
+        &self,
+        request: Request<SignVoteRequest>,
+    ) -> Result<Response<SignedVoteResponse>, Status> {
+        match self
+            .relay(Sum::SignVoteRequest(request.into_inner()))
+            .await
+            .map_err(|e| *e)?
+        {
+            Sum::SignedVoteResponse(mut response) => {
+                check(response.error.take()).map_err(|e| *e)?;
+                Ok(Response::new(response))
+            }
+            other => Err(unexpected(other)),
+        }
+    }
+
+    async fn sign_proposal(
+        &self,
+        request: Request<SignProposalRequest>,
+    ) -> Result<Response<SignedProposalResponse>, Status> {
+        match self
+            .relay(Sum::SignProposalRequest(request.into_inner()))
+            .await
+            .map_err(|e| *e)?
+        {
+            Sum::SignedProposalResponse(mut response) => {
+                check(response.error.take()).map_err(|e| *e)?;
+                Ok(Response::new(response))
+            }
+            other => Err(unexpected(other)),
+        }
+    }
+}
+
+/// TLS settings of the server (if the certificate is set)
+fn tls_config(config: &GrpcConfig, allow_insecure: bool) -> Result<Option<ServerTlsConfig>, Error> {
+    let (cert_path, key_path) = match (&config.cert_path, &config.key_path) {
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        (None, None) if config.client_ca_path.is_none() => return Ok(None),
+        _ => fail!(
+            ErrorKind::ConfigError,
+            "gRPC TLS needs both `cert_path` and `key_path`"
+        ),
+    };
+    let read = |path: &Path, secret: bool| {
+        let contents = if secret {
+            crate::secure_file::read(path, allow_insecure)
+        } else {
+            fs::read(path)
+        };
+        contents.map_err(|e| format_err!(ErrorKind::IoError, "{}: {}", path.display(), e))
+    };
+    let mut tls = ServerTlsConfig::new().identity(Identity::from_pem(
+        read(cert_path, false)?,
+        read(key_path, true)?,
+    ));
+    if let Some(client_ca_path) = &config.client_ca_path {
+        tls = tls.client_ca_root(Certificate::from_pem(read(client_ca_path, false)?));
+    }
+    Ok(Some(tls))
+}
+
+/// Starts the gRPC server (on its own thread) relaying the calls
+/// to the signing session that connects to the Unix socket at `socket_path`
+/// (the private key file is refused if others could access it, unless `allow_insecure` is set)
+pub fn spawn(
+    config: &GrpcConfig,
+    socket_path: &Path,
+    allow_insecure: bool,
+) -> Result<thread::JoinHandle<()>, Error> {
+    let tls = tls_config(config, allow_insecure)?;
+    // a socket left behind by a previous run
+    if let Ok(metadata) = fs::symlink_metadata(socket_path) {
+        if metadata.file_type().is_socket() {
+            let _ = fs::remove_file(socket_path);
+        }
+    }
+    let listener = UnixListener::bind(socket_path).map_err(|e| {
+        format_err!(
+            ErrorKind::IoError,
+            "error binding relay socket {}: {}",
+            socket_path.display(),
+            e
+        )
+    })?;
+    // only the signing session (running as the same user) may connect
+    fs::set_permissions(socket_path, fs::Permissions::from_mode(0o600)).map_err(|e| {
+        format_err!(
+            ErrorKind::IoError,
+            "error restricting relay socket {}: {}",
+            socket_path.display(),
+            e
+        )
+    })?;
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| format_err!(ErrorKind::IoError, "failed to start gRPC runtime: {}", e))?;
+    let mut server = Server::builder();
+    if let Some(tls) = tls {
+        server = server
+            .tls_config(tls)
+            .map_err(|e| format_err!(ErrorKind::ConfigError, "invalid gRPC TLS config: {}", e))?;
+    }
+    let relay = Arc::new(Relay {
+        connection: Mutex::new(None),
+        connected: Condvar::new(),
+    });
+    let acceptor = relay.clone();
+    thread::spawn(move || acceptor.accept_loop(listener));
+    let router = server.add_service(PrivValidatorApiServer::new(RelayService(relay)));
+    let listen_addr = config.listen_addr;
+    info!(
+        "serving privval gRPC on {} (relayed to {})",
+        listen_addr,
+        socket_path.display()
+    );
+    Ok(thread::spawn(move || {
+        if let Err(e) = runtime.block_on(router.serve(listen_addr)) {
+            error!("privval gRPC server failed: {}", e);
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::service::priv_validator_api_client::PrivValidatorApiClient;
+    use super::*;
+    use std::net::TcpListener;
+    use std::time::Duration;
+    use tendermint_proto::types::Vote;
+
+    #[test]
+    fn relays_calls_to_the_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("relay.sock");
+        let listen_addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let config = GrpcConfig {
+            listen_addr,
+            cert_path: None,
+            key_path: None,
+            client_ca_path: None,
+        };
+        spawn(&config, &socket_path, false).unwrap();
+
+        // a session rejecting the vote (as if it were a double sign)
+        let mut session = UnixStream::connect(&socket_path).unwrap();
+        thread::spawn(move || {
+            let mut msg_reader = MsgReader::default();
+            let request = msg_reader.read_msg(&mut session).unwrap();
+            let request = PrivMessage::decode_length_delimited(request.as_ref()).unwrap();
+            assert!(matches!(request.sum, Some(Sum::SignVoteRequest(_))));
+            let response = Sum::SignedVoteResponse(SignedVoteResponse {
+                vote: None,
+                error: Some(RemoteSignerError {
+                    code: 2,
+                    description: "double signing requested".to_owned(),
+                }),
+            });
+            let mut buf = Vec::new();
+            PrivMessage {
+                sum: Some(response),
+            }
+            .encode_length_delimited(&mut buf)
+            .unwrap();
+            session.write_all(&buf).unwrap();
+        });
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let status = runtime.block_on(async {
+            let mut client = loop {
+                match PrivValidatorApiClient::connect(format!("http://{}", listen_addr)).await {
+                    Ok(client) => break client,
+                    // the server may not be listening yet
+                    Err(_) => thread::sleep(Duration::from_millis(50)),
+                }
+            };
+            let vote = Vote {
+                r#type: 1,
+                height: 1,
+                ..Default::default()
+            };
+            client
+                .sign_vote(SignVoteRequest {
+                    vote: Some(vote),
+                    chain_id: "testchain-1".to_owned(),
+                })
+                .await
+                .unwrap_err()
+        });
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(status.message().contains("double signing requested"));
+    }
+}
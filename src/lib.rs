@@ -1,7 +1,17 @@
+pub mod admin;
+pub mod audit;
 pub mod chain;
 pub mod config;
 pub mod connection;
 pub mod error;
+#[cfg(all(feature = "grpc", unix))]
+pub mod grpc;
+pub mod metrics;
 mod rpc;
+#[cfg(unix)]
+pub mod secure_file;
 pub mod session;
+pub mod signer;
+#[cfg(feature = "threshold")]
+pub mod threshold;
 pub mod utils;
@@ -0,0 +1,445 @@
+//! Signing activity metrics (exposed in the Prometheus text format)
+//! Copyright (c) 2021, Foris Limited (licensed under the Apache License, Version 2.0)
+//!
+//! Sessions report `MetricEvent`s to a `MetricsSink`. On the host, the sink
+//! is usually the `Metrics` registry; enclaves forward the events to the host
+//! over a separate stream (SGX) or their log channel (Nitro).
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use tendermint::chain;
+use tracing::{debug, info, warn};
+
+/// `tracing` target of the log records that carry metric events
+/// (used for forwarding them over a log channel)
+pub const METRICS_LOG_TARGET: &str = "tmkms_light::metrics";
+
+/// Upper bounds (in seconds) of the duration histogram buckets
+const DURATION_BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+/// Type of the signed message
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MsgType {
+    /// block proposal
+    Proposal,
+    /// prevote
+    Prevote,
+    /// precommit
+    Precommit,
+}
+
+impl MsgType {
+    /// The message type of the consensus state step
+    /// (0: proposal, 1: prevote, 2: precommit)
+    pub fn from_step(step: i8) -> Self {
+        match step {
+            0 => MsgType::Proposal,
+            1 => MsgType::Prevote,
+            _ => MsgType::Precommit,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            MsgType::Proposal => "proposal",
+            MsgType::Prevote => "prevote",
+            MsgType::Precommit => "precommit",
+        }
+    }
+}
+
+/// Signing activity reported by sessions
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum MetricEvent {
+    /// a new signature was produced
+    Signed {
+        chain_id: chain::Id,
+        msg_type: MsgType,
+        height: u64,
+        round: u32,
+        /// time taken by the signing backend
+        duration_us: u64,
+    },
+    /// a double sign attempt was refused
+    DoubleSignRefused {
+        chain_id: chain::Id,
+        msg_type: MsgType,
+    },
+    /// a request for a different chain id was refused
+    ChainIdError { chain_id: chain::Id },
+    /// the consensus state was checked and persisted
+    StatePersisted {
+        chain_id: chain::Id,
+        duration_us: u64,
+    },
+    /// the session was reconnected to the validator node
+    Reconnect { chain_id: chain::Id },
+    /// a ping request was answered
+    Ping { chain_id: chain::Id },
+}
+
+/// Receiver of the metric events
+pub trait MetricsSink: Send + Sync {
+    /// Records the event
+    fn record(&self, event: MetricEvent);
+}
+
+/// Forwards the events as `tracing` records with the `METRICS_LOG_TARGET` target
+/// (the JSON-encoded event is the message)
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TracingSink;
+
+impl MetricsSink for TracingSink {
+    fn record(&self, event: MetricEvent) {
+        match serde_json::to_string(&event) {
+            Ok(json) => info!(target: METRICS_LOG_TARGET, "{}", json),
+            Err(e) => warn!("failed to serialize metric event: {}", e),
+        }
+    }
+}
+
+/// Cumulative histogram of durations
+#[derive(Clone, Debug, Default)]
+struct Histogram {
+    buckets: [u64; DURATION_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration_us: u64) {
+        let seconds = duration_us as f64 / 1_000_000.0;
+        for (bucket, upper_bound) in self.buckets.iter_mut().zip(DURATION_BUCKETS.iter()) {
+            if seconds <= *upper_bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+/// Current values of all metrics
+#[derive(Debug, Default)]
+struct Registry {
+    signatures: BTreeMap<(String, MsgType), u64>,
+    double_sign_refusals: BTreeMap<(String, MsgType), u64>,
+    chain_id_errors: BTreeMap<String, u64>,
+    signing_duration: BTreeMap<String, Histogram>,
+    state_persist_duration: BTreeMap<String, Histogram>,
+    last_signed: BTreeMap<String, (u64, u32)>,
+    reconnects: BTreeMap<String, u64>,
+    pings: BTreeMap<String, u64>,
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn write_counters(out: &mut String, name: &str, help: &str, values: &BTreeMap<String, u64>) {
+    write_header(out, name, "counter", help);
+    for (chain_id, value) in values.iter() {
+        let _ = writeln!(out, "{}{{chain_id=\"{}\"}} {}", name, chain_id, value);
+    }
+}
+
+fn write_typed_counters(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    values: &BTreeMap<(String, MsgType), u64>,
+) {
+    write_header(out, name, "counter", help);
+    for ((chain_id, msg_type), value) in values.iter() {
+        let _ = writeln!(
+            out,
+            "{}{{chain_id=\"{}\",type=\"{}\"}} {}",
+            name,
+            chain_id,
+            msg_type.as_str(),
+            value
+        );
+    }
+}
+
+fn write_histograms(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    values: &BTreeMap<String, Histogram>,
+) {
+    write_header(out, name, "histogram", help);
+    for (chain_id, histogram) in values.iter() {
+        for (bucket, upper_bound) in histogram.buckets.iter().zip(DURATION_BUCKETS.iter()) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{chain_id=\"{}\",le=\"{}\"}} {}",
+                name, chain_id, upper_bound, bucket
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{chain_id=\"{}\",le=\"+Inf\"}} {}",
+            name, chain_id, histogram.count
+        );
+        let _ = writeln!(
+            out,
+            "{}_sum{{chain_id=\"{}\"}} {}",
+            name, chain_id, histogram.sum
+        );
+        let _ = writeln!(
+            out,
+            "{}_count{{chain_id=\"{}\"}} {}",
+            name, chain_id, histogram.count
+        );
+    }
+}
+
+/// In-memory metrics registry
+#[derive(Debug, Default)]
+pub struct Metrics {
+    registry: Mutex<Registry>,
+}
+
+impl MetricsSink for Metrics {
+    fn record(&self, event: MetricEvent) {
+        let mut registry = match self.registry.lock() {
+            Ok(registry) => registry,
+            Err(e) => e.into_inner(),
+        };
+        match event {
+            MetricEvent::Signed {
+                chain_id,
+                msg_type,
+                height,
+                round,
+                duration_us,
+            } => {
+                let chain_id = chain_id.to_string();
+                *registry
+                    .signatures
+                    .entry((chain_id.clone(), msg_type))
+                    .or_default() += 1;
+                registry
+                    .signing_duration
+                    .entry(chain_id.clone())
+                    .or_default()
+                    .observe(duration_us);
+                registry.last_signed.insert(chain_id, (height, round));
+            }
+            MetricEvent::DoubleSignRefused { chain_id, msg_type } => {
+                *registry
+                    .double_sign_refusals
+                    .entry((chain_id.to_string(), msg_type))
+                    .or_default() += 1;
+            }
+            MetricEvent::ChainIdError { chain_id } => {
+                *registry
+                    .chain_id_errors
+                    .entry(chain_id.to_string())
+                    .or_default() += 1;
+            }
+            MetricEvent::StatePersisted {
+                chain_id,
+                duration_us,
+            } => {
+                registry
+                    .state_persist_duration
+                    .entry(chain_id.to_string())
+                    .or_default()
+                    .observe(duration_us);
+            }
+            MetricEvent::Reconnect { chain_id } => {
+                *registry.reconnects.entry(chain_id.to_string()).or_default() += 1;
+            }
+            MetricEvent::Ping { chain_id } => {
+                *registry.pings.entry(chain_id.to_string()).or_default() += 1;
+            }
+        }
+    }
+}
+
+impl Metrics {
+    /// Renders all metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let registry = match self.registry.lock() {
+            Ok(registry) => registry,
+            Err(e) => e.into_inner(),
+        };
+        let mut out = String::new();
+        write_typed_counters(
+            &mut out,
+            "tmkms_signatures_total",
+            "Number of produced signatures",
+            &registry.signatures,
+        );
+        write_typed_counters(
+            &mut out,
+            "tmkms_double_sign_refusals_total",
+            "Number of refused double sign attempts",
+            &registry.double_sign_refusals,
+        );
+        write_counters(
+            &mut out,
+            "tmkms_chain_id_errors_total",
+            "Number of refused requests for a different chain id",
+            &registry.chain_id_errors,
+        );
+        write_histograms(
+            &mut out,
+            "tmkms_signing_duration_seconds",
+            "Time taken by the signing backend",
+            &registry.signing_duration,
+        );
+        write_histograms(
+            &mut out,
+            "tmkms_state_persist_duration_seconds",
+            "Time taken to check and persist the consensus state",
+            &registry.state_persist_duration,
+        );
+        write_header(
+            &mut out,
+            "tmkms_last_signed_height",
+            "gauge",
+            "Height of the last signed message",
+        );
+        for (chain_id, (height, _)) in registry.last_signed.iter() {
+            let _ = writeln!(
+                out,
+                "tmkms_last_signed_height{{chain_id=\"{}\"}} {}",
+                chain_id, height
+            );
+        }
+        write_header(
+            &mut out,
+            "tmkms_last_signed_round",
+            "gauge",
+            "Round of the last signed message",
+        );
+        for (chain_id, (_, round)) in registry.last_signed.iter() {
+            let _ = writeln!(
+                out,
+                "tmkms_last_signed_round{{chain_id=\"{}\"}} {}",
+                chain_id, round
+            );
+        }
+        write_counters(
+            &mut out,
+            "tmkms_reconnects_total",
+            "Number of reconnections to the validator node",
+            &registry.reconnects,
+        );
+        write_counters(
+            &mut out,
+            "tmkms_pings_total",
+            "Number of answered ping requests",
+            &registry.pings,
+        );
+        out
+    }
+
+    /// Parses a metric event forwarded in a log message (see `TracingSink`)
+    /// and records it
+    pub fn record_log_message(&self, message: &str) -> Result<(), serde_json::Error> {
+        let event = serde_json::from_str(message)?;
+        self.record(event);
+        Ok(())
+    }
+
+    /// Serves the metrics on `GET /metrics` (in a background thread)
+    pub fn serve(self: Arc<Self>, addr: SocketAddr) -> io::Result<thread::JoinHandle<()>> {
+        let listener = TcpListener::bind(addr)?;
+        info!("serving metrics on http://{}/metrics", addr);
+        Ok(thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = self.respond(stream) {
+                            debug!("metrics request failed: {}", e);
+                        }
+                    }
+                    Err(e) => warn!("metrics connection failed: {}", e),
+                }
+            }
+        }))
+    }
+
+    /// Answers a single HTTP request
+    fn respond(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut buf = [0u8; 1024];
+        let n = stream.read(&mut buf)?;
+        let (status, body) = if buf[..n].starts_with(b"GET /metrics ") {
+            ("200 OK", self.render())
+        } else {
+            ("404 Not Found", String::new())
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\n",
+            status
+        )?;
+        write!(
+            stream,
+            "Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn renders_recorded_events() {
+        let chain_id = chain::Id::try_from("testchain-1".to_owned()).unwrap();
+        let metrics = Metrics::default();
+        let signed = MetricEvent::Signed {
+            chain_id: chain_id.clone(),
+            msg_type: MsgType::Prevote,
+            height: 10,
+            round: 2,
+            duration_us: 700,
+        };
+        metrics
+            .record_log_message(&serde_json::to_string(&signed).unwrap())
+            .unwrap();
+        metrics.record(MetricEvent::DoubleSignRefused {
+            chain_id: chain_id.clone(),
+            msg_type: MsgType::Precommit,
+        });
+        metrics.record(MetricEvent::Ping { chain_id });
+        let out = metrics.render();
+        let signatures = "tmkms_signatures_total{chain_id=\"testchain-1\",type=\"prevote\"} 1";
+        assert!(out.contains(signatures));
+        assert!(out.contains(
+            "tmkms_double_sign_refusals_total{chain_id=\"testchain-1\",type=\"precommit\"} 1"
+        ));
+        assert!(out.contains(
+            "tmkms_signing_duration_seconds_bucket{chain_id=\"testchain-1\",le=\"0.0005\"} 0"
+        ));
+        assert!(out.contains(
+            "tmkms_signing_duration_seconds_bucket{chain_id=\"testchain-1\",le=\"0.001\"} 1"
+        ));
+        assert!(out.contains("tmkms_last_signed_height{chain_id=\"testchain-1\"} 10"));
+        assert!(out.contains("tmkms_last_signed_round{chain_id=\"testchain-1\"} 2"));
+        assert!(out.contains("tmkms_pings_total{chain_id=\"testchain-1\"} 1"));
+    }
+}
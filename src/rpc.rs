@@ -2,22 +2,39 @@
 //! Copyright (c) 2018-2021 Iqlusion Inc. (licensed under the Apache License, Version 2.0)
 //! Modifications Copyright (c) 2021, Foris Limited (licensed under the Apache License, Version 2.0)
 
+mod amino;
+#[cfg(feature = "bls")]
+mod bls12_381;
+mod extension;
+
+#[cfg(feature = "threshold")]
+pub use amino::CanonicalBlockId as AminoCanonicalBlockId;
+pub use amino::{CanonicalProposal as AminoCanonicalProposal, CanonicalVote as AminoCanonicalVote};
+#[cfg(feature = "threshold")]
+pub use extension::CanonicalVoteExtension;
+pub use extension::{SignedExtension, VoteExtension};
+
+use crate::config::validator::ProtocolVersion;
 use crate::error::{Error, ErrorKind};
+use crate::signer::ConsensusPublicKey;
 use anomaly::{fail, format_err};
 use prost::Message as _;
 use std::convert::TryFrom;
 use std::io::Read;
-use tendermint::proposal::{SignProposalRequest, SignedProposalResponse};
+use tendermint::chain;
+use tendermint::proposal::SignProposalRequest;
 use tendermint::public_key::{PubKeyRequest, PublicKey};
-use tendermint::vote::{SignVoteRequest, SignedVoteResponse};
+use tendermint::vote::SignVoteRequest;
 use tendermint_p2p::secret_connection::DATA_MAX_SIZE;
 use tendermint_proto::{
     crypto::{public_key::Sum as PkSum, PublicKey as RawPublicKey},
+    google::protobuf::Timestamp,
     privval::{
         message::Sum, Message as PrivMessage, PingRequest, PingResponse, PubKeyResponse,
         RemoteSignerError, SignedProposalResponse as RawProposalResponse,
         SignedVoteResponse as RawVoteResponse,
     },
+    types::{Proposal as RawProposal, Vote as RawVote},
 };
 
 /// Requests to the KMS
@@ -25,7 +42,8 @@ use tendermint_proto::{
 pub enum Request {
     /// Sign the given message
     SignProposal(SignProposalRequest),
-    SignVote(SignVoteRequest),
+    /// (with the vote extension of a v0.38 precommit)
+    SignVote(SignVoteRequest, Option<VoteExtension>),
     ShowPublicKey(PubKeyRequest),
 
     // PingRequest is a PrivValidatorSocket message to keep the connection alive.
@@ -33,14 +51,27 @@ pub enum Request {
 }
 
 impl Request {
-    /// Read a request from the given readable
-    pub fn read(conn: &mut impl Read) -> Result<Self, Error> {
-        let msg = read_msg(conn)?;
+    /// Read a request of the given protocol version from the given readable
+    /// (any bytes of the following messages are kept in the reader's buffer;
+    /// the chain ID is only used for the Amino-encoded requests, which don't carry it)
+    pub fn read(
+        conn: &mut impl Read,
+        reader: &mut MsgReader,
+        version: ProtocolVersion,
+        chain_id: &chain::Id,
+    ) -> Result<Self, Error> {
+        let raw_msg = reader.read_msg(conn)?;
 
-        // Parse Protobuf-encoded request message
-        let msg = PrivMessage::decode_length_delimited(msg.as_ref())
-            .map_err(|e| format_err!(ErrorKind::ProtocolError, "malformed message packet: {}", e))?
-            .sum;
+        let msg = if version.is_protobuf() {
+            // Parse Protobuf-encoded request message
+            PrivMessage::decode_length_delimited(raw_msg.as_ref())
+                .map_err(|e| {
+                    format_err!(ErrorKind::ProtocolError, "malformed message packet: {}", e)
+                })?
+                .sum
+        } else {
+            Some(amino::decode_request(&raw_msg, chain_id)?)
+        };
 
         match msg {
             Some(Sum::SignVoteRequest(req)) => {
@@ -51,7 +82,12 @@ impl Request {
                         e
                     )
                 })?;
-                Ok(Request::SignVote(svr))
+                let extension = if version.has_vote_extensions() {
+                    VoteExtension::from_request(&raw_msg, &svr.vote)?
+                } else {
+                    None
+                };
+                Ok(Request::SignVote(svr, extension))
             }
             Some(Sum::SignProposalRequest(spr)) => {
                 let spr = SignProposalRequest::try_from(spr).map_err(|e| {
@@ -82,13 +118,13 @@ impl Request {
 /// Responses from the KMS
 #[derive(Debug)]
 pub enum Response {
-    /// Signature response
-    SignedVote(SignedVoteResponse),
+    /// Signature response (with the signed vote extension of a v0.38 precommit)
+    SignedVote(RawVoteResponse, Option<SignedExtension>),
     SignedVoteError(RemoteSignerError),
-    SignedProposal(SignedProposalResponse),
+    SignedProposal(RawProposalResponse),
     SignedProposalError(RemoteSignerError),
     Ping(PingResponse),
-    PublicKey(PublicKey),
+    PublicKey(ConsensusPublicKey),
     PublicKeyError(RemoteSignerError),
 }
 
@@ -98,6 +134,12 @@ pub enum DoubleSignErrorType {
     Proposal,
 }
 
+/// possible options for signing paused error
+pub enum PausedErrorType {
+    Vote,
+    Proposal,
+}
+
 /// possible options for chain id error
 pub enum ChainIdErrorType {
     Pubkey,
@@ -106,24 +148,62 @@ pub enum ChainIdErrorType {
 }
 
 impl Response {
-    /// signed vote
-    pub fn vote_response(vote: SignVoteRequest, signature: ed25519_dalek::Signature) -> Self {
-        let mut vote = vote.vote;
-        vote.signature = signature.into();
-        Response::SignedVote(SignedVoteResponse {
-            vote: Some(vote),
+    /// signed vote (the raw signature is produced by the consensus signer)
+    pub fn vote_response(
+        vote: SignVoteRequest,
+        signature: Vec<u8>,
+        extension: Option<SignedExtension>,
+    ) -> Self {
+        let mut vote = RawVote::from(vote.vote);
+        vote.signature = signature;
+        Response::SignedVote(
+            RawVoteResponse {
+                vote: Some(vote),
+                error: None,
+            },
+            extension,
+        )
+    }
+
+    /// previously signed vote (with its original timestamp)
+    pub fn vote_response_with_timestamp(
+        vote: SignVoteRequest,
+        signature: Vec<u8>,
+        timestamp: Option<Timestamp>,
+        extension: Option<SignedExtension>,
+    ) -> Self {
+        let mut vote = RawVote::from(vote.vote);
+        vote.signature = signature;
+        vote.timestamp = timestamp;
+        Response::SignedVote(
+            RawVoteResponse {
+                vote: Some(vote),
+                error: None,
+            },
+            extension,
+        )
+    }
+
+    /// signed proposal (the raw signature is produced by the consensus signer)
+    pub fn proposal_response(proposal: SignProposalRequest, signature: Vec<u8>) -> Self {
+        let mut proposal = RawProposal::from(proposal.proposal);
+        proposal.signature = signature;
+        Response::SignedProposal(RawProposalResponse {
+            proposal: Some(proposal),
             error: None,
         })
     }
 
-    /// signed proposal
-    pub fn proposal_response(
+    /// previously signed proposal (with its original timestamp)
+    pub fn proposal_response_with_timestamp(
         proposal: SignProposalRequest,
-        signature: ed25519_dalek::Signature,
+        signature: Vec<u8>,
+        timestamp: Option<Timestamp>,
     ) -> Self {
-        let mut proposal = proposal.proposal;
-        proposal.signature = signature.into();
-        Response::SignedProposal(SignedProposalResponse {
+        let mut proposal = RawProposal::from(proposal.proposal);
+        proposal.signature = signature;
+        proposal.timestamp = timestamp;
+        Response::SignedProposal(RawProposalResponse {
             proposal: Some(proposal),
             error: None,
         })
@@ -141,6 +221,18 @@ impl Response {
         }
     }
 
+    /// signing paused (via the admin socket) error
+    pub fn signing_paused(req_type: PausedErrorType) -> Self {
+        let error = RemoteSignerError {
+            code: 0,
+            description: "signing is paused".to_owned(),
+        };
+        match req_type {
+            PausedErrorType::Vote => Self::SignedVoteError(error),
+            PausedErrorType::Proposal => Self::SignedProposalError(error),
+        }
+    }
+
     /// invalid chain id error
     pub fn invalid_chain_id(req_type: ChainIdErrorType, chain_id: &tendermint::chain::Id) -> Self {
         let error = RemoteSignerError {
@@ -154,19 +246,42 @@ impl Response {
         }
     }
 
-    /// Encode response to bytes
-    pub fn encode(self) -> Result<Vec<u8>, Error> {
+    /// Encode response to bytes (of the given protocol version)
+    pub fn encode(self, version: ProtocolVersion) -> Result<Vec<u8>, Error> {
         let mut buf = Vec::new();
 
         let msg = match self {
-            Response::SignedVote(resp) => Sum::SignedVoteResponse(resp.into()),
-            Response::SignedProposal(resp) => Sum::SignedProposalResponse(resp.into()),
+            // the v0.34 types have no vote extension fields
+            Response::SignedVote(resp, Some(signed_extension)) => {
+                return extension::encode_response(resp, signed_extension)
+            }
+            Response::SignedVote(resp, None) => Sum::SignedVoteResponse(resp),
+            Response::SignedProposal(resp) => Sum::SignedProposalResponse(resp),
             Response::Ping(_) => Sum::PingResponse(PingResponse {}),
-            Response::PublicKey(pk) => {
+            // the v0.34 types have no BLS12-381 keys
+            #[cfg(feature = "bls")]
+            Response::PublicKey(ConsensusPublicKey::Bls12_381(pk)) => {
+                if version < crate::signer::KeyType::Bls12_381.min_protocol_version() {
+                    fail!(
+                        ErrorKind::ProtocolError,
+                        "BLS12-381 keys are not supported by {} nodes",
+                        version
+                    );
+                }
+                return bls12_381::encode_response(pk);
+            }
+            Response::PublicKey(ConsensusPublicKey::Tendermint(pk)) => {
+                let sum = match pk {
+                    PublicKey::Ed25519(_) => PkSum::Ed25519(pk.to_bytes()),
+                    PublicKey::Secp256k1(_) => PkSum::Secp256k1(pk.to_bytes()),
+                    _ => fail!(
+                        ErrorKind::ProtocolError,
+                        "unsupported consensus public key: {:?}",
+                        pk
+                    ),
+                };
                 let pkr = PubKeyResponse {
-                    pub_key: Some(RawPublicKey {
-                        sum: Some(PkSum::Ed25519(pk.to_bytes())),
-                    }),
+                    pub_key: Some(RawPublicKey { sum: Some(sum) }),
                     error: None,
                 };
                 Sum::PubKeyResponse(pkr)
@@ -187,6 +302,9 @@ impl Response {
             }),
         };
 
+        if !version.is_protobuf() {
+            return amino::encode_response(msg);
+        }
         PrivMessage { sum: Some(msg) }
             .encode_length_delimited(&mut buf)
             .map_err(|e| {
@@ -196,13 +314,198 @@ impl Response {
     }
 }
 
-/// Read a message from a Secret Connection
-// TODO(tarcieri): extract this into Secret Connection
-fn read_msg(conn: &mut impl Read) -> Result<Vec<u8>, Error> {
-    let mut buf = vec![0; DATA_MAX_SIZE];
-    let buf_read = conn
-        .read(&mut buf)
-        .map_err(|e| format_err!(ErrorKind::IoError, "read msg failed: {}", e))?;
-    buf.truncate(buf_read);
-    Ok(buf)
+/// Canonical sign bytes of the vote (of the given protocol version)
+pub fn vote_signable_bytes(
+    req: &SignVoteRequest,
+    version: ProtocolVersion,
+) -> Result<Vec<u8>, Error> {
+    if !version.is_protobuf() {
+        return amino::vote_sign_bytes(req);
+    }
+    req.to_signable_vec().map_err(|e| {
+        format_err!(
+            ErrorKind::SigningError,
+            "cannot get vote signable bytes: {}",
+            e
+        )
+        .into()
+    })
+}
+
+/// Canonical sign bytes of the proposal (of the given protocol version)
+pub fn proposal_signable_bytes(
+    req: &SignProposalRequest,
+    version: ProtocolVersion,
+) -> Result<Vec<u8>, Error> {
+    if !version.is_protobuf() {
+        return amino::proposal_sign_bytes(req);
+    }
+    req.to_signable_vec().map_err(|e| {
+        format_err!(
+            ErrorKind::SigningError,
+            "cannot get proposal signable bytes: {}",
+            e
+        )
+        .into()
+    })
+}
+
+/// Maximum size of a privval message (without the length prefix)
+/// -- the same limit as in Tendermint's remote signer
+const MAX_MSG_LEN: usize = 1024 * 10;
+
+/// Maximum size of the varint length prefix
+const MAX_VARINT_LEN: usize = 10;
+
+/// Buffered reader of length-delimited privval messages:
+/// a single read from a connection may return a partial message
+/// or more than one message, so the leftover bytes are kept for the next call
+#[derive(Debug, Default)]
+pub struct MsgReader {
+    buf: Vec<u8>,
+}
+
+impl MsgReader {
+    /// Read exactly one length-delimited message (including its length prefix)
+    pub fn read_msg(&mut self, conn: &mut impl Read) -> Result<Vec<u8>, Error> {
+        loop {
+            if let Some((prefix_len, msg_len)) = decode_length_prefix(&self.buf)? {
+                if msg_len > MAX_MSG_LEN {
+                    fail!(
+                        ErrorKind::ProtocolError,
+                        "message too large: {} bytes",
+                        msg_len
+                    );
+                }
+                let total_len = prefix_len + msg_len;
+                if self.buf.len() >= total_len {
+                    return Ok(self.buf.drain(..total_len).collect());
+                }
+            }
+            let mut chunk = vec![0; DATA_MAX_SIZE];
+            let chunk_read = conn
+                .read(&mut chunk)
+                .map_err(|e| format_err!(ErrorKind::IoError, "read msg failed: {}", e))?;
+            if chunk_read == 0 {
+                fail!(ErrorKind::IoError, "read msg failed: connection closed");
+            }
+            self.buf.extend_from_slice(&chunk[..chunk_read]);
+        }
+    }
+}
+
+/// Decode the protobuf varint length prefix:
+/// returns its size and the decoded message length
+/// or `None` if more bytes are needed
+fn decode_length_prefix(buf: &[u8]) -> Result<Option<(usize, usize)>, Error> {
+    let mut len: u64 = 0;
+    for (i, byte) in buf.iter().take(MAX_VARINT_LEN).enumerate() {
+        len |= u64::from(byte & 0x7F) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((i + 1, len as usize)));
+        }
+    }
+    if buf.len() >= MAX_VARINT_LEN {
+        fail!(ErrorKind::ProtocolError, "invalid message length prefix");
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    /// returns the data in chunks of at most the given size
+    struct ChunkedReader {
+        data: Vec<u8>,
+        chunk: usize,
+    }
+
+    impl Read for ChunkedReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.chunk.min(buf.len()).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data.drain(..n);
+            Ok(n)
+        }
+    }
+
+    fn chain_id() -> chain::Id {
+        "testchain-1".parse().unwrap()
+    }
+
+    fn ping_request() -> Vec<u8> {
+        let mut buf = Vec::new();
+        PrivMessage {
+            sum: Some(Sum::PingRequest(PingRequest {})),
+        }
+        .encode_length_delimited(&mut buf)
+        .unwrap();
+        buf
+    }
+
+    fn pubkey_request() -> Vec<u8> {
+        let mut buf = Vec::new();
+        PrivMessage {
+            sum: Some(Sum::PubKeyRequest(
+                tendermint_proto::privval::PubKeyRequest {
+                    chain_id: "testchain-1".to_owned(),
+                },
+            )),
+        }
+        .encode_length_delimited(&mut buf)
+        .unwrap();
+        buf
+    }
+
+    #[test]
+    fn reads_partial_messages() {
+        let mut conn = ChunkedReader {
+            data: pubkey_request(),
+            chunk: 1,
+        };
+        let mut reader = MsgReader::default();
+        assert!(matches!(
+            Request::read(&mut conn, &mut reader, ProtocolVersion::V0_34, &chain_id()).unwrap(),
+            Request::ShowPublicKey(_)
+        ));
+    }
+
+    #[test]
+    fn reads_coalesced_messages() {
+        let mut data = ping_request();
+        data.extend_from_slice(&pubkey_request());
+        data.extend_from_slice(&ping_request());
+        let mut conn = ChunkedReader {
+            chunk: data.len(),
+            data,
+        };
+        let mut reader = MsgReader::default();
+        assert!(matches!(
+            Request::read(&mut conn, &mut reader, ProtocolVersion::V0_34, &chain_id()).unwrap(),
+            Request::ReplyPing(_)
+        ));
+        assert!(matches!(
+            Request::read(&mut conn, &mut reader, ProtocolVersion::V0_34, &chain_id()).unwrap(),
+            Request::ShowPublicKey(_)
+        ));
+        assert!(matches!(
+            Request::read(&mut conn, &mut reader, ProtocolVersion::V0_34, &chain_id()).unwrap(),
+            Request::ReplyPing(_)
+        ));
+        assert!(
+            Request::read(&mut conn, &mut reader, ProtocolVersion::V0_34, &chain_id()).is_err()
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_messages() {
+        let mut conn = ChunkedReader {
+            data: vec![0xFF, 0xFF, 0x01],
+            chunk: 3,
+        };
+        let mut reader = MsgReader::default();
+        assert!(reader.read_msg(&mut conn).is_err());
+    }
 }
@@ -0,0 +1,386 @@
+//! Amino-encoded privval messages of Tendermint v0.33
+//!
+//! The messages are framed like the Protobuf ones (a varint length prefix),
+//! but start with the 4-byte prefix of their registered Amino name.
+//! Their fields (the votes and proposals included) are encoded
+//! as in the v0.34 Protobuf messages, except for the public keys.
+//! The requests don't carry the chain ID, so the configured one is used.
+//! The sign bytes differ from v0.34 in the field order of the part set header.
+
+use crate::error::{Error, ErrorKind};
+use anomaly::{fail, format_err};
+use prost::Message as _;
+use sha2::{Digest, Sha256};
+use tendermint::{chain, proposal::SignProposalRequest, vote::SignVoteRequest};
+use tendermint_proto::{
+    crypto::public_key::Sum as PkSum,
+    google::protobuf::Timestamp,
+    privval::{
+        message::Sum, PubKeyRequest as RawPubKeyRequest, RemoteSignerError,
+        SignProposalRequest as RawProposalRequest, SignVoteRequest as RawVoteRequest,
+    },
+    types::{BlockId as RawBlockId, Proposal as RawProposal, Vote as RawVote},
+};
+
+const PUB_KEY_REQUEST: &str = "tendermint/remotesigner/PubKeyRequest";
+const PUB_KEY_RESPONSE: &str = "tendermint/remotesigner/PubKeyResponse";
+const SIGN_VOTE_REQUEST: &str = "tendermint/remotesigner/SignVoteRequest";
+const SIGNED_VOTE_RESPONSE: &str = "tendermint/remotesigner/SignedVoteResponse";
+const SIGN_PROPOSAL_REQUEST: &str = "tendermint/remotesigner/SignProposalRequest";
+const SIGNED_PROPOSAL_RESPONSE: &str = "tendermint/remotesigner/SignedProposalResponse";
+const PING_REQUEST: &str = "tendermint/remotesigner/PingRequest";
+const PING_RESPONSE: &str = "tendermint/remotesigner/PingResponse";
+const PUB_KEY_ED25519: &str = "tendermint/PubKeyEd25519";
+const PUB_KEY_SECP256K1: &str = "tendermint/PubKeySecp256k1";
+
+/// Prefix of a registered Amino type: the SHA-256 digest of its name
+/// without the leading zero bytes, the 3 disambiguation bytes
+/// and then again the leading zero bytes
+fn prefix(name: &str) -> [u8; 4] {
+    let digest = Sha256::digest(name.as_bytes());
+    let mut bytes = digest
+        .iter()
+        .copied()
+        .skip_while(|b| *b == 0)
+        .skip(3)
+        .skip_while(|b| *b == 0);
+    let mut prefix = [0u8; 4];
+    for byte in prefix.iter_mut() {
+        *byte = bytes.next().expect("SHA-256 digest long enough");
+    }
+    prefix
+}
+
+/// `privval.PubKeyResponse` (the public key is Amino-encoded)
+#[derive(Clone, PartialEq, prost::Message)]
+struct PubKeyResponse {
+    #[prost(bytes = "vec", tag = "1")]
+    pub_key: Vec<u8>,
+    #[prost(message, optional, tag = "2")]
+    error: Option<RemoteSignerError>,
+}
+
+/// `types.CanonicalPartSetHeader` of v0.33
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CanonicalPartSetHeader {
+    #[prost(bytes = "vec", tag = "1")]
+    pub hash: Vec<u8>,
+    #[prost(int64, tag = "2")]
+    pub total: i64,
+}
+
+/// `types.CanonicalBlockID` of v0.33
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CanonicalBlockId {
+    #[prost(bytes = "vec", tag = "1")]
+    pub hash: Vec<u8>,
+    #[prost(message, optional, tag = "2")]
+    pub parts_header: Option<CanonicalPartSetHeader>,
+}
+
+/// `types.CanonicalVote` of v0.33
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CanonicalVote {
+    #[prost(uint32, tag = "1")]
+    pub r#type: u32,
+    #[prost(sfixed64, tag = "2")]
+    pub height: i64,
+    #[prost(sfixed64, tag = "3")]
+    pub round: i64,
+    #[prost(message, optional, tag = "4")]
+    pub block_id: Option<CanonicalBlockId>,
+    #[prost(message, optional, tag = "5")]
+    pub timestamp: Option<Timestamp>,
+    #[prost(string, tag = "6")]
+    pub chain_id: String,
+}
+
+/// `types.CanonicalProposal` of v0.33
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CanonicalProposal {
+    #[prost(uint32, tag = "1")]
+    pub r#type: u32,
+    #[prost(sfixed64, tag = "2")]
+    pub height: i64,
+    #[prost(sfixed64, tag = "3")]
+    pub round: i64,
+    #[prost(sfixed64, tag = "4")]
+    pub pol_round: i64,
+    #[prost(message, optional, tag = "5")]
+    pub block_id: Option<CanonicalBlockId>,
+    #[prost(message, optional, tag = "6")]
+    pub timestamp: Option<Timestamp>,
+    #[prost(string, tag = "7")]
+    pub chain_id: String,
+}
+
+/// Amino omits the empty structs, so a nil block ID has no canonical form
+fn canonical_block_id(block_id: Option<RawBlockId>) -> Option<CanonicalBlockId> {
+    let block_id = block_id?;
+    let parts_header = block_id
+        .part_set_header
+        .filter(|psh| psh.total != 0 || !psh.hash.is_empty())
+        .map(|psh| CanonicalPartSetHeader {
+            hash: psh.hash,
+            total: psh.total.into(),
+        });
+    if block_id.hash.is_empty() && parts_header.is_none() {
+        None
+    } else {
+        Some(CanonicalBlockId {
+            hash: block_id.hash,
+            parts_header,
+        })
+    }
+}
+
+/// Canonical (length-delimited) sign bytes of the vote
+pub fn vote_sign_bytes(req: &SignVoteRequest) -> Result<Vec<u8>, Error> {
+    let vote = RawVote::from(req.vote.clone());
+    let canonical = CanonicalVote {
+        r#type: vote.r#type as u32,
+        height: vote.height,
+        round: vote.round.into(),
+        block_id: canonical_block_id(vote.block_id),
+        timestamp: vote.timestamp,
+        chain_id: req.chain_id.to_string(),
+    };
+    let mut buf = Vec::new();
+    canonical.encode_length_delimited(&mut buf).map_err(|e| {
+        format_err!(
+            ErrorKind::SigningError,
+            "cannot get vote signable bytes: {}",
+            e
+        )
+    })?;
+    Ok(buf)
+}
+
+/// Canonical (length-delimited) sign bytes of the proposal
+pub fn proposal_sign_bytes(req: &SignProposalRequest) -> Result<Vec<u8>, Error> {
+    let proposal = RawProposal::from(req.proposal.clone());
+    let canonical = CanonicalProposal {
+        r#type: proposal.r#type as u32,
+        height: proposal.height,
+        round: proposal.round.into(),
+        pol_round: proposal.pol_round.into(),
+        block_id: canonical_block_id(proposal.block_id),
+        timestamp: proposal.timestamp,
+        chain_id: req.chain_id.to_string(),
+    };
+    let mut buf = Vec::new();
+    canonical.encode_length_delimited(&mut buf).map_err(|e| {
+        format_err!(
+            ErrorKind::SigningError,
+            "cannot get proposal signable bytes: {}",
+            e
+        )
+    })?;
+    Ok(buf)
+}
+
+/// Decodes a request (the length-delimited message) as the v0.34 message
+/// with the given chain ID
+pub fn decode_request(msg: &[u8], chain_id: &chain::Id) -> Result<Sum, Error> {
+    let body = prost::decode_length_delimiter(msg)
+        .ok()
+        .map(|len| &msg[prost::length_delimiter_len(len)..])
+        .filter(|body| body.len() >= 4)
+        .ok_or_else(|| format_err!(ErrorKind::ProtocolError, "malformed Amino message"))?;
+    let (msg_prefix, fields) = body.split_at(4);
+    let malformed =
+        |e: prost::DecodeError| format_err!(ErrorKind::ProtocolError, "malformed message: {}", e);
+    let chain_id = chain_id.to_string();
+    if msg_prefix == prefix(SIGN_VOTE_REQUEST) {
+        let mut request = RawVoteRequest::decode(fields).map_err(malformed)?;
+        request.chain_id = chain_id;
+        Ok(Sum::SignVoteRequest(request))
+    } else if msg_prefix == prefix(SIGN_PROPOSAL_REQUEST) {
+        let mut request = RawProposalRequest::decode(fields).map_err(malformed)?;
+        request.chain_id = chain_id;
+        Ok(Sum::SignProposalRequest(request))
+    } else if msg_prefix == prefix(PUB_KEY_REQUEST) {
+        Ok(Sum::PubKeyRequest(RawPubKeyRequest { chain_id }))
+    } else if msg_prefix == prefix(PING_REQUEST) {
+        Ok(Sum::PingRequest(Default::default()))
+    } else {
+        fail!(
+            ErrorKind::ProtocolError,
+            "unknown Amino message prefix: {:02X?}",
+            msg_prefix
+        )
+    }
+}
+
+/// Encodes a response given as the v0.34 message (with its length prefix)
+pub fn encode_response(msg: Sum) -> Result<Vec<u8>, Error> {
+    let mut body = Vec::new();
+    let result = match msg {
+        Sum::SignedVoteResponse(response) => {
+            body.extend_from_slice(&prefix(SIGNED_VOTE_RESPONSE));
+            response.encode(&mut body)
+        }
+        Sum::SignedProposalResponse(response) => {
+            body.extend_from_slice(&prefix(SIGNED_PROPOSAL_RESPONSE));
+            response.encode(&mut body)
+        }
+        Sum::PingResponse(_) => {
+            body.extend_from_slice(&prefix(PING_RESPONSE));
+            Ok(())
+        }
+        Sum::PubKeyResponse(response) => {
+            body.extend_from_slice(&prefix(PUB_KEY_RESPONSE));
+            let pub_key = match response.pub_key.and_then(|pk| pk.sum) {
+                Some(pk) => encode_public_key(pk)?,
+                None => Vec::new(),
+            };
+            PubKeyResponse {
+                pub_key,
+                error: response.error,
+            }
+            .encode(&mut body)
+        }
+        other => fail!(
+            ErrorKind::ProtocolError,
+            "not an Amino response: {:?}",
+            other
+        ),
+    };
+    result
+        .map_err(|e| format_err!(ErrorKind::ProtocolError, "failed to encode response: {}", e))?;
+    let mut buf = Vec::new();
+    prost::encode_length_delimiter(body.len(), &mut buf)
+        .map_err(|e| format_err!(ErrorKind::ProtocolError, "failed to encode response: {}", e))?;
+    buf.extend_from_slice(&body);
+    Ok(buf)
+}
+
+/// The Amino encoding of a public key (its type prefix and its length-prefixed bytes)
+fn encode_public_key(pk: PkSum) -> Result<Vec<u8>, Error> {
+    let (name, key) = match pk {
+        PkSum::Ed25519(key) => (PUB_KEY_ED25519, key),
+        PkSum::Secp256k1(key) => (PUB_KEY_SECP256K1, key),
+    };
+    let mut buf = prefix(name).to_vec();
+    prost::encode_length_delimiter(key.len(), &mut buf).map_err(|e| {
+        format_err!(
+            ErrorKind::ProtocolError,
+            "failed to encode public key: {}",
+            e
+        )
+    })?;
+    buf.extend_from_slice(&key);
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use tendermint_proto::crypto::PublicKey as RawPublicKey;
+    use tendermint_proto::privval::PubKeyResponse as RawPubKeyResponse;
+    use tendermint_proto::types::PartSetHeader;
+
+    fn raw_vote(hash: Vec<u8>) -> RawVote {
+        RawVote {
+            r#type: 2,
+            height: 5,
+            round: 1,
+            block_id: Some(RawBlockId {
+                part_set_header: Some(PartSetHeader {
+                    total: if hash.is_empty() { 0 } else { 2 },
+                    hash: if hash.is_empty() { vec![] } else { vec![2; 32] },
+                }),
+                hash,
+            }),
+            timestamp: Some(Timestamp {
+                seconds: 1,
+                nanos: 0,
+            }),
+            validator_address: vec![0xAB; 20],
+            validator_index: 0,
+            signature: vec![],
+        }
+    }
+
+    fn vote_request(hash: Vec<u8>) -> SignVoteRequest {
+        SignVoteRequest::try_from(RawVoteRequest {
+            vote: Some(raw_vote(hash)),
+            chain_id: "testchain-1".to_owned(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn computes_prefixes() {
+        // the well-known prefixes of the Amino-encoded public keys
+        assert_eq!(prefix(PUB_KEY_ED25519), [0x16, 0x24, 0xDE, 0x64]);
+        assert_eq!(prefix(PUB_KEY_SECP256K1), [0xEB, 0x5A, 0xE9, 0x87]);
+    }
+
+    #[test]
+    fn decodes_requests_with_configured_chain_id() {
+        let mut vote = Vec::new();
+        raw_vote(vec![1; 32]).encode(&mut vote).unwrap();
+        let mut body = prefix(SIGN_VOTE_REQUEST).to_vec();
+        prost::encoding::bytes::encode(1, &vote, &mut body);
+        let mut msg = Vec::new();
+        prost::encode_length_delimiter(body.len(), &mut msg).unwrap();
+        msg.extend_from_slice(&body);
+
+        let chain_id = chain::Id::try_from("testchain-1".to_owned()).unwrap();
+        match decode_request(&msg, &chain_id).unwrap() {
+            Sum::SignVoteRequest(request) => {
+                assert_eq!(request.vote, Some(raw_vote(vec![1; 32])));
+                assert_eq!(request.chain_id, "testchain-1");
+            }
+            other => panic!("unexpected request: {:?}", other),
+        }
+        let ping = [4, 0x1B, 0xAD, 0xB6, 0x0D];
+        assert!(matches!(
+            decode_request(&ping, &chain_id),
+            Ok(Sum::PingRequest(_))
+        ));
+        assert!(decode_request(&[4, 0, 0, 0, 0], &chain_id).is_err());
+    }
+
+    #[test]
+    fn encodes_public_keys() {
+        let response = Sum::PubKeyResponse(RawPubKeyResponse {
+            pub_key: Some(RawPublicKey {
+                sum: Some(PkSum::Ed25519(vec![1; 32])),
+            }),
+            error: None,
+        });
+        let mut want = vec![43];
+        want.extend_from_slice(&prefix(PUB_KEY_RESPONSE));
+        want.extend_from_slice(&[0x0A, 37, 0x16, 0x24, 0xDE, 0x64, 32]);
+        want.extend_from_slice(&[1; 32]);
+        assert_eq!(encode_response(response).unwrap(), want);
+    }
+
+    #[test]
+    fn encodes_vote_sign_bytes() {
+        let mut want = vec![
+            111, // length prefix
+            0x08, 2, // precommit
+            0x11, 5, 0, 0, 0, 0, 0, 0, 0, // height
+            0x19, 1, 0, 0, 0, 0, 0, 0, 0, // round
+            0x22, 72, 0x0A, 32, // block ID hash
+        ];
+        want.extend_from_slice(&[1; 32]);
+        // the part set header starts with its hash
+        want.extend_from_slice(&[0x12, 36, 0x0A, 32]);
+        want.extend_from_slice(&[2; 32]);
+        want.extend_from_slice(&[0x10, 2]);
+        want.extend_from_slice(&[0x2A, 2, 0x08, 1]); // timestamp
+        want.extend_from_slice(&[0x32, 11]);
+        want.extend_from_slice(b"testchain-1");
+        assert_eq!(vote_sign_bytes(&vote_request(vec![1; 32])).unwrap(), want);
+
+        // the nil block ID is omitted
+        let nil_vote = vote_sign_bytes(&vote_request(vec![])).unwrap();
+        assert_eq!(nil_vote.len(), 1 + 111 - 74);
+        assert!(!nil_vote.contains(&0x22));
+    }
+}
@@ -0,0 +1,65 @@
+//! Public key responses of BLS12-381 keys (CometBFT 1.x)
+//!
+//! The `tendermint-proto` types predate the `bls12381` field of `crypto.PublicKey`,
+//! so the response is encoded from the raw messages here.
+
+use crate::error::{Error, ErrorKind};
+use anomaly::format_err;
+use prost::Message as _;
+
+/// `privval.Message` with the public key response
+#[derive(Clone, PartialEq, prost::Message)]
+struct RawResponseMessage {
+    #[prost(message, optional, tag = "2")]
+    pub_key_response: Option<RawPubKeyResponse>,
+}
+
+/// `privval.PubKeyResponse` (without an error)
+#[derive(Clone, PartialEq, prost::Message)]
+struct RawPubKeyResponse {
+    #[prost(message, optional, tag = "1")]
+    pub_key: Option<RawPublicKey>,
+}
+
+/// `crypto.PublicKey` with only its BLS12-381 key
+#[derive(Clone, PartialEq, prost::Message)]
+struct RawPublicKey {
+    #[prost(bytes = "vec", tag = "3")]
+    bls12381: Vec<u8>,
+}
+
+/// Encodes the public key response (with its length prefix) of the compressed BLS12-381 key
+pub fn encode_response(public_key: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let mut buf = Vec::new();
+    RawResponseMessage {
+        pub_key_response: Some(RawPubKeyResponse {
+            pub_key: Some(RawPublicKey {
+                bls12381: public_key,
+            }),
+        }),
+    }
+    .encode_length_delimited(&mut buf)
+    .map_err(|e| format_err!(ErrorKind::ProtocolError, "failed to encode response: {}", e))?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_public_keys() {
+        let encoded = encode_response(vec![0xAB; 48]).unwrap();
+        let mut expected = vec![
+            54,   // length prefix
+            0x12, // pub_key_response
+            52,   //
+            0x0A, // pub_key
+            50,   //
+            0x1A, // bls12381
+            48,
+        ];
+        expected.extend_from_slice(&[0xAB; 48]);
+        assert_eq!(encoded, expected);
+    }
+}
@@ -0,0 +1,285 @@
+//! Vote extensions of CometBFT v0.38 precommits
+//!
+//! The `tendermint-proto` types predate the `extension` and `extension_signature`
+//! fields of `Vote` and the `skip_extension_signing` field of `SignVoteRequest`,
+//! so these are decoded from (and encoded into) the raw messages here.
+
+use crate::error::{Error, ErrorKind};
+use anomaly::{fail, format_err};
+use prost::Message as _;
+use tendermint::{chain, hash::Hash, vote::Vote};
+use tendermint_proto::privval::SignedVoteResponse as RawVoteResponse;
+
+/// `privval.Message` with only the sign vote request (`oneof` fields are plain on the wire)
+#[derive(Clone, PartialEq, prost::Message)]
+struct RawRequestMessage {
+    #[prost(message, optional, tag = "3")]
+    sign_vote_request: Option<RawSignVoteRequest>,
+}
+
+/// `privval.SignVoteRequest` with only the fields added in v0.38
+#[derive(Clone, PartialEq, prost::Message)]
+struct RawSignVoteRequest {
+    #[prost(message, optional, tag = "1")]
+    vote: Option<RawVoteExtension>,
+    #[prost(bool, tag = "3")]
+    skip_extension_signing: bool,
+}
+
+/// `types.Vote` with only the fields added in v0.38
+/// (encoded after the other fields, as a message may be merged from concatenated parts)
+#[derive(Clone, PartialEq, prost::Message)]
+struct RawVoteExtension {
+    #[prost(bytes = "vec", tag = "10")]
+    extension: Vec<u8>,
+    #[prost(bytes = "vec", tag = "11")]
+    extension_signature: Vec<u8>,
+}
+
+/// `privval.Message` with the signed vote response
+/// (its vote is encoded separately, as a length-delimited field is the same on the wire)
+#[derive(Clone, PartialEq, prost::Message)]
+struct RawResponseMessage {
+    #[prost(message, optional, tag = "4")]
+    signed_vote_response: Option<RawSignedVote>,
+}
+
+/// `privval.SignedVoteResponse` (without an error)
+#[derive(Clone, PartialEq, prost::Message)]
+struct RawSignedVote {
+    #[prost(bytes = "vec", tag = "1")]
+    vote: Vec<u8>,
+}
+
+/// `types.CanonicalVoteExtension`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CanonicalVoteExtension {
+    #[prost(bytes = "vec", tag = "1")]
+    pub extension: Vec<u8>,
+    #[prost(sfixed64, tag = "2")]
+    pub height: i64,
+    #[prost(sfixed64, tag = "3")]
+    pub round: i64,
+    #[prost(string, tag = "4")]
+    pub chain_id: String,
+}
+
+/// Vote extension whose signature is requested with a precommit
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VoteExtension {
+    /// the application's extension (may be empty)
+    pub extension: Vec<u8>,
+}
+
+/// Vote extension with its signature (returned with the signed precommit)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SignedExtension {
+    /// the application's extension
+    pub extension: Vec<u8>,
+    /// signature of the canonical extension sign bytes
+    pub signature: Vec<u8>,
+}
+
+impl VoteExtension {
+    /// The extension of a sign vote request (the length-delimited message), if it's to be signed:
+    /// only non-nil precommits are extended, and the node may ask to skip the extension
+    pub fn from_request(msg: &[u8], vote: &Vote) -> Result<Option<Self>, Error> {
+        let request = RawRequestMessage::decode_length_delimited(msg)
+            .map_err(|e| format_err!(ErrorKind::ProtocolError, "malformed message packet: {}", e))?
+            .sign_vote_request
+            .unwrap_or_default();
+        if request.skip_extension_signing {
+            return Ok(None);
+        }
+        let extension = request.vote.unwrap_or_default().extension;
+        let non_nil = match &vote.block_id {
+            Some(block_id) => block_id.hash != Hash::None,
+            None => false,
+        };
+        if vote.is_precommit() && non_nil {
+            Ok(Some(Self { extension }))
+        } else if !extension.is_empty() {
+            fail!(
+                ErrorKind::InvalidMessageError,
+                "unexpected vote extension: only non-nil precommits are extended"
+            )
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Canonical (length-delimited) sign bytes of the extension
+    pub fn to_signable_vec(&self, vote: &Vote, chain_id: &chain::Id) -> Result<Vec<u8>, Error> {
+        let mut buf = Vec::new();
+        CanonicalVoteExtension {
+            extension: self.extension.clone(),
+            height: vote.height.into(),
+            round: vote.round.value().into(),
+            chain_id: chain_id.to_string(),
+        }
+        .encode_length_delimited(&mut buf)
+        .map_err(|e| {
+            format_err!(
+                ErrorKind::SigningError,
+                "cannot get vote extension signable bytes: {}",
+                e
+            )
+        })?;
+        Ok(buf)
+    }
+
+    /// The extension with its signature
+    pub fn signed(self, signature: Vec<u8>) -> SignedExtension {
+        SignedExtension {
+            extension: self.extension,
+            signature,
+        }
+    }
+}
+
+/// Encodes the signed vote response (with its length prefix) with the extension
+/// and its signature appended to the vote
+pub fn encode_response(
+    response: RawVoteResponse,
+    extension: SignedExtension,
+) -> Result<Vec<u8>, Error> {
+    let encode_error = |e: prost::EncodeError| {
+        format_err!(ErrorKind::ProtocolError, "failed to encode response: {}", e)
+    };
+    let mut vote = Vec::new();
+    if let Some(raw_vote) = response.vote {
+        raw_vote.encode(&mut vote).map_err(encode_error)?;
+    }
+    RawVoteExtension {
+        extension: extension.extension,
+        extension_signature: extension.signature,
+    }
+    .encode(&mut vote)
+    .map_err(encode_error)?;
+    let mut buf = Vec::new();
+    RawResponseMessage {
+        signed_vote_response: Some(RawSignedVote { vote }),
+    }
+    .encode_length_delimited(&mut buf)
+    .map_err(encode_error)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use tendermint_proto::privval::{message::Sum, Message as PrivMessage};
+    use tendermint_proto::types::{BlockId, Vote as RawVote};
+
+    fn raw_vote(precommit: bool, hash: Vec<u8>) -> RawVote {
+        RawVote {
+            r#type: if precommit { 2 } else { 1 },
+            height: 5,
+            round: 1,
+            block_id: Some(BlockId {
+                hash,
+                part_set_header: Some(Default::default()),
+            }),
+            timestamp: Some(Default::default()),
+            validator_address: vec![0xAB; 20],
+            validator_index: 0,
+            signature: vec![],
+        }
+    }
+
+    /// a v0.38 sign vote request (the v0.34 message with the new fields appended)
+    fn request(vote: RawVote, extension: &[u8]) -> Vec<u8> {
+        let mut vote_bytes = Vec::new();
+        vote.encode(&mut vote_bytes).unwrap();
+        RawVoteExtension {
+            extension: extension.to_vec(),
+            extension_signature: vec![],
+        }
+        .encode(&mut vote_bytes)
+        .unwrap();
+        let mut request = Vec::new();
+        prost::encoding::bytes::encode(1, &vote_bytes, &mut request);
+        prost::encoding::string::encode(2, &"testchain-1".to_owned(), &mut request);
+        let mut msg = Vec::new();
+        prost::encoding::bytes::encode(3, &request, &mut msg);
+        let mut buf = Vec::new();
+        prost::encoding::encode_varint(msg.len() as u64, &mut buf);
+        buf.extend_from_slice(&msg);
+        buf
+    }
+
+    #[test]
+    fn extends_only_non_nil_precommits() {
+        let precommit = raw_vote(true, vec![1; 32]);
+        let vote = Vote::try_from(precommit.clone()).unwrap();
+        let extension = VoteExtension::from_request(&request(precommit, b"ext"), &vote).unwrap();
+        assert_eq!(
+            extension,
+            Some(VoteExtension {
+                extension: b"ext".to_vec()
+            })
+        );
+
+        let prevote = raw_vote(false, vec![1; 32]);
+        let vote = Vote::try_from(prevote.clone()).unwrap();
+        assert_eq!(
+            VoteExtension::from_request(&request(prevote.clone(), b""), &vote).unwrap(),
+            None
+        );
+        assert!(VoteExtension::from_request(&request(prevote, b"ext"), &vote).is_err());
+
+        let nil_precommit = raw_vote(true, vec![]);
+        let vote = Vote::try_from(nil_precommit.clone()).unwrap();
+        assert_eq!(
+            VoteExtension::from_request(&request(nil_precommit, b""), &vote).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn encodes_extension_signatures() {
+        let raw = raw_vote(true, vec![1; 32]);
+        let response = RawVoteResponse {
+            vote: Some(raw.clone()),
+            error: None,
+        };
+        let signed = VoteExtension {
+            extension: b"ext".to_vec(),
+        }
+        .signed(vec![7; 64]);
+        let encoded = encode_response(response, signed).unwrap();
+
+        // the v0.34 types still decode the rest of the vote
+        let decoded = PrivMessage::decode_length_delimited(encoded.as_ref()).unwrap();
+        match decoded.sum {
+            Some(Sum::SignedVoteResponse(response)) => assert_eq!(response.vote, Some(raw)),
+            other => panic!("unexpected response: {:?}", other),
+        }
+        let decoded = RawResponseMessage::decode_length_delimited(encoded.as_ref()).unwrap();
+        let vote = decoded.signed_vote_response.unwrap().vote;
+        let extension = RawVoteExtension::decode(vote.as_ref()).unwrap();
+        assert_eq!(extension.extension, b"ext".to_vec());
+        assert_eq!(extension.extension_signature, vec![7; 64]);
+    }
+
+    #[test]
+    fn extension_sign_bytes() {
+        let vote = Vote::try_from(raw_vote(true, vec![1; 32])).unwrap();
+        let chain_id = chain::Id::try_from("testchain-1".to_owned()).unwrap();
+        let sign_bytes = VoteExtension {
+            extension: b"ext".to_vec(),
+        }
+        .to_signable_vec(&vote, &chain_id)
+        .unwrap();
+        let mut expected = vec![
+            36, // length prefix
+            0x0A, 3, b'e', b'x', b't', // extension
+            0x11, 5, 0, 0, 0, 0, 0, 0, 0, // height
+            0x19, 1, 0, 0, 0, 0, 0, 0, 0, // round
+            0x22, 11, // chain_id
+        ];
+        expected.extend_from_slice(b"testchain-1");
+        assert_eq!(sign_bytes, expected);
+    }
+}
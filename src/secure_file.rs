@@ -0,0 +1,178 @@
+//! Reading and writing secret files (keys, sealed keys, backups) and state files:
+//! they are only readable by the owner, and secrets are refused if anyone else
+//! could have read or replaced them (unless explicitly allowed)
+
+use std::{
+    fs::{self, File, OpenOptions, Permissions},
+    io::{self, Read, Write},
+    os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt},
+    path::Path,
+};
+use tempfile::NamedTempFile;
+
+/// File permissions for secret data
+pub const SECRET_FILE_MODE: u32 = 0o600;
+
+/// group or world permission bits
+const GROUP_OTHER_MASK: u32 = 0o077;
+
+fn insecure(path: &Path, reason: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("refusing to load {}: {}", path.display(), reason),
+    )
+}
+
+/// Opens the secret file for reading, checking (unless `allow_insecure` is set)
+/// that it isn't a symlink, isn't accessible by the group or others
+/// and is owned by the current user
+pub fn open(path: &Path, allow_insecure: bool) -> io::Result<File> {
+    if allow_insecure {
+        return File::open(path);
+    }
+    if fs::symlink_metadata(path)?.file_type().is_symlink() {
+        return Err(insecure(path, "it is a symlink".to_owned()));
+    }
+    // `O_NOFOLLOW` in case it was replaced by a symlink in the meantime
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(insecure(path, "it is not a regular file".to_owned()));
+    }
+    let mode = metadata.mode() & 0o777;
+    if mode & GROUP_OTHER_MASK != 0 {
+        return Err(insecure(
+            path,
+            format!(
+                "its permissions {:o} allow access by others (expected {:o})",
+                mode, SECRET_FILE_MODE
+            ),
+        ));
+    }
+    // safe: `geteuid` has no preconditions and can't fail
+    let euid = unsafe { libc::geteuid() };
+    if metadata.uid() != euid {
+        return Err(insecure(
+            path,
+            format!("it is owned by uid {} (expected {})", metadata.uid(), euid),
+        ));
+    }
+    Ok(file)
+}
+
+/// Reads the secret file (see `open` for the checks)
+pub fn read(path: &Path, allow_insecure: bool) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    open(path, allow_insecure)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Reads the secret file as a string (see `open` for the checks)
+pub fn read_to_string(path: &Path, allow_insecure: bool) -> io::Result<String> {
+    let mut data = String::new();
+    open(path, allow_insecure)?.read_to_string(&mut data)?;
+    Ok(data)
+}
+
+/// Options for creating or writing a secret file:
+/// a new file is only accessible by the owner and a symlink isn't followed
+pub fn write_options() -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options
+        .write(true)
+        .mode(SECRET_FILE_MODE)
+        .custom_flags(libc::O_NOFOLLOW);
+    options
+}
+
+/// Restricts the permissions of an opened file to the owner
+/// (as the mode in `write_options` is only applied to new files)
+pub fn restrict_permissions(file: &File) -> io::Result<()> {
+    file.set_permissions(Permissions::from_mode(SECRET_FILE_MODE))
+}
+
+/// Creates or replaces the secret file (refusing a symlink)
+/// via a synced temporary file renamed over it, so a crash leaves either
+/// the old or the new contents
+pub fn write(path: &Path, data: &[u8]) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("refusing to write {}: it is a symlink", path.display()),
+            ));
+        }
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut file = NamedTempFile::new_in(dir)?;
+    restrict_permissions(file.as_file())?;
+    file.write_all(data)?;
+    file.as_file().sync_all()?;
+    file.persist(path).map_err(|e| e.error)?;
+    // the rename is only durable once the directory entry is synced
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("tmkms-secure-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn refuses_readable_by_others() {
+        let path = temp_path("key");
+        write(&path, b"secret").unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            SECRET_FILE_MODE
+        );
+        assert_eq!(read(&path, false).unwrap(), b"secret");
+        fs::set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+        let err = read(&path, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(read(&path, true).unwrap(), b"secret");
+        // rewriting restricts the permissions again
+        write(&path, b"secret2").unwrap();
+        assert_eq!(read_to_string(&path, false).unwrap(), "secret2");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replaces_via_a_renamed_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+        write(&path, b"secret").unwrap();
+        write(&path, b"secret2").unwrap();
+        assert_eq!(read(&path, false).unwrap(), b"secret2");
+        // no temporary file is left behind
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn refuses_symlinks() {
+        let path = temp_path("target");
+        let link = temp_path("link");
+        write(&path, b"secret").unwrap();
+        std::os::unix::fs::symlink(&path, &link).unwrap();
+        assert!(read(&link, false).is_err());
+        assert!(write(&link, b"other").is_err());
+        assert_eq!(read(&link, true).unwrap(), b"secret");
+        fs::remove_file(&link).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
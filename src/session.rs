@@ -1,30 +1,163 @@
 //! Copyright (c) 2018-2021 Iqlusion Inc. (licensed under the Apache License, Version 2.0)
 //! Modifications Copyright (c) 2021, Foris Limited (licensed under the Apache License, Version 2.0)
 
+mod watchdog;
+
 use crate::{
-    chain::state::{PersistStateSync, State, StateErrorKind},
-    config::validator::ValidatorConfig,
-    connection::Connection,
+    admin::SessionControl,
+    audit::{AuditEntry, AuditLog, Outcome},
+    chain::state::{consensus, LastSigned, PersistStateSync, State, StateError, StateErrorKind},
+    config::validator::{ProtocolVersion, ValidatorConfig},
+    connection::{Connection, ConnectionManager},
     error::{Error, ErrorKind},
-    rpc::{ChainIdErrorType, DoubleSignErrorType, Request, Response},
+    metrics::{MetricEvent, MetricsSink, MsgType},
+    rpc::{
+        proposal_signable_bytes, vote_signable_bytes, AminoCanonicalProposal, AminoCanonicalVote,
+        ChainIdErrorType, DoubleSignErrorType, PausedErrorType, Request, Response, SignedExtension,
+        VoteExtension,
+    },
+    signer::ConsensusSigner,
 };
 use anomaly::{fail, format_err};
-use ed25519_dalek::{Keypair, Signer};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::Instant;
-use tendermint_proto::privval::PingResponse;
+use tendermint::{chain, proposal::SignProposalRequest, vote::SignVoteRequest, Time};
+use tendermint_proto::{
+    google::protobuf::Timestamp,
+    privval::PingResponse,
+    types::{CanonicalProposal, CanonicalVote},
+};
 use tracing::{debug, error, info};
+use watchdog::Link;
 
-/// Encrypted or plain session with a validator node
-pub struct Session<S: PersistStateSync> {
-    /// Validator configuration options
-    config: ValidatorConfig,
+/// Canonical sign bytes types which may be re-requested with a different timestamp
+trait Timestamped: prost::Message + Default + PartialEq {
+    fn timestamp_mut(&mut self) -> &mut Option<Timestamp>;
+}
 
-    /// connection to a validator node
-    connection: Box<dyn Connection>,
+impl Timestamped for CanonicalVote {
+    fn timestamp_mut(&mut self) -> &mut Option<Timestamp> {
+        &mut self.timestamp
+    }
+}
+
+impl Timestamped for CanonicalProposal {
+    fn timestamp_mut(&mut self) -> &mut Option<Timestamp> {
+        &mut self.timestamp
+    }
+}
+
+impl Timestamped for AminoCanonicalVote {
+    fn timestamp_mut(&mut self) -> &mut Option<Timestamp> {
+        &mut self.timestamp
+    }
+}
+
+impl Timestamped for AminoCanonicalProposal {
+    fn timestamp_mut(&mut self) -> &mut Option<Timestamp> {
+        &mut self.timestamp
+    }
+}
+
+/// Sign requests that are checked against the double-sign state
+trait SignableRequest: Clone + Into<State> {
+    /// canonical sign bytes (Protobuf)
+    type Canonical: Timestamped;
+    /// canonical sign bytes (Amino, before Tendermint v0.34)
+    type AminoCanonical: Timestamped;
+    /// what is signed (in errors)
+    const NAME: &'static str;
+
+    fn chain_id(&self) -> &chain::Id;
+    fn height(&self) -> i64;
+    fn timestamp(&self) -> Option<Time>;
+    fn signable_bytes(&self, version: ProtocolVersion) -> Result<Vec<u8>, Error>;
+    fn paused_error_type() -> PausedErrorType;
+    fn chain_id_error_type() -> ChainIdErrorType;
+    fn double_sign_error_type() -> DoubleSignErrorType;
+}
+
+impl SignableRequest for SignProposalRequest {
+    type Canonical = CanonicalProposal;
+    type AminoCanonical = AminoCanonicalProposal;
+    const NAME: &'static str = "proposal";
+
+    fn chain_id(&self) -> &chain::Id {
+        &self.chain_id
+    }
+
+    fn height(&self) -> i64 {
+        self.proposal.height.into()
+    }
+
+    fn timestamp(&self) -> Option<Time> {
+        self.proposal.timestamp
+    }
+
+    fn signable_bytes(&self, version: ProtocolVersion) -> Result<Vec<u8>, Error> {
+        proposal_signable_bytes(self, version)
+    }
+
+    fn paused_error_type() -> PausedErrorType {
+        PausedErrorType::Proposal
+    }
+
+    fn chain_id_error_type() -> ChainIdErrorType {
+        ChainIdErrorType::Proposal
+    }
+
+    fn double_sign_error_type() -> DoubleSignErrorType {
+        DoubleSignErrorType::Proposal
+    }
+}
+
+impl SignableRequest for SignVoteRequest {
+    type Canonical = CanonicalVote;
+    type AminoCanonical = AminoCanonicalVote;
+    const NAME: &'static str = "vote";
+
+    fn chain_id(&self) -> &chain::Id {
+        &self.chain_id
+    }
+
+    fn height(&self) -> i64 {
+        self.vote.height.into()
+    }
 
-    /// consensus signing key
-    signing_key: Keypair,
+    fn timestamp(&self) -> Option<Time> {
+        self.vote.timestamp
+    }
+
+    fn signable_bytes(&self, version: ProtocolVersion) -> Result<Vec<u8>, Error> {
+        vote_signable_bytes(self, version)
+    }
 
+    fn paused_error_type() -> PausedErrorType {
+        PausedErrorType::Vote
+    }
+
+    fn chain_id_error_type() -> ChainIdErrorType {
+        ChainIdErrorType::Vote
+    }
+
+    fn double_sign_error_type() -> DoubleSignErrorType {
+        DoubleSignErrorType::Vote
+    }
+}
+
+/// Decision on a sign request
+enum Signing {
+    /// newly signed
+    Signed(Vec<u8>),
+    /// the previous signature (with the timestamp it was signed with)
+    Resent(Vec<u8>, Option<Timestamp>),
+    /// not signed (e.g. a double sign or paused signing)
+    Refused(Response),
+}
+
+/// Consensus state and its persistence shared by all sessions
+/// of the same validator (e.g. connected to redundant nodes)
+pub struct SharedState<S: PersistStateSync> {
     /// consensus state
     state: State,
 
@@ -32,25 +165,189 @@ pub struct Session<S: PersistStateSync> {
     state_syncer: S,
 }
 
-impl<S: PersistStateSync> Session<S> {
+impl<S: PersistStateSync> SharedState<S> {
+    pub fn new(state: State, state_syncer: S) -> Self {
+        Self {
+            state,
+            state_syncer,
+        }
+    }
+
+    /// Check the new consensus state (before it's signed)
+    fn check(&self, new_state: &State) -> Result<(), StateError> {
+        self.state
+            .check_consensus_state(new_state.consensus_state())
+    }
+
+    /// Persist the new consensus state with the sign bytes and the signature
+    /// (like in Go's `FilePV`, the signature is only sent once it was persisted)
+    fn update_signed(
+        &mut self,
+        consensus_state: &consensus::State,
+        sign_bytes: Vec<u8>,
+        signature: Vec<u8>,
+    ) -> Result<(), StateError> {
+        let last_signed = LastSigned {
+            sign_bytes,
+            signature,
+        };
+        self.state.check_update(
+            State::new(consensus_state.clone(), Some(last_signed)),
+            &mut self.state_syncer,
+        )
+    }
+
+    /// If the request is for the last signed height/round/step and its sign bytes
+    /// differ from the last signed ones at most in the timestamp,
+    /// returns the previous signature with the original timestamp
+    fn last_signature<M: Timestamped>(
+        &self,
+        new_state: &consensus::State,
+        sign_bytes: &[u8],
+    ) -> Option<(Vec<u8>, Option<Timestamp>)> {
+        let last = self.state.last_signed()?;
+        let last_state = self.state.consensus_state();
+        if last_state.height != new_state.height
+            || last_state.round != new_state.round
+            || last_state.step != new_state.step
+        {
+            return None;
+        }
+        let mut last_msg = M::decode_length_delimited(last.sign_bytes.as_ref()).ok()?;
+        let mut new_msg = M::decode_length_delimited(sign_bytes).ok()?;
+        let timestamp = last_msg.timestamp_mut().take();
+        new_msg.timestamp_mut().take();
+        if last_msg == new_msg {
+            Some((last.signature.clone(), timestamp))
+        } else {
+            None
+        }
+    }
+}
+
+/// Encrypted or plain session with a validator node
+pub struct Session<S: PersistStateSync, K: ConsensusSigner> {
+    /// Validator configuration options
+    config: ValidatorConfig,
+
+    /// connection to a validator node
+    link: Link,
+
+    /// consensus signing backend
+    signer: Arc<K>,
+
+    /// consensus state (shared between connections to the same validator)
+    state: Arc<Mutex<SharedState<S>>>,
+
+    /// audit log of sign requests and decisions (if enabled)
+    audit_log: Option<Arc<Mutex<AuditLog>>>,
+
+    /// receiver of signing activity metrics (if enabled)
+    metrics: Option<Arc<dyn MetricsSink>>,
+
+    /// admin control (pause/resume) and status of this session
+    control: Arc<SessionControl>,
+}
+
+impl<S: PersistStateSync, K: ConsensusSigner> Session<S, K> {
     pub fn reset_connection(&mut self, connection: Box<dyn Connection>) {
-        self.connection = connection;
+        self.link = Link::new(connection, &self.config);
+        self.control.set_connected(true);
+        self.metric(MetricEvent::Reconnect {
+            chain_id: self.config.chain_id.clone(),
+        });
     }
 
     pub fn new(
         config: ValidatorConfig,
         connection: Box<dyn Connection>,
-        signing_key: Keypair,
+        signer: K,
         state: State,
         state_syncer: S,
     ) -> Self {
+        let control = Arc::new(SessionControl::new(config.chain_id.clone(), None));
+        let link = Link::new(connection, &config);
         Self {
             config,
-            connection,
-            signing_key,
+            link,
+            signer: Arc::new(signer),
+            state: Arc::new(Mutex::new(SharedState::new(state, state_syncer))),
+            audit_log: None,
+            metrics: None,
+            control,
+        }
+    }
+
+    /// Creates a session with the signer and the double-sign state
+    /// that are shared with other sessions of the same validator
+    pub fn new_shared(
+        config: ValidatorConfig,
+        connection: Box<dyn Connection>,
+        signer: Arc<K>,
+        state: Arc<Mutex<SharedState<S>>>,
+    ) -> Self {
+        let control = Arc::new(SessionControl::new(config.chain_id.clone(), None));
+        let link = Link::new(connection, &config);
+        Self {
+            config,
+            link,
+            signer,
             state,
-            state_syncer,
+            audit_log: None,
+            metrics: None,
+            control,
+        }
+    }
+
+    /// Records all sign requests and decisions in the provided audit log
+    /// (which may be shared with other sessions)
+    pub fn set_audit_log(&mut self, audit_log: Arc<Mutex<AuditLog>>) {
+        self.audit_log = Some(audit_log);
+    }
+
+    /// Reports signing activity to the provided metrics sink
+    pub fn set_metrics(&mut self, metrics: Arc<dyn MetricsSink>) {
+        self.metrics = Some(metrics);
+    }
+
+    /// Uses the provided admin control (e.g. one that is shared with an admin socket)
+    pub fn set_control(&mut self, control: Arc<SessionControl>) {
+        self.control = control;
+    }
+
+    /// Admin control and status of this session
+    pub fn control(&self) -> Arc<SessionControl> {
+        self.control.clone()
+    }
+
+    /// Reports a metric event (if enabled)
+    fn metric(&self, event: MetricEvent) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record(event);
+        }
+    }
+
+    /// Appends a record to the audit log (if enabled)
+    fn audit(&self, entry: AuditEntry<'_>) -> Result<(), Error> {
+        if let Some(audit_log) = &self.audit_log {
+            audit_log
+                .lock()
+                .map_err(|e| format_err!(ErrorKind::PoisonError, "audit log lock poisoned: {}", e))?
+                .append(entry)?;
         }
+        Ok(())
+    }
+
+    /// Locks the shared consensus state
+    fn lock_state(&self) -> Result<MutexGuard<'_, SharedState<S>>, Error> {
+        self.state.lock().map_err(|e| {
+            format_err!(
+                ErrorKind::PoisonError,
+                "consensus state lock poisoned: {}",
+                e
+            )
+            .into()
+        })
     }
 
     /// Check chain id matches the configured one
@@ -79,126 +376,213 @@ impl<S: PersistStateSync> Session<S> {
         Ok(())
     }
 
+    /// Signs the vote extension of a precommit that is signed (or re-sent):
+    /// extensions are non-deterministic, so (like the timestamp) the extension isn't
+    /// part of the double-sign state and is signed again when the vote is re-sent
+    fn sign_extension(
+        &self,
+        req: &SignVoteRequest,
+        extension: Option<VoteExtension>,
+    ) -> Result<Option<SignedExtension>, Error> {
+        match extension {
+            Some(extension) => {
+                let sign_bytes = extension.to_signable_vec(&req.vote, &req.chain_id)?;
+                let signature = self.signer.sign_bytes(&sign_bytes)?;
+                Ok(Some(extension.signed(signature)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Checks the sign request against the double-sign state and signs it
+    /// (or re-sends the previous signature if only the timestamp differs)
+    fn sign<R: SignableRequest>(&self, req: &R) -> Result<Signing, Error> {
+        let request_state: State = req.clone().into();
+        let req_cs = request_state.consensus_state();
+        let audit_entry = |outcome, signature| AuditEntry {
+            chain_id: req.chain_id(),
+            consensus_state: req_cs,
+            timestamp: req.timestamp(),
+            outcome,
+            signature,
+        };
+        if self.control.is_paused() {
+            info!("[{}] signing is paused", &self.config.chain_id);
+            return Ok(Signing::Refused(Response::signing_paused(
+                R::paused_error_type(),
+            )));
+        }
+        if self.check_chain_id(req.chain_id()).is_err() {
+            self.audit(audit_entry(Outcome::ChainIdMismatch, None))?;
+            self.metric(MetricEvent::ChainIdError {
+                chain_id: self.config.chain_id.clone(),
+            });
+            return Ok(Signing::Refused(Response::invalid_chain_id(
+                R::chain_id_error_type(),
+                req.chain_id(),
+            )));
+        }
+        if let Err(e) = self.check_max_height(req.height()) {
+            self.audit(audit_entry(Outcome::MaxHeightExceeded, None))?;
+            return Err(e);
+        }
+        let version = self.config.protocol_version;
+        let signable_bytes = req.signable_bytes(version)?;
+        // the lock is held until signing is done, so that the requests
+        // from different nodes are signed in the order they were checked
+        let mut shared_state = self.lock_state()?;
+        let last_signature = if version.is_protobuf() {
+            shared_state.last_signature::<R::Canonical>(req_cs, &signable_bytes)
+        } else {
+            shared_state.last_signature::<R::AminoCanonical>(req_cs, &signable_bytes)
+        };
+        if let Some((signature, timestamp)) = last_signature {
+            info!(
+                "[{}] re-sending the previous signature:{} at h/r/s {}",
+                &self.config.chain_id,
+                req_cs.block_id_prefix(),
+                req_cs,
+            );
+            self.audit(audit_entry(Outcome::Resent, Some(signature.clone())))?;
+            return Ok(Signing::Resent(signature, timestamp));
+        }
+        match shared_state.check(&request_state) {
+            Ok(_) => {
+                let started_at = Instant::now();
+                let signature = self.signer.sign_bytes(&signable_bytes)?;
+                let signing_duration = started_at.elapsed();
+                let persist_started_at = Instant::now();
+                if let Err(e) =
+                    shared_state.update_signed(req_cs, signable_bytes, signature.clone())
+                {
+                    self.audit(audit_entry(Outcome::Failed, None))?;
+                    fail!(ErrorKind::SigningError, "failed signing {}: {}", R::NAME, e)
+                }
+                self.metric(MetricEvent::StatePersisted {
+                    chain_id: self.config.chain_id.clone(),
+                    duration_us: persist_started_at.elapsed().as_micros() as u64,
+                });
+                info!(
+                    "[{}] signed:{} at h/r/s {} ({} ms)",
+                    &self.config.chain_id,
+                    req_cs.block_id_prefix(),
+                    req_cs,
+                    signing_duration.as_millis(),
+                );
+                self.metric(MetricEvent::Signed {
+                    chain_id: self.config.chain_id.clone(),
+                    msg_type: MsgType::from_step(req_cs.step),
+                    height: req_cs.height.value(),
+                    round: req_cs.round.value(),
+                    duration_us: signing_duration.as_micros() as u64,
+                });
+                self.control.record_signed(req_cs);
+                self.audit(audit_entry(Outcome::Signed, Some(signature.clone())))?;
+                Ok(Signing::Signed(signature))
+            }
+            Err(e) if e.kind() == &StateErrorKind::DoubleSign => {
+                // Report double signing error back to the validator
+                let original_block_id = shared_state.state.consensus_state().block_id_prefix();
+
+                error!(
+                    "[{}] attempted double sign at h/r/s: {} ({} != {})",
+                    &self.config.chain_id,
+                    req_cs,
+                    original_block_id,
+                    req_cs.block_id_prefix()
+                );
+                self.audit(audit_entry(Outcome::DoubleSignRefused, None))?;
+                self.metric(MetricEvent::DoubleSignRefused {
+                    chain_id: self.config.chain_id.clone(),
+                    msg_type: MsgType::from_step(req_cs.step),
+                });
+
+                Ok(Signing::Refused(Response::double_sign(
+                    R::double_sign_error_type(),
+                    req_cs.height.into(),
+                )))
+            }
+            Err(e) => {
+                self.audit(audit_entry(Outcome::Failed, None))?;
+                fail!(ErrorKind::SigningError, "failed signing {}: {}", R::NAME, e)
+            }
+        }
+    }
+
     /// Main request loop
     pub fn request_loop(&mut self) -> Result<(), Error> {
-        while self.handle_request()? {}
-        Ok(())
+        loop {
+            match self.handle_request() {
+                Ok(true) => {}
+                Ok(false) => return Ok(()),
+                Err(e) => {
+                    // the caller is expected to reset the connection
+                    self.control.set_connected(false);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Runs the request loop, reopening the connection with the manager
+    /// after connection errors (until it stops retrying)
+    pub fn run(&mut self, manager: &mut ConnectionManager) -> Result<(), Error> {
+        loop {
+            match self.request_loop() {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    error!("[{}] request loop failed: {}", &self.config.chain_id, e);
+                    if !manager.retry() {
+                        return Err(e);
+                    }
+                }
+            }
+            let connection = manager.connect()?;
+            self.reset_connection(connection);
+        }
     }
 
     /// Handle an incoming request from the validator
     fn handle_request(&mut self) -> Result<bool, Error> {
-        let request = Request::read(&mut self.connection)?;
+        let request = self.link.read_request()?;
         debug!(
             "[{}] received request: {:?}",
             &self.config.chain_id, &request
         );
+        // a shutdown waits until the request is answered
+        let control = self.control.clone();
+        let _busy = control.begin_request();
         let response = match request {
-            Request::SignProposal(req) => {
-                if self.check_chain_id(&req.chain_id).is_err() {
-                    Response::invalid_chain_id(ChainIdErrorType::Proposal, &req.chain_id)
-                } else {
-                    self.check_max_height(req.proposal.height.into())?;
-                    let request_state = State::from(req.clone());
-                    let req_cs = request_state.consensus_state();
-                    match self
-                        .state
-                        .check_update_consensus_state(req_cs.clone(), &mut self.state_syncer)
-                    {
-                        Ok(_) => {
-                            let signable_bytes = req.to_signable_vec().map_err(|e| {
-                                format_err!(
-                                    ErrorKind::SigningError,
-                                    "cannot get proposal signable bytes: {}",
-                                    e
-                                )
-                            })?;
-                            let started_at = Instant::now();
-                            let signature = self.signing_key.sign(&signable_bytes);
-                            info!(
-                                "[{}] signed:{} at h/r/s {} ({} ms)",
-                                &self.config.chain_id,
-                                req_cs.block_id_prefix(),
-                                req_cs,
-                                started_at.elapsed().as_millis(),
-                            );
-                            Response::proposal_response(req, signature)
-                        }
-                        Err(e) if e.kind() == &StateErrorKind::DoubleSign => {
-                            // Report double signing error back to the validator
-                            let original_block_id = self.state.consensus_state().block_id_prefix();
-
-                            error!(
-                                "[{}] attempted double sign at h/r/s: {} ({} != {})",
-                                &self.config.chain_id,
-                                req_cs,
-                                original_block_id,
-                                req_cs.block_id_prefix()
-                            );
-
-                            Response::double_sign(
-                                DoubleSignErrorType::Proposal,
-                                req_cs.height.into(),
-                            )
-                        }
-                        Err(e) => fail!(ErrorKind::SigningError, "failed signing proposal: {}", e),
-                    }
+            Request::SignProposal(req) => match self.sign(&req)? {
+                Signing::Signed(signature) => Response::proposal_response(req, signature),
+                Signing::Resent(signature, timestamp) => {
+                    Response::proposal_response_with_timestamp(req, signature, timestamp)
                 }
-            }
-            Request::SignVote(req) => {
-                if self.check_chain_id(&req.chain_id).is_err() {
-                    Response::invalid_chain_id(ChainIdErrorType::Vote, &req.chain_id)
-                } else {
-                    self.check_max_height(req.vote.height.into())?;
-                    let request_state = State::from(req.clone());
-                    let req_cs = request_state.consensus_state();
-                    match self
-                        .state
-                        .check_update_consensus_state(req_cs.clone(), &mut self.state_syncer)
-                    {
-                        Ok(_) => {
-                            let signable_bytes = req.to_signable_vec().map_err(|e| {
-                                format_err!(
-                                    ErrorKind::SigningError,
-                                    "cannot get vote signable bytes: {}",
-                                    e
-                                )
-                            })?;
-                            let started_at = Instant::now();
-                            let signature = self.signing_key.sign(&signable_bytes);
-                            info!(
-                                "[{}] signed:{} at h/r/s {} ({} ms)",
-                                &self.config.chain_id,
-                                req_cs.block_id_prefix(),
-                                req_cs,
-                                started_at.elapsed().as_millis(),
-                            );
-                            Response::vote_response(req, signature)
-                        }
-                        Err(e) if e.kind() == &StateErrorKind::DoubleSign => {
-                            // Report double signing error back to the validator
-                            let original_block_id = self.state.consensus_state().block_id_prefix();
-
-                            error!(
-                                "[{}] attempted double sign at h/r/s: {} ({} != {})",
-                                &self.config.chain_id,
-                                req_cs,
-                                original_block_id,
-                                req_cs.block_id_prefix()
-                            );
-
-                            Response::double_sign(DoubleSignErrorType::Vote, req_cs.height.into())
-                        }
-                        Err(e) => fail!(ErrorKind::SigningError, "failed signing vote: {}", e),
-                    }
+                Signing::Refused(response) => response,
+            },
+            Request::SignVote(req, extension) => match self.sign(&req)? {
+                Signing::Signed(signature) => {
+                    let extension = self.sign_extension(&req, extension)?;
+                    Response::vote_response(req, signature, extension)
                 }
-            }
+                Signing::Resent(signature, timestamp) => {
+                    let extension = self.sign_extension(&req, extension)?;
+                    Response::vote_response_with_timestamp(req, signature, timestamp, extension)
+                }
+                Signing::Refused(response) => response,
+            },
             // non-signable requests:
-            Request::ReplyPing(_) => Response::Ping(PingResponse {}),
+            Request::ReplyPing(_) => {
+                self.metric(MetricEvent::Ping {
+                    chain_id: self.config.chain_id.clone(),
+                });
+                Response::Ping(PingResponse {})
+            }
             Request::ShowPublicKey(ref req) => {
                 if self.check_chain_id(&req.chain_id).is_err() {
                     Response::invalid_chain_id(ChainIdErrorType::Pubkey, &req.chain_id)
                 } else {
-                    Response::PublicKey(self.signing_key.public.into())
+                    Response::PublicKey(self.signer.public_key())
                 }
             }
         };
@@ -207,11 +591,78 @@ impl<S: PersistStateSync> Session<S> {
             &self.config.chain_id, &response
         );
 
-        let response_bytes = response.encode()?;
-        self.connection
-            .write_all(&response_bytes)
-            .map_err(|e| format_err!(ErrorKind::IoError, "write response failed: {}", e))?;
+        let response_bytes = response.encode(self.config.protocol_version)?;
+        self.link.write_response(response_bytes)?;
 
         Ok(true)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message as _;
+    use tendermint_proto::types::CanonicalBlockId;
+
+    struct NoPersist;
+
+    impl PersistStateSync for NoPersist {
+        fn load_state(&mut self) -> Result<State, StateError> {
+            Ok(State::from(consensus::State::default()))
+        }
+
+        fn persist_state(&mut self, _new_state: &State) -> Result<(), StateError> {
+            Ok(())
+        }
+    }
+
+    fn vote_bytes(hash: &[u8], seconds: i64) -> Vec<u8> {
+        let vote = CanonicalVote {
+            r#type: 1,
+            height: 1,
+            block_id: Some(CanonicalBlockId {
+                hash: hash.to_vec(),
+                ..Default::default()
+            }),
+            timestamp: Some(Timestamp { seconds, nanos: 0 }),
+            chain_id: "testchain-1".to_owned(),
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        vote.encode_length_delimited(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn resigns_only_timestamp_changes() {
+        let cs = consensus::State {
+            height: 1u32.into(),
+            step: 1,
+            ..Default::default()
+        };
+        let mut shared_state = SharedState::new(State::from(cs.clone()), NoPersist);
+        assert!(shared_state
+            .last_signature::<CanonicalVote>(&cs, &vote_bytes(&[1; 32], 1))
+            .is_none());
+        shared_state
+            .update_signed(&cs, vote_bytes(&[1; 32], 1), vec![42; 64])
+            .unwrap();
+        assert_eq!(
+            shared_state.last_signature::<CanonicalVote>(&cs, &vote_bytes(&[1; 32], 2)),
+            Some((
+                vec![42; 64],
+                Some(Timestamp {
+                    seconds: 1,
+                    nanos: 0
+                })
+            ))
+        );
+        assert!(shared_state
+            .last_signature::<CanonicalVote>(&cs, &vote_bytes(&[2; 32], 1))
+            .is_none());
+        let next_step = consensus::State { step: 2, ..cs };
+        assert!(shared_state
+            .last_signature::<CanonicalVote>(&next_step, &vote_bytes(&[1; 32], 1))
+            .is_none());
+    }
+}
@@ -0,0 +1,289 @@
+//! Idle timeout of validator connections
+//!
+//! A blocked read can't be interrupted on every connection type (e.g. vsock or
+//! the enclave usercall streams), so with an idle timeout, the requests are read
+//! on a separate thread. If nothing (not even a ping) arrives within the timeout,
+//! the connection is shut down (so that the blocked read returns), the thread
+//! is joined and the session gets a new one. Connections without a shutdown handle
+//! are left to that thread (which stops once its read returns).
+
+use crate::{
+    config::validator::{ProtocolVersion, ValidatorConfig},
+    connection::{Connection, ShutdownHandle},
+    error::{Error, ErrorKind},
+    rpc::{MsgReader, Request},
+};
+use anomaly::{fail, format_err};
+use std::{
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+use tendermint::chain;
+use tracing::debug;
+
+/// Connection to a validator node
+pub(super) enum Link {
+    /// requests are read on the session's thread
+    Direct {
+        connection: Box<dyn Connection>,
+        msg_reader: MsgReader,
+        version: ProtocolVersion,
+        chain_id: chain::Id,
+    },
+    /// requests are read on a separate thread (with the idle timeout)
+    Watched(WatchedConnection),
+}
+
+impl Link {
+    /// Link with the protocol and the idle timeout (if enabled) of the validator
+    pub fn new(connection: Box<dyn Connection>, config: &ValidatorConfig) -> Self {
+        let version = config.protocol_version;
+        let chain_id = config.chain_id.clone();
+        match config.idle_timeout {
+            Some(seconds) => Link::Watched(WatchedConnection::new(
+                connection,
+                Duration::from_secs(seconds.into()),
+                version,
+                chain_id,
+            )),
+            None => Link::Direct {
+                connection,
+                msg_reader: MsgReader::default(),
+                version,
+                chain_id,
+            },
+        }
+    }
+
+    /// Waits for the next request
+    pub fn read_request(&mut self) -> Result<Request, Error> {
+        match self {
+            Link::Direct {
+                connection,
+                msg_reader,
+                version,
+                chain_id,
+            } => Request::read(connection, msg_reader, *version, chain_id),
+            Link::Watched(watched) => watched.read_request(),
+        }
+    }
+
+    /// Sends the encoded response
+    pub fn write_response(&mut self, response: Vec<u8>) -> Result<(), Error> {
+        match self {
+            Link::Direct { connection, .. } => connection.write_all(&response).map_err(|e| {
+                format_err!(ErrorKind::IoError, "write response failed: {}", e).into()
+            }),
+            Link::Watched(watched) => watched.write_response(response),
+        }
+    }
+}
+
+/// Connection whose requests are read (and responses written) by a separate thread
+pub(super) struct WatchedConnection {
+    requests: Receiver<Result<Request, Error>>,
+    /// dropped when the connection is given up (the reader stops waiting for responses)
+    responses: Option<Sender<Vec<u8>>>,
+    idle_timeout: Duration,
+    /// the thread reading the requests
+    reader: Option<JoinHandle<()>>,
+    /// unblocks the reader when the connection is given up
+    shutdown: Option<ShutdownHandle>,
+}
+
+impl WatchedConnection {
+    fn new(
+        mut connection: Box<dyn Connection>,
+        idle_timeout: Duration,
+        version: ProtocolVersion,
+        chain_id: chain::Id,
+    ) -> Self {
+        let (request_sender, requests) = mpsc::channel();
+        let (responses, response_receiver) = mpsc::channel::<Vec<u8>>();
+        let shutdown = connection.shutdown_handle();
+        let reader = thread::spawn(move || {
+            let mut msg_reader = MsgReader::default();
+            loop {
+                let request = Request::read(&mut connection, &mut msg_reader, version, &chain_id);
+                let failed = request.is_err();
+                if request_sender.send(request).is_err() || failed {
+                    break;
+                }
+                // each request is answered before the next one is read
+                // (the sender is dropped when the session gives up the connection)
+                let response = match response_receiver.recv() {
+                    Ok(response) => response,
+                    Err(_) => break,
+                };
+                if let Err(e) = connection.write_all(&response) {
+                    let _ = request_sender.send(Err(format_err!(
+                        ErrorKind::IoError,
+                        "write response failed: {}",
+                        e
+                    )
+                    .into()));
+                    break;
+                }
+            }
+            debug!("request reader stopped");
+        });
+        Self {
+            requests,
+            responses: Some(responses),
+            idle_timeout,
+            reader: Some(reader),
+            shutdown,
+        }
+    }
+
+    fn read_request(&mut self) -> Result<Request, Error> {
+        let result = match self.requests.recv_timeout(self.idle_timeout) {
+            Ok(request) => request,
+            Err(RecvTimeoutError::Timeout) => Err(format_err!(
+                ErrorKind::IoError,
+                "no request received within {:?}",
+                self.idle_timeout
+            )
+            .into()),
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(format_err!(ErrorKind::IoError, "request reader stopped").into())
+            }
+        };
+        if result.is_err() {
+            self.stop();
+        }
+        result
+    }
+
+    fn write_response(&mut self, response: Vec<u8>) -> Result<(), Error> {
+        let sent = match &self.responses {
+            Some(responses) => responses.send(response).is_ok(),
+            None => false,
+        };
+        if !sent {
+            self.stop();
+            fail!(ErrorKind::IoError, "request reader stopped");
+        }
+        Ok(())
+    }
+
+    /// Gives up the connection: shuts it down and joins the reader
+    /// (which would otherwise keep its thread and socket until its read returns)
+    fn stop(&mut self) {
+        self.responses = None;
+        let reader = match self.reader.take() {
+            Some(reader) => reader,
+            None => return,
+        };
+        match &self.shutdown {
+            Some(shutdown) => {
+                if let Err(e) = shutdown() {
+                    debug!("failed to shut down the connection: {}", e);
+                }
+                if reader.join().is_err() {
+                    debug!("request reader panicked");
+                }
+            }
+            None => debug!("leaving the request reader to its pending read"),
+        }
+    }
+}
+
+impl Drop for WatchedConnection {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message as _;
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+    use tendermint_proto::privval::{message::Sum, Message as PrivMessage, PingRequest};
+
+    /// reads what is sent on the channel (blocking until then)
+    struct TestConnection {
+        incoming: Mutex<Receiver<Vec<u8>>>,
+        buf: Vec<u8>,
+        written: Arc<Mutex<Vec<u8>>>,
+        /// the sending side (dropped on shutdown, which ends a blocked read)
+        sender: Arc<Mutex<Option<Sender<Vec<u8>>>>>,
+    }
+
+    impl io::Read for TestConnection {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.buf.is_empty() {
+                match self.incoming.lock().unwrap().recv() {
+                    Ok(data) => self.buf = data,
+                    Err(_) => return Ok(0),
+                }
+            }
+            let n = buf.len().min(self.buf.len());
+            buf[..n].copy_from_slice(&self.buf[..n]);
+            self.buf.drain(..n);
+            Ok(n)
+        }
+    }
+
+    impl io::Write for TestConnection {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for TestConnection {
+        fn shutdown_handle(&self) -> Option<ShutdownHandle> {
+            let sender = self.sender.clone();
+            Some(Arc::new(move || {
+                sender.lock().unwrap().take();
+                Ok(())
+            }))
+        }
+    }
+
+    #[test]
+    fn drops_idle_connections() {
+        let (sender, incoming) = mpsc::channel();
+        let sender = Arc::new(Mutex::new(Some(sender)));
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let connection = TestConnection {
+            incoming: Mutex::new(incoming),
+            buf: Vec::new(),
+            written: written.clone(),
+            sender: sender.clone(),
+        };
+        let mut link = Link::Watched(WatchedConnection::new(
+            Box::new(connection),
+            Duration::from_millis(200),
+            ProtocolVersion::V0_34,
+            "testchain-1".parse().unwrap(),
+        ));
+
+        let mut ping = Vec::new();
+        PrivMessage {
+            sum: Some(Sum::PingRequest(PingRequest {})),
+        }
+        .encode_length_delimited(&mut ping)
+        .unwrap();
+        sender.lock().unwrap().as_ref().unwrap().send(ping).unwrap();
+        assert!(matches!(link.read_request(), Ok(Request::ReplyPing(_))));
+        link.write_response(b"pong".to_vec()).unwrap();
+
+        // the node stays connected, but nothing arrives
+        assert!(link.read_request().is_err());
+        assert_eq!(written.lock().unwrap().as_slice(), b"pong");
+        // the connection was shut down and its reader (owning it) joined
+        assert!(sender.lock().unwrap().is_none());
+        assert_eq!(Arc::strong_count(&written), 1);
+    }
+}
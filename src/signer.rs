@@ -0,0 +1,399 @@
+//! Consensus signing backends
+//! Copyright (c) 2021, Foris Limited (licensed under the Apache License, Version 2.0)
+
+use crate::config::validator::ProtocolVersion;
+use crate::error::{Error, ErrorKind};
+use anomaly::{fail, format_err};
+use ed25519_dalek::SECRET_KEY_LENGTH;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "bls")]
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+use tendermint::{account, PublicKey};
+use zeroize::Zeroizing;
+
+/// Domain-separation tag of the BLS12-381 signatures
+/// (the proof-of-possession ciphersuite with public keys in G1, as in CometBFT and Ethereum)
+#[cfg(feature = "bls")]
+pub const BLS12_381_DST: &[u8] = b"BLS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_POP_";
+
+/// Longest message that is signed as is with BLS12-381 keys
+/// (the SHA-256 digest of longer ones is signed instead, as in CometBFT)
+#[cfg(feature = "bls")]
+pub const BLS12_381_MAX_MSG_LEN: usize = 32;
+
+/// Types of supported consensus keys
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyType {
+    /// Ed25519 keys
+    #[default]
+    Ed25519,
+    /// secp256k1 ECDSA keys
+    Secp256k1,
+    /// BLS12-381 keys (CometBFT v1)
+    #[cfg(feature = "bls")]
+    Bls12_381,
+}
+
+impl KeyType {
+    /// The oldest protocol version whose nodes accept keys of this type
+    pub fn min_protocol_version(self) -> ProtocolVersion {
+        match self {
+            KeyType::Ed25519 | KeyType::Secp256k1 => ProtocolVersion::V0_33,
+            #[cfg(feature = "bls")]
+            KeyType::Bls12_381 => ProtocolVersion::V1,
+        }
+    }
+
+    /// Checks that nodes of the provided protocol version accept keys of this type
+    pub fn check_protocol_version(self, version: ProtocolVersion) -> Result<(), Error> {
+        let min_version = self.min_protocol_version();
+        if version < min_version {
+            fail!(
+                ErrorKind::ConfigError,
+                "{:?} keys need `protocol_version = \"{}\"` or later (not {})",
+                self,
+                min_version,
+                version
+            );
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for KeyType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ed25519" => Ok(KeyType::Ed25519),
+            "secp256k1" => Ok(KeyType::Secp256k1),
+            #[cfg(feature = "bls")]
+            "bls12_381" => Ok(KeyType::Bls12_381),
+            _ => Err("unknown key type".to_owned()),
+        }
+    }
+}
+
+/// Consensus public key
+/// (`tendermint::PublicKey` has no BLS12-381 keys)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConsensusPublicKey {
+    /// Ed25519 or secp256k1 key
+    Tendermint(PublicKey),
+    /// BLS12-381 key (the compressed G1 point)
+    #[cfg(feature = "bls")]
+    Bls12_381(Vec<u8>),
+}
+
+impl ConsensusPublicKey {
+    /// Parses the raw bytes of a key of the provided type
+    pub fn from_raw(key_type: KeyType, bytes: &[u8]) -> Result<Self, Error> {
+        let public_key = match key_type {
+            KeyType::Ed25519 => PublicKey::from_raw_ed25519(bytes).map(Self::Tendermint),
+            KeyType::Secp256k1 => PublicKey::from_raw_secp256k1(bytes).map(Self::Tendermint),
+            #[cfg(feature = "bls")]
+            KeyType::Bls12_381 => blst::min_pk::PublicKey::key_validate(bytes)
+                .ok()
+                .map(|pk| Self::Bls12_381(pk.compress().to_vec())),
+        };
+        public_key.ok_or_else(|| {
+            format_err!(ErrorKind::InvalidKey, "invalid {:?} public key", key_type).into()
+        })
+    }
+
+    /// raw bytes of the key
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            ConsensusPublicKey::Tendermint(pk) => pk.to_bytes(),
+            #[cfg(feature = "bls")]
+            ConsensusPublicKey::Bls12_381(pk) => pk.clone(),
+        }
+    }
+
+    /// validator address of the key
+    pub fn address(&self) -> Option<account::Id> {
+        match self {
+            ConsensusPublicKey::Tendermint(PublicKey::Ed25519(pk)) => Some(account::Id::from(*pk)),
+            ConsensusPublicKey::Tendermint(PublicKey::Secp256k1(pk)) => {
+                Some(account::Id::from(*pk))
+            }
+            ConsensusPublicKey::Tendermint(_) => None,
+            // truncated SHA-256 of the key
+            #[cfg(feature = "bls")]
+            ConsensusPublicKey::Bls12_381(pk) => {
+                let mut address = [0u8; account::LENGTH];
+                address.copy_from_slice(&Sha256::digest(pk)[..account::LENGTH]);
+                Some(account::Id::new(address))
+            }
+        }
+    }
+}
+
+impl From<PublicKey> for ConsensusPublicKey {
+    fn from(pk: PublicKey) -> Self {
+        ConsensusPublicKey::Tendermint(pk)
+    }
+}
+
+/// Backend that holds the consensus key and signs
+/// the canonical sign bytes of votes and proposals
+pub trait ConsensusSigner: Send + Sync {
+    /// the consensus public key (as reported to the validator node)
+    fn public_key(&self) -> ConsensusPublicKey;
+
+    /// the type of the consensus key
+    fn key_type(&self) -> KeyType;
+
+    /// sign the provided bytes and return the raw signature
+    fn sign_bytes(&self, msg: &[u8]) -> Result<Vec<u8>, Error>;
+}
+
+impl<T: ConsensusSigner + ?Sized> ConsensusSigner for Box<T> {
+    fn public_key(&self) -> ConsensusPublicKey {
+        (**self).public_key()
+    }
+
+    fn key_type(&self) -> KeyType {
+        (**self).key_type()
+    }
+
+    fn sign_bytes(&self, msg: &[u8]) -> Result<Vec<u8>, Error> {
+        (**self).sign_bytes(msg)
+    }
+}
+
+impl ConsensusSigner for ed25519_dalek::Keypair {
+    fn public_key(&self) -> ConsensusPublicKey {
+        PublicKey::Ed25519(self.public).into()
+    }
+
+    fn key_type(&self) -> KeyType {
+        KeyType::Ed25519
+    }
+
+    fn sign_bytes(&self, msg: &[u8]) -> Result<Vec<u8>, Error> {
+        let signature = ed25519_dalek::Signer::try_sign(self, msg)
+            .map_err(|e| format_err!(ErrorKind::SigningError, "Ed25519 signing failed: {}", e))?;
+        Ok(signature.to_bytes().to_vec())
+    }
+}
+
+impl ConsensusSigner for k256::ecdsa::SigningKey {
+    fn public_key(&self) -> ConsensusPublicKey {
+        PublicKey::Secp256k1(self.verifying_key()).into()
+    }
+
+    fn key_type(&self) -> KeyType {
+        KeyType::Secp256k1
+    }
+
+    /// ECDSA over SHA-256 of the message with the signature in the compact (r || s) form
+    fn sign_bytes(&self, msg: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut signature: k256::ecdsa::Signature =
+            k256::ecdsa::signature::Signer::try_sign(self, msg).map_err(|e| {
+                format_err!(ErrorKind::SigningError, "secp256k1 signing failed: {}", e)
+            })?;
+        // Tendermint rejects signatures with the higher S value
+        signature.normalize_s().map_err(|e| {
+            format_err!(
+                ErrorKind::SigningError,
+                "secp256k1 signature normalization failed: {}",
+                e
+            )
+        })?;
+        Ok(signature.as_ref().to_vec())
+    }
+}
+
+#[cfg(feature = "bls")]
+impl ConsensusSigner for blst::min_pk::SecretKey {
+    fn public_key(&self) -> ConsensusPublicKey {
+        ConsensusPublicKey::Bls12_381(self.sk_to_pk().compress().to_vec())
+    }
+
+    fn key_type(&self) -> KeyType {
+        KeyType::Bls12_381
+    }
+
+    /// BLS signature (the compressed G2 point) of the message or its SHA-256 digest
+    fn sign_bytes(&self, msg: &[u8]) -> Result<Vec<u8>, Error> {
+        let signature = if msg.len() > BLS12_381_MAX_MSG_LEN {
+            self.sign(&Sha256::digest(msg), BLS12_381_DST, &[])
+        } else {
+            self.sign(msg, BLS12_381_DST, &[])
+        };
+        Ok(signature.compress().to_vec())
+    }
+}
+
+/// Generates a new random secret key of the provided type
+pub fn generate_secret_key(key_type: KeyType) -> Zeroizing<Vec<u8>> {
+    match key_type {
+        KeyType::Ed25519 => {
+            let mut secret_key = Zeroizing::new(vec![0u8; SECRET_KEY_LENGTH]);
+            OsRng.fill_bytes(&mut secret_key);
+            secret_key
+        }
+        KeyType::Secp256k1 => {
+            let signing_key = k256::ecdsa::SigningKey::random(&mut OsRng);
+            Zeroizing::new(signing_key.to_bytes().to_vec())
+        }
+        #[cfg(feature = "bls")]
+        KeyType::Bls12_381 => {
+            // the key is derived from the random input keying material (as in CometBFT)
+            let mut ikm = Zeroizing::new([0u8; 32]);
+            OsRng.fill_bytes(&mut *ikm);
+            let secret_key =
+                blst::min_pk::SecretKey::key_gen(&*ikm, &[]).expect("enough keying material");
+            Zeroizing::new(secret_key.to_bytes().to_vec())
+        }
+    }
+}
+
+/// Loads a consensus signer of the provided type from the raw secret key bytes
+pub fn signer_from_secret_key(
+    key_type: KeyType,
+    secret_key: &[u8],
+) -> Result<Box<dyn ConsensusSigner>, Error> {
+    match key_type {
+        KeyType::Ed25519 => {
+            let secret = ed25519_dalek::SecretKey::from_bytes(secret_key)
+                .map_err(|e| format_err!(ErrorKind::InvalidKey, "invalid Ed25519 key: {}", e))?;
+            let public = ed25519_dalek::PublicKey::from(&secret);
+            Ok(Box::new(ed25519_dalek::Keypair { secret, public }))
+        }
+        KeyType::Secp256k1 => {
+            let signing_key = k256::ecdsa::SigningKey::from_bytes(secret_key)
+                .map_err(|e| format_err!(ErrorKind::InvalidKey, "invalid secp256k1 key: {}", e))?;
+            Ok(Box::new(signing_key))
+        }
+        #[cfg(feature = "bls")]
+        KeyType::Bls12_381 => {
+            let secret_key = blst::min_pk::SecretKey::from_bytes(secret_key).map_err(|e| {
+                format_err!(ErrorKind::InvalidKey, "invalid BLS12-381 key: {:?}", e)
+            })?;
+            Ok(Box::new(secret_key))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Keypair, SecretKey, Verifier};
+    use k256::ecdsa::signature::Signature as _;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn ed25519_signer_roundtrip() {
+        let secret = SecretKey::from_bytes(&[7u8; 32]).unwrap();
+        let public = (&secret).into();
+        let keypair = Keypair { secret, public };
+        let msg = b"canonical sign bytes";
+        let raw = keypair.sign_bytes(msg).unwrap();
+        let signature = ed25519_dalek::Signature::try_from(raw.as_slice()).unwrap();
+        assert!(keypair.public.verify(msg, &signature).is_ok());
+        assert_eq!(
+            keypair.public_key(),
+            PublicKey::Ed25519(keypair.public).into()
+        );
+        assert_eq!(keypair.key_type(), KeyType::Ed25519);
+    }
+
+    #[test]
+    fn secp256k1_signer_low_s() {
+        let secret = generate_secret_key(KeyType::Secp256k1);
+        let signer = signer_from_secret_key(KeyType::Secp256k1, &secret).unwrap();
+        assert_eq!(signer.key_type(), KeyType::Secp256k1);
+        let verifying_key = match signer.public_key() {
+            ConsensusPublicKey::Tendermint(PublicKey::Secp256k1(vk)) => vk,
+            pk => panic!("unexpected public key: {:?}", pk),
+        };
+        for i in 0..16u8 {
+            let msg = [i; 64];
+            let raw = signer.sign_bytes(&msg).unwrap();
+            assert_eq!(raw.len(), 64);
+            let mut signature = k256::ecdsa::Signature::from_bytes(&raw).unwrap();
+            assert!(
+                k256::ecdsa::signature::Verifier::verify(&verifying_key, &msg, &signature).is_ok()
+            );
+            assert!(!signature.normalize_s().unwrap());
+        }
+    }
+
+    #[cfg(feature = "bls")]
+    #[test]
+    fn bls12_381_needs_cometbft_v1() {
+        let key_type: KeyType = "bls12_381".parse().unwrap();
+        assert!(key_type
+            .check_protocol_version(ProtocolVersion::V0_38)
+            .is_err());
+        assert!(key_type.check_protocol_version(ProtocolVersion::V1).is_ok());
+        assert!(KeyType::Ed25519
+            .check_protocol_version(ProtocolVersion::V0_33)
+            .is_ok());
+    }
+
+    /// vectors of the Ethereum consensus specs (`sign` test cases of the same ciphersuite)
+    #[cfg(feature = "bls")]
+    #[test]
+    fn bls12_381_known_answers() {
+        let vectors = [
+            (
+                "263dbd792f5b1be47ed85f8938c0f29586af0d3ac7b977f21c278fe1462040e3",
+                "a491d1b0ecd9bb917989f0e74f0dea0422eac4a873e5e2644f368dffb9a6e20fd6e10c1b77654d067c0618f6e5a7f79a",
+                "882730e5d03f6b42c3abc26d3372625034e1d871b65a8a6b900a56dae22da98abbe1b68f85e49fe7652a55ec3d0591c20767677e33e5cbb1207315c41a9ac03be39c2e7668edc043d6cb1d9fd93033caa8a1c5b0e84bedaeb6c64972503a43eb",
+            ),
+            (
+                "47b8192d77bf871b62e87859d653922725724a5c031afeabc60bcef5ff665138",
+                "b301803f8b5ac4a1133581fc676dfedc60d891dd5fa99028805e5ea5b08d3491af75d0707adab3b70c6a6a580217bf81",
+                "af1390c3c47acdb37131a51216da683c509fce0e954328a59f93aebda7e4ff974ba208d9a4a2a2389f892a9d418d618418dd7f7a6bc7aa0da999a9d3a5b815bc085e14fd001f6a1948768a3f4afefc8b8240dda329f984cb345c6363272ba4fe",
+            ),
+            (
+                "328388aff0d4a5b7dc9205abd374e7e98f3cd9f3418edb4eafda5fb16473d216",
+                "b53d21a4cfd562c469cc81514d4ce5a6b577d8403d32a394dc265dd190b47fa9f829fdd7963afdf972e5e77854051f6f",
+                "a4efa926610b8bd1c8330c918b7a5e9bf374e53435ef8b7ec186abf62e1b1f65aeaaeb365677ac1d1172a1f5b44b4e6d022c252c58486c0a759fbdc7de15a756acc4d343064035667a594b4c2a6f0b0b421975977f297dba63ee2f63ffe47bb6",
+            ),
+        ];
+        let msg = [0x56u8; 32];
+        for (secret_key, public_key, signature) in vectors.iter() {
+            let hex = |s: &str| subtle_encoding::hex::decode(s).unwrap();
+            let signer = signer_from_secret_key(KeyType::Bls12_381, &hex(secret_key)).unwrap();
+            assert_eq!(signer.key_type(), KeyType::Bls12_381);
+            assert_eq!(
+                signer.public_key(),
+                ConsensusPublicKey::Bls12_381(hex(public_key))
+            );
+            assert_eq!(
+                ConsensusPublicKey::from_raw(KeyType::Bls12_381, &hex(public_key)).unwrap(),
+                signer.public_key()
+            );
+            assert_eq!(signer.sign_bytes(&msg).unwrap(), hex(signature));
+        }
+    }
+
+    #[cfg(feature = "bls")]
+    #[test]
+    fn bls12_381_signs_digest_of_long_messages() {
+        let secret = generate_secret_key(KeyType::Bls12_381);
+        assert_eq!(secret.len(), 32);
+        let signer = signer_from_secret_key(KeyType::Bls12_381, &secret).unwrap();
+        let public_key = match signer.public_key() {
+            ConsensusPublicKey::Bls12_381(pk) => blst::min_pk::PublicKey::uncompress(&pk).unwrap(),
+            pk => panic!("unexpected public key: {:?}", pk),
+        };
+        let msg = [7u8; 100];
+        let raw = signer.sign_bytes(&msg).unwrap();
+        let signature = blst::min_pk::Signature::uncompress(&raw).unwrap();
+        let digest = Sha256::digest(&msg);
+        assert_eq!(
+            signature.verify(true, &digest, BLS12_381_DST, &[], &public_key, true),
+            blst::BLST_ERROR::BLST_SUCCESS
+        );
+        // the zero key is rejected
+        assert!(signer_from_secret_key(KeyType::Bls12_381, &[0u8; 32]).is_err());
+    }
+}
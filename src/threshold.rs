@@ -0,0 +1,405 @@
+//! Threshold (FROST) Ed25519 signing with other KMS nodes
+//!
+//! The consensus key is split t-of-n between the nodes in a distributed key generation
+//! ([`dkg`]), so it's never held by one node. The node whose session gets a sign request
+//! coordinates the two FROST rounds: it collects the nonce commitments of `t` nodes
+//! (its own included, if it may sign) and then their signature shares,
+//! which add up to one Ed25519 signature of the group key.
+//! Each node signs with its share only after checking the sign bytes against its own
+//! double-sign state of the chain. The threshold is more than half of the `n` nodes,
+//! so any two signing quorums share at least `2t - n` nodes, and up to `2t - n - 1`
+//! compromised nodes can't get a conflicting vote signed.
+//! The nodes talk over secret connections authenticated with their identity keys.
+
+mod cosigner;
+pub mod dkg;
+mod peer;
+
+pub use cosigner::Cosigner;
+pub use peer::{serve, Peers};
+
+use crate::error::{Error, ErrorKind};
+use crate::signer::{ConsensusPublicKey, ConsensusSigner, KeyType};
+use anomaly::{fail, format_err};
+use frost_ed25519::{
+    keys::{KeyPackage, PublicKeyPackage},
+    Identifier, SigningPackage,
+};
+use peer::PeerRequest;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    sync::{mpsc, Arc},
+    thread,
+    time::Instant,
+};
+use tendermint::{chain, PublicKey};
+use tracing::{debug, warn};
+use zeroize::Zeroizing;
+
+/// FROST identifier of a participant number
+fn identifier(id: u16) -> Result<Identifier, Error> {
+    Identifier::try_from(id).map_err(|e| {
+        format_err!(
+            ErrorKind::ConfigError,
+            "invalid participant number {}: {}",
+            id,
+            e
+        )
+        .into()
+    })
+}
+
+/// Key share of this node with the public keys of all the shares
+#[derive(Serialize, Deserialize)]
+pub struct KeyShare {
+    key_package: KeyPackage,
+    public_key_package: PublicKeyPackage,
+}
+
+impl KeyShare {
+    /// Parses a key share (e.g. as written to the key file) and checks it's from the same key generation
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let key_share: Self = serde_json::from_slice(bytes)
+            .map_err(|e| format_err!(ErrorKind::InvalidKey, "invalid key share: {}", e))?;
+        let verifying_share = key_share
+            .public_key_package
+            .verifying_shares()
+            .get(key_share.key_package.identifier());
+        if key_share.key_package.verifying_key() != key_share.public_key_package.verifying_key()
+            || verifying_share != Some(key_share.key_package.verifying_share())
+        {
+            fail!(
+                ErrorKind::InvalidKey,
+                "the key share doesn't match the group public keys"
+            );
+        }
+        Ok(key_share)
+    }
+
+    /// Serialized key share (to be written to the key file)
+    pub fn to_bytes(&self) -> Result<Zeroizing<Vec<u8>>, Error> {
+        serde_json::to_vec(self).map(Zeroizing::new).map_err(|e| {
+            format_err!(
+                ErrorKind::SerializationError,
+                "failed to serialize key share: {}",
+                e
+            )
+            .into()
+        })
+    }
+
+    /// The group (consensus) public key
+    pub fn public_key(&self) -> Result<ConsensusPublicKey, Error> {
+        let bytes = self
+            .public_key_package
+            .verifying_key()
+            .serialize()
+            .map_err(|e| format_err!(ErrorKind::InvalidKey, "invalid group key: {}", e))?;
+        PublicKey::from_raw_ed25519(&bytes)
+            .map(ConsensusPublicKey::from)
+            .ok_or_else(|| format_err!(ErrorKind::InvalidKey, "invalid group key").into())
+    }
+
+    /// Number of the nodes needed to sign
+    pub fn min_signers(&self) -> u16 {
+        *self.key_package.min_signers()
+    }
+}
+
+/// Consensus signer of one chain that coordinates the signatures with the other nodes
+/// (the chain ID is sent with the sign bytes for their double-sign checks)
+pub struct ThresholdSigner {
+    chain_id: chain::Id,
+    cosigner: Arc<Cosigner>,
+    peers: Arc<Peers>,
+}
+
+impl ThresholdSigner {
+    /// Creates a signer for the chain with the cosigner of this node
+    /// and the connections to the other nodes (both shared by all chains)
+    pub fn new(chain_id: chain::Id, cosigner: Arc<Cosigner>, peers: Arc<Peers>) -> Self {
+        Self {
+            chain_id,
+            cosigner,
+            peers,
+        }
+    }
+}
+
+impl ConsensusSigner for ThresholdSigner {
+    fn public_key(&self) -> ConsensusPublicKey {
+        self.cosigner.public_key().clone()
+    }
+
+    fn key_type(&self) -> KeyType {
+        KeyType::Ed25519
+    }
+
+    /// Collects the commitments of the first `t` nodes that answer
+    /// and then their signature shares (within the round timeout each)
+    fn sign_bytes(&self, msg: &[u8]) -> Result<Vec<u8>, Error> {
+        let min_signers = self.cosigner.min_signers() as usize;
+        let request = Arc::new(PeerRequest::Commit {
+            chain_id: self.chain_id.clone(),
+            sign_bytes: msg.to_vec(),
+        });
+        let (commitments_tx, commitments_rx) = mpsc::channel();
+        let deadline = Instant::now() + self.peers.timeout();
+        for peer in self.peers.iter() {
+            let peer = peer.clone();
+            let request = request.clone();
+            let commitments_tx = commitments_tx.clone();
+            thread::spawn(move || {
+                let committed = peer.commit(&request);
+                // the connection of a late node is kept for the next signing
+                if let Err(mpsc::SendError((peer, Ok((connection, _))))) =
+                    commitments_tx.send((peer, committed))
+                {
+                    peer.release(connection);
+                }
+            });
+        }
+        drop(commitments_tx);
+
+        let mut commitments = BTreeMap::new();
+        let own_nonces = match self.cosigner.commit(&self.chain_id, msg) {
+            Ok(committed) => {
+                commitments.insert(*self.cosigner.identifier(), committed.commitments());
+                Some(committed)
+            }
+            Err(e) => {
+                warn!("[{}] this node can't sign: {}", &self.chain_id, e);
+                None
+            }
+        };
+        let mut signers = Vec::new();
+        while commitments.len() < min_signers {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match commitments_rx.recv_timeout(timeout) {
+                Ok((peer, Ok((connection, peer_commitments)))) => {
+                    commitments.insert(peer.identifier, peer_commitments);
+                    signers.push((peer, connection));
+                }
+                Ok((peer, Err(e))) => {
+                    warn!("[{}] node {} didn't commit: {}", &self.chain_id, peer.id, e);
+                }
+                Err(_) => fail!(
+                    ErrorKind::SigningError,
+                    "only {} of the {} nodes needed committed to sign",
+                    commitments.len(),
+                    min_signers
+                ),
+            }
+        }
+        drop(commitments_rx);
+        debug!(
+            "[{}] signing with nodes {:?}",
+            &self.chain_id,
+            signers.iter().map(|(peer, _)| peer.id).collect::<Vec<_>>()
+        );
+
+        let signing_package = SigningPackage::new(commitments, msg);
+        let request = Arc::new(PeerRequest::Sign {
+            signing_package: signing_package.clone(),
+        });
+        let (shares_tx, shares_rx) = mpsc::channel();
+        let deadline = Instant::now() + self.peers.timeout();
+        let remote_signers = signers.len();
+        for (peer, connection) in signers {
+            let request = request.clone();
+            let shares_tx = shares_tx.clone();
+            thread::spawn(move || {
+                let share = peer.sign(connection, &request);
+                let _ = shares_tx.send((peer, share));
+            });
+        }
+        drop(shares_tx);
+
+        let mut shares = BTreeMap::new();
+        if let Some(committed) = own_nonces {
+            let share = self.cosigner.sign(committed, &signing_package)?;
+            shares.insert(*self.cosigner.identifier(), share);
+        }
+        for _ in 0..remote_signers {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match shares_rx.recv_timeout(timeout) {
+                Ok((peer, Ok(share))) => {
+                    shares.insert(peer.identifier, share);
+                }
+                Ok((peer, Err(e))) => fail!(
+                    ErrorKind::SigningError,
+                    "node {} didn't sign: {}",
+                    peer.id,
+                    e
+                ),
+                Err(_) => fail!(
+                    ErrorKind::SigningError,
+                    "the signature shares didn't arrive in time"
+                ),
+            }
+        }
+        // the shares are verified (a node sending an invalid one is reported)
+        let signature = frost_ed25519::aggregate(
+            &signing_package,
+            &shares,
+            self.cosigner.public_key_package(),
+        )
+        .map_err(|e| format_err!(ErrorKind::SigningError, "failed to aggregate shares: {}", e))?;
+        signature.serialize().map_err(|e| {
+            format_err!(
+                ErrorKind::SigningError,
+                "failed to serialize signature: {}",
+                e
+            )
+            .into()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::state::{consensus, PersistStateSync, State, StateError};
+    use crate::config::threshold::{ThresholdConfig, ThresholdPeer};
+    use crate::config::validator::ProtocolVersion;
+    use ed25519_dalek as ed25519;
+    use prost::Message as _;
+    use std::convert::TryFrom;
+    use std::net::TcpListener;
+    use std::time::Duration;
+    use tendermint::net;
+    use tendermint_p2p::secret_connection::PublicKey as NodeKey;
+    use tendermint_proto::google::protobuf::Timestamp;
+    use tendermint_proto::types::{CanonicalBlockId, CanonicalPartSetHeader, CanonicalVote};
+
+    struct NoPersist;
+
+    impl PersistStateSync for NoPersist {
+        fn load_state(&mut self) -> Result<State, StateError> {
+            Ok(State::from(consensus::State::default()))
+        }
+
+        fn persist_state(&mut self, _new_state: &State) -> Result<(), StateError> {
+            Ok(())
+        }
+    }
+
+    fn identity_key(id: u16) -> ed25519::Keypair {
+        let secret = ed25519::SecretKey::from_bytes(&[id as u8; 32]).unwrap();
+        let public = ed25519::PublicKey::from(&secret);
+        ed25519::Keypair { secret, public }
+    }
+
+    /// configs of n nodes on localhost
+    fn configs(n: u16, threshold: u16) -> Vec<ThresholdConfig> {
+        let addrs: Vec<_> = (0..n)
+            .map(|_| {
+                TcpListener::bind("127.0.0.1:0")
+                    .unwrap()
+                    .local_addr()
+                    .unwrap()
+            })
+            .collect();
+        (1..=n)
+            .map(|id| ThresholdConfig {
+                id,
+                threshold,
+                listen_addr: addrs[id as usize - 1],
+                timeout: None,
+                peers: (1..=n)
+                    .filter(|peer| *peer != id)
+                    .map(|peer| ThresholdPeer {
+                        id: peer,
+                        address: net::Address::Tcp {
+                            peer_id: Some(NodeKey::from(&identity_key(peer)).peer_id()),
+                            host: "127.0.0.1".to_owned(),
+                            port: addrs[peer as usize - 1].port(),
+                        },
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    fn vote_bytes(hash: u8) -> Vec<u8> {
+        let vote = CanonicalVote {
+            r#type: 1,
+            height: 5,
+            round: 0,
+            block_id: Some(CanonicalBlockId {
+                hash: vec![hash; 32],
+                part_set_header: Some(CanonicalPartSetHeader {
+                    total: 1,
+                    hash: vec![hash; 32],
+                }),
+            }),
+            timestamp: Some(Timestamp::default()),
+            chain_id: "testchain-1".to_owned(),
+        };
+        let mut buf = Vec::new();
+        vote.encode_length_delimited(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn nodes_generate_keys_and_sign_together() {
+        let configs = configs(3, 2);
+        let key_gens: Vec<_> = configs
+            .iter()
+            .cloned()
+            .map(|config| {
+                thread::spawn(move || {
+                    dkg::run(&config, &identity_key(config.id), Duration::from_secs(30)).unwrap()
+                })
+            })
+            .collect();
+        let key_shares: Vec<KeyShare> = key_gens.into_iter().map(|k| k.join().unwrap()).collect();
+        let group_key = key_shares[0].public_key().unwrap();
+        for key_share in key_shares.iter() {
+            assert_eq!(key_share.public_key().unwrap(), group_key);
+            assert_eq!(key_share.min_signers(), 2);
+            let bytes = key_share.to_bytes().unwrap();
+            assert!(KeyShare::from_bytes(&bytes).is_ok());
+        }
+
+        let chain_id = chain::Id::try_from("testchain-1".to_owned()).unwrap();
+        let mut cosigners = Vec::new();
+        for key_share in key_shares {
+            let mut cosigner = Cosigner::new(key_share).unwrap();
+            cosigner
+                .add_chain(chain_id.clone(), ProtocolVersion::V0_34, NoPersist)
+                .unwrap();
+            cosigners.push(Arc::new(cosigner));
+        }
+        // the third node is down
+        for i in 0..2 {
+            serve(
+                &configs[i],
+                &identity_key(configs[i].id),
+                cosigners[i].clone(),
+            )
+            .unwrap();
+        }
+        let peers = Arc::new(Peers::new(&configs[0], &identity_key(1)).unwrap());
+        let signer = ThresholdSigner::new(chain_id, cosigners[0].clone(), peers);
+        assert_eq!(signer.public_key(), group_key);
+
+        let public_key = match group_key {
+            ConsensusPublicKey::Tendermint(PublicKey::Ed25519(pk)) => pk,
+            pk => panic!("unexpected public key: {:?}", pk),
+        };
+        let msg = vote_bytes(1);
+        let raw = signer.sign_bytes(&msg).unwrap();
+        let signature = ed25519::Signature::try_from(raw.as_slice()).unwrap();
+        assert!(ed25519::Verifier::verify(&public_key, &msg, &signature).is_ok());
+        // the same vote again (over the kept connection)
+        let raw = signer.sign_bytes(&msg).unwrap();
+        let signature = ed25519::Signature::try_from(raw.as_slice()).unwrap();
+        assert!(ed25519::Verifier::verify(&public_key, &msg, &signature).is_ok());
+
+        // a different block at the same height/round/step is refused by the nodes
+        assert!(signer.sign_bytes(&vote_bytes(2)).is_err());
+    }
+}
@@ -0,0 +1,499 @@
+//! Signing with the key share of this node (after the double-sign checks)
+
+use super::KeyShare;
+use crate::chain::state::{consensus, PersistStateSync, State, StateErrorKind};
+use crate::config::validator::ProtocolVersion;
+use crate::error::{Error, ErrorKind};
+use crate::rpc::{
+    AminoCanonicalBlockId, AminoCanonicalProposal, AminoCanonicalVote, CanonicalVoteExtension,
+};
+use crate::signer::ConsensusPublicKey;
+use anomaly::{fail, format_err};
+use frost_ed25519::{
+    keys::PublicKeyPackage,
+    round1::{self, SigningCommitments, SigningNonces},
+    round2::{self, SignatureShare},
+    Identifier, SigningPackage,
+};
+use prost::Message as _;
+use rand_core::OsRng;
+use std::{collections::BTreeMap, convert::TryFrom, sync::Mutex};
+use tendermint::{block, chain};
+use tendermint_proto::types::{
+    CanonicalBlockId as RawCanonicalBlockId, CanonicalPartSetHeader as RawCanonicalPartSetHeader,
+    CanonicalProposal as RawCanonicalProposal, CanonicalVote as RawCanonicalVote,
+};
+use tracing::info;
+
+/// `SignedMsgType` of the proposals
+const PROPOSAL_TYPE: i32 = 32;
+
+/// The first field of the canonical votes and proposals
+#[derive(Clone, PartialEq, prost::Message)]
+struct RawSignBytesType {
+    #[prost(int32, tag = "1")]
+    r#type: i32,
+}
+
+/// What the sign bytes are of
+#[derive(Clone, Debug, PartialEq)]
+enum Signable {
+    /// a vote or a proposal (at the consensus state)
+    Consensus(consensus::State),
+    /// a vote extension (with its precommit)
+    Extension {
+        height: block::Height,
+        round: block::Round,
+    },
+}
+
+fn malformed(e: impl std::fmt::Display) -> Error {
+    format_err!(
+        ErrorKind::InvalidMessageError,
+        "malformed sign bytes: {}",
+        e
+    )
+    .into()
+}
+
+fn consensus_state(
+    height: i64,
+    round: i64,
+    step: i8,
+    block_id: Option<RawCanonicalBlockId>,
+) -> Result<Signable, Error> {
+    let block_id = block_id
+        .map(block::Id::try_from)
+        .transpose()
+        .map_err(malformed)?
+        // a nil vote
+        .filter(|block_id| *block_id != block::Id::default());
+    Ok(Signable::Consensus(consensus::State {
+        height: block::Height::try_from(height).map_err(malformed)?,
+        round: i32::try_from(round)
+            .map_err(malformed)
+            .and_then(|round| block::Round::try_from(round).map_err(malformed))?,
+        step,
+        block_id,
+    }))
+}
+
+/// Amino block IDs in the v0.34 form (which has the part set header fields swapped)
+fn amino_block_id(block_id: Option<AminoCanonicalBlockId>) -> Option<RawCanonicalBlockId> {
+    block_id.map(|block_id| {
+        let parts_header = block_id.parts_header.unwrap_or_default();
+        RawCanonicalBlockId {
+            hash: block_id.hash,
+            part_set_header: Some(RawCanonicalPartSetHeader {
+                total: parts_header.total as u32,
+                hash: parts_header.hash,
+            }),
+        }
+    })
+}
+
+/// Decodes the canonical sign bytes with their chain ID
+fn decode(sign_bytes: &[u8], version: ProtocolVersion) -> Result<(String, Signable), Error> {
+    let sign_bytes_type = RawSignBytesType::decode_length_delimited(sign_bytes).map(|t| t.r#type);
+    match sign_bytes_type {
+        Ok(PROPOSAL_TYPE) if version.is_protobuf() => {
+            let proposal =
+                RawCanonicalProposal::decode_length_delimited(sign_bytes).map_err(malformed)?;
+            let signable = consensus_state(proposal.height, proposal.round, 0, proposal.block_id)?;
+            Ok((proposal.chain_id, signable))
+        }
+        Ok(PROPOSAL_TYPE) => {
+            let proposal =
+                AminoCanonicalProposal::decode_length_delimited(sign_bytes).map_err(malformed)?;
+            let block_id = amino_block_id(proposal.block_id);
+            let signable = consensus_state(proposal.height, proposal.round, 0, block_id)?;
+            Ok((proposal.chain_id, signable))
+        }
+        Ok(step @ 1..=2) if version.is_protobuf() => {
+            let vote = RawCanonicalVote::decode_length_delimited(sign_bytes).map_err(malformed)?;
+            let signable = consensus_state(vote.height, vote.round, step as i8, vote.block_id)?;
+            Ok((vote.chain_id, signable))
+        }
+        Ok(step @ 1..=2) => {
+            let vote =
+                AminoCanonicalVote::decode_length_delimited(sign_bytes).map_err(malformed)?;
+            let block_id = amino_block_id(vote.block_id);
+            let signable = consensus_state(vote.height, vote.round, step as i8, block_id)?;
+            Ok((vote.chain_id, signable))
+        }
+        // the extension bytes are in the first field (omitted if empty)
+        Ok(0) | Err(_) if version.has_vote_extensions() => {
+            let extension =
+                CanonicalVoteExtension::decode_length_delimited(sign_bytes).map_err(malformed)?;
+            let height = block::Height::try_from(extension.height).map_err(malformed)?;
+            let round = i32::try_from(extension.round)
+                .map_err(malformed)
+                .and_then(|round| block::Round::try_from(round).map_err(malformed))?;
+            Ok((extension.chain_id, Signable::Extension { height, round }))
+        }
+        Ok(t) => fail!(
+            ErrorKind::InvalidMessageError,
+            "unexpected sign bytes type: {}",
+            t
+        ),
+        Err(e) => Err(malformed(e)),
+    }
+}
+
+/// Double-sign state of a chain
+struct ChainState {
+    protocol_version: ProtocolVersion,
+    state: State,
+    state_syncer: Box<dyn PersistStateSync + Send>,
+}
+
+impl ChainState {
+    /// Checks the sign bytes against the last signed state (and updates it if persisting)
+    fn check(&mut self, signable: &Signable, persist: bool) -> Result<(), Error> {
+        match signable {
+            Signable::Consensus(new_state) => {
+                self.state
+                    .check_consensus_state(new_state)
+                    .map_err(|e| match e.kind() {
+                        StateErrorKind::DoubleSign => {
+                            format_err!(ErrorKind::DoubleSign, "{}", e)
+                        }
+                        _ => format_err!(ErrorKind::InvalidMessageError, "{}", e),
+                    })?;
+                if persist {
+                    // the cosigner doesn't see the final signature
+                    let new_state = State::from(new_state.clone());
+                    self.state_syncer.persist_state(&new_state).map_err(|e| {
+                        format_err!(ErrorKind::IoError, "failed to persist state: {}", e)
+                    })?;
+                    self.state = new_state;
+                }
+            }
+            // the extensions are signed with their precommits (and not recorded on their own)
+            Signable::Extension { height, round } => {
+                let last = self.state.consensus_state();
+                if (*height, *round) < (last.height, last.round) {
+                    fail!(
+                        ErrorKind::InvalidMessageError,
+                        "vote extension of an earlier round: {}/{}",
+                        height,
+                        round
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Key share of this node with the double-sign state of each chain
+/// (shared by the signers of this node and the signing requests of the other nodes)
+pub struct Cosigner {
+    key_share: KeyShare,
+    public_key: ConsensusPublicKey,
+    chains: BTreeMap<chain::Id, Mutex<ChainState>>,
+}
+
+/// Nonces of the first round with what they commit to
+pub(crate) struct Committed {
+    chain_id: chain::Id,
+    sign_bytes: Vec<u8>,
+    signable: Signable,
+    nonces: SigningNonces,
+}
+
+impl Committed {
+    /// The commitments sent to the coordinating node
+    pub(crate) fn commitments(&self) -> SigningCommitments {
+        *self.nonces.commitments()
+    }
+}
+
+impl Cosigner {
+    /// Creates a cosigner (without any chains)
+    pub fn new(key_share: KeyShare) -> Result<Self, Error> {
+        let public_key = key_share.public_key()?;
+        Ok(Self {
+            key_share,
+            public_key,
+            chains: BTreeMap::new(),
+        })
+    }
+
+    /// Signs for the chain (loading its last signed state)
+    pub fn add_chain(
+        &mut self,
+        chain_id: chain::Id,
+        protocol_version: ProtocolVersion,
+        mut state_syncer: impl PersistStateSync + Send + 'static,
+    ) -> Result<(), Error> {
+        let state = state_syncer
+            .load_state()
+            .map_err(|e| format_err!(ErrorKind::IoError, "failed to load state: {}", e))?;
+        info!(
+            "[{}] cosigner state: {:?}",
+            &chain_id,
+            state.consensus_state()
+        );
+        self.chains.insert(
+            chain_id,
+            Mutex::new(ChainState {
+                protocol_version,
+                state,
+                state_syncer: Box::new(state_syncer),
+            }),
+        );
+        Ok(())
+    }
+
+    /// The group (consensus) public key
+    pub fn public_key(&self) -> &ConsensusPublicKey {
+        &self.public_key
+    }
+
+    /// FROST identifier of this node
+    pub(crate) fn identifier(&self) -> &Identifier {
+        self.key_share.key_package.identifier()
+    }
+
+    /// Number of the nodes needed to sign
+    pub(crate) fn min_signers(&self) -> u16 {
+        self.key_share.min_signers()
+    }
+
+    /// Public keys of all the shares
+    pub(crate) fn public_key_package(&self) -> &PublicKeyPackage {
+        &self.key_share.public_key_package
+    }
+
+    fn chain(&self, chain_id: &chain::Id) -> Result<&Mutex<ChainState>, Error> {
+        self.chains.get(chain_id).ok_or_else(|| {
+            format_err!(ErrorKind::ChainIdError, "unknown chain: {}", chain_id).into()
+        })
+    }
+
+    /// First round: the nonces for the sign bytes (if they could be signed)
+    pub(crate) fn commit(
+        &self,
+        chain_id: &chain::Id,
+        sign_bytes: &[u8],
+    ) -> Result<Committed, Error> {
+        let mut chain = self
+            .chain(chain_id)?
+            .lock()
+            .map_err(|e| format_err!(ErrorKind::PoisonError, "{}", e))?;
+        let (signed_chain_id, signable) = decode(sign_bytes, chain.protocol_version)?;
+        if signed_chain_id != chain_id.as_str() {
+            fail!(
+                ErrorKind::ChainIdError,
+                "sign bytes of chain {} for {}",
+                signed_chain_id,
+                chain_id
+            );
+        }
+        chain.check(&signable, false)?;
+        let (nonces, _) = round1::commit(self.key_share.key_package.signing_share(), &mut OsRng);
+        Ok(Committed {
+            chain_id: chain_id.clone(),
+            sign_bytes: sign_bytes.to_vec(),
+            signable,
+            nonces,
+        })
+    }
+
+    /// Second round: the signature share (the state is persisted first)
+    pub(crate) fn sign(
+        &self,
+        committed: Committed,
+        signing_package: &SigningPackage,
+    ) -> Result<SignatureShare, Error> {
+        if signing_package.message() != &committed.sign_bytes
+            || signing_package.signing_commitment(self.identifier())
+                != Some(committed.commitments())
+        {
+            fail!(
+                ErrorKind::InvalidMessageError,
+                "the signing package doesn't match the commitments"
+            );
+        }
+        // the state may have changed since the commitments
+        self.chain(&committed.chain_id)?
+            .lock()
+            .map_err(|e| format_err!(ErrorKind::PoisonError, "{}", e))?
+            .check(&committed.signable, true)?;
+        round2::sign(
+            signing_package,
+            &committed.nonces,
+            &self.key_share.key_package,
+        )
+        .map_err(|e| format_err!(ErrorKind::SigningError, "failed to sign the share: {}", e).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::state::StateError;
+    use frost_ed25519::keys::{self, IdentifierList, KeyPackage};
+    use tendermint_proto::google::protobuf::Timestamp;
+
+    struct NoPersist;
+
+    impl PersistStateSync for NoPersist {
+        fn load_state(&mut self) -> Result<State, StateError> {
+            Ok(State::from(consensus::State::default()))
+        }
+
+        fn persist_state(&mut self, _new_state: &State) -> Result<(), StateError> {
+            Ok(())
+        }
+    }
+
+    fn chain_id() -> chain::Id {
+        chain::Id::try_from("testchain-1".to_owned()).unwrap()
+    }
+
+    /// a cosigner with the key share of another node
+    fn cosigner(protocol_version: ProtocolVersion) -> (Cosigner, KeyPackage) {
+        let (shares, public_key_package) =
+            keys::generate_with_dealer(3, 2, IdentifierList::Default, OsRng).unwrap();
+        let mut key_packages = shares
+            .into_values()
+            .map(|share| KeyPackage::try_from(share).unwrap());
+        let key_share = KeyShare {
+            key_package: key_packages.next().unwrap(),
+            public_key_package,
+        };
+        let mut cosigner = Cosigner::new(key_share).unwrap();
+        cosigner
+            .add_chain(chain_id(), protocol_version, NoPersist)
+            .unwrap();
+        (cosigner, key_packages.next().unwrap())
+    }
+
+    fn block_id(hash: u8) -> RawCanonicalBlockId {
+        RawCanonicalBlockId {
+            hash: vec![hash; 32],
+            part_set_header: Some(RawCanonicalPartSetHeader {
+                total: 1,
+                hash: vec![hash; 32],
+            }),
+        }
+    }
+
+    fn encode(msg: impl prost::Message) -> Vec<u8> {
+        let mut buf = Vec::new();
+        msg.encode_length_delimited(&mut buf).unwrap();
+        buf
+    }
+
+    fn vote(r#type: i32, height: i64, block_id: Option<RawCanonicalBlockId>) -> Vec<u8> {
+        encode(RawCanonicalVote {
+            r#type,
+            height,
+            round: 0,
+            block_id,
+            timestamp: Some(Timestamp::default()),
+            chain_id: chain_id().to_string(),
+        })
+    }
+
+    fn state(height: u32, step: i8, block_id: Option<RawCanonicalBlockId>) -> Signable {
+        Signable::Consensus(consensus::State {
+            height: block::Height::from(height),
+            round: block::Round::default(),
+            step,
+            block_id: block_id.map(|id| block::Id::try_from(id).unwrap()),
+        })
+    }
+
+    #[test]
+    fn decodes_sign_bytes() {
+        let (chain, signable) =
+            decode(&vote(2, 5, Some(block_id(1))), ProtocolVersion::V0_34).unwrap();
+        assert_eq!(chain, chain_id().as_str());
+        assert_eq!(signable, state(5, 2, Some(block_id(1))));
+        let (_, signable) = decode(&vote(1, 5, None), ProtocolVersion::V0_34).unwrap();
+        assert_eq!(signable, state(5, 1, None));
+
+        let proposal = encode(RawCanonicalProposal {
+            r#type: PROPOSAL_TYPE,
+            height: 7,
+            round: 0,
+            pol_round: -1,
+            block_id: Some(block_id(3)),
+            timestamp: Some(Timestamp::default()),
+            chain_id: chain_id().to_string(),
+        });
+        let (_, signable) = decode(&proposal, ProtocolVersion::V0_34).unwrap();
+        assert_eq!(signable, state(7, 0, Some(block_id(3))));
+
+        let amino_vote = encode(AminoCanonicalVote {
+            r#type: 1,
+            height: 5,
+            round: 0,
+            block_id: Some(AminoCanonicalBlockId {
+                hash: vec![1; 32],
+                parts_header: None,
+            }),
+            timestamp: Some(Timestamp::default()),
+            chain_id: chain_id().to_string(),
+        });
+        let (_, signable) = decode(&amino_vote, ProtocolVersion::V0_33).unwrap();
+        let amino_block_id = RawCanonicalBlockId {
+            hash: vec![1; 32],
+            part_set_header: Some(RawCanonicalPartSetHeader::default()),
+        };
+        assert_eq!(signable, state(5, 1, Some(amino_block_id)));
+
+        for extension in [vec![], vec![1, 2, 3]].iter() {
+            let extension = encode(CanonicalVoteExtension {
+                extension: extension.clone(),
+                height: 5,
+                round: 0,
+                chain_id: chain_id().to_string(),
+            });
+            let (_, signable) = decode(&extension, ProtocolVersion::V0_38).unwrap();
+            assert_eq!(
+                signable,
+                Signable::Extension {
+                    height: block::Height::from(5u32),
+                    round: block::Round::default(),
+                }
+            );
+            // no vote extensions before v0.38
+            assert!(decode(&extension, ProtocolVersion::V0_37).is_err());
+        }
+    }
+
+    #[test]
+    fn refuses_double_signing() {
+        let (cosigner, other) = cosigner(ProtocolVersion::V0_34);
+        let committed = cosigner
+            .commit(&chain_id(), &vote(1, 5, Some(block_id(1))))
+            .unwrap();
+        let (_, other_commitments) = round1::commit(other.signing_share(), &mut OsRng);
+        let mut commitments = BTreeMap::new();
+        commitments.insert(*cosigner.identifier(), committed.commitments());
+        commitments.insert(*other.identifier(), other_commitments);
+        let signing_package = SigningPackage::new(commitments, &vote(1, 5, Some(block_id(1))));
+        assert!(cosigner.sign(committed, &signing_package).is_ok());
+
+        let err = cosigner
+            .commit(&chain_id(), &vote(1, 5, Some(block_id(2))))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), &ErrorKind::DoubleSign);
+        // an earlier height
+        assert!(cosigner.commit(&chain_id(), &vote(1, 4, None)).is_err());
+        // the same vote again
+        assert!(cosigner
+            .commit(&chain_id(), &vote(1, 5, Some(block_id(1))))
+            .is_ok());
+
+        let unknown = chain::Id::try_from("otherchain-1".to_owned()).unwrap();
+        assert!(cosigner
+            .commit(&unknown, &vote(1, 6, Some(block_id(1))))
+            .is_err());
+    }
+}
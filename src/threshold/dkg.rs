@@ -0,0 +1,191 @@
+//! Distributed key generation of the key shares
+//!
+//! All the nodes run it at the same time: each node connects to the nodes
+//! with higher participant numbers and accepts the connections of the lower ones,
+//! then the two rounds of the FROST key generation are exchanged
+//! and finally the digests of the group public keys are compared.
+
+use super::{
+    identifier,
+    peer::{accept, clone_key, manager, receive, send},
+    KeyShare,
+};
+use crate::config::threshold::ThresholdConfig;
+use crate::connection::Connection;
+use crate::error::{Error, ErrorKind};
+use anomaly::{fail, format_err};
+use ed25519_dalek as ed25519;
+use frost_ed25519::keys::dkg::{self, round1, round2};
+use rand_core::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    io,
+    net::TcpListener,
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{debug, info};
+
+/// How long to wait before connecting again to a node that isn't up yet
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Messages of the key generation
+#[derive(Serialize, Deserialize)]
+enum DkgMessage {
+    Round1(round1::Package),
+    Round2(round2::Package),
+    /// SHA-256 digest of the public key package
+    Confirm(Vec<u8>),
+}
+
+fn dkg_failed(e: impl std::fmt::Display) -> Error {
+    format_err!(ErrorKind::CryptoError, "key generation failed: {}", e).into()
+}
+
+fn unexpected(id: u16) -> Error {
+    format_err!(
+        ErrorKind::ProtocolError,
+        "unexpected message from node {}",
+        id
+    )
+    .into()
+}
+
+/// Connects to all the other nodes (within the timeout)
+fn connect(
+    config: &ThresholdConfig,
+    identity_key: &ed25519::Keypair,
+    timeout: Duration,
+) -> Result<BTreeMap<u16, Box<dyn Connection>>, Error> {
+    let deadline = Instant::now() + timeout;
+    let listener = TcpListener::bind(config.listen_addr)
+        .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+        .map_err(|e| {
+            format_err!(
+                ErrorKind::IoError,
+                "failed to listen on {}: {}",
+                config.listen_addr,
+                e
+            )
+        })?;
+    let mut connections = BTreeMap::new();
+    for peer in config.peers.iter().filter(|peer| peer.id > config.id) {
+        let manager = manager(peer, identity_key, timeout)?;
+        loop {
+            match manager.try_connect() {
+                Ok(connection) => {
+                    connections.insert(peer.id, connection);
+                    break;
+                }
+                Err(e) if Instant::now() < deadline => {
+                    debug!("node {} not connected yet: {}", peer.id, e);
+                    thread::sleep(RETRY_DELAY);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    while connections.len() < config.peers.len() {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                stream
+                    .set_nonblocking(false)
+                    .map_err(|e| format_err!(ErrorKind::IoError, "{}", e))?;
+                let (id, connection) = accept(stream, identity_key, &config.peers, timeout)?;
+                if id > config.id || connections.contains_key(&id) {
+                    fail!(
+                        ErrorKind::ProtocolError,
+                        "unexpected connection of node {}",
+                        id
+                    );
+                }
+                connections.insert(id, Box::new(connection));
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock && Instant::now() < deadline => {
+                thread::sleep(RETRY_DELAY);
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => fail!(
+                ErrorKind::IoError,
+                "only {} of the {} other nodes connected",
+                connections.len(),
+                config.peers.len()
+            ),
+            Err(e) => fail!(ErrorKind::IoError, "failed to accept connection: {}", e),
+        }
+    }
+    Ok(connections)
+}
+
+/// Generates the key share of this node with the other nodes
+/// (waiting for them up to the timeout)
+pub fn run(
+    config: &ThresholdConfig,
+    identity_key: &ed25519::Keypair,
+    timeout: Duration,
+) -> Result<KeyShare, Error> {
+    config.validate()?;
+    let identity_key = clone_key(identity_key)?;
+    let mut connections = connect(config, &identity_key, timeout)?;
+    info!("key generation: all the nodes connected");
+
+    let (secret1, package1) = dkg::part1(
+        identifier(config.id)?,
+        config.max_signers(),
+        config.threshold,
+        OsRng,
+    )
+    .map_err(dkg_failed)?;
+    let message = DkgMessage::Round1(package1);
+    for connection in connections.values_mut() {
+        send(connection.as_mut(), &message)?;
+    }
+    let mut round1_packages = BTreeMap::new();
+    for (id, connection) in connections.iter_mut() {
+        match receive(connection.as_mut())? {
+            DkgMessage::Round1(package) => {
+                round1_packages.insert(identifier(*id)?, package);
+            }
+            _ => return Err(unexpected(*id)),
+        }
+    }
+
+    let (secret2, mut packages2) = dkg::part2(secret1, &round1_packages).map_err(dkg_failed)?;
+    for (id, connection) in connections.iter_mut() {
+        let package = packages2
+            .remove(&identifier(*id)?)
+            .ok_or_else(|| dkg_failed(format!("no package for node {}", id)))?;
+        send(connection.as_mut(), &DkgMessage::Round2(package))?;
+    }
+    let mut round2_packages = BTreeMap::new();
+    for (id, connection) in connections.iter_mut() {
+        match receive(connection.as_mut())? {
+            DkgMessage::Round2(package) => {
+                round2_packages.insert(identifier(*id)?, package);
+            }
+            _ => return Err(unexpected(*id)),
+        }
+    }
+
+    let (key_package, public_key_package) =
+        dkg::part3(&secret2, &round1_packages, &round2_packages).map_err(dkg_failed)?;
+    // all the nodes need to end up with the same group key and verifying shares
+    let digest = Sha256::digest(&public_key_package.serialize().map_err(dkg_failed)?).to_vec();
+    for connection in connections.values_mut() {
+        send(connection.as_mut(), &DkgMessage::Confirm(digest.clone()))?;
+    }
+    for (id, connection) in connections.iter_mut() {
+        match receive(connection.as_mut())? {
+            DkgMessage::Confirm(peer_digest) if peer_digest == digest => {}
+            DkgMessage::Confirm(_) => {
+                fail!(ErrorKind::CryptoError, "node {} has other public keys", id)
+            }
+            _ => return Err(unexpected(*id)),
+        }
+    }
+    Ok(KeyShare {
+        key_package,
+        public_key_package,
+    })
+}
@@ -0,0 +1,382 @@
+//! Messages between the nodes over secret connections
+
+use super::Cosigner;
+use crate::config::threshold::{ThresholdConfig, ThresholdPeer};
+use crate::connection::{manager::ConnectionManager, Connection};
+use crate::error::{Error, ErrorKind};
+use anomaly::{fail, format_err};
+use ed25519_dalek as ed25519;
+use frost_ed25519::{
+    round1::SigningCommitments, round2::SignatureShare, Identifier, SigningPackage,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+use subtle::ConstantTimeEq;
+use tendermint::{chain, net};
+use tendermint_p2p::secret_connection::{SecretConnection, Version, DATA_MAX_SIZE};
+use tracing::{debug, error, info, warn};
+
+/// How long a connection from another node is kept without any request
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Maximum size of a message between the nodes
+const MAX_MSG_LEN: usize = 64 * 1024;
+
+/// Request of the coordinating node
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum PeerRequest {
+    /// the first round (the nonce commitments) for the sign bytes
+    Commit {
+        chain_id: chain::Id,
+        sign_bytes: Vec<u8>,
+    },
+    /// the second round (the signature share) for the commitments of the signers
+    Sign { signing_package: SigningPackage },
+}
+
+/// Reply to the coordinating node
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum PeerResponse {
+    Commitments(Box<SigningCommitments>),
+    SignatureShare(SignatureShare),
+    /// the node doesn't sign (e.g. the sign bytes would be a double sign)
+    Refused(String),
+}
+
+/// Sends a JSON message (prefixed with its length)
+pub(crate) fn send<C: Write + ?Sized, T: Serialize>(
+    connection: &mut C,
+    msg: &T,
+) -> Result<(), Error> {
+    let bytes = serde_json::to_vec(msg).map_err(|e| {
+        format_err!(
+            ErrorKind::SerializationError,
+            "failed to serialize message: {}",
+            e
+        )
+    })?;
+    if bytes.len() > MAX_MSG_LEN {
+        fail!(ErrorKind::ProtocolError, "message too long");
+    }
+    let mut buf = (bytes.len() as u32).to_be_bytes().to_vec();
+    buf.extend_from_slice(&bytes);
+    // the secret connection only writes the data up to a frame size correctly
+    for chunk in buf.chunks(DATA_MAX_SIZE) {
+        connection
+            .write_all(chunk)
+            .map_err(|e| format_err!(ErrorKind::IoError, "write message failed: {}", e))?;
+    }
+    connection
+        .flush()
+        .map_err(|e| format_err!(ErrorKind::IoError, "write message failed: {}", e).into())
+}
+
+/// Receives a JSON message (prefixed with its length)
+/// -- read by whole frames, as the secret connection can't keep the rest of a frame
+/// (the messages are written in their own frames)
+pub(crate) fn receive<C: Read + ?Sized, T: DeserializeOwned>(
+    connection: &mut C,
+) -> Result<T, Error> {
+    let mut buf = Vec::new();
+    let mut frame = [0u8; DATA_MAX_SIZE];
+    loop {
+        let len = buf
+            .get(..4)
+            .map(|len| u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize);
+        match len {
+            Some(len) if len > MAX_MSG_LEN => {
+                fail!(ErrorKind::ProtocolError, "message too long: {} bytes", len)
+            }
+            Some(len) if buf.len() == len + 4 => break,
+            Some(len) if buf.len() > len + 4 => {
+                fail!(ErrorKind::ProtocolError, "unexpected data after message")
+            }
+            _ => {}
+        }
+        let n = connection
+            .read(&mut frame)
+            .map_err(|e| format_err!(ErrorKind::IoError, "read message failed: {}", e))?;
+        if n == 0 {
+            fail!(ErrorKind::IoError, "connection closed");
+        }
+        buf.extend_from_slice(&frame[..n]);
+    }
+    serde_json::from_slice(&buf[4..])
+        .map_err(|e| format_err!(ErrorKind::ProtocolError, "malformed message: {}", e).into())
+}
+
+/// A copy of the identity key
+// the `Clone` is not derived for Keypair
+// TODO: https://github.com/dalek-cryptography/ed25519-dalek/issues/76
+pub(crate) fn clone_key(identity_key: &ed25519::Keypair) -> Result<ed25519::Keypair, Error> {
+    ed25519::Keypair::from_bytes(&identity_key.to_bytes())
+        .map_err(|e| format_err!(ErrorKind::InvalidKey, "identity key: {}", e).into())
+}
+
+/// Connection manager that dials the other node once
+/// (verifying its peer ID against the address)
+pub(crate) fn manager(
+    peer: &ThresholdPeer,
+    identity_key: &ed25519::Keypair,
+    timeout: Duration,
+) -> Result<ConnectionManager, Error> {
+    let mut manager = ConnectionManager::new(peer.address.clone(), Some(clone_key(identity_key)?))?;
+    manager.set_timeout(Some(timeout));
+    manager.set_retry(false);
+    Ok(manager)
+}
+
+/// Accepts a connection of another node and finds its participant number
+/// from the peer ID (in constant time)
+pub(crate) fn accept(
+    stream: TcpStream,
+    identity_key: &ed25519::Keypair,
+    peers: &[ThresholdPeer],
+    timeout: Duration,
+) -> Result<(u16, SecretConnection<TcpStream>), Error> {
+    stream
+        .set_read_timeout(Some(timeout))
+        .and_then(|_| stream.set_write_timeout(Some(timeout)))
+        .map_err(|e| format_err!(ErrorKind::IoError, "{}", e))?;
+    let connection = SecretConnection::new(stream, clone_key(identity_key)?, Version::V0_34)
+        .map_err(|e| format_err!(ErrorKind::ProtocolError, "secret connection: {}", e))?;
+    let remote_peer_id = connection.remote_pubkey().peer_id();
+    let id = peers.iter().fold(None, |found, peer| match &peer.address {
+        net::Address::Tcp {
+            peer_id: Some(peer_id),
+            ..
+        } if peer_id.ct_eq(&remote_peer_id).unwrap_u8() == 1 => Some(peer.id),
+        _ => found,
+    });
+    match id {
+        Some(id) => Ok((id, connection)),
+        None => fail!(
+            ErrorKind::AccessError,
+            "unknown node peer ID: {}",
+            remote_peer_id
+        ),
+    }
+}
+
+/// Answers the requests of the other nodes (in the background)
+pub fn serve(
+    config: &ThresholdConfig,
+    identity_key: &ed25519::Keypair,
+    cosigner: Arc<Cosigner>,
+) -> Result<JoinHandle<()>, Error> {
+    let listener = TcpListener::bind(config.listen_addr).map_err(|e| {
+        format_err!(
+            ErrorKind::IoError,
+            "failed to listen on {}: {}",
+            config.listen_addr,
+            e
+        )
+    })?;
+    info!("threshold signing: listening on {}", config.listen_addr);
+    let identity_key = clone_key(identity_key)?;
+    let peers = config.peers.clone();
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("threshold signing: failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            let identity_key = match clone_key(&identity_key) {
+                Ok(identity_key) => identity_key,
+                Err(e) => {
+                    error!("threshold signing: {}", e);
+                    continue;
+                }
+            };
+            let peers = peers.clone();
+            let cosigner = cosigner.clone();
+            thread::spawn(move || {
+                let (id, connection) = match accept(stream, &identity_key, &peers, IDLE_TIMEOUT) {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        error!("threshold signing: connection refused: {}", e);
+                        return;
+                    }
+                };
+                debug!("threshold signing: node {} connected", id);
+                if let Err(e) = cosign(connection, &cosigner) {
+                    debug!("threshold signing: node {} disconnected: {}", id, e);
+                }
+            });
+        }
+    }))
+}
+
+/// Answers the requests of one node until it disconnects
+fn cosign(mut connection: SecretConnection<TcpStream>, cosigner: &Cosigner) -> Result<(), Error> {
+    let mut committed = None;
+    loop {
+        let request: PeerRequest = receive(&mut connection)?;
+        let response = match request {
+            PeerRequest::Commit {
+                chain_id,
+                sign_bytes,
+            } => match cosigner.commit(&chain_id, &sign_bytes) {
+                Ok(new_commitment) => {
+                    let commitments = new_commitment.commitments();
+                    committed = Some(new_commitment);
+                    PeerResponse::Commitments(Box::new(commitments))
+                }
+                Err(e) => {
+                    warn!("[{}] refused to commit: {}", &chain_id, e);
+                    committed = None;
+                    PeerResponse::Refused(e.to_string())
+                }
+            },
+            PeerRequest::Sign { signing_package } => match committed.take() {
+                Some(committed) => match cosigner.sign(committed, &signing_package) {
+                    Ok(share) => PeerResponse::SignatureShare(share),
+                    Err(e) => {
+                        warn!("refused to sign: {}", e);
+                        PeerResponse::Refused(e.to_string())
+                    }
+                },
+                None => PeerResponse::Refused("no commitments".to_owned()),
+            },
+        };
+        send(&mut connection, &response)?;
+    }
+}
+
+/// Another node (the connections to it are kept for the next signatures)
+pub(crate) struct Peer {
+    /// participant number
+    pub(crate) id: u16,
+    pub(crate) identifier: Identifier,
+    manager: ConnectionManager,
+    idle: Mutex<Vec<Box<dyn Connection>>>,
+}
+
+/// Error of a node that refused a request
+fn refused(id: u16, reason: String) -> Error {
+    format_err!(ErrorKind::SigningError, "node {} refused: {}", id, reason).into()
+}
+
+impl Peer {
+    fn request(
+        &self,
+        connection: &mut Box<dyn Connection>,
+        request: &PeerRequest,
+    ) -> Result<PeerResponse, Error> {
+        send(connection.as_mut(), request)?;
+        receive(connection.as_mut())
+    }
+
+    /// Sends the first round request (over a kept connection or a new one
+    /// if that one was closed); the connection is returned for the second round
+    pub(crate) fn commit(
+        &self,
+        request: &PeerRequest,
+    ) -> Result<(Box<dyn Connection>, SigningCommitments), Error> {
+        let idle = self.idle.lock().ok().and_then(|mut idle| idle.pop());
+        let (connection, response) = match idle {
+            Some(mut connection) => match self.request(&mut connection, request) {
+                Ok(response) => (connection, response),
+                Err(e) if e.kind() == &ErrorKind::IoError => {
+                    debug!("node {}: reconnecting: {}", self.id, e);
+                    let mut connection = self.manager.try_connect()?;
+                    let response = self.request(&mut connection, request)?;
+                    (connection, response)
+                }
+                Err(e) => return Err(e),
+            },
+            None => {
+                let mut connection = self.manager.try_connect()?;
+                let response = self.request(&mut connection, request)?;
+                (connection, response)
+            }
+        };
+        match response {
+            PeerResponse::Commitments(commitments) => Ok((connection, *commitments)),
+            PeerResponse::Refused(reason) => {
+                self.release(connection);
+                Err(refused(self.id, reason))
+            }
+            PeerResponse::SignatureShare(_) => {
+                fail!(
+                    ErrorKind::ProtocolError,
+                    "unexpected reply of node {}",
+                    self.id
+                )
+            }
+        }
+    }
+
+    /// Sends the second round request over the connection of the first round
+    pub(crate) fn sign(
+        &self,
+        mut connection: Box<dyn Connection>,
+        request: &PeerRequest,
+    ) -> Result<SignatureShare, Error> {
+        let response = self.request(&mut connection, request)?;
+        self.release(connection);
+        match response {
+            PeerResponse::SignatureShare(share) => Ok(share),
+            PeerResponse::Refused(reason) => Err(refused(self.id, reason)),
+            PeerResponse::Commitments(_) => {
+                fail!(
+                    ErrorKind::ProtocolError,
+                    "unexpected reply of node {}",
+                    self.id
+                )
+            }
+        }
+    }
+
+    /// Keeps the connection for the next signature
+    pub(crate) fn release(&self, connection: Box<dyn Connection>) {
+        if let Ok(mut idle) = self.idle.lock() {
+            idle.push(connection);
+        }
+    }
+}
+
+/// The other nodes
+pub struct Peers {
+    peers: Vec<Arc<Peer>>,
+    timeout: Duration,
+}
+
+impl Peers {
+    /// Connects to the configured nodes (when signing)
+    pub fn new(config: &ThresholdConfig, identity_key: &ed25519::Keypair) -> Result<Self, Error> {
+        config.validate()?;
+        let timeout = config.round_timeout();
+        let peers = config
+            .peers
+            .iter()
+            .map(|peer| {
+                Ok(Arc::new(Peer {
+                    id: peer.id,
+                    identifier: super::identifier(peer.id)?,
+                    manager: manager(peer, identity_key, timeout)?,
+                    idle: Mutex::new(Vec::new()),
+                }))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Self { peers, timeout })
+    }
+
+    /// How long to wait for the nodes in each round
+    pub(crate) fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Arc<Peer>> {
+        self.peers.iter()
+    }
+}
@@ -1,12 +1,15 @@
 use anomaly::format_err;
 use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
 use std::str::FromStr;
+use tendermint::PublicKey;
 use tracing::{debug, trace};
 
 use crate::error::{Error, ErrorKind::IoError};
+use crate::signer::ConsensusPublicKey;
 
 /// Options for displaying public key
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum PubkeyDisplay {
     Base64,
     Bech32,
@@ -24,17 +27,40 @@ impl FromStr for PubkeyDisplay {
     }
 }
 
+/// Amino prefix of Ed25519 public keys
+const AMINO_ED25519_PREFIX: [u8; 5] = [0x16, 0x24, 0xDE, 0x64, 0x20];
+
+/// Amino prefix of secp256k1 public keys
+const AMINO_SECP256K1_PREFIX: [u8; 5] = [0xEB, 0x5A, 0xE9, 0x87, 0x21];
+
+/// Amino prefix of BLS12-381 public keys
+#[cfg(feature = "bls")]
+const AMINO_BLS12_381_PREFIX: [u8; 5] = [0x6C, 0x33, 0x80, 0x95, 0x30];
+
 /// prints public key in the desired format
 pub fn print_pubkey(
     bech32_prefix: Option<String>,
     ptype: Option<PubkeyDisplay>,
-    public: ed25519_dalek::PublicKey,
+    public: ConsensusPublicKey,
 ) {
     match ptype {
         Some(PubkeyDisplay::Bech32) => {
             let prefix = bech32_prefix.unwrap_or_else(|| "cosmosvalconspub".to_owned());
-            let mut data = vec![0x16, 0x24, 0xDE, 0x64, 0x20];
-            data.extend_from_slice(public.as_bytes());
+            let mut data = match public {
+                ConsensusPublicKey::Tendermint(PublicKey::Ed25519(_)) => {
+                    AMINO_ED25519_PREFIX.to_vec()
+                }
+                ConsensusPublicKey::Tendermint(PublicKey::Secp256k1(_)) => {
+                    AMINO_SECP256K1_PREFIX.to_vec()
+                }
+                #[cfg(feature = "bls")]
+                ConsensusPublicKey::Bls12_381(_) => AMINO_BLS12_381_PREFIX.to_vec(),
+                ConsensusPublicKey::Tendermint(pk) => {
+                    eprintln!("no bech32 encoding for public key {:?}", pk);
+                    return;
+                }
+            };
+            data.extend_from_slice(&public.to_bytes());
             println!(
                 "public key: {}",
                 subtle_encoding::bech32::encode(prefix, data)
@@ -43,12 +69,49 @@ pub fn print_pubkey(
         _ => {
             println!(
                 "public key: {}",
-                String::from_utf8(subtle_encoding::base64::encode(public)).unwrap()
+                String::from_utf8(subtle_encoding::base64::encode(public.to_bytes())).unwrap()
             );
-            let id = tendermint::node::Id::from(public);
-            println!("address: {}", id);
+            match public {
+                ConsensusPublicKey::Tendermint(PublicKey::Ed25519(pk)) => {
+                    let id = tendermint::node::Id::from(pk);
+                    println!("address: {}", id);
+                }
+                _ => {
+                    if let Some(id) = public.address() {
+                        println!("address: {}", id);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Resolves the path (symbolic links, `.` and `..` included) so that the configured file paths
+/// can be compared, also when the file doesn't exist yet
+/// (then only its existing ancestors are resolved and the rest is normalized)
+pub fn canonical_path(path: &Path) -> PathBuf {
+    let absolute = match std::env::current_dir() {
+        Ok(dir) => dir.join(path),
+        Err(_) => path.to_path_buf(),
+    };
+    let mut resolved = PathBuf::new();
+    let mut exists = true;
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => continue,
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            component => resolved.push(component),
+        }
+        if exists {
+            match resolved.canonicalize() {
+                Ok(path) => resolved = path,
+                Err(_) => exists = false,
+            }
         }
     }
+    resolved
 }
 
 /// Read u16-size payload (for vsock)